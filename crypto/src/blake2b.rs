@@ -1,6 +1,8 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::io::{self, Read};
+
 use sodiumoxide::crypto::generichash::State;
 use failure::Fail;
 
@@ -26,6 +28,23 @@ pub fn digest_128(data: &[u8]) -> Vec<u8> {
         .expect("Blake2b unexpectedly failed on correct digest length")
 }
 
+/// Generate digest of length 256 bits (32bytes) from all data of the reader, data are read incrementally,
+/// so it is suitable also for big files
+pub fn digest_256_reader<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut hasher = State::new(32, None).expect("Blake2b unexpectedly failed on correct digest length");
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]).expect("Failed to update hasher state");
+    }
+
+    let hash = hasher.finalize().unwrap();
+    Ok(hash.as_ref().to_vec())
+}
+
 /// Arbitrary Blake2b digest generation from generic data.
 // Should be noted, that base Blake2b supports arbitrary digest length from 16 to 64 bytes
 fn digest(data: &[u8], out_len: usize) -> Result<Vec<u8>, Blake2bLengthError> {
@@ -49,6 +68,12 @@ mod tests {
         assert_eq!(expected, hash)
     }
 
+    #[test]
+    fn blake2b_256_reader() {
+        let data = vec![7u8; 100_000];
+        assert_eq!(digest_256(&data), digest_256_reader(&mut &data[..]).unwrap());
+    }
+
     #[test]
     fn blake2b_128() {
         let hash = digest_128(b"hello world");
@@ -0,0 +1,89 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Proof of work of the peer identity, compatible with the `Crypto_box` of the OCaml node.
+//!
+//! Stamp is valid, if blake2b digest of the public key followed by the stamp (read as a little-endian number)
+//! is not greater than the target derived from the expected difficulty.
+
+use failure::Fail;
+use num_bigint::BigUint;
+use num_traits::One;
+
+use crate::blake2b;
+
+/// Size of the proof of work stamp in bytes
+pub const POW_STAMP_SIZE: usize = 24;
+
+#[derive(Debug, Fail)]
+pub enum PowError {
+    #[fail(display = "Proof of work difficulty must be in range [0, 256], but was: {}", difficulty)]
+    InvalidDifficulty {
+        difficulty: f64,
+    },
+}
+
+/// Highest digest value of a valid proof of work
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowTarget(BigUint);
+
+impl PowTarget {
+    /// Create target from the expected difficulty (`expected_pow`), roughly the number of leading zero bits of the digest
+    pub fn new(difficulty: f64) -> Result<Self, PowError> {
+        if !(0.0..=256.0).contains(&difficulty) {
+            return Err(PowError::InvalidDifficulty { difficulty });
+        }
+
+        let shift = difficulty.trunc() as usize;
+        let frac = difficulty.fract();
+        let mantissa = BigUint::from(if frac == 0.0 {
+            (1u64 << 48) - 1
+        } else {
+            2f64.powf(48.0 - frac) as u64
+        });
+
+        let target = if shift < 208 {
+            let shift = 256 - 48 - shift;
+            (mantissa << shift) + ((BigUint::one() << shift) - 1u32)
+        } else {
+            mantissa >> (shift - 208)
+        };
+        Ok(PowTarget(target))
+    }
+}
+
+/// Check, that the proof of work stamp of the public key satisfies the target
+pub fn check_proof_of_work(public_key: &[u8], stamp: &[u8], target: &PowTarget) -> bool {
+    let mut data = Vec::with_capacity(public_key.len() + stamp.len());
+    data.extend_from_slice(public_key);
+    data.extend_from_slice(stamp);
+    BigUint::from_bytes_le(&blake2b::digest_256(&data)) <= target.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pow_target_bounds() -> Result<(), failure::Error> {
+        assert_eq!(PowTarget((BigUint::one() << 256) - 1u32), PowTarget::new(0.0)?);
+        assert_eq!(PowTarget(BigUint::from(0u32)), PowTarget::new(256.0)?);
+        assert!(PowTarget::new(-1.0).is_err());
+        assert!(PowTarget::new(256.5).is_err());
+        assert!(PowTarget::new(f64::NAN).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_identity_pow() -> Result<(), failure::Error> {
+        let public_key = hex::decode("5fd7ba1d15650abc9a510c37a8bee566b708fe6577f0d832b903e8f13d71c94a")?;
+        let stamp = hex::decode("4b1354dcfc087e52c8fb510317b9464c297b8a55b79bfc95")?;
+        assert_eq!(POW_STAMP_SIZE, stamp.len());
+
+        assert!(check_proof_of_work(&public_key, &stamp, &PowTarget::new(0.0)?));
+        assert!(check_proof_of_work(&public_key, &stamp, &PowTarget::new(26.0)?));
+        assert!(!check_proof_of_work(&public_key, &stamp, &PowTarget::new(27.0)?));
+        assert!(!check_proof_of_work(&public_key, &[0; POW_STAMP_SIZE], &PowTarget::new(26.0)?));
+        Ok(())
+    }
+}
@@ -13,7 +13,11 @@ use clap::{App, Arg};
 
 use shell::peer_manager::P2p;
 use shell::PeerConnectionThreshold;
-use storage::persistent::{DbConfiguration, DbConfigurationBuilder};
+use storage::history::{HistoryConfiguration, HistoryMode};
+use storage::persistent::{ColumnTuning, DbConfiguration, DbConfigurationBuilder, DbProfile};
+use storage::persistent::tuning::parse_size;
+use storage::context_trace::TraceFormat;
+use storage::snapshot::SnapshotMode;
 use tezos_api::environment;
 use tezos_api::environment::TezosEnvironment;
 use tezos_api::ffi::PatchContext;
@@ -39,7 +43,64 @@ pub struct Storage {
     pub db_path: PathBuf,
     pub tezos_data_dir: PathBuf,
     pub store_context_actions: bool,
+    pub context_hash_check: bool,
     pub patch_context: Option<PatchContext>,
+    pub snapshot: Option<Snapshot>,
+    /// Backups requested by the RPC are created in this directory
+    pub backup_dir: Option<PathBuf>,
+    /// Restore backup from the directory to the empty database on startup
+    pub restore_backup: Option<PathBuf>,
+    /// Run just read-only RPC server on the database of another running node, replica keeps its own files in this directory
+    pub replica_path: Option<PathBuf>,
+    pub history: HistoryConfiguration,
+    pub migration_dry_run: bool,
+    pub storage_check: Option<StorageCheck>,
+    pub compact_commit_log: bool,
+    /// Verify that generated sequence numbers (context action and skip list ids) were not used before
+    pub sequence_audit: bool,
+    pub context_trace: Option<ContextTrace>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ContextTrace {
+    /// Record context actions received from the protocol to the file
+    Record {
+        path: PathBuf,
+        format: TraceFormat,
+    },
+    /// Export stored context actions of the blocks in the level range to the file and exit
+    Export {
+        path: PathBuf,
+        format: TraceFormat,
+        from_level: i32,
+        to_level: i32,
+    },
+    /// Replay trace from the file to the context storage and exit
+    Replay {
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Snapshot {
+    /// Export snapshot of the block (current head, if not set) to the file and exit
+    Export {
+        path: PathBuf,
+        block_hash: Option<String>,
+        mode: SnapshotMode,
+    },
+    /// Import snapshot from the file to the empty database on startup
+    Import {
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageCheck {
+    /// Just report found storage issues and exit
+    Report,
+    /// Repair found storage issues and exit
+    Repair,
 }
 
 #[derive(Debug, Clone)]
@@ -91,6 +152,18 @@ macro_rules! parse_validator_fn {
     ($t:ident, $err:expr) => {|v| if v.parse::<$t>().is_ok() { Ok(()) } else { Err($err.to_string()) } }
 }
 
+/// Parses `<column family name>:<options>`, see [ColumnTuning] for the options format
+fn parse_column_override(value: &str) -> Result<(String, ColumnTuning), String> {
+    let mut parts = value.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(options)) if !name.is_empty() => {
+            let tuning = options.parse::<ColumnTuning>().map_err(|e| e.to_string())?;
+            Ok((name.to_string(), tuning))
+        }
+        _ => Err(format!("Value must be in format <column family name>:<options>, got: {}", value)),
+    }
+}
+
 // Creates tezos app
 pub fn tezos_app() -> App<'static, 'static> {
     // Default values for arguments are specidied in default configuration file
@@ -143,8 +216,11 @@ pub fn tezos_app() -> App<'static, 'static> {
             .long("identity-expected-pow")
             .takes_value(true)
             .value_name("NUM")
-            .help("Expected power of identity for node. It is used to generate new identity. Default: 26.0")
-            .validator(parse_validator_fn!(f64, "Value must be a valid f64 number for expected_pow")))
+            .help("Expected power of identity for node. It is used to generate new identity and remote peers with lower proof of work are rejected. Default: 26.0")
+            .validator(|v| match v.parse::<f64>() {
+                Ok(expected_pow) if (0.0..=256.0).contains(&expected_pow) => Ok(()),
+                _ => Err("Value must be a valid f64 number in range [0, 256] for expected_pow".to_string()),
+            }))
         .arg(Arg::with_name("bootstrap-db-path")
             .long("bootstrap-db-path")
             .takes_value(true)
@@ -157,6 +233,189 @@ pub fn tezos_app() -> App<'static, 'static> {
             .value_name("NUM")
             .help("Max number of threads used by database configuration. If not specified, then number of threads equal to CPU cores.")
             .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("db-cfg-profile")
+            .long("db-cfg-profile")
+            .takes_value(true)
+            .possible_values(&["default", "ssd", "hdd", "low-memory"])
+            .help("Preset of the database tuning, explicitly set --db-cfg-* options have priority over the preset. Default: default"))
+        .arg(Arg::with_name("db-cfg-block-cache-size")
+            .long("db-cfg-block-cache-size")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Size of the block cache shared by all column families, e.g. 512M. Default: according to --db-cfg-profile")
+            .validator(|v| parse_size(&v).map(|_| ()).map_err(|e| e.to_string())))
+        .arg(Arg::with_name("db-cfg-column-tuning")
+            .long("db-cfg-column-tuning")
+            .takes_value(true)
+            .value_name("OPTIONS")
+            .help("Options of all column families, e.g. compression=zstd,bloom_filter_bits=10,block_size=16K,write_buffer_size=64M,max_write_buffer_number=4.
+                       Supported compressions: none, snappy, lz4, zstd")
+            .validator(parse_validator_fn!(ColumnTuning, "Value must be a comma separated list of <option>=<value>")))
+        .arg(Arg::with_name("db-cfg-column")
+            .long("db-cfg-column")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NAME:OPTIONS")
+            .help("Options of one column family with priority over --db-cfg-column-tuning, e.g. block_storage:compression=zstd. Can be used multiple times")
+            .validator(|v| parse_column_override(&v).map(|_| ())))
+        .arg(Arg::with_name("db-cfg-rate-limit")
+            .long("db-cfg-rate-limit")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Limit of the database flush and compaction writes per second, e.g. 64M. Default: no limit")
+            .validator(|v| parse_size(&v).map(|_| ()).map_err(|e| e.to_string())))
+        .arg(Arg::with_name("db-cfg-wal-dir")
+            .long("db-cfg-wal-dir")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Directory of the database write-ahead log, e.g. on the faster disk. Default: in the --bootstrap-db-path
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("db-cfg-max-total-wal-size")
+            .long("db-cfg-max-total-wal-size")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Column families are flushed, when the database write-ahead log gets bigger, e.g. 1G. Default: according to RocksDB")
+            .validator(|v| parse_size(&v).map(|_| ()).map_err(|e| e.to_string())))
+        .arg(Arg::with_name("db-cfg-statistics")
+            .long("db-cfg-statistics")
+            .takes_value(true)
+            .value_name("BOOL")
+            .help("Collect database statistics, which are needed for the block cache hit ratio in storage stats, but slow down the database a bit. Default: false")
+            .validator(parse_validator_fn!(bool, "Value must be a valid bool")))
+        .arg(Arg::with_name("snapshot-export")
+            .long("snapshot-export")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with("snapshot-import")
+            .help("Export chain data snapshot to the file and exit. Snapshot is created for --snapshot-block or for the current head.
+                       Protocol context is exported to the directory <PATH>.context.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("snapshot-block")
+            .long("snapshot-block")
+            .takes_value(true)
+            .value_name("HASH")
+            .requires("snapshot-export")
+            .help("Hash of the applied block, for which snapshot is exported. Default: current head"))
+        .arg(Arg::with_name("snapshot-rolling")
+            .long("snapshot-rolling")
+            .takes_value(true)
+            .value_name("NUM")
+            .requires("snapshot-export")
+            .help("Export rolling snapshot with just last NUM blocks. If not specified, full snapshot (down to the genesis) is exported")
+            .validator(parse_validator_fn!(usize, "Value must be a valid number")))
+        .arg(Arg::with_name("snapshot-import")
+            .long("snapshot-import")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Import chain data snapshot from the file to the empty --bootstrap-db-path on startup.
+                       Protocol context is imported from the directory <PATH>.context, if it is missing, it has to be already in the --tezos-data-dir.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("backup-dir")
+            .long("backup-dir")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Enables online backups of the running node (RPC /dev/backup), every backup is created in its own subdirectory of PATH.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("restore-backup")
+            .long("restore-backup")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with("snapshot-import")
+            .help("Verify the backup directory and restore it to the empty --bootstrap-db-path and --tezos-data-dir on startup.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("replica-path")
+            .long("replica-path")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with_all(&["snapshot-import", "snapshot-export", "restore-backup"])
+            .help("Run read-only replica of the node running on the --bootstrap-db-path, replica serves just RPC and keeps its own files in PATH.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("db-migration-dry-run")
+            .long("db-migration-dry-run")
+            .takes_value(true)
+            .value_name("BOOL")
+            .help("Just report, what would be changed by the migration of the database to the current version, and exit. Default: false"))
+        .arg(Arg::with_name("storage-check")
+            .long("storage-check")
+            .takes_value(true)
+            .possible_values(&["report", "repair"])
+            .help("Check integrity of the stored blocks, operations and context, and exit.
+                       report - found issues are just logged,
+                       repair - found issues are also repaired (e.g. blocks with missing data are marked to be applied again)"))
+        .arg(Arg::with_name("compact-commit-log")
+            .long("compact-commit-log")
+            .takes_value(true)
+            .value_name("BOOL")
+            .help("Rewrite block storage commit log without records, which are not referenced anymore (e.g. removed by history pruning), and exit. Default: false"))
+        .arg(Arg::with_name("sequence-audit")
+            .long("sequence-audit")
+            .takes_value(true)
+            .value_name("BOOL")
+            .help("Verify that every generated sequence number (context action and skip list ids) was not used before, reuse fails the write. Default: false"))
+        .arg(Arg::with_name("context-trace-record")
+            .long("context-trace-record")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with_all(&["context-trace-export", "context-trace-replay"])
+            .help("Record all context actions received from the protocol to the trace file, which can be replayed by --context-trace-replay.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("context-trace-export")
+            .long("context-trace-export")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with("context-trace-replay")
+            .requires("context-trace-to-level")
+            .help("Export stored context actions (see --store-context-actions) of the blocks from --context-trace-from-level to --context-trace-to-level to the trace file and exit.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("context-trace-from-level")
+            .long("context-trace-from-level")
+            .takes_value(true)
+            .value_name("NUM")
+            .requires("context-trace-export")
+            .help("Level of the first exported block. Default: 0")
+            .validator(parse_validator_fn!(i32, "Value must be a valid number")))
+        .arg(Arg::with_name("context-trace-to-level")
+            .long("context-trace-to-level")
+            .takes_value(true)
+            .value_name("NUM")
+            .requires("context-trace-export")
+            .help("Level of the last exported block")
+            .validator(parse_validator_fn!(i32, "Value must be a valid number")))
+        .arg(Arg::with_name("context-trace-format")
+            .long("context-trace-format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .possible_values(&["binary", "json"])
+            .help("Format of the recorded or exported trace file, json format has one record per line. Default: binary"))
+        .arg(Arg::with_name("context-trace-replay")
+            .long("context-trace-replay")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Replay context actions from the trace file to the context storage without the protocol and exit.
+                       Storage has to be empty (for trace starting at the genesis) or has to contain contexts of all blocks preceding the trace.
+                       In case it starts with ./ or ../, it is relative path to the current dir, otherwise to the --tezos-data-dir"))
+        .arg(Arg::with_name("history-mode")
+            .long("history-mode")
+            .takes_value(true)
+            .possible_values(&["archive", "full", "rolling"])
+            .help("How much of the chain history is kept in the database.
+                       archive - everything is kept,
+                       full - context and context actions older than --history-keep-cycles are removed,
+                       rolling - also blocks and operations older than --history-keep-cycles are removed.
+                       Default: archive"))
+        .arg(Arg::with_name("history-keep-cycles")
+            .long("history-keep-cycles")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Number of cycles (before the cycle of the current head), which are kept in full/rolling history mode. Default: 5")
+            .validator(parse_validator_fn!(u16, "Value must be a valid number")))
+        .arg(Arg::with_name("history-blocks-per-cycle")
+            .long("history-blocks-per-cycle")
+            .takes_value(true)
+            .value_name("NUM")
+            .help("Number of blocks in one cycle used for history pruning. Default: according to --network (mainnet: 4096)")
+            .validator(parse_validator_fn!(u16, "Value must be a valid number")))
         .arg(Arg::with_name("bootstrap-lookup-address")
             .long("bootstrap-lookup-address")
             .takes_value(true)
@@ -311,6 +570,11 @@ pub fn tezos_app() -> App<'static, 'static> {
             .takes_value(true)
             .value_name("BOOL")
             .help("Activate recording of context storage actions"))
+        .arg(Arg::with_name("context-hash-check")
+            .long("context-hash-check")
+            .takes_value(true)
+            .value_name("BOOL")
+            .help("Store context also to the merkle storage and verify, that calculated context hashes match hashes from protocol. On mismatch, no further block is applied. Has to be enabled on empty storage."))
         .arg(Arg::with_name("sandbox-patch-context-json-file")
             .long("sandbox-patch-context-json-file")
             .takes_value(true)
@@ -382,6 +646,16 @@ pub fn get_final_path(tezos_data_dir: &PathBuf, path: PathBuf) -> PathBuf {
     final_path
 }
 
+// Returns number of blocks per cycle of the network, used by history pruning, when not set explicitly
+fn default_blocks_per_cycle(tezos_network: &TezosEnvironment) -> i32 {
+    match tezos_network {
+        TezosEnvironment::Mainnet => 4096,
+        TezosEnvironment::Alphanet | TezosEnvironment::Babylonnet | TezosEnvironment::Carthagenet => 2048,
+        TezosEnvironment::Zeronet => 128,
+        TezosEnvironment::Sandbox => 8,
+    }
+}
+
 // Parses config file and returns vector of OsString representing all argument strings from file
 // All lines that are empty or begin with "#" or "//" are ignored
 pub fn parse_config(config_path: PathBuf) -> Vec<OsString> {
@@ -548,6 +822,33 @@ impl Environment {
                             .expect("Provided value cannot be converted to number");
                         db_cfg.max_threads(Some(max_treads));
                     }
+                    if let Some(value) = args.value_of("db-cfg-profile") {
+                        db_cfg.profile(value.parse::<DbProfile>().expect("Was expecting one value from DbProfile"));
+                    }
+                    if let Some(value) = args.value_of("db-cfg-block-cache-size") {
+                        db_cfg.block_cache_size(Some(parse_size(value).expect("Provided value cannot be converted to size")));
+                    }
+                    if let Some(value) = args.value_of("db-cfg-column-tuning") {
+                        db_cfg.column_tuning(value.parse::<ColumnTuning>().expect("Provided value cannot be converted to column tuning"));
+                    }
+                    if let Some(values) = args.values_of("db-cfg-column") {
+                        db_cfg.column_overrides(values
+                            .map(|value| parse_column_override(value).expect("Provided value cannot be converted to column tuning"))
+                            .collect());
+                    }
+                    if let Some(value) = args.value_of("db-cfg-rate-limit") {
+                        db_cfg.rate_limit(Some(parse_size(value).expect("Provided value cannot be converted to size")));
+                    }
+                    if let Some(value) = args.value_of("db-cfg-wal-dir") {
+                        let wal_dir = value.parse::<PathBuf>().expect("Provided value cannot be converted to path");
+                        db_cfg.wal_dir(Some(get_final_path(&data_dir, wal_dir)));
+                    }
+                    if let Some(value) = args.value_of("db-cfg-max-total-wal-size") {
+                        db_cfg.max_total_wal_size(Some(parse_size(value).expect("Provided value cannot be converted to size") as u64));
+                    }
+                    if let Some(value) = args.value_of("db-cfg-statistics") {
+                        db_cfg.statistics(value.parse::<bool>().expect("Provided value cannot be converted to bool"));
+                    }
 
                     db_cfg.build().unwrap()
                 },
@@ -562,6 +863,10 @@ impl Environment {
                     .unwrap_or("true")
                     .parse::<bool>()
                     .expect("Provided value cannot be converted to bool"),
+                context_hash_check: args.value_of("context-hash-check")
+                    .unwrap_or("false")
+                    .parse::<bool>()
+                    .expect("Provided value cannot be converted to bool"),
                 patch_context: {
                     match args.value_of("sandbox-patch-context-json-file") {
                         Some(path) => {
@@ -578,6 +883,97 @@ impl Environment {
                         None => None
                     }
                 },
+                snapshot: {
+                    if let Some(path) = args.value_of("snapshot-export") {
+                        let path = path.parse::<PathBuf>().expect("Provided value cannot be converted to path");
+                        Some(crate::configuration::Snapshot::Export {
+                            path: get_final_path(&data_dir, path),
+                            block_hash: args.value_of("snapshot-block").map(|block_hash| block_hash.to_string()),
+                            mode: match args.value_of("snapshot-rolling") {
+                                Some(value) => SnapshotMode::Rolling(value.parse::<usize>().expect("Provided value cannot be converted to number")),
+                                None => SnapshotMode::Full,
+                            },
+                        })
+                    } else if let Some(path) = args.value_of("snapshot-import") {
+                        let path = path.parse::<PathBuf>().expect("Provided value cannot be converted to path");
+                        Some(crate::configuration::Snapshot::Import {
+                            path: get_final_path(&data_dir, path),
+                        })
+                    } else {
+                        None
+                    }
+                },
+                backup_dir: args.value_of("backup-dir")
+                    .map(|path| get_final_path(&data_dir, path.parse::<PathBuf>().expect("Provided value cannot be converted to path"))),
+                restore_backup: args.value_of("restore-backup")
+                    .map(|path| get_final_path(&data_dir, path.parse::<PathBuf>().expect("Provided value cannot be converted to path"))),
+                replica_path: args.value_of("replica-path")
+                    .map(|path| get_final_path(&data_dir, path.parse::<PathBuf>().expect("Provided value cannot be converted to path"))),
+                history: HistoryConfiguration {
+                    mode: args.value_of("history-mode")
+                        .unwrap_or("archive")
+                        .parse::<HistoryMode>()
+                        .expect("Was expecting one value from HistoryMode"),
+                    keep_cycles: args.value_of("history-keep-cycles")
+                        .unwrap_or("5")
+                        .parse::<u16>()
+                        .expect("Provided value cannot be converted to number") as i32,
+                    blocks_per_cycle: match args.value_of("history-blocks-per-cycle") {
+                        Some(value) => value.parse::<u16>().expect("Provided value cannot be converted to number") as i32,
+                        None => default_blocks_per_cycle(&tezos_network),
+                    },
+                },
+                migration_dry_run: args.value_of("db-migration-dry-run")
+                    .unwrap_or("false")
+                    .parse::<bool>()
+                    .expect("Provided value cannot be converted to bool"),
+                storage_check: match args.value_of("storage-check") {
+                    Some("report") => Some(StorageCheck::Report),
+                    Some("repair") => Some(StorageCheck::Repair),
+                    _ => None,
+                },
+                compact_commit_log: args.value_of("compact-commit-log")
+                    .unwrap_or("false")
+                    .parse::<bool>()
+                    .expect("Provided value cannot be converted to bool"),
+                sequence_audit: args.value_of("sequence-audit")
+                    .unwrap_or("false")
+                    .parse::<bool>()
+                    .expect("Provided value cannot be converted to bool"),
+                context_trace: {
+                    let format = args.value_of("context-trace-format")
+                        .unwrap_or("binary")
+                        .parse::<TraceFormat>()
+                        .expect("Was expecting one value from TraceFormat");
+                    if let Some(path) = args.value_of("context-trace-record") {
+                        let path = path.parse::<PathBuf>().expect("Provided value cannot be converted to path");
+                        Some(crate::configuration::ContextTrace::Record {
+                            path: get_final_path(&data_dir, path),
+                            format,
+                        })
+                    } else if let Some(path) = args.value_of("context-trace-export") {
+                        let path = path.parse::<PathBuf>().expect("Provided value cannot be converted to path");
+                        Some(crate::configuration::ContextTrace::Export {
+                            path: get_final_path(&data_dir, path),
+                            format,
+                            from_level: args.value_of("context-trace-from-level")
+                                .unwrap_or("0")
+                                .parse::<i32>()
+                                .expect("Provided value cannot be converted to number"),
+                            to_level: args.value_of("context-trace-to-level")
+                                .unwrap_or("0")
+                                .parse::<i32>()
+                                .expect("Provided value cannot be converted to number"),
+                        })
+                    } else if let Some(path) = args.value_of("context-trace-replay") {
+                        let path = path.parse::<PathBuf>().expect("Provided value cannot be converted to path");
+                        Some(crate::configuration::ContextTrace::Replay {
+                            path: get_final_path(&data_dir, path),
+                        })
+                    } else {
+                        None
+                    }
+                },
             },
             identity: crate::configuration::Identity {
                 identity_json_file_path: {
@@ -6,10 +6,13 @@ use std::sync::atomic::Ordering;
 use std::thread;
 use std::time::Duration;
 use std::fs;
+use std::path::PathBuf;
 
 use riker::actors::*;
 use slog::{crit, debug, Drain, error, info, Logger};
 
+use crypto::hash::HashType;
+use crypto::proof_of_work::PowTarget;
 use logging::detailed_json;
 use logging::file::FileAppenderBuilder;
 use monitoring::{Monitor, WebsocketHandler};
@@ -20,11 +23,20 @@ use shell::chain_manager::ChainManager;
 use shell::context_listener::ContextListener;
 use shell::mempool_prevalidator::MempoolPrevalidator;
 use shell::peer_manager::PeerManager;
-use shell::shell_channel::{ShellChannel, ShellChannelTopic, ShuttingDown};
-use storage::{block_storage, BlockMetaStorage, BlockStorage, ChainMetaStorage, check_database_compatibility, context_action_storage, ContextActionStorage, MempoolStorage, OperationsMetaStorage, OperationsStorage, resolve_storage_init_chain_data, StorageInitInfo, SystemStorage};
-use storage::persistent::{CommitLogSchema, KeyValueSchema, open_cl, open_kv, PersistentStorage};
-use storage::persistent::sequence::Sequences;
-use storage::skip_list::{DatabaseBackedSkipList, Lane, ListValue};
+use shell::shell_channel::{BlockApplied, ShellChannel, ShellChannelMsg, ShellChannelTopic, ShuttingDown};
+use shell::storage_pruner::StoragePruner;
+use storage::{BlockStorage, BlockStorageReader, ChainMetaStorage, check_database_compatibility, database_schemas, resolve_storage_init_chain_data, StorageInitInfo};
+use storage::backup::{BackupConfiguration, restore_backup};
+use storage::commit_log_maintenance::{compact_commit_log, recover_commit_log};
+use storage::context_trace::{ContextReplayer, ContextTraceReader, ContextTraceWriter, export_context_trace};
+use storage::fsck::check_storage;
+use storage::history::HistoryMode;
+use storage::migration::{database_migrations, Migrator};
+use storage::persistent::{CommitLogSchema, DbTuning, open_cl, open_cl_read_only, open_kv, open_kv_secondary, PersistentStorage};
+use storage::chain_meta_storage::ChainMetaStorageReader;
+use storage::persistent::secondary::spawn_catch_up;
+use storage::sequence_recovery::recover_sequences;
+use storage::snapshot::{export_snapshot, import_snapshot};
 use tezos_api::environment;
 use tezos_api::environment::TezosEnvironmentConfiguration;
 use tezos_api::ffi::TezosRuntimeConfiguration;
@@ -33,15 +45,19 @@ use tezos_messages::p2p::encoding::version::NetworkVersion;
 use tezos_wrapper::{TezosApiConnectionPool, TezosApiConnectionPoolConfiguration};
 use tezos_wrapper::service::{ExecutableProtocolRunner, ProtocolEndpointConfiguration, ProtocolRunnerEndpoint};
 
-use crate::configuration::LogFormat;
+use crate::configuration::{ContextTrace, LogFormat, Snapshot, StorageCheck};
 
 mod configuration;
 mod identity;
 mod system;
 
-const DATABASE_VERSION: i64 = 15;
+const DATABASE_VERSION: i64 = 20;
 const SUPPORTED_DISTRIBUTED_DB_VERSION: u16 = 0;
 const SUPPORTED_P2P_VERSION: u16 = 1;
+/// Remote peers announcing older p2p version are rejected
+const MIN_SUPPORTED_P2P_VERSION: u16 = 0;
+/// How often read-only replica catches up with the node writing to the database
+const REPLICA_CATCH_UP_INTERVAL: Duration = Duration::from_secs(1);
 
 macro_rules! shutdown_and_exit {
     ($err:expr, $sys:ident) => {{
@@ -150,6 +166,35 @@ fn create_tezos_writeable_api_pool(env: &crate::configuration::Environment, tezo
     )
 }
 
+/// Export or import snapshot, if requested.
+/// Returns true, if node should continue with bootstrap, false means, that node should exit
+fn process_snapshot(
+    env: &crate::configuration::Environment,
+    init_storage_data: &StorageInitInfo,
+    persistent_storage: &PersistentStorage,
+    log: &Logger) -> Result<bool, failure::Error> {
+    match &env.storage.snapshot {
+        Some(Snapshot::Export { path, block_hash, mode }) => {
+            let block_hash = match block_hash {
+                Some(block_hash) => HashType::BlockHash.string_to_bytes(block_hash)?,
+                None => ChainMetaStorage::new(persistent_storage)
+                    .get_current_head(&init_storage_data.chain_id)?
+                    .map(|head| head.hash)
+                    .ok_or_else(|| failure::format_err!("Current head is not set, there is nothing to export"))?,
+            };
+            info!(log, "Exporting snapshot"; "block_hash" => HashType::BlockHash.bytes_to_string(&block_hash), "path" => format!("{:?}", path));
+            export_snapshot(persistent_storage, &env.storage.tezos_data_dir, &block_hash, *mode, path, log)?;
+            Ok(false)
+        }
+        Some(Snapshot::Import { path }) => {
+            info!(log, "Importing snapshot"; "path" => format!("{:?}", path));
+            import_snapshot(persistent_storage, &env.storage.tezos_data_dir, &init_storage_data.chain_id, path, log)?;
+            Ok(true)
+        }
+        None => Ok(true)
+    }
+}
+
 fn block_on_actors(
     env: crate::configuration::Environment,
     tezos_env: &TezosEnvironmentConfiguration,
@@ -210,12 +255,23 @@ fn block_on_actors(
         .expect("Failed to create shell channel");
 
     // it's important to start ContextListener before ChainFeeder, because chain_feeder can trigger init_genesis which sends ContextAction, and we need to process this action first
-    let _ = ContextListener::actor(&actor_system, &persistent_storage, apply_block_protocol_events.expect("Context listener needs event server"), log.clone(), env.storage.store_context_actions)
+    let context_trace = match &env.storage.context_trace {
+        Some(ContextTrace::Record { path, format }) => match ContextTraceWriter::append(path, *format) {
+            Ok(writer) => Some(writer),
+            Err(e) => shutdown_and_exit!(error!(log, "Failed to create context trace file"; "path" => format!("{:?}", path), "reason" => e), actor_system),
+        },
+        _ => None,
+    };
+    let _ = ContextListener::actor(&actor_system, &persistent_storage, apply_block_protocol_events.expect("Context listener needs event server"), log.clone(), env.storage.store_context_actions, env.storage.context_hash_check, context_trace)
         .expect("Failed to create context event listener");
     let _ = ChainFeeder::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, &tezos_env, apply_block_protocol_commands, log.clone())
         .expect("Failed to create chain feeder");
     let _ = ChainManager::actor(&actor_system, network_channel.clone(), shell_channel.clone(), &persistent_storage, &init_storage_data.chain_id, is_sandbox, &env.p2p.peer_threshold)
         .expect("Failed to create chain manager");
+    if env.storage.history.mode != HistoryMode::Archive {
+        let _ = StoragePruner::actor(&actor_system, shell_channel.clone(), &persistent_storage, &init_storage_data, env.storage.history.clone(), log.clone())
+            .expect("Failed to create storage pruner");
+    }
 
     let _ = MempoolPrevalidator::actor(
         &actor_system,
@@ -233,8 +289,11 @@ fn block_on_actors(
         shell_channel.clone(),
         tokio_runtime.handle().clone(),
         identity,
+        PowTarget::new(env.identity.expected_pow).expect("Invalid expected proof of work"),
         network_version.clone(),
+        MIN_SUPPORTED_P2P_VERSION,
         env.p2p.clone(),
+        &persistent_storage,
     ).expect("Failed to create peer manager");
     let websocket_handler = WebsocketHandler::actor(&actor_system, env.rpc.websocket_address, log.clone())
         .expect("Failed to start websocket actor");
@@ -250,6 +309,11 @@ fn block_on_actors(
         tezos_env.clone(),
         network_version,
         &init_storage_data,
+        env.storage.backup_dir.as_ref().map(|backup_dir| BackupConfiguration {
+            db_path: env.storage.db_path.clone(),
+            tezos_data_dir: env.storage.tezos_data_dir.clone(),
+            backup_dir: backup_dir.clone(),
+        }),
         is_sandbox,
     ).expect("Failed to create RPC server");
 
@@ -304,6 +368,104 @@ fn block_on_actors(
     });
 }
 
+/// Run read-only replica of the node writing to the `--bootstrap-db-path`, replica serves just RPC.
+/// Database is opened as secondary instance and caught up periodically, new current head is announced to the RPC server after the catch-up.
+fn block_on_replica(
+    env: crate::configuration::Environment,
+    tezos_env: &TezosEnvironmentConfiguration,
+    db_tuning: &DbTuning,
+    replica_path: PathBuf,
+    actor_system: ActorSystem,
+    log: Logger) {
+    let rocks_db = match open_kv_secondary(&env.storage.db_path, replica_path.join("db"), database_schemas(db_tuning), &env.storage.db_cfg) {
+        Ok(db) => Arc::new(db),
+        Err(e) => shutdown_and_exit!(error!(log, "Failed to open RocksDB database as secondary instance"; "path" => format!("{:?}", &env.storage.db_path), "reason" => format!("{}", e)), actor_system)
+    };
+    let commit_logs = match open_cl_read_only(&env.storage.db_path, replica_path.join("commit_logs"), vec![BlockStorage::descriptor()]) {
+        Ok(commit_logs) => Arc::new(commit_logs),
+        Err(e) => shutdown_and_exit!(error!(log, "Failed to open commit logs"; "reason" => e), actor_system)
+    };
+    let persistent_storage = PersistentStorage::new_secondary(rocks_db, commit_logs);
+    let init_storage_data = match resolve_storage_init_chain_data(
+        &tezos_env,
+        &env.storage.db_path,
+        &env.storage.tezos_data_dir,
+        &env.storage.patch_context,
+        &log) {
+        Ok(init_data) => init_data,
+        Err(e) => shutdown_and_exit!(error!(log, "Failed to resolve init storage chain data. Reason: {}", e), actor_system),
+    };
+
+    let tezos_readonly_api = Arc::new(create_tezos_readonly_api_pool(&env, tezos_env.clone(), log.clone()));
+    let mut tokio_runtime = create_tokio_runtime(&env);
+    let shell_channel = ShellChannel::actor(&actor_system)
+        .expect("Failed to create shell channel");
+    let network_version = NetworkVersion::new(
+        tezos_env.version.clone(),
+        SUPPORTED_DISTRIBUTED_DB_VERSION,
+        SUPPORTED_P2P_VERSION,
+    );
+    let _ = RpcServer::actor(
+        &actor_system,
+        shell_channel.clone(),
+        ([0, 0, 0, 0], env.rpc.listener_port).into(),
+        &tokio_runtime.handle(),
+        &persistent_storage,
+        tezos_readonly_api.clone(),
+        tezos_env.clone(),
+        network_version,
+        &init_storage_data,
+        None,
+        false,
+    ).expect("Failed to create RPC server");
+
+    let catch_up = {
+        let shell_channel = shell_channel.clone();
+        let chain_meta_storage = ChainMetaStorage::new(&persistent_storage);
+        let block_storage = BlockStorage::new(&persistent_storage);
+        let chain_id = init_storage_data.chain_id.clone();
+        let mut current_head_hash = None;
+        spawn_catch_up(&persistent_storage, REPLICA_CATCH_UP_INTERVAL, log.clone(), move || {
+            let head = match chain_meta_storage.get_current_head(&chain_id) {
+                Ok(Some(head)) => head,
+                _ => return,
+            };
+            if current_head_hash.as_ref() == Some(&head.hash) {
+                return;
+            }
+            if let Ok(Some((block, json_data))) = block_storage.get_with_json_data(&head.hash) {
+                current_head_hash = Some(head.hash.clone());
+                shell_channel.tell(
+                    Publish {
+                        msg: ShellChannelMsg::NewCurrentHead(head, BlockApplied::new(block, json_data)),
+                        topic: ShellChannelTopic::ShellEvents.into(),
+                    }, None);
+            }
+        })
+    };
+    let catch_up = match catch_up {
+        Ok(catch_up) => catch_up,
+        Err(e) => shutdown_and_exit!(error!(log, "Failed to start catch-up of the replica"; "reason" => format!("{}", e)), actor_system),
+    };
+    info!(log, "Read-only replica started"; "db_path" => format!("{:?}", &env.storage.db_path), "replica_path" => format!("{:?}", &replica_path));
+
+    tokio_runtime.block_on(async move {
+        use tokio::signal;
+
+        signal::ctrl_c().await.expect("Failed to listen for ctrl-c event");
+        info!(log, "ctrl-c received!");
+
+        drop(catch_up);
+
+        info!(log, "Shutting down actors");
+        let _ = actor_system.shutdown().await;
+        info!(log, "Shutdown actors complete");
+
+        drop(tezos_readonly_api);
+        info!(log, "Shutdown complete");
+    });
+}
+
 fn main() {
     // Parses config + cli args
     let env = crate::configuration::Environment::from_args();
@@ -328,32 +490,40 @@ fn main() {
         Err(e) => shutdown_and_exit!(error!(log, "Failed to load identity"; "reason" => e, "file" => env.identity.identity_json_file_path.into_os_string().into_string().unwrap()), actor_system),
     };
 
-    let schemas = vec![
-        block_storage::BlockPrimaryIndex::descriptor(),
-        block_storage::BlockByLevelIndex::descriptor(),
-        block_storage::BlockByContextHashIndex::descriptor(),
-        BlockMetaStorage::descriptor(),
-        OperationsStorage::descriptor(),
-        OperationsMetaStorage::descriptor(),
-        context_action_storage::ContextActionByBlockHashIndex::descriptor(),
-        context_action_storage::ContextActionByContractIndex::descriptor(),
-        context_action_storage::ContextActionByTypeIndex::descriptor(),
-        ContextActionStorage::descriptor(),
-        SystemStorage::descriptor(),
-        DatabaseBackedSkipList::descriptor(),
-        Lane::descriptor(),
-        ListValue::descriptor(),
-        Sequences::descriptor(),
-        MempoolStorage::descriptor(),
-        ChainMetaStorage::descriptor(),
-    ];
+    // backup has to be restored before the database is opened
+    if let Some(backup_path) = &env.storage.restore_backup {
+        let chain_id = match tezos_env.main_chain_id() {
+            Ok(chain_id) => chain_id,
+            Err(e) => shutdown_and_exit!(error!(log, "Failed to resolve chain id of the backup"; "reason" => format!("{}", e)), actor_system),
+        };
+        info!(log, "Restoring backup"; "path" => format!("{:?}", backup_path));
+        if let Err(e) = restore_backup(backup_path, &env.storage.db_path, &env.storage.tezos_data_dir, &chain_id, &env.storage.db_cfg, &log) {
+            shutdown_and_exit!(error!(log, "Failed to restore backup"; "reason" => e), actor_system)
+        }
+    }
+
+    let db_tuning = match DbTuning::new(&env.storage.db_cfg) {
+        Ok(db_tuning) => db_tuning,
+        Err(e) => shutdown_and_exit!(error!(log, "Failed to create database tuning"; "reason" => format!("{}", e)), actor_system)
+    };
+
+    // replica just reads the database of another running node
+    if let Some(replica_path) = env.storage.replica_path.clone() {
+        return block_on_replica(env, tezos_env, &db_tuning, replica_path, actor_system, log);
+    }
+
+    let schemas = database_schemas(&db_tuning);
     let rocks_db = match open_kv(&env.storage.db_path, schemas, &env.storage.db_cfg) {
         Ok(db) => Arc::new(db),
         Err(_) => shutdown_and_exit!(error!(log, "Failed to create RocksDB database at '{:?}'", &env.storage.db_path), actor_system)
     };
     debug!(log, "Loaded RocksDB database");
 
-    match check_database_compatibility(rocks_db.clone(), DATABASE_VERSION, &tezos_env, &log) {
+    let migrator = match Migrator::new(database_migrations()) {
+        Ok(migrator) => migrator,
+        Err(e) => shutdown_and_exit!(error!(log, "Invalid database migrations"; "reason" => e), actor_system)
+    };
+    match check_database_compatibility(rocks_db.clone(), DATABASE_VERSION, &migrator, &tezos_env, &log) {
         Ok(false) => shutdown_and_exit!(crit!(log, "Database incompatibility detected"), actor_system),
         Err(e) => shutdown_and_exit!(error!(log, "Failed to verify database compatibility"; "reason" => e), actor_system),
         _ => ()
@@ -370,13 +540,78 @@ fn main() {
         };
 
         let persistent_storage = PersistentStorage::new(rocks_db, commit_logs);
+        if let Err(e) = recover_commit_log(&persistent_storage, &log) {
+            shutdown_and_exit!(error!(log, "Failed to recover commit log"; "reason" => e), actor_system)
+        }
+        if let Err(e) = recover_sequences(&persistent_storage, &log) {
+            shutdown_and_exit!(error!(log, "Failed to recover sequence generators"; "reason" => e), actor_system)
+        }
+        persistent_storage.seq().set_audit(env.storage.sequence_audit);
+
+        match migrator.migrate(&persistent_storage, DATABASE_VERSION, env.storage.migration_dry_run, &log) {
+            Ok(report) => if report.dry_run() {
+                if report.steps().is_empty() {
+                    shutdown_and_exit!(info!(log, "Database migration dry-run finished, nothing to do, database is up to date"; "version" => DATABASE_VERSION), actor_system)
+                }
+                for step in report.steps() {
+                    info!(log, "Database migration step (dry-run)"; "report" => step.to_string());
+                }
+                shutdown_and_exit!(info!(log, "Database migration dry-run finished"; "steps" => report.steps().len()), actor_system)
+            },
+            Err(e) => shutdown_and_exit!(error!(log, "Failed to migrate database"; "reason" => e), actor_system),
+        }
+
+        if let Some(storage_check) = env.storage.storage_check {
+            match check_storage(&persistent_storage, storage_check == StorageCheck::Repair, &log) {
+                Ok(report) => shutdown_and_exit!(info!(log, "Exiting after storage check"; "is_ok" => report.is_ok()), actor_system),
+                Err(e) => shutdown_and_exit!(error!(log, "Failed to check storage"; "reason" => e), actor_system),
+            }
+        }
+
+        if env.storage.compact_commit_log {
+            match compact_commit_log(&persistent_storage, &log) {
+                Ok(report) => shutdown_and_exit!(info!(log, "Exiting after commit log compaction"; "reclaimed_bytes" => report.size_before().saturating_sub(report.size_after())), actor_system),
+                Err(e) => shutdown_and_exit!(error!(log, "Failed to compact commit log"; "reason" => e), actor_system),
+            }
+        }
+
+        match &env.storage.context_trace {
+            Some(ContextTrace::Export { path, format, from_level, to_level }) => {
+                let exported = ContextTraceWriter::create(path, *format)
+                    .and_then(|mut writer| {
+                        let blocks = export_context_trace(&persistent_storage, *from_level, *to_level, &mut writer, &log)?;
+                        writer.finish().map(|records| (blocks, records))
+                    });
+                match exported {
+                    Ok((blocks, records)) => shutdown_and_exit!(info!(log, "Context trace exported"; "blocks" => blocks, "records" => records), actor_system),
+                    Err(e) => shutdown_and_exit!(error!(log, "Failed to export context trace"; "reason" => e), actor_system),
+                }
+            }
+            Some(ContextTrace::Replay { path }) => {
+                let replayed = ContextTraceReader::open(path)
+                    .and_then(|reader| ContextReplayer::new(&persistent_storage).replay(reader));
+                match replayed {
+                    Ok(report) => shutdown_and_exit!(info!(log, "Context trace replayed";
+                                                           "actions" => report.actions(),
+                                                           "commits" => report.commits(),
+                                                           "elapsed" => format!("{:?}", report.elapsed())), actor_system),
+                    Err(e) => shutdown_and_exit!(error!(log, "Failed to replay context trace"; "reason" => e), actor_system),
+                }
+            }
+            _ => (),
+        }
+
         match resolve_storage_init_chain_data(
             &tezos_env,
             &env.storage.db_path,
             &env.storage.tezos_data_dir,
             &env.storage.patch_context,
             &log) {
-            Ok(init_data) => block_on_actors(env, tezos_env, init_data, tezos_identity, actor_system, persistent_storage, log),
+            Ok(init_data) => match process_snapshot(&env, &init_data, &persistent_storage, &log) {
+                Ok(true) => block_on_actors(env, tezos_env, init_data, tezos_identity, actor_system, persistent_storage, log),
+                Ok(false) => shutdown_and_exit!(info!(log, "Snapshot exported"), actor_system),
+                Err(e) => shutdown_and_exit!(error!(log, "Failed to process snapshot"; "reason" => format!("{}", e)), actor_system),
+            },
             Err(e) => shutdown_and_exit!(error!(log, "Failed to resolve init storage chain data. Reason: {}", e), actor_system),
         }
     }
@@ -6,6 +6,8 @@ use std::iter::FromIterator;
 use serde::Serialize;
 use slog_derive::SerdeValue;
 
+use storage::persistent::metrics::StorageStats;
+
 use crate::monitors::PeerMonitor;
 use crate::monitors::ChainMonitor;
 
@@ -133,6 +135,9 @@ pub enum HandlerMessage {
     ChainStatus {
         payload:  ChainMonitor,
     },
+    StorageStatus {
+        payload: StorageStats,
+    },
     NotImplemented(String),
 }
 
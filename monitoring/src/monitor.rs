@@ -32,6 +32,7 @@ use crate::handlers::handler_messages::HandlerMessage;
 pub enum BroadcastSignal {
     PublishPeerStatistics,
     PublishBlocksStatistics,
+    PublishStorageStatistics,
     PeerUpdate(PeerConnectionStatus),
 }
 
@@ -48,6 +49,7 @@ pub struct Monitor {
     blocks_monitor: BlocksMonitor,
     block_application_monitor: ApplicationMonitor,
     chain_monitor: ChainMonitor,
+    persistent_storage: PersistentStorage,
 }
 
 impl Monitor {
@@ -121,6 +123,7 @@ impl ActorFactoryArgs<(NetworkChannelRef, ActorRef<WebsocketHandlerMsg>, ShellCh
             blocks_monitor: BlocksMonitor::new(4096, downloaded),
             block_application_monitor: ApplicationMonitor::new(),
             chain_monitor: ChainMonitor::new(),
+            persistent_storage,
         }
     }
 }
@@ -148,6 +151,10 @@ impl Actor for Monitor {
                      Duration::from_secs(1),
                      ctx.myself(), None,
                      BroadcastSignal::PublishBlocksStatistics);
+        ctx.schedule(Duration::from_secs(5),
+                     Duration::from_secs(5),
+                     ctx.myself(), None,
+                     BroadcastSignal::PublishStorageStatistics);
     }
 
     fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Option<BasicActorRef>) {
@@ -205,6 +212,12 @@ impl Receive<BroadcastSignal> for Monitor {
                 let payload = self.chain_monitor.snapshot();
                 self.msg_channel.tell(HandlerMessage::ChainStatus { payload }, ctx.myself().into());
             }
+            BroadcastSignal::PublishStorageStatistics => {
+                match self.persistent_storage.stats() {
+                    Ok(payload) => self.msg_channel.tell(HandlerMessage::StorageStatus { payload }, ctx.myself().into()),
+                    Err(e) => warn!(ctx.system.log(), "Failed to collect storage statistics"; "reason" => e),
+                }
+            }
             BroadcastSignal::PeerUpdate(msg) => {
                 let msg: HandlerMessage = msg.into();
                 self.msg_channel.tell(msg, ctx.myself().into())
@@ -239,6 +252,7 @@ impl Receive<NetworkChannelMsg> for Monitor {
                 }
             }
             NetworkChannelMsg::PeerMessageReceived(msg) => self.process_peer_message(msg, &ctx.system.log()),
+            NetworkChannelMsg::PeerBehaviorReported(_) => (),
         }
     }
 }
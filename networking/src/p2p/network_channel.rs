@@ -47,12 +47,39 @@ pub struct PeerMessageReceived {
     pub peer_address: SocketAddr,
 }
 
+/// Behavior of the remote peer, which affects its reputation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeerBehavior {
+    /// Peer sent block header, which does not match the requested block
+    InvalidBlock,
+    /// Peer sent operations, which do not match the requested block
+    InvalidOperation,
+    /// Message received from the peer cannot be deserialized
+    DeserializationError,
+    /// Peer did not respond in time
+    Timeout,
+    /// Peer sent data, which were not requested
+    UnsolicitedMessage,
+    /// Peer failed at bootstrap process
+    BootstrapFailure,
+    /// Peer sent requested data, which were new to us
+    UsefulData,
+}
+
+/// Behavior of the remote peer was evaluated
+#[derive(Clone, Debug)]
+pub struct PeerBehaviorReported {
+    pub peer: PeerRef,
+    pub behavior: PeerBehavior,
+}
+
 /// Network channel event message.
 #[derive(Clone, Debug)]
 pub enum NetworkChannelMsg {
     PeerCreated(PeerCreated),
     PeerBootstrapped(PeerBootstrapped),
     PeerMessageReceived(PeerMessageReceived),
+    PeerBehaviorReported(PeerBehaviorReported),
 }
 
 impl From<PeerCreated> for NetworkChannelMsg {
@@ -73,6 +100,12 @@ impl From<PeerMessageReceived> for NetworkChannelMsg {
     }
 }
 
+impl From<PeerBehaviorReported> for NetworkChannelMsg {
+    fn from(msg: PeerBehaviorReported) -> Self {
+        NetworkChannelMsg::PeerBehaviorReported(msg)
+    }
+}
+
 /// Represents various topics
 pub enum NetworkChannelTopic {
     /// Events generated from networking layer
@@ -1,6 +1,8 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::cmp;
+use std::collections::HashSet;
 use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
@@ -17,12 +19,13 @@ use tokio::time::timeout;
 use crypto::crypto_box::precompute;
 use crypto::hash::HashType;
 use crypto::nonce::{self, Nonce, NoncePair};
+use crypto::proof_of_work::{check_proof_of_work, PowTarget};
 use tezos_encoding::binary_reader::BinaryReaderError;
 use tezos_messages::p2p::binary_message::{BinaryChunk, BinaryChunkError, BinaryMessage};
 use tezos_messages::p2p::encoding::ack::{NackInfo, NackMotive};
 use tezos_messages::p2p::encoding::prelude::*;
 
-use super::network_channel::{NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerMessageReceived};
+use super::network_channel::{NetworkChannelRef, NetworkChannelTopic, PeerBehavior, PeerBehaviorReported, PeerBootstrapped, PeerMessageReceived};
 use super::stream::{EncryptedMessageReader, EncryptedMessageWriter, MessageStream, StreamError};
 
 const IO_TIMEOUT: Duration = Duration::from_secs(6);
@@ -46,6 +49,14 @@ enum PeerError {
     NackWithMotiveReceived {
         nack_info: NackInfo
     },
+    #[fail(display = "Remote peer has not enough proof of work - peer_id: {}", peer_id)]
+    NotEnoughProofOfWork {
+        peer_id: PeerId,
+    },
+    #[fail(display = "Connection was rejected by the local node - motive: {:?}", motive)]
+    ConnectionRejected {
+        motive: NackMotive,
+    },
     #[fail(display = "Failed to create precomputed key")]
     FailedToPrecomputeKey,
     #[fail(display = "Network error: {}", message)]
@@ -116,15 +127,31 @@ pub struct Bootstrap {
     incoming: bool,
     disable_mempool: bool,
     private_node: bool,
+    /// Known peers offered to the remote peer, when the connection is not acknowledged
+    potential_peers: Vec<String>,
+    /// Reject the connection with this motive after the handshake
+    reject_motive: Option<NackMotive>,
+    /// Already connected peers, connection from/to them is rejected
+    connected_peers: HashSet<PeerId>,
 }
 
 impl Bootstrap {
-    pub fn incoming(stream: Arc<Mutex<Option<TcpStream>>>, address: SocketAddr, disable_mempool: bool, private_node: bool) -> Self {
-        Bootstrap { stream, address, incoming: true, disable_mempool, private_node }
+    pub fn incoming(stream: Arc<Mutex<Option<TcpStream>>>, address: SocketAddr, disable_mempool: bool, private_node: bool, potential_peers: Vec<String>) -> Self {
+        Bootstrap { stream, address, incoming: true, disable_mempool, private_node, potential_peers, reject_motive: None, connected_peers: HashSet::new() }
+    }
+
+    pub fn outgoing(stream: TcpStream, address: SocketAddr, disable_mempool: bool, private_node: bool, potential_peers: Vec<String>) -> Self {
+        Bootstrap { stream: Arc::new(Mutex::new(Some(stream))), address, incoming: false, disable_mempool, private_node, potential_peers, reject_motive: None, connected_peers: HashSet::new() }
+    }
+
+    /// Do the handshake, but answer with NACK instead of ACK
+    pub fn reject(self, motive: NackMotive) -> Self {
+        Bootstrap { reject_motive: Some(motive), ..self }
     }
 
-    pub fn outgoing(stream: TcpStream, address: SocketAddr, disable_mempool: bool, private_node: bool) -> Self {
-        Bootstrap { stream: Arc::new(Mutex::new(Some(stream))), address, incoming: false, disable_mempool, private_node }
+    /// Reject the connection with `AlreadyConnected` motive, if the remote peer is one of `connected_peers`
+    pub fn with_connected_peers(self, connected_peers: HashSet<PeerId>) -> Self {
+        Bootstrap { connected_peers, ..self }
     }
 }
 
@@ -162,8 +189,12 @@ pub struct Local {
     secret_key: String,
     /// proof of work
     proof_of_work_stamp: String,
+    /// proof of work required from remote peers
+    pow_target: PowTarget,
     /// version of network protocol
     version: NetworkVersion,
+    /// oldest p2p version of the remote peer, which is still supported
+    min_p2p_version: u16,
 }
 
 pub type PeerRef = ActorRef<PeerMsg>;
@@ -191,16 +222,20 @@ impl Peer {
                  public_key: &str,
                  secret_key: &str,
                  proof_of_work_stamp: &str,
+                 pow_target: PowTarget,
                  version: NetworkVersion,
+                 min_p2p_version: u16,
                  tokio_executor: Handle,
                  socket_address: &SocketAddr) -> Result<PeerRef, CreateError>
     {
         let info = Local {
             listener_port,
             proof_of_work_stamp: proof_of_work_stamp.into(),
+            pow_target,
             public_key: public_key.into(),
             secret_key: secret_key.into(),
             version,
+            min_p2p_version,
         };
         let props = Props::new_args::<Peer, _>((network_channel, Arc::new(info), tokio_executor, *socket_address));
         let actor_id = ACTOR_ID_GENERATOR.fetch_add(1, Ordering::SeqCst);
@@ -278,6 +313,11 @@ impl Receive<Bootstrap> for Peer {
                     // connection to peer was closed, stop this actor
                     system.stop(myself);
                 }
+                Err(PeerError::ConnectionRejected { motive }) => {
+                    // rejected by us, so it is not a failure of the remote peer
+                    debug!(system.log(), "Connection to peer rejected"; "motive" => format!("{:?}", motive), "ip" => &peer_address, "peer" => myself.name());
+                    system.stop(myself);
+                }
                 Err(err) => {
                     info!(system.log(), "Connection to peer failed"; "reason" => &err, "ip" => &peer_address, "peer" => myself.name());
 
@@ -388,7 +428,7 @@ async fn bootstrap(
 
     // from now on all messages will be encrypted
     let mut msg_tx = EncryptedMessageWriter::new(msg_tx, precomputed_key.clone(), nonce_local, peer_id.clone(), log.clone());
-    let mut msg_rx = EncryptedMessageReader::new(msg_rx, precomputed_key, nonce_remote, peer_id, log.clone());
+    let mut msg_rx = EncryptedMessageReader::new(msg_rx, precomputed_key, nonce_remote, peer_id.clone(), log.clone());
 
     let connecting_to_self = hex::encode(connection_message.public_key()) == info.public_key;
     if connecting_to_self {
@@ -405,10 +445,12 @@ async fn bootstrap(
     let metadata_received = timeout(IO_TIMEOUT, msg_rx.read_message::<MetadataMessage>()).await??;
     debug!(log, "Received remote peer metadata"; "disable_mempool" => metadata_received.disable_mempool(), "private_node" => metadata_received.private_node());
 
-    let protocol_not_supported = !connection_message.versions().iter().any(|version| supported_protocol_version.supports(version));
-    if protocol_not_supported {
+    // peers talking p2p version 0 do not understand NACK with motive
+    let p2p_version = negotiate_p2p_version(&supported_protocol_version, connection_message.versions());
+
+    if let Some(motive) = unsupported_version_motive(&supported_protocol_version, connection_message.versions(), info.min_p2p_version) {
         // send nack
-        timeout(IO_TIMEOUT, msg_tx.write_message(&AckMessage::NackV0)).await??;
+        timeout(IO_TIMEOUT, msg_tx.write_message(&nack_message(p2p_version, motive, &msg.potential_peers))).await??;
 
         return Err(
             PeerError::UnsupportedProtocol {
@@ -418,6 +460,26 @@ async fn bootstrap(
         );
     }
 
+    let not_enough_pow = !check_proof_of_work(connection_message.public_key(), connection_message.proof_of_work_stamp(), &info.pow_target);
+    if not_enough_pow {
+        // send nack
+        timeout(IO_TIMEOUT, msg_tx.write_message(&nack_message(p2p_version, NackMotive::NoMotive, &msg.potential_peers))).await??;
+
+        return Err(PeerError::NotEnoughProofOfWork { peer_id });
+    }
+
+    let reject_motive = if msg.connected_peers.contains(&peer_id) {
+        Some(NackMotive::AlreadyConnected)
+    } else {
+        msg.reject_motive
+    };
+    if let Some(motive) = reject_motive {
+        // send nack
+        timeout(IO_TIMEOUT, msg_tx.write_message(&nack_message(p2p_version, motive.clone(), &msg.potential_peers))).await??;
+
+        return Err(PeerError::ConnectionRejected { motive });
+    }
+
     // send ack
     timeout(IO_TIMEOUT, msg_tx.write_message(&AckMessage::Ack)).await??;
 
@@ -440,6 +502,35 @@ async fn bootstrap(
     }
 }
 
+/// p2p version used for the connection, newer version talks the older one
+fn negotiate_p2p_version(local: &NetworkVersion, remote_versions: &[NetworkVersion]) -> u16 {
+    remote_versions.iter()
+        .map(|version| version.p2p_version())
+        .max()
+        .map_or(0, |remote| cmp::min(local.p2p_version(), remote))
+}
+
+/// Motive for the rejection of the remote peer, which announced only versions not supported by the local node
+fn unsupported_version_motive(local: &NetworkVersion, remote_versions: &[NetworkVersion], min_p2p_version: u16) -> Option<NackMotive> {
+    if !remote_versions.iter().any(|version| local.is_same_chain(version)) {
+        Some(NackMotive::UnknownChainName)
+    } else if !remote_versions.iter().any(|version| local.supports(version)) {
+        Some(NackMotive::DeprecatedDistributedDbVersion)
+    } else if negotiate_p2p_version(local, remote_versions) < min_p2p_version {
+        Some(NackMotive::DeprecatedP2pVersion)
+    } else {
+        None
+    }
+}
+
+/// NACK with motive is supported since p2p version 1
+fn nack_message(p2p_version: u16, motive: NackMotive, potential_peers: &[String]) -> AckMessage {
+    if p2p_version == 0 {
+        AckMessage::NackV0
+    } else {
+        AckMessage::Nack(NackInfo::new(motive, potential_peers))
+    }
+}
 
 /// Generate nonces (sent and recv encoding must be with length bytes also)
 ///
@@ -475,6 +566,9 @@ async fn begin_process_incoming(mut rx: EncryptedMessageReader, net: Network, my
                     if let StreamError::DeserializationError { error: BinaryReaderError::UnsupportedTag { .. } } = e {
                         info!(log, "Messages with unsupported tags are ignored");
                     } else {
+                        if let StreamError::DeserializationError { .. } = e {
+                            report_behavior(&event_channel, &myself, PeerBehavior::DeserializationError);
+                        }
                         warn!(log, "Failed to read peer message"; "reason" => e);
                         break;
                     }
@@ -482,6 +576,7 @@ async fn begin_process_incoming(mut rx: EncryptedMessageReader, net: Network, my
             }
             Err(_) => {
                 warn!(log, "Peer message read timed out"; "secs" => READ_TIMEOUT_LONG.as_secs());
+                report_behavior(&event_channel, &myself, PeerBehavior::Timeout);
                 break;
             }
         }
@@ -499,3 +594,52 @@ async fn begin_process_incoming(mut rx: EncryptedMessageReader, net: Network, my
 
     info!(log, "Stopped to accept messages"; "ip" => format!("{:?}", &peer_address));
 }
+
+/// Notify others about behavior of the peer
+fn report_behavior(event_channel: &NetworkChannelRef, myself: &PeerRef, behavior: PeerBehavior) {
+    event_channel.tell(
+        Publish {
+            msg: PeerBehaviorReported {
+                peer: myself.clone(),
+                behavior,
+            }.into(),
+            topic: NetworkChannelTopic::NetworkEvents.into(),
+        }, Some(myself.clone().into()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(chain_name: &str, distributed_db_version: u16, p2p_version: u16) -> NetworkVersion {
+        NetworkVersion::new(chain_name.to_string(), distributed_db_version, p2p_version)
+    }
+
+    #[test]
+    fn test_negotiate_p2p_version() {
+        let local = version("TEZOS_MAINNET", 0, 1);
+        assert_eq!(1, negotiate_p2p_version(&local, &[version("TEZOS_MAINNET", 0, 1)]));
+        assert_eq!(1, negotiate_p2p_version(&local, &[version("TEZOS_MAINNET", 0, 2)]));
+        assert_eq!(0, negotiate_p2p_version(&local, &[version("TEZOS_MAINNET", 0, 0)]));
+        assert_eq!(0, negotiate_p2p_version(&local, &[]));
+    }
+
+    #[test]
+    fn test_unsupported_version_motive() {
+        let local = version("TEZOS_MAINNET", 0, 1);
+        assert_eq!(None, unsupported_version_motive(&local, &[version("TEZOS_MAINNET", 0, 1)], 0));
+        assert_eq!(Some(NackMotive::UnknownChainName), unsupported_version_motive(&local, &[version("TEZOS_CARTHAGENET", 0, 1)], 0));
+        assert_eq!(Some(NackMotive::DeprecatedDistributedDbVersion), unsupported_version_motive(&local, &[version("TEZOS_MAINNET", 1, 1)], 0));
+        assert_eq!(Some(NackMotive::DeprecatedP2pVersion), unsupported_version_motive(&local, &[version("TEZOS_MAINNET", 0, 0)], 1));
+    }
+
+    #[test]
+    fn test_nack_message_by_p2p_version() {
+        let potential_peers = vec!["127.0.0.1:9732".to_string()];
+        assert_eq!(AckMessage::NackV0, nack_message(0, NackMotive::TooManyConnections, &potential_peers));
+        assert_eq!(
+            AckMessage::Nack(NackInfo::new(NackMotive::TooManyConnections, &potential_peers)),
+            nack_message(1, NackMotive::TooManyConnections, &potential_peers)
+        );
+    }
+}
@@ -11,6 +11,7 @@ use tokio::runtime::Handle;
 
 use crypto::hash::ChainId;
 use shell::shell_channel::{BlockApplied, CurrentMempoolState, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use storage::backup::BackupConfiguration;
 use storage::persistent::PersistentStorage;
 use storage::StorageInitInfo;
 use tezos_api::environment::TezosEnvironmentConfiguration;
@@ -63,6 +64,7 @@ impl RpcServer {
         tezos_env: TezosEnvironmentConfiguration,
         network_version: NetworkVersion,
         init_storage_data: &StorageInitInfo,
+        backup: Option<BackupConfiguration>,
         is_sandbox: bool) -> Result<RpcServerRef, CreateError> {
         let shared_state = Arc::new(RwLock::new(RpcCollectedState {
             current_head: load_current_head(persistent_storage, &init_storage_data.chain_id, &sys.log()),
@@ -88,6 +90,7 @@ impl RpcServer {
                 tezos_readonly_api,
                 &init_storage_data.genesis_block_header_hash,
                 shared_state,
+                backup,
                 &sys.log(),
             );
             let inner_log = sys.log();
@@ -4,9 +4,9 @@
 use hyper::{Body, Request};
 use slog::warn;
 
-use crate::{empty, make_json_response, result_to_json_response, ServiceResult, unwrap_block_hash};
+use crate::{empty, make_json_response, not_found, result_to_json_response, ServiceResult, unwrap_block_hash};
 use crate::server::{HasSingleValue, Params, Query, RpcServiceEnvironment};
-use crate::services::base_services;
+use crate::services::{base_services, mempool_services};
 
 pub async fn dev_blocks(_: Request<Body>, _: Params, query: Query, env: RpcServiceEnvironment) -> ServiceResult {
     warn!(env.log(), "Getting dev_blocks");
@@ -21,6 +21,50 @@ pub async fn dev_blocks(_: Request<Body>, _: Params, query: Query, env: RpcServi
     result_to_json_response(base_services::get_blocks(every_nth_level, &from_block_id, limit, env.persistent_storage(), env.state()), env.log())
 }
 
+/// Blocks by `baker` (optionally in `cycle`), `protocol` (from `from_level`), `proto` level or by time range `from`..`to` (unix time in seconds)
+pub async fn dev_blocks_search(_: Request<Body>, _: Params, query: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let limit = query.get_usize("limit").unwrap_or(50);
+    let search = if let Some(baker) = query.get_str("baker") {
+        base_services::BlockSearch::Baker {
+            baker: baker.to_string(),
+            cycle: query.get_str("cycle").and_then(|cycle| cycle.parse().ok()),
+        }
+    } else if let Some(protocol_hash) = query.get_str("protocol") {
+        base_services::BlockSearch::Protocol {
+            protocol_hash: protocol_hash.to_string(),
+            from_level: query.get_str("from_level").and_then(|level| level.parse().ok()).unwrap_or(0),
+        }
+    } else if let Some(proto) = query.get_str("proto").and_then(|proto| proto.parse().ok()) {
+        base_services::BlockSearch::ProtoLevel(proto)
+    } else {
+        base_services::BlockSearch::TimestampRange {
+            from: query.get_str("from").and_then(|from| from.parse().ok()).unwrap_or(0),
+            to: query.get_str("to").and_then(|to| to.parse().ok()).unwrap_or(std::i64::MAX),
+        }
+    };
+    result_to_json_response(base_services::search_blocks(search, limit, env.persistent_storage(), env.state()), env.log())
+}
+
+/// Applied operations by `operation_hash`, by `contract_address` (optionally of `kind`) or by `kind`, both starting from `from_level`
+pub async fn dev_operations(_: Request<Body>, params: Params, query: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let limit = query.get_usize("limit").unwrap_or(50);
+    let from_level = query.get_str("from_level").and_then(|level| level.parse().ok()).unwrap_or(0);
+    let search = if let Some(operation_hash) = params.get_str("operation_hash") {
+        base_services::OperationSearch::Hash(operation_hash.to_string())
+    } else if let Some(contract_address) = params.get_str("contract_address") {
+        base_services::OperationSearch::Account {
+            account: contract_address.to_string(),
+            kind: query.get_str("kind").map(|kind| kind.to_string()),
+            from_level,
+        }
+    } else if let Some(kind) = params.get_str("kind") {
+        base_services::OperationSearch::Kind { kind: kind.to_string(), from_level }
+    } else {
+        return not_found();
+    };
+    result_to_json_response(base_services::search_operations(search, limit, env.persistent_storage()), env.log())
+}
+
 #[allow(dead_code)]
 pub async fn dev_block_actions(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
     let block_id = params.get_str("block_hash").unwrap();
@@ -38,30 +82,84 @@ pub async fn dev_contract_actions(_: Request<Body>, params: Params, query: Query
 pub async fn dev_action_cursor(_: Request<Body>, params: Params, query: Query, env: RpcServiceEnvironment) -> ServiceResult {
     let cursor_id = query.get_u64("cursor_id");
     let limit = query.get_u64("limit").map(|limit| limit as usize);
-    let action_types = query.get_str("action_types");
+    let cursor_filters = base_services::ActionCursorFilters {
+        action_types: query.get_str("action_types"),
+        key_prefix: query.get_str("key_prefix"),
+        time_range: get_time_range(&query),
+    };
     result_to_json_response(if let Some(block_hash) = params.get_str("block_hash") {
-        base_services::get_block_actions_cursor(block_hash, cursor_id, limit, action_types, env.persistent_storage(), env.state())
+        base_services::get_block_actions_cursor(block_hash, cursor_id, limit, cursor_filters, env.persistent_storage(), env.state())
     } else if let Some(contract_address) = params.get_str("contract_address") {
-        base_services::get_contract_actions_cursor(contract_address, cursor_id, limit, action_types, env.persistent_storage())
+        base_services::get_contract_actions_cursor(contract_address, cursor_id, limit, cursor_filters, env.persistent_storage())
+    } else if let Some(operation_hash) = params.get_str("operation_hash") {
+        base_services::get_operation_actions_cursor(operation_hash, cursor_id, limit, cursor_filters, env.persistent_storage())
     } else {
         unreachable!()
     }, env.log())
 }
 
+/// Actions started in the time range `from_time`..`to_time` (unix time in seconds)
+pub async fn dev_actions_by_time(_: Request<Body>, _: Params, query: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let (from, to) = get_time_range(&query).unwrap_or((0.0, std::f64::MAX));
+    let limit = query.get_usize("limit").unwrap_or(100);
+    result_to_json_response(base_services::get_actions_by_time_range(from, to, limit, env.persistent_storage()), env.log())
+}
+
+pub async fn dev_block_actions_stats(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let block_id = params.get_str("block_hash").unwrap();
+    result_to_json_response(base_services::get_block_actions_stats(block_id, env.persistent_storage(), env.state()), env.log())
+}
+
+/// Time range from the `from_time` and `to_time` query parameters, any of them can be omitted
+fn get_time_range(query: &Query) -> Option<(f64, f64)> {
+    let from = query.get_str("from_time").and_then(|value| value.parse::<f64>().ok());
+    let to = query.get_str("to_time").and_then(|value| value.parse::<f64>().ok());
+    if from.is_none() && to.is_none() {
+        None
+    } else {
+        Some((from.unwrap_or(0.0), to.unwrap_or(std::f64::MAX)))
+    }
+}
+
 pub async fn dev_context(_: Request<Body>, params: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
     // TODO: Add parameter checks
     let context_level = params.get_str("id").unwrap();
     result_to_json_response(base_services::get_context(context_level, env.persistent_storage().context_storage()), env.log())
 }
 
-#[allow(dead_code)]
+/// History of mempool operations, filtered by `operation_hash`, `source` or time range `from`..`to` (unix time in seconds)
+pub async fn dev_mempool_history(_: Request<Body>, _: Params, query: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    let limit = query.get_usize("limit").unwrap_or(100);
+    let filter = if let Some(operation_hash) = query.get_str("operation_hash") {
+        mempool_services::MempoolHistoryFilter::OperationHash(operation_hash.to_string())
+    } else if let Some(source) = query.get_str("source") {
+        mempool_services::MempoolHistoryFilter::Source(source.to_string())
+    } else {
+        mempool_services::MempoolHistoryFilter::TimeRange {
+            from: query.get_u64("from").unwrap_or(0),
+            to: query.get_u64("to").unwrap_or(std::u64::MAX),
+        }
+    };
+    result_to_json_response(mempool_services::get_mempool_history(filter, limit, env.persistent_storage()), env.log())
+}
+
+/// Create backup of the running node, just if the backup dir is configured.
+/// Backup copies all node data, so it runs on the blocking thread pool.
+pub async fn dev_backup(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
+    match env.backup().clone() {
+        Some(backup_cfg) => {
+            let backup_env = env.clone();
+            let created = tokio::task::spawn_blocking(move || {
+                base_services::create_backup(&backup_cfg, backup_env.persistent_storage(), backup_env.state(), backup_env.log())
+            }).await;
+            result_to_json_response(created.map_err(failure::Error::from).and_then(|created| created), env.log())
+        }
+        None => not_found(),
+    }
+}
+
 pub async fn dev_stats_storage(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
-    result_to_json_response(
-        crate::services::stats_services::compute_storage_stats(
-            env.state(),
-            env.genesis_hash(),
-            env.persistent_storage()),
-        env.log())
+    result_to_json_response(env.persistent_storage().stats().map_err(|e| e.into()), env.log())
 }
 
 pub async fn dev_stats_memory(_: Request<Body>, _: Params, _: Query, env: RpcServiceEnvironment) -> ServiceResult {
@@ -15,6 +15,7 @@ use slog::Logger;
 
 use crypto::hash::{BlockHash, HashType};
 use shell::shell_channel::ShellChannelRef;
+use storage::backup::BackupConfiguration;
 use storage::persistent::PersistentStorage;
 use tezos_api::environment::TezosEnvironmentConfiguration;
 use tezos_messages::p2p::encoding::version::NetworkVersion;
@@ -50,6 +51,9 @@ pub struct RpcServiceEnvironment {
     log: Logger,
     #[get = "pub(crate)"]
     tezos_readonly_api: Arc<TezosApiConnectionPool>,
+    /// Online backups are enabled just if configured
+    #[get = "pub(crate)"]
+    backup: Option<BackupConfiguration>,
 }
 
 impl RpcServiceEnvironment {
@@ -63,6 +67,7 @@ impl RpcServiceEnvironment {
         tezos_readonly_api: Arc<TezosApiConnectionPool>,
         genesis_hash: &BlockHash,
         state: RpcCollectedStateRef,
+        backup: Option<BackupConfiguration>,
         log: &Logger) -> Self {
         Self {
             sys,
@@ -75,6 +80,7 @@ impl RpcServiceEnvironment {
             state,
             log: log.clone(),
             tezos_readonly_api,
+            backup,
         }
     }
 }
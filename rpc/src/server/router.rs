@@ -61,11 +61,20 @@ pub(crate) fn create_routes(is_sandbox: bool) -> PathTree<Handler> {
 
     // Tezedge dev and support rpcs
     routes.handle("/dev/chains/main/blocks", dev_handler::dev_blocks);
+    routes.handle("/dev/chains/main/blocks/search", dev_handler::dev_blocks_search);
+    routes.handle("/dev/chains/main/operations/:operation_hash", dev_handler::dev_operations);
+    routes.handle("/dev/chains/main/operations/accounts/:contract_address", dev_handler::dev_operations);
+    routes.handle("/dev/chains/main/operations/kinds/:kind", dev_handler::dev_operations);
     routes.handle("/dev/chains/main/actions/blocks/:block_hash", dev_handler::dev_action_cursor);
+    routes.handle("/dev/chains/main/actions/blocks/:block_hash/stats", dev_handler::dev_block_actions_stats);
     routes.handle("/dev/chains/main/actions/contracts/:contract_address", dev_handler::dev_action_cursor);
+    routes.handle("/dev/chains/main/actions/operations/:operation_hash", dev_handler::dev_action_cursor);
+    routes.handle("/dev/chains/main/actions/time", dev_handler::dev_actions_by_time);
     routes.handle("/dev/context/:id", dev_handler::dev_context);
+    routes.handle("/dev/mempool/history", dev_handler::dev_mempool_history);
+    routes.handle("/dev/backup", dev_handler::dev_backup);
     routes.handle("/stats/memory", dev_handler::dev_stats_memory);
-    //routes.handle("/stats/storage", dev_handler::dev_stats_storage);
+    routes.handle("/stats/storage", dev_handler::dev_stats_storage);
 
     routes
 }
@@ -4,6 +4,7 @@
 use std::collections::HashMap;
 use std::convert::TryInto;
 
+use chrono::Utc;
 use failure::bail;
 use serde::{Deserialize, Serialize};
 use slog::Logger;
@@ -11,10 +12,12 @@ use slog::Logger;
 use crypto::hash::{chain_id_to_b58_string, HashType};
 use shell::shell_channel::BlockApplied;
 use shell::stats::memory::{Memory, MemoryData, MemoryStatsResult};
-use storage::{BlockHeaderWithHash, BlockStorage, BlockStorageReader, ContextActionRecordValue, ContextActionStorage, num_from_slice};
+use storage::{BlockHeaderWithHash, BlockStorage, BlockStorageReader, ContextActionRecordValue, ContextActionStorage, num_from_slice, OperationsIndexStorage};
+use storage::backup::{self, BackupConfiguration};
 use storage::block_storage::BlockJsonData;
 use storage::context::{ContextApi, ContextIndex, TezedgeContext};
-use storage::context_action_storage::{ContextActionFilters, ContextActionJson, contract_id_to_contract_address_for_index};
+use storage::context_action_storage::{ContextActionFilters, ContextActionJson, ContextActionTypeStats, contract_id_to_contract_address_for_index};
+use storage::operations_index_storage::{AccountRole, IndexedOperation, OperationKind};
 use storage::persistent::{ContextMap, PersistentStorage};
 use storage::skip_list::Bucket;
 use tezos_context::channel::ContextAction;
@@ -57,6 +60,146 @@ pub(crate) fn get_blocks(every_nth_level: Option<i32>, block_id: &str, limit: us
     Ok(blocks)
 }
 
+/// Criteria of the block search, blocks are returned in ascending order by level
+pub(crate) enum BlockSearch {
+    /// Block timestamp range `[from, to]` as unix time in seconds
+    TimestampRange { from: i64, to: i64 },
+    ProtoLevel(u8),
+    /// Protocol, which applied the block, starting from the level
+    Protocol { protocol_hash: String, from_level: i32 },
+    /// Baker address (tz..), optionally just in one cycle
+    Baker { baker: String, cycle: Option<i32> },
+}
+
+pub(crate) fn search_blocks(search: BlockSearch, limit: usize, persistent_storage: &PersistentStorage, state: &RpcCollectedStateRef) -> Result<Vec<FullBlockInfo>, failure::Error> {
+    let block_storage = BlockStorage::new(persistent_storage);
+    let blocks = match search {
+        BlockSearch::TimestampRange { from, to } => block_storage.get_by_timestamp_range(from, to, limit)?,
+        BlockSearch::ProtoLevel(proto) => block_storage.get_by_proto_level(proto, limit)?,
+        BlockSearch::Protocol { protocol_hash, from_level } => {
+            block_storage.get_by_protocol(&HashType::ProtocolHash.string_to_bytes(&protocol_hash)?, from_level, limit)?
+        }
+        BlockSearch::Baker { baker, cycle } => {
+            block_storage.get_by_baker(&contract_id_to_contract_address_for_index(&baker)?, cycle, limit)?
+        }
+    };
+
+    let mut result = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        if let Some((header, json_data)) = block_storage.get_with_json_data(&block.hash)? {
+            result.push(map_header_and_json_to_full_block_info(header, json_data, state));
+        }
+    }
+    Ok(result)
+}
+
+/// Criteria of the search over the indexed operations of the applied blocks, operations are returned in ascending order by level
+pub(crate) enum OperationSearch {
+    Hash(String),
+    /// Operations touching the account (tz.. or KT1..) from the level, optionally just of one kind
+    Account { account: String, kind: Option<String>, from_level: i32 },
+    Kind { kind: String, from_level: i32 },
+}
+
+#[derive(Serialize, Debug)]
+pub struct IndexedOperationJson {
+    pub hash: String,
+    pub block_hash: String,
+    pub level: i32,
+    pub validation_pass: u8,
+    pub index: u32,
+    pub kinds: Vec<OperationKind>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<AccountRole>,
+    /// Block of the operation is not the block of its level anymore, it was replaced by another branch (reorg)
+    pub forked: bool,
+    /// Operation with its metadata, as returned in the block
+    pub operation: Option<serde_json::Value>,
+}
+
+pub(crate) fn search_operations(search: OperationSearch, limit: usize, persistent_storage: &PersistentStorage) -> Result<Vec<IndexedOperationJson>, failure::Error> {
+    let index_storage = OperationsIndexStorage::new(persistent_storage);
+    let operations = match search {
+        OperationSearch::Hash(operation_hash) => {
+            index_storage.get_by_hash(&HashType::OperationHash.string_to_bytes(&operation_hash)?)?
+        }
+        OperationSearch::Account { account, kind, from_level } => {
+            let kind = kind.map(|kind| kind.parse::<OperationKind>()).transpose()?;
+            index_storage.get_by_account(&contract_id_to_contract_address_for_index(&account)?, kind, from_level, limit)?
+        }
+        OperationSearch::Kind { kind, from_level } => {
+            index_storage.get_by_kind(kind.parse()?, from_level, limit)?
+        }
+    };
+
+    // operations of one block are usually returned together, so the block operations are parsed just once
+    let block_storage = BlockStorage::new(persistent_storage);
+    let mut block_operations: HashMap<Vec<u8>, (bool, Vec<Vec<serde_json::Value>>)> = HashMap::new();
+    let mut result = Vec::with_capacity(operations.len());
+    for operation in operations {
+        if !block_operations.contains_key(operation.block_hash()) {
+            let forked = block_storage.get_by_block_level(operation.level())?
+                .map_or(true, |level_block| level_block.hash != *operation.block_hash());
+            let operations_json = block_storage.get_with_json_data(operation.block_hash())?
+                .and_then(|(_, json_data)| serde_json::from_str(json_data.operations_proto_metadata_json()).ok())
+                .unwrap_or_default();
+            block_operations.insert(operation.block_hash().clone(), (forked, operations_json));
+        }
+        let (forked, operations_json) = &block_operations[operation.block_hash()];
+        let operation_json = operations_json
+            .get(operation.validation_pass() as usize)
+            .and_then(|validation_pass| validation_pass.get(operation.index() as usize))
+            .cloned();
+        result.push(map_indexed_operation(operation, *forked, operation_json));
+    }
+    Ok(result)
+}
+
+fn map_indexed_operation(operation: IndexedOperation, forked: bool, operation_json: Option<serde_json::Value>) -> IndexedOperationJson {
+    IndexedOperationJson {
+        hash: HashType::OperationHash.bytes_to_string(operation.operation_hash()),
+        block_hash: HashType::BlockHash.bytes_to_string(operation.block_hash()),
+        level: operation.level(),
+        validation_pass: operation.validation_pass(),
+        index: operation.index(),
+        kinds: operation.kinds().clone(),
+        roles: operation.roles().clone(),
+        forked,
+        operation: operation_json,
+    }
+}
+
+/// Backup created by the running node
+#[derive(Serialize, Debug)]
+pub struct BackupJson {
+    pub path: String,
+    pub chain_id: String,
+    pub head_hash: String,
+    pub head_level: i32,
+    /// Unix time in seconds
+    pub created_at: u64,
+    pub files: usize,
+    pub size: u64,
+}
+
+/// Create backup of the node data in the new subdirectory of the configured backup dir, named by the current time
+pub(crate) fn create_backup(backup_cfg: &BackupConfiguration, persistent_storage: &PersistentStorage, state: &RpcCollectedStateRef, log: &Logger) -> Result<BackupJson, failure::Error> {
+    let chain_id = state.read().unwrap().chain_id().clone();
+    let backup_name = format!("backup_{}", Utc::now().format("%Y%m%dT%H%M%S"));
+    let backup_path = backup_cfg.backup_dir.join(&backup_name);
+    let manifest = backup::create_backup(persistent_storage, &chain_id, &backup_cfg.db_path, &backup_cfg.tezos_data_dir, &backup_cfg.backup_dir, &backup_name, log)?;
+
+    Ok(BackupJson {
+        path: backup_path.to_string_lossy().to_string(),
+        chain_id: manifest.chain_id().clone(),
+        head_hash: manifest.head_hash().clone(),
+        head_level: manifest.head_level(),
+        created_at: manifest.created_at(),
+        files: manifest.files().len(),
+        size: manifest.size(),
+    })
+}
+
 /// Get actions for a specific block in ascending order.
 #[allow(dead_code)]
 pub(crate) fn get_block_actions(block_id: &str, persistent_storage: &PersistentStorage, state: &RpcCollectedStateRef) -> Result<Vec<ContextAction>, failure::Error> {
@@ -71,32 +214,70 @@ pub(crate) fn get_block_actions_by_hash(context_action_storage: &ContextActionSt
         .map_err(|e| e.into())
 }
 
-pub(crate) fn get_block_actions_cursor(block_id: &str, cursor_id: Option<u64>, limit: Option<usize>, action_types: Option<&str>, persistent_storage: &PersistentStorage, state: &RpcCollectedStateRef) -> Result<Vec<ContextActionJson>, failure::Error> {
-    let context_action_storage = ContextActionStorage::new(persistent_storage);
-    let block_hash = get_block_hash_by_block_id(block_id, persistent_storage, state)?;
-    let mut filters = ContextActionFilters::with_block_hash(block_hash);
-    if let Some(action_types) = action_types {
-        filters = filters.with_action_types(get_action_types(action_types));
+/// Optional filters of the context actions cursor
+pub(crate) struct ActionCursorFilters<'a> {
+    /// Comma separated action types
+    pub action_types: Option<&'a str>,
+    /// Slash separated key path, e.g. `data/contracts/index`
+    pub key_prefix: Option<&'a str>,
+    /// Wall-clock time range in seconds
+    pub time_range: Option<(f64, f64)>,
+}
+
+impl ActionCursorFilters<'_> {
+    fn apply(&self, mut filters: ContextActionFilters) -> ContextActionFilters {
+        if let Some(action_types) = self.action_types {
+            filters = filters.with_action_types(get_action_types(action_types));
+        }
+        if let Some(key_prefix) = self.key_prefix {
+            filters = filters.with_key_prefix(key_prefix.split('/').filter(|segment| !segment.is_empty()).map(|segment| segment.to_string()).collect());
+        }
+        if let Some((from, to)) = self.time_range {
+            filters = filters.with_time_range(from, to);
+        }
+        filters
     }
-    let values = context_action_storage.load_cursor(cursor_id, limit, filters)?
+}
+
+pub(crate) fn get_block_actions_cursor(block_id: &str, cursor_id: Option<u64>, limit: Option<usize>, cursor_filters: ActionCursorFilters, persistent_storage: &PersistentStorage, state: &RpcCollectedStateRef) -> Result<Vec<ContextActionJson>, failure::Error> {
+    let block_hash = get_block_hash_by_block_id(block_id, persistent_storage, state)?;
+    load_actions_cursor(ContextActionFilters::with_block_hash(block_hash), cursor_id, limit, cursor_filters, persistent_storage)
+}
+
+pub(crate) fn get_contract_actions_cursor(contract_address: &str, cursor_id: Option<u64>, limit: Option<usize>, cursor_filters: ActionCursorFilters, persistent_storage: &PersistentStorage) -> Result<Vec<ContextActionJson>, failure::Error> {
+    let contract_address = contract_id_to_contract_address_for_index(contract_address)?;
+    load_actions_cursor(ContextActionFilters::with_contract_id(contract_address), cursor_id, limit, cursor_filters, persistent_storage)
+}
+
+pub(crate) fn get_operation_actions_cursor(operation_hash: &str, cursor_id: Option<u64>, limit: Option<usize>, cursor_filters: ActionCursorFilters, persistent_storage: &PersistentStorage) -> Result<Vec<ContextActionJson>, failure::Error> {
+    let operation_hash = HashType::OperationHash.string_to_bytes(operation_hash)?;
+    load_actions_cursor(ContextActionFilters::with_operation_hash(operation_hash), cursor_id, limit, cursor_filters, persistent_storage)
+}
+
+fn load_actions_cursor(filters: ContextActionFilters, cursor_id: Option<u64>, limit: Option<usize>, cursor_filters: ActionCursorFilters, persistent_storage: &PersistentStorage) -> Result<Vec<ContextActionJson>, failure::Error> {
+    let context_action_storage = ContextActionStorage::new(persistent_storage);
+    let values = context_action_storage.load_cursor(cursor_id, limit, cursor_filters.apply(filters))?
         .into_iter().map(|value| ContextActionJson::from(value))
         .collect();
     Ok(values)
 }
 
-pub(crate) fn get_contract_actions_cursor(contract_address: &str, cursor_id: Option<u64>, limit: Option<usize>, action_types: Option<&str>, persistent_storage: &PersistentStorage) -> Result<Vec<ContextActionJson>, failure::Error> {
+/// Get actions started in the wall-clock time range `[from, to)`, ordered by the start time
+pub(crate) fn get_actions_by_time_range(from: f64, to: f64, limit: usize, persistent_storage: &PersistentStorage) -> Result<Vec<ContextActionJson>, failure::Error> {
     let context_action_storage = ContextActionStorage::new(persistent_storage);
-    let contract_address = contract_id_to_contract_address_for_index(contract_address)?;
-    let mut filters = ContextActionFilters::with_contract_id(contract_address);
-    if let Some(action_types) = action_types {
-        filters = filters.with_action_types(get_action_types(action_types));
-    }
-    let values = context_action_storage.load_cursor(cursor_id, limit, filters)?
+    let values = context_action_storage.get_by_time_range(from, to, limit)?
         .into_iter().map(|value| ContextActionJson::from(value))
         .collect();
     Ok(values)
 }
 
+/// Get count and total duration of the block actions per action type
+pub(crate) fn get_block_actions_stats(block_id: &str, persistent_storage: &PersistentStorage, state: &RpcCollectedStateRef) -> Result<Vec<ContextActionTypeStats>, failure::Error> {
+    let context_action_storage = ContextActionStorage::new(persistent_storage);
+    let block_hash = get_block_hash_by_block_id(block_id, persistent_storage, state)?;
+    Ok(context_action_storage.aggregate_by_block_hash(&block_hash)?)
+}
+
 /// Get actions for a specific contract in ascending order.
 #[allow(dead_code)]
 pub(crate) fn get_contract_actions(contract_id: &str, from_id: Option<u64>, limit: usize, persistent_storage: &PersistentStorage) -> Result<PagedResult<Vec<ContextActionRecordValue>>, failure::Error> {
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use failure::format_err;
 use riker::actors::*;
@@ -9,7 +9,8 @@ use slog::Logger;
 
 use crypto::hash::{HashType, OperationHash, ProtocolHash};
 use shell::shell_channel::{CurrentMempoolState, MempoolOperationReceived, ShellChannelRef, ShellChannelTopic, InjectBlock};
-use storage::mempool_storage::MempoolOperationType;
+use storage::context_action_storage::contract_id_to_contract_address_for_index;
+use storage::mempool_storage::{MempoolHistoryRecord, MempoolOperationOutcome, MempoolOperationType};
 use storage::MempoolStorage;
 use storage::persistent::PersistentStorage;
 use tezos_api::ffi::{Applied, Errored, ComputePathRequest};
@@ -127,6 +128,73 @@ fn convert_errored(errored: &Vec<Errored>, operations: &HashMap<OperationHash, O
     Ok(result)
 }
 
+/// Filter of the mempool history query
+pub enum MempoolHistoryFilter {
+    OperationHash(String),
+    /// Source address of the operation (tz.. or KT1..)
+    Source(String),
+    /// Range of the first reception `[from, to)` as unix time in seconds
+    TimeRange { from: u64, to: u64 },
+}
+
+#[derive(Serialize, Debug)]
+pub struct MempoolHistoryJson {
+    pub hash: String,
+    /// Unix time in milliseconds
+    pub first_seen: u64,
+    pub peer: Option<String>,
+    pub sources: Vec<String>,
+    pub prevalidation: Option<Value>,
+    pub included_in: Option<String>,
+    pub dropped_reason: Option<String>,
+    /// Unix time in milliseconds, when operation was included or dropped
+    pub removed_at: Option<u64>,
+}
+
+impl From<MempoolHistoryRecord> for MempoolHistoryJson {
+    fn from(record: MempoolHistoryRecord) -> Self {
+        let (included_in, dropped_reason, removed_at) = match record.outcome() {
+            Some(MempoolOperationOutcome::Included { block_hash, at }) => (Some(HashType::BlockHash.bytes_to_string(block_hash)), None, Some(*at)),
+            Some(MempoolOperationOutcome::Dropped { reason, at }) => (None, Some(reason.clone()), Some(*at)),
+            None => (None, None, None),
+        };
+        Self {
+            hash: HashType::OperationHash.bytes_to_string(record.operation_hash()),
+            first_seen: record.first_seen(),
+            peer: record.peer().clone(),
+            sources: record.sources().clone(),
+            prevalidation: record.prevalidation().as_ref().map(|prevalidation| serde_json::json!({
+                "status": prevalidation.status(),
+                "validated_at": prevalidation.validated_at(),
+                "error": prevalidation.error().as_ref().map(|error| serde_json::from_str(error).unwrap_or_else(|_| Value::String(error.clone()))),
+            })),
+            included_in,
+            dropped_reason,
+            removed_at,
+        }
+    }
+}
+
+pub fn get_mempool_history(filter: MempoolHistoryFilter, limit: usize, persistent_storage: &PersistentStorage) -> Result<Vec<MempoolHistoryJson>, failure::Error> {
+    let mempool_storage = MempoolStorage::new(persistent_storage);
+    let records = match filter {
+        MempoolHistoryFilter::OperationHash(operation_hash) => {
+            mempool_storage.get_history(&HashType::OperationHash.string_to_bytes(&operation_hash)?)?
+                .into_iter()
+                .collect()
+        }
+        MempoolHistoryFilter::Source(source) => {
+            mempool_storage.find_history_by_source(&contract_id_to_contract_address_for_index(&source)?, limit)?
+        }
+        MempoolHistoryFilter::TimeRange { from, to } => {
+            let from = UNIX_EPOCH.checked_add(Duration::from_secs(from)).ok_or_else(|| format_err!("Invalid time: {}", from))?;
+            let to = UNIX_EPOCH.checked_add(Duration::from_secs(to)).unwrap_or_else(SystemTime::now);
+            mempool_storage.find_history_by_time(from, to, limit)?
+        }
+    };
+    Ok(records.into_iter().map(MempoolHistoryJson::from).collect())
+}
+
 pub fn inject_operation(
     operation_data: &str,
     persistent_storage: &PersistentStorage,
@@ -141,6 +209,7 @@ pub fn inject_operation(
     let operation_hash = operation.message_hash()?;
 
     mempool_storage.put(MempoolOperationType::Pending, operation_message, ttl)?;
+    mempool_storage.record_received(&operation_hash, None, SystemTime::now())?;
 
     shell_channel.tell(
         Publish {
@@ -6,4 +6,3 @@
 pub mod base_services;
 pub mod mempool_services;
 pub mod protocol;
-pub mod stats_services;
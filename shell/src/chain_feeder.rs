@@ -5,7 +5,7 @@
 //! This actor is responsible for correct applying of blocks with Tezos protocol in context
 //! This actor is aslo responsible for correct initialization of genesis in storage.
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
@@ -20,10 +20,10 @@ use storage::{BlockMetaStorage, BlockStorage, BlockStorageReader, ChainMetaStora
 use storage::chain_meta_storage::ChainMetaStorageReader;
 use storage::persistent::PersistentStorage;
 use tezos_api::environment::TezosEnvironmentConfiguration;
-use tezos_api::ffi::ApplyBlockRequest;
-use tezos_wrapper::service::{IpcCmdServer, ProtocolController, ProtocolServiceError};
+use tezos_api::ffi::{ApplyBlockError, ApplyBlockRequest};
+use tezos_wrapper::service::{IpcCmdServer, ProtocolController, ProtocolError, ProtocolServiceError};
 
-use crate::shell_channel::{BlockApplied, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
+use crate::shell_channel::{BlockApplicationFailed, BlockApplied, ShellChannelMsg, ShellChannelRef, ShellChannelTopic};
 use crate::subscription::subscribe_to_shell_events;
 
 /// This command triggers feeding of completed blocks to the tezos protocol
@@ -77,6 +77,7 @@ impl ChainFeeder {
                 let chain_meta_storage = ChainMetaStorage::new(&persistent_storage);
                 let operations_storage = OperationsStorage::new(&persistent_storage);
                 let operations_meta_storage = OperationsMetaStorage::new(&persistent_storage);
+                let apply_lock = persistent_storage.apply_lock();
                 let mut ipc_server = ipc_server;
 
                 while apply_block_run.load(Ordering::Acquire) {
@@ -92,6 +93,7 @@ impl ChainFeeder {
                                 &chain_meta_storage,
                                 &operations_storage,
                                 &operations_meta_storage,
+                                &apply_lock,
                                 protocol_controller,
                                 &log,
                             ) {
@@ -239,6 +241,7 @@ fn feed_chain_to_protocol(
     chain_meta_storage: &ChainMetaStorage,
     operations_storage: &OperationsStorage,
     operations_meta_storage: &OperationsMetaStorage,
+    apply_lock: &RwLock<()>,
     protocol_controller: ProtocolController,
     log: &Logger,
 ) -> Result<(), FeedChainError> {
@@ -304,7 +307,9 @@ fn feed_chain_to_protocol(
                                     Some(predecesor_data) => predecesor_data
                                 };
 
-                                let apply_block_result = protocol_controller.apply_block(
+                                // protocol context is written just under the lock, so backups can pause the apply
+                                let apply_guard = apply_lock.read().expect("lock poisoning");
+                                let apply_block_result = match protocol_controller.apply_block(
                                     ApplyBlockRequest {
                                         chain_id: chain_id.clone(),
                                         block_header: (&*current_head.header).clone(),
@@ -312,7 +317,20 @@ fn feed_chain_to_protocol(
                                         operations: ApplyBlockRequest::convert_operations(operations),
                                         max_operations_ttl: predecessor_additional_data.max_operations_ttl() as i32,
                                     }
-                                )?;
+                                ) {
+                                    Ok(apply_block_result) => apply_block_result,
+                                    Err(e) => {
+                                        if let ProtocolServiceError::ProtocolError { reason: ProtocolError::ApplyBlockError { reason: ApplyBlockError::FailedToApplyBlock { .. } } } = &e {
+                                            // notify others that the block was rejected, so the peer which sent it can be reported
+                                            shell_channel.tell(
+                                                Publish {
+                                                    msg: BlockApplicationFailed { hash: current_head.hash.clone() }.into(),
+                                                    topic: ShellChannelTopic::ShellEvents.into(),
+                                                }, None);
+                                        }
+                                        return Err(e.into());
+                                    }
+                                };
                                 debug!(
                                     log,
                                     "Block was applied";
@@ -322,14 +340,17 @@ fn feed_chain_to_protocol(
                                 );
 
 
-                                // store result
+                                // store result and move current head in one batch
                                 let (block_json_data, _) = store_applied_block_result(
                                     block_storage,
                                     block_meta_storage,
+                                    chain_meta_storage,
+                                    chain_id,
                                     &current_head.hash,
                                     apply_block_result,
                                     &mut current_head_meta,
                                 )?;
+                                drop(apply_guard);
 
                                 // notify listeners
                                 if apply_block_run.load(Ordering::Acquire) {
@@ -385,12 +406,24 @@ pub(crate) fn initialize_protocol_context(
     tezos_env: &TezosEnvironmentConfiguration,
     init_storage_data: &StorageInitInfo) -> Result<(), FeedChainError> {
 
-    // we must check if genesis is applied, if not then we need "commit_genesis" to context
-    let need_commit_genesis = match block_meta_storage.get(&init_storage_data.genesis_block_header_hash)? {
-        Some(genesis_meta) => !genesis_meta.is_applied(),
-        None => true,
+    // we must check if genesis is applied, if not then we need "commit_genesis" to context,
+    // but storage imported from (rolling) snapshot has current head without applied genesis, which must not be overwritten
+    let genesis_applied = match block_meta_storage.get(&init_storage_data.genesis_block_header_hash)? {
+        Some(genesis_meta) => genesis_meta.is_applied(),
+        None => false,
     };
+    let imported_head = if genesis_applied {
+        None
+    } else {
+        chain_meta_storage.get_current_head(&init_storage_data.chain_id)?
+    };
+    let need_commit_genesis = !genesis_applied && imported_head.is_none();
     trace!(log, "Looking for genesis if applied"; "need_commit_genesis" => need_commit_genesis);
+    if let Some(head) = &imported_head {
+        info!(log, "Storage contains imported current head, skipping commit genesis";
+                   "block_hash" => HashType::BlockHash.bytes_to_string(&head.hash),
+                   "level" => head.level);
+    }
 
     // initialize protocol context runtime
     let context_init_info = protocol_controller.init_protocol_for_write(need_commit_genesis, &init_storage_data.patch_context)?;
@@ -21,7 +21,7 @@ use riker::actors::*;
 use slog::{debug, info, Logger, trace, warn};
 
 use crypto::hash::{BlockHash, ChainId, HashType, OperationHash};
-use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, PeerBootstrapped};
+use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBehavior, PeerBehaviorReported, PeerBootstrapped};
 use networking::p2p::peer::{PeerRef, SendMessage};
 use storage::{BlockHeaderWithHash, BlockStorage, BlockStorageReader, ChainMetaStorage, MempoolStorage, OperationsStorage, OperationsStorageReader, StorageError};
 use storage::chain_meta_storage::ChainMetaStorageReader;
@@ -60,6 +60,8 @@ const STALLED_CHAIN_COMPLETENESS_TIMEOUT: Duration = Duration::from_secs(240);
 const BLOCK_HASH_ENCODING: HashType = HashType::BlockHash;
 /// Mempool operation time to live
 const MEMPOOL_OPERATION_TTL: Duration = Duration::from_secs(60);
+/// Limit to how many not yet applied blocks we remember the sending peer for
+const MAX_BLOCK_SOURCES: usize = 4096;
 
 /// Message commands [`ChainManager`] to disconnect stalled peers.
 #[derive(Clone, Debug)]
@@ -140,6 +142,8 @@ pub struct ChainManager {
     shell_channel: ShellChannelRef,
     /// Holds the state of all peers
     peers: HashMap<ActorUri, PeerState>,
+    /// Peers which sent us not yet applied block headers, so they can be reported if the protocol rejects the block
+    block_sources: HashMap<BlockHash, PeerRef>,
     /// Block storage
     block_storage: Box<dyn BlockStorageReader>,
     /// Chain meta storage
@@ -224,38 +228,10 @@ impl ChainManager {
 
     /// Check for missing blocks in local chain copy, and schedule downloading for those blocks
     fn check_chain_completeness(&mut self, ctx: &Context<ChainManagerMsg>) -> Result<(), Error> {
-        let ChainManager { peers, chain_state, operations_state, stats, .. } = self;
-
         // check for missing blocks
-        if chain_state.has_missing_blocks() {
-            peers.values_mut()
-                .filter(|peer| peer.current_head_level.is_some())
-                .filter(|peer| peer.available_block_queue_capacity() > 0)
-                .sorted_by_key(|peer| peer.available_block_queue_capacity()).rev()
-                .for_each(|peer| {
-                    let mut missing_blocks = chain_state.drain_missing_blocks(peer.available_block_queue_capacity(), peer.current_head_level.unwrap());
-                    if !missing_blocks.is_empty() {
-                        let queued_blocks = missing_blocks.drain(..)
-                            .map(|missing_block| {
-                                let missing_block_hash = missing_block.block_hash.clone();
-                                if peer.queued_block_headers.insert(missing_block_hash.clone(), missing_block).is_none() {
-                                    // block was not already present in queue
-                                    Some(missing_block_hash)
-                                } else {
-                                    // block was already in queue
-                                    None
-                                }
-                            })
-                            .filter_map(|missing_block_hash| missing_block_hash)
-                            .collect::<Vec<_>>();
+        self.schedule_missing_blocks();
 
-                        if !queued_blocks.is_empty() {
-                            peer.block_request_last = Instant::now();
-                            tell_peer(GetBlockHeadersMessage::new(queued_blocks).into(), peer);
-                        }
-                    }
-                });
-        }
+        let ChainManager { peers, operations_state, stats, .. } = self;
 
         // check for missing block operations
         if operations_state.has_missing_block_operations() {
@@ -297,11 +273,48 @@ impl ChainManager {
         Ok(())
     }
 
+    /// Schedule downloading of the missing blocks from the peers, which announced their current head
+    fn schedule_missing_blocks(&mut self) {
+        let ChainManager { peers, chain_state, .. } = self;
+
+        if chain_state.has_missing_blocks() {
+            peers.values_mut()
+                .filter(|peer| peer.current_head_level.is_some())
+                .filter(|peer| peer.available_block_queue_capacity() > 0)
+                .sorted_by_key(|peer| peer.available_block_queue_capacity()).rev()
+                .for_each(|peer| {
+                    let mut missing_blocks = chain_state.drain_missing_blocks(peer.available_block_queue_capacity(), peer.current_head_level.unwrap());
+                    if !missing_blocks.is_empty() {
+                        let queued_blocks = missing_blocks.drain(..)
+                            .map(|missing_block| {
+                                let missing_block_hash = missing_block.block_hash.clone();
+                                if peer.queued_block_headers.insert(missing_block_hash.clone(), missing_block).is_none() {
+                                    // block was not already present in queue
+                                    Some(missing_block_hash)
+                                } else {
+                                    // block was already in queue
+                                    None
+                                }
+                            })
+                            .filter_map(|missing_block_hash| missing_block_hash)
+                            .collect::<Vec<_>>();
+
+                        if !queued_blocks.is_empty() {
+                            peer.block_request_last = Instant::now();
+                            tell_peer(GetBlockHeadersMessage::new(queued_blocks).into(), peer);
+                        }
+                    }
+                });
+        }
+    }
+
     fn process_network_channel_message(&mut self, ctx: &Context<ChainManagerMsg>, msg: NetworkChannelMsg) -> Result<(), Error> {
         let ChainManager {
             peers,
+            block_sources,
             chain_state,
             operations_state,
+            network_channel,
             shell_channel,
             block_storage,
             operations_storage,
@@ -368,6 +381,7 @@ impl ChainManager {
                                     }
 
                                     // update peer stats
+                                    peer.is_chain_active = true;
                                     if peer.current_head_level.is_none() || (message.current_branch().current_head().level() > peer.current_head_level.unwrap()) {
                                         peer.current_head_level = Some(message.current_branch().current_head().level());
                                         peer.current_head_update_last = Instant::now();
@@ -389,6 +403,7 @@ impl ChainManager {
                                 PeerMessage::GetCurrentBranch(message) => {
                                     debug!(log, "Current branch requested by a peer");
                                     if chain_state.get_chain_id() == &message.chain_id {
+                                        peer.is_chain_active = true;
                                         if let Some(current_head_local) = &current_head.local {
                                             if let Some(current_head) = block_storage.get(&current_head_local.hash)? {
                                                 let history = chain_state.get_history()?;
@@ -399,7 +414,14 @@ impl ChainManager {
                                     }
                                 }
                                 PeerMessage::BlockHeader(message) => {
-                                    let block_header_with_hash = BlockHeaderWithHash::new(message.block_header().clone()).unwrap();
+                                    let block_header_with_hash = match BlockHeaderWithHash::new(message.block_header().clone()) {
+                                        Ok(block_header_with_hash) => block_header_with_hash,
+                                        Err(e) => {
+                                            warn!(log, "Received invalid block header"; "reason" => format!("{:?}", e));
+                                            report_peer(network_channel, &received.peer, PeerBehavior::InvalidBlock);
+                                            continue;
+                                        }
+                                    };
                                     match peer.queued_block_headers.remove(&block_header_with_hash.hash) {
                                         Some(_) => {
                                             trace!(log, "Received block header");
@@ -410,6 +432,13 @@ impl ChainManager {
                                                     .and(operations_state.process_block_header(&block_header_with_hash))?;
 
                                             if is_new_block {
+                                                report_peer(network_channel, &received.peer, PeerBehavior::UsefulData);
+
+                                                // remember who sent the block, so it can be reported if the protocol rejects it
+                                                if block_sources.len() < MAX_BLOCK_SOURCES {
+                                                    block_sources.insert(block_header_with_hash.hash.clone(), received.peer.clone());
+                                                }
+
                                                 // update stats
                                                 stats.unseen_block_last = Instant::now();
                                                 stats.unseen_block_count += 1;
@@ -430,6 +459,7 @@ impl ChainManager {
                                         }
                                         None => {
                                             warn!(log, "Received unexpected block header"; "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_header_with_hash.hash));
+                                            report_peer(network_channel, &received.peer, PeerBehavior::UnsolicitedMessage);
                                         }
                                     }
                                 }
@@ -462,6 +492,7 @@ impl ChainManager {
                                         Some(missing_operations) => {
                                             let operation_was_expected = missing_operations.validation_passes.remove(&operations.operations_for_block().validation_pass());
                                             if operation_was_expected {
+                                                report_peer(network_channel, &received.peer, PeerBehavior::UsefulData);
                                                 peer.block_operations_response_last = Instant::now();
                                                 trace!(log, "Received operations validation pass"; "validation_pass" => operations.operations_for_block().validation_pass(), "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_hash));
 
@@ -488,11 +519,13 @@ impl ChainManager {
                                                 }
                                             } else {
                                                 warn!(log, "Received unexpected validation pass"; "validation_pass" => operations.operations_for_block().validation_pass(), "block_header_hash" => BLOCK_HASH_ENCODING.bytes_to_string(&block_hash));
+                                                report_peer(network_channel, &received.peer, PeerBehavior::UnsolicitedMessage);
                                                 ctx.system.stop(received.peer.clone());
                                             }
                                         }
                                         None => {
                                             warn!(log, "Received unexpected operations");
+                                            report_peer(network_channel, &received.peer, PeerBehavior::UnsolicitedMessage);
                                             ctx.system.stop(received.peer.clone());
                                         }
                                     }
@@ -512,6 +545,7 @@ impl ChainManager {
                                 PeerMessage::CurrentHead(message) => {
                                     debug!(log, "Current head received");
                                     if chain_state.get_chain_id() == message.chain_id() {
+                                        peer.is_chain_active = true;
                                         let peer_current_mempool = message.current_mempool();
 
                                         // all operations (known_valid + pending) should be added to pending and validated afterwards
@@ -545,8 +579,12 @@ impl ChainManager {
                                     match peer.queued_mempool_operations.remove(&message.operation().message_hash()?) {
                                         Some((op_type, op_ttl)) => {
                                             // store mempool operation
+                                            report_peer(network_channel, &received.peer, PeerBehavior::UsefulData);
                                             peer.mempool_operations_response_last = Instant::now();
                                             mempool_storage.put(op_type.clone(), message.clone(), op_ttl)?;
+                                            if let Err(e) = mempool_storage.record_received(&message.operation().message_hash()?, Some(received.peer_address.to_string()), SystemTime::now()) {
+                                                warn!(log, "Failed to record received mempool operation to history"; "peer" => received.peer_address.to_string(), "error" => format!("{:?}", e));
+                                            }
 
                                             // trigger CheckMempoolCompleteness
                                             ctx.myself().tell(CheckMempoolCompleteness, None);
@@ -561,12 +599,21 @@ impl ChainManager {
                                                     topic: ShellChannelTopic::ShellEvents.into(),
                                                 }, Some(ctx.myself().into()));
                                         }
-                                        None => debug!(log, "Unexpected mempool operation received")
+                                        None => {
+                                            debug!(log, "Unexpected mempool operation received");
+                                            report_peer(network_channel, &received.peer, PeerBehavior::UnsolicitedMessage);
+                                        }
                                     }
                                 }
                                 PeerMessage::Bootstrap => {
                                     // on bootstrap reset peer state
                                 }
+                                PeerMessage::Deactivate(message) => {
+                                    if chain_state.get_chain_id() == message.deactivate() {
+                                        info!(log, "Peer deactivated chain - stopping chain synchronization with the peer"; "chain_id" => HashType::ChainId.bytes_to_string(message.deactivate()));
+                                        deactivate_chain(peer, chain_state, operations_state)?;
+                                    }
+                                }
                                 ignored_message => trace!(log, "Ignored message"; "message" => format!("{:?}", ignored_message))
                             }
                         }
@@ -583,6 +630,7 @@ impl ChainManager {
     fn process_shell_channel_message(&mut self, ctx: &Context<ChainManagerMsg>, msg: ShellChannelMsg) -> Result<(), Error> {
         match msg {
             ShellChannelMsg::BlockApplied(message) => {
+                self.block_sources.remove(&message.header().hash);
 
                 // this logic is equivalent to [chain_validator.ml][let on_request]
                 // if applied block is winner we need to:
@@ -630,6 +678,7 @@ impl ChainManager {
                             let chain_id = self.chain_state.get_chain_id();
 
                             self.peers.iter()
+                                .filter(|(_, peer)| peer.is_chain_active)
                                 .for_each(|(_, peer)| {
                                     tell_peer(
                                         CurrentHeadMessage::new(
@@ -665,7 +714,7 @@ impl ChainManager {
                     if !mempool_to_send.is_empty() {
                         let ChainManager { peers, chain_state, .. } = self;
                         peers.iter_mut()
-                            .filter(|(_, peer)| peer.mempool_enabled)
+                            .filter(|(_, peer)| peer.mempool_enabled && peer.is_chain_active)
                             .for_each(|(_, peer)| {
                                 tell_peer(
                                     CurrentHeadMessage::new(
@@ -756,6 +805,12 @@ impl ChainManager {
                     }
                 }
             }
+            ShellChannelMsg::BlockApplicationFailed(message) => {
+                if let Some(peer) = self.block_sources.remove(&message.hash) {
+                    warn!(ctx.system.log(), "Protocol rejected block, reporting peer which sent it"; "block_header_hash" => HashType::BlockHash.bytes_to_string(&message.hash), "peer" => peer.name());
+                    report_peer(&self.network_channel, &peer, PeerBehavior::InvalidBlock);
+                }
+            }
             ShellChannelMsg::ShuttingDown(_) => {
                 self.shutting_down = true;
                 unsubscribe_from_dead_letters(ctx.system.dead_letters(), ctx.myself());
@@ -848,6 +903,7 @@ impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, PersistentStorage, Ch
             chain_state: BlockchainState::new(&persistent_storage, &chain_id),
             operations_state: OperationsState::new(&persistent_storage, &chain_id),
             peers: HashMap::new(),
+            block_sources: HashMap::new(),
             current_head: CurrentHead {
                 local: None,
                 remote: None,
@@ -929,13 +985,7 @@ impl Receive<SystemEvent> for ChainManager {
     fn receive(&mut self, _ctx: &Context<Self::Msg>, msg: SystemEvent, _sender: Option<BasicActorRef>) {
         if let SystemEvent::ActorTerminated(evt) = msg {
             if let Some(mut peer) = self.peers.remove(evt.actor.uri()) {
-                peer.queued_block_headers
-                    .drain()
-                    .for_each(|(_, missing_block)| {
-                        self.chain_state.push_missing_block(missing_block).expect("Failed to re-schedule block hash");
-                    });
-
-                self.operations_state.push_missing_block_operations(peer.queued_block_operations.drain().map(|(_, op)| op))
+                reschedule_queued(&mut peer, &mut self.chain_state, &mut self.operations_state)
                     .expect("Failed to return to queue")
             }
         }
@@ -993,6 +1043,7 @@ impl Receive<DisconnectStalledPeers> for ChainManager {
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: DisconnectStalledPeers, _sender: Sender) {
         self.peers.iter()
+            .filter(|(_, state)| state.is_chain_active)
             .for_each(|(uri, state)| {
                 let block_response_pending = state.block_request_last > state.block_response_last;
                 let block_operations_response_pending = state.block_operations_request_last > state.block_operations_response_last;
@@ -1021,6 +1072,7 @@ impl Receive<DisconnectStalledPeers> for ChainManager {
                 };
 
                 if should_disconnect {
+                    report_peer(&self.network_channel, &state.peer_ref, PeerBehavior::Timeout);
                     ctx.system.stop(state.peer_ref.clone());
                 }
             });
@@ -1080,6 +1132,7 @@ impl Receive<AskPeersAboutCurrentBranch> for ChainManager {
     fn receive(&mut self, _ctx: &Context<Self::Msg>, _msg: AskPeersAboutCurrentBranch, _sender: Sender) {
         let ChainManager { peers, chain_state, .. } = self;
         peers.iter_mut()
+            .filter(|(_, peer)| peer.is_chain_active)
             .for_each(|(_, peer)| tell_peer(GetCurrentBranchMessage::new(chain_state.get_chain_id().clone()).into(), peer))
     }
 }
@@ -1092,6 +1145,8 @@ struct PeerState {
     mempool_enabled: bool,
     /// Is bootstrapped flag
     is_bootstrapped: bool,
+    /// Chain synchronization with the peer is active, until the peer deactivates our chain
+    is_chain_active: bool,
 
     /// Queued blocks
     queued_block_headers: HashMap<BlockHash, MissingBlock>,
@@ -1128,6 +1183,7 @@ impl PeerState {
             peer_ref,
             mempool_enabled: !peer_metadata.disable_mempool(),
             is_bootstrapped: false,
+            is_chain_active: true,
             queued_block_headers: HashMap::new(),
             queued_block_operations: HashMap::new(),
             missing_mempool_operations: Vec::new(),
@@ -1171,10 +1227,40 @@ impl PeerState {
     }
 }
 
+/// Return blocks and operations queued for the peer back to the missing ones, so they can be requested from other peers
+fn reschedule_queued(peer: &mut PeerState, chain_state: &mut BlockchainState, operations_state: &mut OperationsState) -> Result<(), StorageError> {
+    for (_, missing_block) in peer.queued_block_headers.drain() {
+        chain_state.push_missing_block(missing_block)?;
+    }
+    operations_state.push_missing_block_operations(peer.queued_block_operations.drain().map(|(_, op)| op))
+}
+
+/// Stop chain synchronization with the peer, which deactivated our chain, blocks are not scheduled to the peer
+/// until it announces its current head again. Peer is activated again by any message of the chain.
+fn deactivate_chain(peer: &mut PeerState, chain_state: &mut BlockchainState, operations_state: &mut OperationsState) -> Result<(), StorageError> {
+    peer.is_chain_active = false;
+    peer.current_head_level = None;
+    peer.missing_mempool_operations.clear();
+    peer.queued_mempool_operations.clear();
+    reschedule_queued(peer, chain_state, operations_state)
+}
+
 fn tell_peer(msg: PeerMessageResponse, peer: &PeerState) {
     peer.peer_ref.tell(SendMessage::new(msg), None);
 }
 
+/// Report behavior of the peer, which affects its reputation
+fn report_peer(network_channel: &NetworkChannelRef, peer: &PeerRef, behavior: PeerBehavior) {
+    network_channel.tell(
+        Publish {
+            msg: PeerBehaviorReported {
+                peer: peer.clone(),
+                behavior,
+            }.into(),
+            topic: NetworkChannelTopic::NetworkEvents.into(),
+        }, None);
+}
+
 fn resolve_mempool_to_send(mempool_state: &CurrentMempoolState) -> Mempool {
     // collect for mempool
     let known_valid = mempool_state.result.applied.iter().map(|a| a.hash.clone()).collect::<Vec<OperationHash>>();
@@ -1210,6 +1296,7 @@ pub mod tests {
 
     use slog::{Drain, Level, Logger};
 
+    use crypto::proof_of_work::PowTarget;
     use networking::p2p::network_channel::NetworkChannel;
     use networking::p2p::peer::Peer;
     use storage::tests_common::TmpStorage;
@@ -1246,7 +1333,9 @@ pub mod tests {
             "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b",
             "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b",
             "000000000000000000000000000000000000000000000000",
+            PowTarget::new(0.0).unwrap(),
             NetworkVersion::new("testet".to_string(), 0, 0),
+            0,
             tokio_runtime.handle().clone(),
             &socket_address,
         ).unwrap();
@@ -1335,4 +1424,72 @@ pub mod tests {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_deactivate_stops_block_scheduling() -> Result<(), Error> {
+        let log = create_logger(Level::Debug);
+        let storage = TmpStorage::create_to_out_dir("__test_deactivate_stops_block_scheduling")?;
+
+        let tokio_runtime = create_tokio_runtime();
+        let actor_system = SystemBuilder::new().name("test_deactivate_stops_block_scheduling").log(log.clone()).create().expect("Failed to create actor system");
+        let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+        let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+        let chain_id = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+        let mut chain_manager = ChainManager::create_args((
+            network_channel.clone(),
+            shell_channel.clone(),
+            storage.storage().clone(),
+            chain_id,
+            false,
+            1,
+        ));
+
+        let mut peer_state = peer(&actor_system, network_channel.clone(), &tokio_runtime);
+        peer_state.current_head_level = Some(10);
+        let peer_key = peer_state.peer_ref.uri().clone();
+        chain_manager.peers.insert(peer_key.clone(), peer_state);
+
+        let block_1 = HashType::BlockHash.string_to_bytes("BLFQ2JjYWHC95Db21cRZC4cgyA1mcXmx1Eg6jKywWy9b8xLzyK9")?;
+        let block_2 = HashType::BlockHash.string_to_bytes("BLockGenesisGenesisGenesisGenesisGenesisf79b5d1CoW2")?;
+
+        // active peer gets the missing block scheduled
+        chain_manager.chain_state.push_missing_block(MissingBlock::with_level(block_1.clone(), 5))?;
+        chain_manager.schedule_missing_blocks();
+        assert!(chain_manager.peers[&peer_key].queued_block_headers.contains_key(&block_1));
+        assert!(!chain_manager.chain_state.has_missing_blocks());
+
+        // deactivation returns queued blocks back to the missing ones
+        {
+            let ChainManager { peers, chain_state, operations_state, .. } = &mut chain_manager;
+            deactivate_chain(peers.get_mut(&peer_key).unwrap(), chain_state, operations_state)?;
+        }
+        assert!(!chain_manager.peers[&peer_key].is_chain_active);
+        assert!(chain_manager.peers[&peer_key].queued_block_headers.is_empty());
+        assert_eq!(1, chain_manager.chain_state.missing_blocks_count());
+
+        // deactivated peer gets no more blocks
+        chain_manager.chain_state.push_missing_block(MissingBlock::with_level(block_2.clone(), 6))?;
+        chain_manager.schedule_missing_blocks();
+        assert!(chain_manager.peers[&peer_key].queued_block_headers.is_empty());
+        assert_eq!(2, chain_manager.chain_state.missing_blocks_count());
+
+        // peer is scheduled again after it announces its current head
+        chain_manager.peers.get_mut(&peer_key).unwrap().current_head_level = Some(10);
+        chain_manager.schedule_missing_blocks();
+        assert_eq!(2, chain_manager.peers[&peer_key].queued_block_headers.len());
+        assert!(!chain_manager.chain_state.has_missing_blocks());
+
+        // close
+        shell_channel.tell(
+            Publish {
+                msg: ShuttingDown.into(),
+                topic: ShellChannelTopic::ShellCommands.into(),
+            }, None,
+        );
+        thread::sleep(Duration::from_secs(1));
+        let _ = actor_system.shutdown();
+
+        Ok(())
+    }
+}
@@ -3,23 +3,28 @@
 
 //! Listens for events from the `protocol_runner`.
 
+use std::fs::File;
+use std::io::BufWriter;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
 
-use failure::Error;
+use failure::{Error, Fail};
 use riker::actors::*;
-use slog::{crit, debug, Logger, warn, info};
+use slog::{crit, debug, error, Logger, warn, info};
 
-use crypto::hash::HashType;
-use storage::{BlockStorage, ContextActionStorage};
+use crypto::hash::{ContextHash, HashType};
+use storage::{BlockStorage, BlockStorageReader, ContextActionStorage};
 use storage::context::{ContextApi, ContextDiff, TezedgeContext};
+use storage::context_trace::{ContextTraceWriter, TraceRecord};
+use storage::merkle_storage::{CommitInfo, MerkleError, MerkleStorage};
 use storage::persistent::PersistentStorage;
 use tezos_context::channel::ContextAction;
 use tezos_wrapper::service::IpcEvtServer;
 
 type SharedJoinHandle = Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>;
+type TraceWriter = ContextTraceWriter<BufWriter<File>>;
 
 /// This actor listens for events generated by the `protocol_runner`.
 #[actor]
@@ -38,12 +43,20 @@ impl ContextListener {
     ///
     /// This actor spawns a new thread in which it listens for incoming events from the `protocol_runner`.
     /// Events are received from IPC channel provided by [`event_server`](IpcEvtServer).
+    ///
+    /// If `check_context_hash` is enabled, every commit is also stored to the [merkle storage](MerkleStorage)
+    /// and resulting hash is compared with the context hash calculated by the protocol.
+    /// On mismatch, context events are not processed anymore, so no further block is applied with the unverified context.
+    ///
+    /// If `context_trace` is provided, all received events are recorded to the trace, which can be replayed later without the protocol.
     pub fn actor(
         sys: &impl ActorRefFactory,
         persistent_storage: &PersistentStorage,
         mut event_server: IpcEvtServer,
         log: Logger,
-        store_context_action: bool
+        store_context_action: bool,
+        check_context_hash: bool,
+        mut context_trace: Option<TraceWriter>,
     ) -> Result<ContextListenerRef, CreateError> {
         let context_storage = persistent_storage.context_storage();
         let listener_run = Arc::new(AtomicBool::new(true));
@@ -54,16 +67,25 @@ impl ContextListener {
             thread::spawn(move || -> Result<(), Error> {
                 let mut context: Box<dyn ContextApi> = Box::new(TezedgeContext::new(BlockStorage::new(&persistent_storage), context_storage));
                 let mut context_action_storage = ContextActionStorage::new(&persistent_storage);
+                let block_storage = BlockStorage::new(&persistent_storage);
+                let merkle_storage = if check_context_hash { Some(MerkleStorage::new(&persistent_storage)) } else { None };
                 while listener_run.load(Ordering::Acquire) {
                     match listen_protocol_events(
                         &listener_run,
                         &mut event_server,
                         &mut context_action_storage,
                         &mut context,
+                        &merkle_storage,
+                        &block_storage,
+                        &mut context_trace,
                         &log,
                         store_context_action,
                     ) {
                         Ok(()) => info!(log, "Context listener finished"),
+                        Err(err) if err.downcast_ref::<ContextHashMismatch>().is_some() => {
+                            crit!(log, "Context hash check failed, context events are not processed anymore"; "reason" => format!("{}", err));
+                            break;
+                        }
                         Err(err) => {
                             if listener_run.load(Ordering::Acquire) {
                                 crit!(log, "Error process context event"; "reason" => format!("{:?}", err))
@@ -72,6 +94,13 @@ impl ContextListener {
                     }
                 }
 
+                if let Some(context_trace) = context_trace {
+                    match context_trace.finish() {
+                        Ok(records) => info!(log, "Context trace finished"; "records" => records),
+                        Err(err) => warn!(log, "Failed to finish context trace"; "reason" => err),
+                    }
+                }
+
                 Ok(())
             })
         };
@@ -134,11 +163,25 @@ fn store_action(storage: &mut ContextActionStorage, should_store: bool, action:
     }
 }
 
+/// Header of the block precedes the commit of its context in the trace, so the trace can be replayed to the empty storage
+fn record_action(context_trace: &mut TraceWriter, block_storage: &BlockStorage, action: &ContextAction) -> Result<(), Error> {
+    if let ContextAction::Commit { block_hash: Some(block_hash), .. } = action {
+        if let Some(block) = block_storage.get(block_hash)? {
+            context_trace.write(&TraceRecord::Block(block))?;
+        }
+    }
+    context_trace.write(&TraceRecord::Action(action.clone()))?;
+    Ok(())
+}
+
 fn listen_protocol_events(
     apply_block_run: &AtomicBool,
     event_server: &mut IpcEvtServer,
     context_action_storage: &mut ContextActionStorage,
     context: &mut Box<dyn ContextApi>,
+    merkle_storage: &Option<MerkleStorage>,
+    block_storage: &BlockStorage,
+    context_trace: &mut Option<TraceWriter>,
     log: &Logger,
     store_context_actions: bool,
 ) -> Result<(), Error> {
@@ -184,8 +227,20 @@ fn listen_protocol_events(
                         if !ignored {
                             context.remove_recursively_to_diff(context_hash, key, &mut context_diff)?;
                         }
-                    ContextAction::Commit { parent_context_hash, new_context_hash, block_hash: Some(block_hash), .. } =>
-                        context.commit(block_hash, parent_context_hash, new_context_hash, &context_diff)?,
+                    ContextAction::Commit { parent_context_hash, new_context_hash, block_hash: Some(block_hash), author, message, date, parents, .. } => {
+                        context.commit(block_hash, parent_context_hash, new_context_hash, &context_diff)?;
+                        if let Some(merkle_storage) = merkle_storage {
+                            let info = CommitInfo { parents, date: *date, author, message };
+                            check_context_hash(merkle_storage, parent_context_hash, new_context_hash, &context_diff, &info, log)?;
+                        }
+                    }
+                    // genesis commit (without block) is not stored by the context, but merkle storage needs it as the parent of the first block
+                    ContextAction::Commit { parent_context_hash, new_context_hash, block_hash: None, author, message, date, parents, .. } => {
+                        if let Some(merkle_storage) = merkle_storage {
+                            let info = CommitInfo { parents, date: *date, author, message };
+                            check_context_hash(merkle_storage, parent_context_hash, new_context_hash, &context_diff, &info, log)?;
+                        }
+                    }
                     ContextAction::Checkout { context_hash, .. } => {
                         event_count = 0;
                         context_diff = context.checkout(context_hash)?;
@@ -193,6 +248,13 @@ fn listen_protocol_events(
                     _ => (),
                 };
 
+                if let Some(writer) = context_trace {
+                    if let Err(err) = record_action(writer, block_storage, &msg) {
+                        warn!(log, "Failed to record context trace, recording is stopped"; "reason" => format!("{}", err));
+                        *context_trace = None;
+                    }
+                }
+
                 store_action(context_action_storage, store_context_actions, msg)?;
             }
             Err(err) => {
@@ -204,3 +266,34 @@ fn listen_protocol_events(
 
     Ok(())
 }
+
+/// Context hash calculated by the merkle storage differs from the one calculated by the protocol
+#[derive(Debug, Fail)]
+#[fail(display = "Merkle context hash {} does not match context hash {} calculated by protocol", calculated, expected)]
+struct ContextHashMismatch {
+    expected: String,
+    calculated: String,
+}
+
+/// Commits context diff to the merkle storage and compares resulting hash with the one calculated by the protocol.
+/// Mismatch is returned as [ContextHashMismatch] error, failures of the merkle storage itself are just reported.
+fn check_context_hash(
+    merkle_storage: &MerkleStorage,
+    parent_context_hash: &Option<ContextHash>,
+    new_context_hash: &ContextHash,
+    context_diff: &ContextDiff,
+    info: &CommitInfo,
+    log: &Logger) -> Result<(), ContextHashMismatch> {
+    match merkle_storage.commit_diff(parent_context_hash, &context_diff.diff, info) {
+        Ok(context_hash) => if &context_hash != new_context_hash {
+            return Err(ContextHashMismatch {
+                expected: HashType::ContextHash.bytes_to_string(new_context_hash),
+                calculated: HashType::ContextHash.bytes_to_string(&context_hash),
+            });
+        }
+        // parent context was committed before the check was enabled
+        Err(MerkleError::EntryNotFound { hash }) => warn!(log, "Context hash check skipped, parent context is not in merkle storage"; "missing" => hash),
+        Err(err) => error!(log, "Failed to commit context to merkle storage"; "reason" => err),
+    }
+    Ok(())
+}
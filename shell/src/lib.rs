@@ -12,7 +12,9 @@ pub mod chain_feeder;
 pub mod context_listener;
 pub mod chain_manager;
 pub mod peer_manager;
+pub mod peer_reputation;
 pub mod mempool_prevalidator;
+pub mod storage_pruner;
 
 /// Simple threshold, for representing integral ranges.
 #[derive(Copy, Clone, Debug)]
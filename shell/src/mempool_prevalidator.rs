@@ -16,17 +16,19 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver as QueueReceiver, Sender as QueueSender};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
 
 use failure::{Error, Fail};
 use riker::actors::*;
 use slog::{debug, info, Logger, trace, warn};
 
 use crypto::hash::{BlockHash, ChainId, HashType, OperationHash};
-use storage::{BlockStorage, BlockStorageReader, MempoolStorage, StorageError, StorageInitInfo};
+use storage::{BlockStorage, BlockStorageReader, MempoolStorage, OperationsStorage, OperationsStorageReader, StorageError, StorageInitInfo};
 use storage::chain_meta_storage::{ChainMetaStorage, ChainMetaStorageReader};
-use storage::mempool_storage::MempoolOperationType;
+use storage::mempool_storage::{MempoolOperationType, MempoolPrevalidation, PrevalidationStatus};
 use storage::persistent::PersistentStorage;
 use tezos_api::ffi::{Applied, BeginConstructionRequest, Errored, PrevalidatorWrapper, ValidateOperationRequest, ValidateOperationResult};
+use tezos_messages::p2p::binary_message::MessageHash;
 use tezos_messages::p2p::encoding::block_header::BlockHeader;
 use tezos_messages::p2p::encoding::prelude::Operation;
 use tezos_wrapper::service::{ProtocolController, ProtocolServiceError};
@@ -37,6 +39,9 @@ use crate::subscription::subscribe_to_shell_events;
 
 type SharedJoinHandle = Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>;
 
+/// How long is the history of mempool operations kept
+const MEMPOOL_HISTORY_RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
+
 /// Feeds blocks and operations to the tezos protocol (ocaml code).
 #[actor(ShellChannelMsg)]
 pub struct MempoolPrevalidator {
@@ -79,6 +84,7 @@ impl MempoolPrevalidator {
                 let mut block_storage = BlockStorage::new(&persistent_storage);
                 let mut chain_meta_storage = ChainMetaStorage::new(&persistent_storage);
                 let mut mempool_storage = MempoolStorage::new(&persistent_storage);
+                let operations_storage = OperationsStorage::new(&persistent_storage);
 
                 while validator_run.load(Ordering::Acquire) {
                     match tezos_readonly_api.pool.get() {
@@ -87,6 +93,7 @@ impl MempoolPrevalidator {
                                 &mut block_storage,
                                 &mut chain_meta_storage,
                                 &mut mempool_storage,
+                                &operations_storage,
                                 &chain_id,
                                 &validator_run,
                                 &shell_channel,
@@ -299,6 +306,7 @@ fn process_prevalidation(
     block_storage: &BlockStorage,
     chain_meta_storage: &ChainMetaStorage,
     mempool_storage: &MempoolStorage,
+    operations_storage: &OperationsStorage,
     chain_id: &ChainId,
     validator_run: &AtomicBool,
     shell_channel: &ShellChannelRef,
@@ -338,6 +346,11 @@ fn process_prevalidation(
                     // notify other actors
                     notify_mempool_changed(&shell_channel, &state);
 
+                    // record, how operations left the mempool, history is not critical for the mempool itself
+                    if let Err(err) = record_new_head_to_history(mempool_storage, operations_storage, &header_hash, &operations_to_delete, &log) {
+                        warn!(log, "Mempool - failed to record history"; "block_hash" => HashType::BlockHash.bytes_to_string(&header_hash), "error" => format!("{:?}", err));
+                    }
+
                     // clear unneeded operations from mempool storage
                     operations_to_delete
                         .drain()
@@ -371,7 +384,7 @@ fn process_prevalidation(
         }
 
         // 2. lets handle pending operations (if any)
-        handle_pending_operations(&shell_channel, &protocol_controller, &mut state, &log);
+        handle_pending_operations(&shell_channel, &protocol_controller, mempool_storage, &mut state, &log);
     }
 
     Ok(())
@@ -410,7 +423,7 @@ fn hydrate_state(
     // TODO: do we need this?
     // and process it immediatly on startup, before any event received to clean old stored unprocessed operations
     if state.can_handle_pending() {
-        handle_pending_operations(&shell_channel, &protocol_controller, &mut state, &log);
+        handle_pending_operations(&shell_channel, &protocol_controller, mempool_storage, &mut state, &log);
     }
 
     Ok(state)
@@ -442,7 +455,7 @@ fn begin_construction(protocol_controller: &ProtocolController,
     Ok(result)
 }
 
-fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controller: &ProtocolController, state: &mut MempoolState, log: &Logger) {
+fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controller: &ProtocolController, mempool_storage: &MempoolStorage, state: &mut MempoolState, log: &Logger) {
     debug!(log, "Mempool - handle_pending_operations "; "pendings" => state.pending.len(), "can" => state.can_handle_pending());
 
     if !state.can_handle_pending() {
@@ -480,12 +493,17 @@ fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controlle
 
                             // merge new result with existing one
                             state_changed |= state.add_result(&result);
+                            record_prevalidation_to_history(mempool_storage, &result, &log);
 
                             // TODO: handle Duplicate/ Outdated - if result is empty
                             // TODO: handle result like ocaml - branch_delayed (is_endorsement) add back to pending and so on - check handle_unprocessed
                         }
                         Err(err) => {
                             warn!(log, "Mempool - failed to validate operation message"; "hash" => HashType::OperationHash.bytes_to_string(&pending_op), "error" => format!("{:?}", err));
+                            let prevalidation = MempoolPrevalidation::new(PrevalidationStatus::Failed, SystemTime::now(), Some(format!("{:?}", err)));
+                            if let Err(err) = mempool_storage.record_prevalidation(&pending_op, prevalidation, vec![]) {
+                                warn!(log, "Mempool - failed to record prevalidation to history"; "hash" => HashType::OperationHash.bytes_to_string(&pending_op), "error" => format!("{:?}", err));
+                            }
                             // TODO: create custom error and add to refused or just revalidate (retry algorithm?)
                             // TODO: handle error?
                         }
@@ -504,6 +522,79 @@ fn handle_pending_operations(shell_channel: &ShellChannelRef, protocol_controlle
     }
 }
 
+/// Store prevalidation results to the mempool history
+fn record_prevalidation_to_history(mempool_storage: &MempoolStorage, result: &ValidateOperationResult, log: &Logger) {
+    let now = SystemTime::now();
+    let mut records = vec![];
+    for applied in &result.applied {
+        records.push((&applied.hash, MempoolPrevalidation::new(PrevalidationStatus::Applied, now, None), operation_sources(&applied.protocol_data_json)));
+    }
+    let errored = [
+        (&result.refused, PrevalidationStatus::Refused),
+        (&result.branch_refused, PrevalidationStatus::BranchRefused),
+        (&result.branch_delayed, PrevalidationStatus::BranchDelayed),
+    ];
+    for (errored, status) in errored.iter() {
+        for errored in errored.iter() {
+            let data = &errored.protocol_data_json_with_error_json;
+            let error = if data.error_json.is_empty() { None } else { Some(data.error_json.clone()) };
+            records.push((&errored.hash, MempoolPrevalidation::new(*status, now, error), operation_sources(&data.protocol_data_json)));
+        }
+    }
+
+    for (operation_hash, prevalidation, sources) in records {
+        if let Err(err) = mempool_storage.record_prevalidation(operation_hash, prevalidation, sources) {
+            warn!(log, "Mempool - failed to record prevalidation to history"; "hash" => HashType::OperationHash.bytes_to_string(operation_hash), "error" => format!("{:?}", err));
+        }
+    }
+}
+
+/// Source addresses of the operation contents, e.g. `{"contents":[{"kind":"transaction","source":"tz1..",..}],..}`
+fn operation_sources(protocol_data_json: &str) -> Vec<String> {
+    let protocol_data: serde_json::Value = match serde_json::from_str(protocol_data_json) {
+        Ok(protocol_data) => protocol_data,
+        Err(_) => return vec![],
+    };
+    let mut sources: Vec<String> = protocol_data["contents"].as_array()
+        .map(|contents| contents.iter().filter_map(|content| content["source"].as_str().map(|source| source.to_string())).collect())
+        .unwrap_or_default();
+    sources.sort();
+    sources.dedup();
+    sources
+}
+
+/// Operations of the new head are marked as included, removed operations as dropped, old history is pruned
+fn record_new_head_to_history(
+    mempool_storage: &MempoolStorage,
+    operations_storage: &OperationsStorage,
+    head_hash: &BlockHash,
+    removed_operations: &HashSet<OperationHash>,
+    log: &Logger) -> Result<(), PrevalidationError> {
+    let now = SystemTime::now();
+
+    let mut included = HashSet::new();
+    for operations in operations_storage.get_operations(head_hash)? {
+        for operation in operations.operations() {
+            included.insert(operation.message_hash().map_err(StorageError::from)?);
+        }
+    }
+    for operation_hash in &included {
+        mempool_storage.record_included(operation_hash, head_hash, now)?;
+    }
+
+    let reason = format!("Removed from mempool on new head {}", HashType::BlockHash.bytes_to_string(head_hash));
+    for operation_hash in removed_operations.difference(&included) {
+        mempool_storage.record_dropped(operation_hash, reason.clone(), now)?;
+    }
+
+    let pruned = mempool_storage.prune_history(now - MEMPOOL_HISTORY_RETENTION)?;
+    if pruned > 0 {
+        debug!(log, "Mempool - old history pruned"; "records" => pruned);
+    }
+
+    Ok(())
+}
+
 /// Notify other actors that mempool state changed
 fn notify_mempool_changed(shell_channel: &ShellChannelRef, mempool_state: &MempoolState) {
     let protocol = if let Some(prevalidator) = &mempool_state.prevalidator {
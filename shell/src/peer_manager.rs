@@ -4,15 +4,16 @@
 //! Manages connected peers.
 
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::iter::FromIterator;
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use dns_lookup::LookupError;
 use futures::lock::Mutex;
+use itertools::Itertools;
 use rand::seq::SliceRandom;
 use riker::actors::*;
 use slog::{debug, info, Logger, warn};
@@ -20,21 +21,35 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Handle;
 use tokio::time::timeout;
 
-use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBootstrapped, PeerCreated};
-use networking::p2p::peer::{Bootstrap, Peer, PeerRef, SendMessage};
+use crypto::proof_of_work::PowTarget;
+use networking::p2p::network_channel::{NetworkChannelMsg, NetworkChannelRef, NetworkChannelTopic, PeerBehavior, PeerBootstrapped, PeerCreated};
+use networking::p2p::peer::{Bootstrap, Peer, PeerId, PeerRef, SendMessage};
+use storage::{PeerStorage, StorageError};
+use storage::peer_storage::MAX_PEER_RECORDS;
+use storage::persistent::PersistentStorage;
 use tezos_api::identity::Identity;
+use tezos_messages::p2p::encoding::ack::NackMotive;
 use tezos_messages::p2p::encoding::prelude::*;
 
+use crate::peer_reputation::PeerReputation;
 use crate::PeerConnectionThreshold;
 use crate::shell_channel::{ShellChannelMsg, ShellChannelRef};
 use crate::subscription::*;
 
 /// Timeout for outgoing connections
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(8);
-/// Whitelist all IP addresses after 30 minutes
-const WHITELIST_INTERVAL: Duration = Duration::from_secs(1_800);
+/// How often to move peer scores towards neutral
+const REPUTATION_DECAY_INTERVAL: Duration = Duration::from_secs(300);
+/// How often to offer a peer swap to rotate connections
+const SWAP_INTERVAL: Duration = Duration::from_secs(1_200);
+/// Minimal time between two accepted swap requests
+const SWAP_LIMIT: Duration = Duration::from_secs(300);
+/// Addresses not seen for this long are removed from the address book
+const ADDRESS_BOOK_RETENTION: Duration = Duration::from_secs(30 * 86_400);
 /// How often to do DNS peer discovery
 const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+/// Maximal number of known peers sent to the remote peer together with NACK
+const NACK_PEERS_COUNT: usize = 20;
 /// Limit how often we allow to trigger check of a peer count
 const CHECK_PEER_COUNT_LIMIT: Duration = Duration::from_secs(5);
 
@@ -42,9 +57,13 @@ const CHECK_PEER_COUNT_LIMIT: Duration = Duration::from_secs(5);
 #[derive(Clone, Debug)]
 pub struct CheckPeerCount;
 
-/// Whitelist all IP address.
+/// Move peer scores towards neutral and forget expired bans.
 #[derive(Clone, Debug)]
-pub struct WhitelistAllIpAddresses;
+pub struct DecayReputation;
+
+/// Offer a peer swap to the connected peer with the worst score.
+#[derive(Clone, Debug)]
+pub struct OfferPeerSwap;
 
 /// Accept incoming peer connection.
 #[derive(Clone, Debug)]
@@ -73,9 +92,9 @@ pub struct P2p {
 /// This actor is responsible for peer management.
 ///
 /// It monitors number of connected peers. If the number of connected peers is too low it tries to
-/// connect to more peers. If the number of connected peers is too high, then randomly selected peers
-/// are disconnected.
-#[actor(CheckPeerCount, WhitelistAllIpAddresses, AcceptPeer, ConnectToPeer, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter)]
+/// connect to more peers. If the number of connected peers is too high, then peers with the worst
+/// score are disconnected. Connections are rotated by periodic peer swaps with the remote peers.
+#[actor(CheckPeerCount, DecayReputation, OfferPeerSwap, AcceptPeer, ConnectToPeer, NetworkChannelMsg, ShellChannelMsg, SystemEvent, DeadLetter)]
 pub struct PeerManager {
     /// All events generated by the network layer will end up in this channel
     network_channel: NetworkChannelRef,
@@ -85,6 +104,11 @@ pub struct PeerManager {
     threshold: PeerConnectionThreshold,
     /// Map of all peers
     peers: HashMap<ActorUri, PeerState>,
+    /// Peers, which are not tracked in `peers`, because they just reject the incoming connection
+    rejecting_peers: HashSet<ActorUri>,
+    /// Recently terminated peers (at most `threshold.high`), behavior reports are published asynchronously
+    /// and can arrive after the termination of the reported peer
+    terminated_peers: VecDeque<(ActorUri, SocketAddr, Option<PeerId>)>,
     /// DNS addresses used for bootstrapping
     bootstrap_addresses: Vec<String>,
     /// Disable DNS bootstrap addresses lookup, if true
@@ -97,19 +121,35 @@ pub struct PeerManager {
     private_node: bool,
     /// List of potential peers to connect to
     potential_peers: HashSet<SocketAddr>,
+    /// Peers from the address book, the most reliable first, they are tried before other potential peers
+    known_peers: Vec<SocketAddr>,
+    /// Persistent address book of the remote peers
+    peer_storage: PeerStorage,
+    /// Advertised addresses, which are written to the address book in one batch by the next peer count check
+    seen_addresses: HashSet<SocketAddr>,
     /// Tokio runtime
     tokio_executor: Handle,
     /// We will listen for incoming connection at this port
     listener_port: u16,
     /// Tezos identity
     identity: Identity,
+    /// Proof of work required from remote peers
+    pow_target: PowTarget,
     /// Network/protocol version
     network_version: NetworkVersion,
+    /// Oldest p2p version of the remote peer, which is still supported
+    min_p2p_version: u16,
     /// Message receiver boolean indicating whether
     /// more connections should be accepted from network
     rx_run: Arc<AtomicBool>,
-    /// set of blacklisted IP addresses
-    ip_blacklist: HashSet<IpAddr>,
+    /// Scores and bans of the peers by peer id and IP address
+    reputation: PeerReputation,
+    /// Peer, to which we offered a swap, and the time of the offer
+    swap_offered: Option<(PeerRef, Instant)>,
+    /// Swaps in progress - when connection to the address succeeds, the peer is disconnected
+    swaps_pending: HashMap<SocketAddr, (PeerRef, Instant)>,
+    /// Last time we accepted a swap request
+    swap_accepted_last: Option<Instant>,
     /// Last time we did DNS peer discovery
     discovery_last: Option<Instant>,
     /// Last time we checked peer count
@@ -127,8 +167,11 @@ impl PeerManager {
                  shell_channel: ShellChannelRef,
                  tokio_executor: Handle,
                  identity: Identity,
+                 pow_target: PowTarget,
                  network_version: NetworkVersion,
+                 min_p2p_version: u16,
                  p2p_config: P2p,
+                 persistent_storage: &PersistentStorage,
     ) -> Result<PeerManagerRef, CreateError> {
         sys.actor_of_props::<PeerManager>(
             PeerManager::name(),
@@ -137,8 +180,11 @@ impl PeerManager {
                 shell_channel,
                 tokio_executor,
                 identity,
+                pow_target,
                 network_version,
+                min_p2p_version,
                 p2p_config,
+                persistent_storage.clone(),
             )),
         )
     }
@@ -177,20 +223,10 @@ impl PeerManager {
     }
 
     /// Create new peer actor
-    fn create_peer(&mut self, sys: &impl ActorRefFactory, socket_address: &SocketAddr) -> PeerRef {
-        let peer = Peer::actor(
-            sys,
-            self.network_channel.clone(),
-            self.listener_port,
-            &self.identity.public_key,
-            &self.identity.secret_key,
-            &self.identity.proof_of_work_stamp,
-            self.network_version.clone(),
-            self.tokio_executor.clone(),
-            socket_address,
-        ).unwrap();
+    fn create_peer(&mut self, sys: &impl ActorRefFactory, socket_address: &SocketAddr, incoming: bool) -> PeerRef {
+        let peer = self.spawn_peer(sys, socket_address);
 
-        self.peers.insert(peer.uri().clone(), PeerState { peer_ref: peer.clone(), address: *socket_address });
+        self.peers.insert(peer.uri().clone(), PeerState { peer_ref: peer.clone(), address: *socket_address, peer_id: None, incoming });
 
         self.network_channel.tell(
             Publish {
@@ -204,9 +240,163 @@ impl PeerManager {
         peer
     }
 
-    /// Check if given ip address is blacklisted to connect to
+    /// Create peer actor, which is not tracked as a connected peer
+    fn spawn_peer(&self, sys: &impl ActorRefFactory, socket_address: &SocketAddr) -> PeerRef {
+        Peer::actor(
+            sys,
+            self.network_channel.clone(),
+            self.listener_port,
+            &self.identity.public_key,
+            &self.identity.secret_key,
+            &self.identity.proof_of_work_stamp,
+            self.pow_target.clone(),
+            self.network_version.clone(),
+            self.min_p2p_version,
+            self.tokio_executor.clone(),
+            socket_address,
+        ).unwrap()
+    }
+
+    /// Peer ids of the bootstrapped peers, another connection to them is rejected
+    fn connected_peer_ids(&self) -> HashSet<PeerId> {
+        self.peers.values()
+            .filter_map(|peer_state| peer_state.peer_id.clone())
+            .collect()
+    }
+
+    /// Random sample of known peer addresses, which is offered to the remote peer when we do not acknowledge the connection
+    fn potential_peers_sample(&self) -> Vec<String> {
+        let addresses = self.potential_peers.iter()
+            .chain(self.peers.values().filter(|peer_state| !peer_state.incoming).map(|peer_state| &peer_state.address))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        addresses.choose_multiple(&mut rand::thread_rng(), NACK_PEERS_COUNT)
+            .map(|address| address.to_string())
+            .collect()
+    }
+
+    /// Check if given ip address is banned to connect to
     fn is_blacklisted(&self, ip_address: &IpAddr) -> bool {
-        self.ip_blacklist.contains(ip_address)
+        self.reputation.is_ip_banned(ip_address, Instant::now())
+    }
+
+    /// Update reputation of the peer and disconnect it, if it was banned
+    fn report_behavior(&mut self, ctx: &Context<PeerManagerMsg>, address: SocketAddr, peer_id: Option<PeerId>, peer_ref: Option<PeerRef>, behavior: PeerBehavior) {
+        if let Some(ban) = self.reputation.report(address.ip(), peer_id.as_ref(), behavior, Instant::now()) {
+            info!(ctx.system.log(), "Banning peer"; "ip" => format!("{}", address.ip()), "peer_id" => peer_id, "behavior" => format!("{:?}", behavior), "secs" => ban.as_secs());
+            self.potential_peers.retain(|potential_peer| potential_peer.ip() != address.ip());
+            self.known_peers.retain(|known_peer| known_peer.ip() != address.ip());
+            log_address_book_error(self.peer_storage.ban_ip(&address.ip(), SystemTime::now() + ban), &ctx.system.log());
+            if let Some(peer_ref) = peer_ref {
+                ctx.system.stop(peer_ref);
+            }
+        }
+    }
+
+    /// Connected peer with the best score, which can be offered to another peer in a swap
+    fn swap_candidate(&self, excluded: &PeerRef) -> Option<SwapMessage> {
+        let reputation = &self.reputation;
+        self.peers.values()
+            .filter(|peer_state| !peer_state.incoming && &peer_state.peer_ref != excluded)
+            .filter_map(|peer_state| peer_state.peer_id.as_ref().map(|peer_id| (peer_state.address, peer_id)))
+            .max_by_key(|(address, peer_id)| reputation.score(&address.ip(), Some(*peer_id)))
+            .map(|(address, peer_id)| SwapMessage::new(address.to_string(), peer_id.clone()))
+    }
+
+    /// Check, that we can connect to the peer offered in a swap
+    fn can_swap_to(&self, address: &SocketAddr, peer_id: &PeerId) -> bool {
+        let now = Instant::now();
+        let already_connected = self.peers.values()
+            .any(|peer_state| &peer_state.address == address || peer_state.peer_id.as_ref() == Some(peer_id));
+
+        peer_id != &self.identity.peer_id
+            && !already_connected
+            && !self.swaps_pending.contains_key(address)
+            && !self.reputation.is_ip_banned(&address.ip(), now)
+            && !self.reputation.is_peer_banned(peer_id, now)
+    }
+
+    /// Connect to the swapped peer, the current peer is disconnected once the connection succeeds
+    fn begin_swap(&mut self, ctx: &Context<PeerManagerMsg>, address: SocketAddr, replaced_peer: &PeerRef) {
+        self.swaps_pending.insert(address, (replaced_peer.clone(), Instant::now()));
+        ctx.myself().tell(ConnectToPeer { address }, None);
+    }
+
+    /// Decide, whether we swap the peer for the offered one, returns address of the offered peer or the reason of the refusal
+    fn accept_swap_request(&mut self, peer: &PeerRef, message: &SwapMessage) -> Result<SocketAddr, &'static str> {
+        let (requester_address, requester_id) = match self.peers.get(peer.uri()) {
+            Some(peer_state) => (peer_state.address, peer_state.peer_id.clone()),
+            None => return Err("unknown peer"),
+        };
+        let address = message.point().parse::<SocketAddr>()
+            .map_err(|_| "invalid address")?;
+
+        if self.private_node {
+            return Err("private node");
+        }
+        let recently_swapped = self.swap_accepted_last
+            .map(|swap_accepted_last| swap_accepted_last.elapsed() < SWAP_LIMIT)
+            .unwrap_or(false);
+        if recently_swapped {
+            return Err("recently swapped");
+        }
+        // swap only to a peer, which is not worse than the requester
+        if self.reputation.score(&address.ip(), Some(message.peer_id())) < self.reputation.score(&requester_address.ip(), requester_id.as_ref()) {
+            return Err("worse peer");
+        }
+        if !self.can_swap_to(&address, message.peer_id()) {
+            return Err("cannot connect");
+        }
+
+        self.swap_accepted_last = Some(Instant::now());
+        Ok(address)
+    }
+
+    fn process_swap_request(&mut self, ctx: &Context<PeerManagerMsg>, peer: &PeerRef, message: &SwapMessage) {
+        match self.accept_swap_request(peer, message) {
+            Ok(address) => {
+                info!(ctx.system.log(), "Accepting swap request"; "peer" => peer.name(), "point" => message.point(), "peer_id" => message.peer_id());
+                if let Some(swap_ack) = self.swap_candidate(peer) {
+                    peer.tell(SendMessage::new(PeerMessage::SwapAck(swap_ack).into()), None);
+                }
+                self.begin_swap(ctx, address, peer);
+            }
+            Err(reason) => debug!(ctx.system.log(), "Refusing swap request"; "peer" => peer.name(), "point" => message.point(), "peer_id" => message.peer_id(), "reason" => reason),
+        }
+    }
+
+    /// Match the swap acknowledgement with our swap offer
+    fn resolve_swap_ack(&mut self, peer: &PeerRef, message: &SwapMessage) -> SwapAck {
+        let swap_offered = match &self.swap_offered {
+            Some((offered_peer, offered_at)) => offered_peer == peer && offered_at.elapsed() < SWAP_INTERVAL,
+            None => false,
+        };
+        if !swap_offered {
+            return SwapAck::Unsolicited;
+        }
+        self.swap_offered = None;
+
+        match message.point().parse::<SocketAddr>() {
+            Ok(address) if self.can_swap_to(&address, message.peer_id()) => SwapAck::Accepted(address),
+            _ => SwapAck::Ignored,
+        }
+    }
+
+    fn process_swap_ack(&mut self, ctx: &Context<PeerManagerMsg>, peer: &PeerRef, message: &SwapMessage) {
+        match self.resolve_swap_ack(peer, message) {
+            SwapAck::Accepted(address) => {
+                info!(ctx.system.log(), "Peer accepted swap"; "peer" => peer.name(), "point" => message.point(), "peer_id" => message.peer_id());
+                self.begin_swap(ctx, address, peer);
+            }
+            SwapAck::Ignored => debug!(ctx.system.log(), "Ignoring swap acknowledgement"; "peer" => peer.name(), "point" => message.point(), "peer_id" => message.peer_id()),
+            SwapAck::Unsolicited => {
+                if let Some(peer_state) = self.peers.get(peer.uri()) {
+                    let (address, peer_id) = (peer_state.address, peer_state.peer_id.clone());
+                    self.report_behavior(ctx, address, peer_id, Some(peer.clone()), PeerBehavior::UnsolicitedMessage);
+                }
+            }
+        }
     }
 
     fn process_shell_channel_message(&mut self, ctx: &Context<PeerManagerMsg>, msg: ShellChannelMsg) -> Result<(), failure::Error> {
@@ -214,6 +404,7 @@ impl PeerManager {
             ShellChannelMsg::ShuttingDown(_) => {
                 self.shutting_down = true;
                 unsubscribe_from_dead_letters(ctx.system.dead_letters(), ctx.myself());
+                self.flush_seen_addresses(&ctx.system.log());
             }
             _ => ()
         }
@@ -237,13 +428,53 @@ impl PeerManager {
             .filter_map(|str_ip_port| str_ip_port.parse().ok())
             .filter(|address: &SocketAddr| !self.is_blacklisted(&address.ip()))
             .collect::<Vec<_>>();
+        for address in &sock_addresses {
+            if self.seen_addresses.len() >= MAX_PEER_RECORDS {
+                break;
+            }
+            self.seen_addresses.insert(*address);
+        }
         self.potential_peers.extend(sock_addresses);
     }
+
+    /// Write addresses advertised since the last flush to the address book
+    fn flush_seen_addresses(&mut self, log: &Logger) {
+        if self.seen_addresses.is_empty() {
+            return;
+        }
+        let seen_addresses = std::mem::take(&mut self.seen_addresses);
+        log_address_book_error(self.peer_storage.seen_all(&seen_addresses, SystemTime::now()), log);
+    }
+
+    /// Seed peers to connect to from the address book and restore bans, which did not expire yet
+    fn load_address_book(&mut self, log: &Logger) {
+        let now = SystemTime::now();
+        if let Some(seen_before) = now.checked_sub(ADDRESS_BOOK_RETENTION) {
+            match self.peer_storage.prune(seen_before, now) {
+                Ok(removed) if removed > 0 => info!(log, "Removed old peers from the address book"; "removed" => removed),
+                Ok(_) => (),
+                Err(e) => warn!(log, "Failed to prune the address book"; "reason" => e),
+            }
+        }
+
+        match self.peer_storage.records() {
+            Ok(records) => {
+                for (address, record) in records {
+                    match record.banned_until().and_then(|banned_until| banned_until.duration_since(now).ok()) {
+                        Some(ban) => self.reputation.ban_ip(address.ip(), Instant::now() + ban),
+                        None => self.known_peers.push(address),
+                    }
+                }
+                info!(log, "Loaded peers from the address book"; "known_peers" => self.known_peers.len());
+            }
+            Err(e) => warn!(log, "Failed to load the address book"; "reason" => e),
+        }
+    }
 }
 
-impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, Handle, Identity, NetworkVersion, P2p)> for PeerManager {
-    fn create_args((network_channel, shell_channel, tokio_executor, identity, network_version, p2p_config):
-                   (NetworkChannelRef, ShellChannelRef, Handle, Identity, NetworkVersion, P2p)) -> Self
+impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, Handle, Identity, PowTarget, NetworkVersion, u16, P2p, PersistentStorage)> for PeerManager {
+    fn create_args((network_channel, shell_channel, tokio_executor, identity, pow_target, network_version, min_p2p_version, p2p_config, persistent_storage):
+                   (NetworkChannelRef, ShellChannelRef, Handle, Identity, PowTarget, NetworkVersion, u16, P2p, PersistentStorage)) -> Self
     {
         PeerManager {
             network_channel,
@@ -255,13 +486,23 @@ impl ActorFactoryArgs<(NetworkChannelRef, ShellChannelRef, Handle, Identity, Net
             threshold: p2p_config.peer_threshold,
             listener_port: p2p_config.listener_port,
             identity,
+            pow_target,
             network_version,
+            min_p2p_version,
             disable_mempool: p2p_config.disable_mempool,
             private_node: p2p_config.private_node,
             rx_run: Arc::new(AtomicBool::new(true)),
             potential_peers: HashSet::new(),
+            known_peers: Vec::new(),
+            peer_storage: PeerStorage::new(&persistent_storage),
+            seen_addresses: HashSet::new(),
             peers: HashMap::new(),
-            ip_blacklist: HashSet::new(),
+            rejecting_peers: HashSet::new(),
+            terminated_peers: VecDeque::new(),
+            reputation: PeerReputation::default(),
+            swap_offered: None,
+            swaps_pending: HashMap::new(),
+            swap_accepted_last: None,
             discovery_last: None,
             check_peer_count_last: None,
             shutting_down: false,
@@ -278,6 +519,8 @@ impl Actor for PeerManager {
         subscribe_to_shell_events(&self.shell_channel, ctx.myself());
         subscribe_to_dead_letters(ctx.system.dead_letters(), ctx.myself());
 
+        self.load_address_book(&ctx.system.log());
+
         ctx.schedule::<Self::Msg, _>(
             Duration::from_secs(3),
             Duration::from_secs(10),
@@ -285,11 +528,17 @@ impl Actor for PeerManager {
             None,
             CheckPeerCount.into());
         ctx.schedule::<Self::Msg, _>(
-            WHITELIST_INTERVAL,
-            WHITELIST_INTERVAL,
+            REPUTATION_DECAY_INTERVAL,
+            REPUTATION_DECAY_INTERVAL,
             ctx.myself(),
             None,
-            WhitelistAllIpAddresses.into());
+            DecayReputation.into());
+        ctx.schedule::<Self::Msg, _>(
+            SWAP_INTERVAL,
+            SWAP_INTERVAL,
+            ctx.myself(),
+            None,
+            OfferPeerSwap.into());
 
 
         let listener_port = self.listener_port;
@@ -342,9 +591,14 @@ impl Receive<SystemEvent> for PeerManager {
 
     fn receive(&mut self, ctx: &Context<Self::Msg>, msg: SystemEvent, _sender: Option<BasicActorRef>) {
         if let SystemEvent::ActorTerminated(evt) = msg {
-            if self.peers.remove(evt.actor.uri()).is_some() {
+            if let Some(peer_state) = self.peers.remove(evt.actor.uri()) {
+                self.terminated_peers.push_back((evt.actor.uri().clone(), peer_state.address, peer_state.peer_id));
+                while self.terminated_peers.len() > self.threshold.high {
+                    self.terminated_peers.pop_front();
+                }
                 self.trigger_check_peer_count(ctx);
             }
+            self.rejecting_peers.remove(evt.actor.uri());
         }
     }
 }
@@ -358,19 +612,30 @@ impl Receive<CheckPeerCount> for PeerManager {
             return;
         }
 
+        self.flush_seen_addresses(&ctx.system.log());
+
         if self.peers.len() < self.threshold.low {
             // peer count is too low, try to connect to more peers
             warn!(ctx.system.log(), "Peer count is too low"; "actual" => self.peers.len(), "required" => self.threshold.low);
-            if self.potential_peers.len() < self.threshold.low {
+            if self.potential_peers.len() + self.known_peers.len() < self.threshold.low {
                 self.discover_peers(&ctx.system.log());
             }
 
             let num_required_peers = cmp::max((self.threshold.high + 3 * self.threshold.low) / 4 - self.peers.len(), self.threshold.low);
-            let mut addresses_to_connect = self.potential_peers.iter().cloned().collect::<Vec<SocketAddr>>();
+            // the most reliable peers from the address book are tried first
+            let mut addresses_to_connect = self.known_peers
+                .drain(0..cmp::min(num_required_peers, self.known_peers.len()))
+                .collect::<Vec<SocketAddr>>();
+            let mut potential_peers = self.potential_peers.iter()
+                .filter(|address| !addresses_to_connect.contains(*address))
+                .cloned()
+                .collect::<Vec<SocketAddr>>();
             // randomize peers as a security measurement
-            addresses_to_connect.shuffle(&mut rand::thread_rng());
+            potential_peers.shuffle(&mut rand::thread_rng());
+            let num_potential_peers = cmp::min(num_required_peers - addresses_to_connect.len(), potential_peers.len());
+            addresses_to_connect.extend(potential_peers.drain(0..num_potential_peers));
             addresses_to_connect
-                .drain(0..cmp::min(num_required_peers, addresses_to_connect.len()))
+                .into_iter()
                 .for_each(|address| {
                     self.potential_peers.remove(&address);
                     ctx.myself().tell(ConnectToPeer { address }, ctx.myself().into())
@@ -379,10 +644,13 @@ impl Receive<CheckPeerCount> for PeerManager {
             // peer count is too high, disconnect some peers
             warn!(ctx.system.log(), "Peer count is too high. Some peers will be stopped"; "actual" => self.peers.len(), "limit" => self.threshold.high);
 
-            // stop some peers
+            // stop peers with the worst score
+            let reputation = &self.reputation;
             self.peers.values()
+                .map(|peer_state| (reputation.score(&peer_state.address.ip(), peer_state.peer_id.as_ref()), peer_state))
+                .sorted_by_key(|(score, _)| *score)
                 .take(self.peers.len() - self.threshold.high)
-                .for_each(|peer_state| ctx.system.stop(peer_state.peer_ref.clone()))
+                .for_each(|(_, peer_state)| ctx.system.stop(peer_state.peer_ref.clone()))
         }
 
         self.check_peer_count_last = Some(Instant::now());
@@ -404,6 +672,8 @@ impl Receive<NetworkChannelMsg> for PeerManager {
                             info!(ctx.system.log(), "Received advertise message"; "peer" => received.peer.name(), "peers" => format!("{:?}", message.id().join(", ")));
                             self.process_potential_peers(message.id());
                         }
+                        PeerMessage::SwapRequest(message) => self.process_swap_request(ctx, &received.peer, message),
+                        PeerMessage::SwapAck(message) => self.process_swap_ack(ctx, &received.peer, message),
                         PeerMessage::Bootstrap => {
                             // to a bootstrap message we will respond with list of potential peers
                             info!(ctx.system.log(), "Received bootstrap message"; "peer" => received.peer.name());
@@ -418,17 +688,42 @@ impl Receive<NetworkChannelMsg> for PeerManager {
                     });
                 self.trigger_check_peer_count(ctx);
             }
+            NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Success { peer, peer_id, .. }) => {
+                if self.reputation.is_peer_banned(&peer_id, Instant::now()) {
+                    info!(ctx.system.log(), "Peer is banned - will disconnect"; "peer_id" => peer_id, "peer" => peer.name());
+                    ctx.system.stop(peer);
+                } else if let Some(peer_state) = self.peers.get_mut(peer.uri()) {
+                    if !peer_state.incoming {
+                        log_address_book_error(self.peer_storage.connected(&peer_state.address, &peer_id, SystemTime::now()), &ctx.system.log());
+                    }
+                    peer_state.peer_id = Some(peer_id);
+                    // swapped peer is connected, so the replaced one can be disconnected
+                    if let Some((replaced_peer, _)) = self.swaps_pending.remove(&peer_state.address) {
+                        info!(ctx.system.log(), "Peer swap completed"; "peer" => peer.name(), "replaced_peer" => replaced_peer.name());
+                        ctx.system.stop(replaced_peer);
+                    }
+                }
+            }
             NetworkChannelMsg::PeerBootstrapped(PeerBootstrapped::Failure { address, potential_peers_to_connect }) => {
+                self.swaps_pending.remove(&address);
+                log_address_book_error(self.peer_storage.failed(&address), &ctx.system.log());
                 // received message that bootstrap process failed for the peer
                 match potential_peers_to_connect {
                     Some(peers) => {
                         self.process_potential_peers(&peers);
                         self.trigger_check_peer_count(ctx);
                     }
-                    None => {
-                        info!(ctx.system.log(), "Blacklisting IP because peer failed at bootstrap process"; "ip" => format!("{}", address.ip()));
-                        self.ip_blacklist.insert(address.ip());
-                    }
+                    None => self.report_behavior(ctx, address, None, None, PeerBehavior::BootstrapFailure),
+                }
+            }
+            NetworkChannelMsg::PeerBehaviorReported(reported) => {
+                if let Some(peer_state) = self.peers.get(reported.peer.uri()) {
+                    let (address, peer_id) = (peer_state.address, peer_state.peer_id.clone());
+                    self.report_behavior(ctx, address, peer_id, Some(reported.peer), reported.behavior);
+                } else if let Some((_, address, peer_id)) = self.terminated_peers.iter().find(|(uri, ..)| uri == reported.peer.uri()) {
+                    // peer was stopped right after the report (e.g. timeout or deserialization error), it still counts
+                    let (address, peer_id) = (*address, peer_id.clone());
+                    self.report_behavior(ctx, address, peer_id, None, reported.behavior);
                 }
             }
             _ => ()
@@ -436,12 +731,39 @@ impl Receive<NetworkChannelMsg> for PeerManager {
     }
 }
 
-impl Receive<WhitelistAllIpAddresses> for PeerManager {
+impl Receive<DecayReputation> for PeerManager {
     type Msg = PeerManagerMsg;
 
-    fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: WhitelistAllIpAddresses, _sender: Sender) {
-        info!(ctx.system.log(), "Whitelisting all IP addresses");
-        self.ip_blacklist.clear();
+    fn receive(&mut self, _ctx: &Context<Self::Msg>, _msg: DecayReputation, _sender: Sender) {
+        self.reputation.decay(Instant::now());
+    }
+}
+
+impl Receive<OfferPeerSwap> for PeerManager {
+    type Msg = PeerManagerMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, _msg: OfferPeerSwap, _sender: Sender) {
+        // forget swaps, which did not complete
+        self.swaps_pending.retain(|_, (_, started_at)| started_at.elapsed() < SWAP_INTERVAL);
+
+        if self.private_node {
+            return;
+        }
+
+        // rotate the least useful connection
+        let reputation = &self.reputation;
+        let target = self.peers.values()
+            .filter(|peer_state| peer_state.peer_id.is_some())
+            .min_by_key(|peer_state| reputation.score(&peer_state.address.ip(), peer_state.peer_id.as_ref()))
+            .map(|peer_state| peer_state.peer_ref.clone());
+
+        if let Some(target) = target {
+            if let Some(swap_request) = self.swap_candidate(&target) {
+                info!(ctx.system.log(), "Offering peer swap"; "peer" => target.name(), "point" => swap_request.point(), "peer_id" => swap_request.peer_id());
+                target.tell(SendMessage::new(PeerMessage::SwapRequest(swap_request).into()), None);
+                self.swap_offered = Some((target, Instant::now()));
+            }
+        }
     }
 }
 
@@ -454,24 +776,29 @@ impl Receive<ConnectToPeer> for PeerManager {
         if self.is_blacklisted(&msg.address.ip()) {
             debug!(ctx.system.log(), "Peer is blacklisted - will not connect"; "ip" => format!("{}", msg.address.ip()));
         } else {
-            let peer = self.create_peer(ctx, &msg.address);
+            let peer = self.create_peer(ctx, &msg.address, false);
             let system = ctx.system.clone();
             let disable_mempool = self.disable_mempool;
             let private_node = self.private_node;
+            let potential_peers = self.potential_peers_sample();
+            let connected_peers = self.connected_peer_ids();
+            let peer_storage = self.peer_storage.clone();
 
             self.tokio_executor.spawn(async move {
                 info!(system.log(), "Connecting to IP"; "ip" => msg.address, "peer" => peer.name());
                 match timeout(CONNECT_TIMEOUT, TcpStream::connect(&msg.address)).await {
                     Ok(Ok(stream)) => {
                         info!(system.log(), "Connection successful"; "ip" => msg.address);
-                        peer.tell(Bootstrap::outgoing(stream, msg.address, disable_mempool, private_node), None);
+                        peer.tell(Bootstrap::outgoing(stream, msg.address, disable_mempool, private_node, potential_peers).with_connected_peers(connected_peers), None);
                     }
                     Ok(Err(e)) => {
                         info!(system.log(), "Connection failed"; "ip" => msg.address, "peer" => peer.name(), "reason" => format!("{:?}", e));
+                        log_address_book_error(peer_storage.failed(&msg.address), &system.log());
                         system.stop(peer);
                     }
                     Err(_) => {
                         info!(system.log(), "Connection timed out"; "ip" => msg.address, "peer" => peer.name());
+                        log_address_book_error(peer_storage.failed(&msg.address), &system.log());
                         system.stop(peer);
                     }
                 }
@@ -488,11 +815,21 @@ impl Receive<AcceptPeer> for PeerManager {
             debug!(ctx.system.log(), "Peer is blacklisted - will not accept connection"; "ip" => format!("{}", msg.address.ip()));
         } else if self.peers.len() < self.threshold.high {
             info!(ctx.system.log(), "Connection from"; "ip" => msg.address);
-            let peer = self.create_peer(ctx, &msg.address);
-            peer.tell(Bootstrap::incoming(msg.stream, msg.address, self.disable_mempool, self.private_node), None);
-        } else {
-            debug!(ctx.system.log(), "Cannot accept incoming peer connection because peer limit was reached");
+            let potential_peers = self.potential_peers_sample();
+            let connected_peers = self.connected_peer_ids();
+            let peer = self.create_peer(ctx, &msg.address, true);
+            peer.tell(Bootstrap::incoming(msg.stream, msg.address, self.disable_mempool, self.private_node, potential_peers).with_connected_peers(connected_peers), None);
+        } else if self.rejecting_peers.len() >= self.threshold.high {
+            debug!(ctx.system.log(), "Cannot accept incoming peer connection because peer limit was reached - too many rejections in progress, will drop"; "ip" => format!("{}", msg.address.ip()));
             drop(msg.stream); // not needed, just wanted to be explicit here
+        } else {
+            debug!(ctx.system.log(), "Cannot accept incoming peer connection because peer limit was reached - will reject"; "ip" => format!("{}", msg.address.ip()));
+            // peer is not tracked, so the rejected connection does not count to the peer limit
+            let peer = self.spawn_peer(ctx, &msg.address);
+            self.rejecting_peers.insert(peer.uri().clone());
+            let bootstrap = Bootstrap::incoming(msg.stream, msg.address, self.disable_mempool, self.private_node, self.potential_peers_sample())
+                .reject(NackMotive::TooManyConnections);
+            peer.tell(bootstrap, None);
         }
     }
 }
@@ -509,6 +846,13 @@ async fn begin_listen_incoming(listener_port: u16, peer_manager: PeerManagerRef,
     }
 }
 
+/// Address book is not essential for the peer manager, so its errors are just logged
+fn log_address_book_error(result: Result<(), StorageError>, log: &Logger) {
+    if let Err(e) = result {
+        warn!(log, "Failed to update the address book"; "reason" => e);
+    }
+}
+
 /// Do DNS lookup for collection of names and create collection of socket addresses
 fn dns_lookup_peers(bootstrap_addresses: &[String], log: &Logger) -> HashSet<SocketAddr> {
     let mut resolved_peers = HashSet::new();
@@ -538,10 +882,241 @@ fn resolve_dns_name_to_peer_address(address: &str) -> Result<Vec<SocketAddr>, Lo
     Ok(addrs)
 }
 
+/// Outcome of the received swap acknowledgement
+#[derive(Debug, PartialEq)]
+enum SwapAck {
+    /// Peer accepted our swap offer, we will connect to the address
+    Accepted(SocketAddr),
+    /// Peer accepted our swap offer, but we cannot connect to the offered peer
+    Ignored,
+    /// We did not offer a swap to the peer
+    Unsolicited,
+}
+
 /// Holds information about a specific peer.
 struct PeerState {
     /// Reference to peer actor
     peer_ref: PeerRef,
     /// Peer IP address
     address: SocketAddr,
+    /// Peer id, known after successful bootstrap
+    peer_id: Option<PeerId>,
+    /// Peer connected to us, so its address is not the one the peer listens at
+    incoming: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use slog::{Drain, Level};
+
+    use networking::p2p::network_channel::NetworkChannel;
+    use storage::tests_common::TmpStorage;
+
+    use crate::shell_channel::ShellChannel;
+
+    use super::*;
+
+    fn create_logger(level: Level) -> Logger {
+        let drain = slog_async::Async::new(
+            slog_term::FullFormat::new(
+                slog_term::TermDecorator::new().build()
+            ).build().fuse()
+        ).build().filter_level(level).fuse();
+
+        Logger::root(drain, slog::o!())
+    }
+
+    fn create_tokio_runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .enable_all()
+            .core_threads(1)
+            .build()
+            .expect("Failed to create tokio runtime")
+    }
+
+    fn peer_manager(network_channel: NetworkChannelRef, shell_channel: ShellChannelRef, storage: &TmpStorage, tokio_runtime: &tokio::runtime::Runtime) -> PeerManager {
+        PeerManager::create_args((
+            network_channel,
+            shell_channel,
+            tokio_runtime.handle().clone(),
+            Identity {
+                peer_id: "idtMyOwnPeerIdMyOwnPeerIdMyOwn1".to_string(),
+                public_key: "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b".to_string(),
+                secret_key: "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b".to_string(),
+                proof_of_work_stamp: "000000000000000000000000000000000000000000000000".to_string(),
+            },
+            PowTarget::new(0.0).unwrap(),
+            NetworkVersion::new("testet".to_string(), 0, 0),
+            0,
+            P2p {
+                listener_port: 0,
+                disable_bootstrap_lookup: true,
+                bootstrap_lookup_addresses: vec![],
+                initial_peers: vec![],
+                peer_threshold: PeerConnectionThreshold::new(1, 10),
+                disable_mempool: false,
+                private_node: false,
+            },
+            storage.storage().clone(),
+        ))
+    }
+
+    /// Add bootstrapped outgoing peer to the peer manager
+    fn peer(sys: &impl ActorRefFactory, peer_manager: &mut PeerManager, address: &str, peer_id: &str, tokio_runtime: &tokio::runtime::Runtime) -> PeerRef {
+        let address: SocketAddr = address.parse().expect("Expected valid ip:port address");
+
+        let peer_ref = Peer::actor(
+            sys,
+            peer_manager.network_channel.clone(),
+            3011,
+            "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b",
+            "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b",
+            "000000000000000000000000000000000000000000000000",
+            PowTarget::new(0.0).unwrap(),
+            NetworkVersion::new("testet".to_string(), 0, 0),
+            0,
+            tokio_runtime.handle().clone(),
+            &address,
+        ).unwrap();
+
+        peer_manager.peers.insert(peer_ref.uri().clone(), PeerState { peer_ref: peer_ref.clone(), address, peer_id: Some(peer_id.to_string()), incoming: false });
+        peer_ref
+    }
+
+    #[test]
+    fn test_accept_swap_request() -> Result<(), failure::Error> {
+        let log = create_logger(Level::Debug);
+        let storage = TmpStorage::create_to_out_dir("__test_accept_swap_request")?;
+
+        let tokio_runtime = create_tokio_runtime();
+        let actor_system = SystemBuilder::new().name("test_accept_swap_request").log(log.clone()).create().expect("Failed to create actor system");
+        let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+        let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+
+        let mut peer_manager = peer_manager(network_channel, shell_channel, &storage, &tokio_runtime);
+        let requester = peer(&actor_system, &mut peer_manager, "127.0.0.1:9732", "idtRequesterRequesterRequester1", &tokio_runtime);
+        let unknown_peer = Peer::actor(
+            &actor_system,
+            peer_manager.network_channel.clone(),
+            3011,
+            "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b",
+            "eaef40186db19fd6f56ed5b1af57f9d9c8a1eed85c29f8e4daaa7367869c0f0b",
+            "000000000000000000000000000000000000000000000000",
+            PowTarget::new(0.0).unwrap(),
+            NetworkVersion::new("testet".to_string(), 0, 0),
+            0,
+            tokio_runtime.handle().clone(),
+            &"127.0.0.9:9732".parse().unwrap(),
+        ).unwrap();
+
+        let offered = SwapMessage::new("127.0.0.2:9732".to_string(), "idtOfferedOfferedOfferedOffere1".to_string());
+
+        // refused requests
+        assert_eq!(Err("unknown peer"), peer_manager.accept_swap_request(&unknown_peer, &offered));
+        assert_eq!(Err("invalid address"), peer_manager.accept_swap_request(&requester, &SwapMessage::new("invalid".to_string(), "idtOfferedOfferedOfferedOffere1".to_string())));
+        assert_eq!(Err("cannot connect"), peer_manager.accept_swap_request(&requester, &SwapMessage::new("127.0.0.1:9732".to_string(), "idtOfferedOfferedOfferedOffere1".to_string())));
+        assert_eq!(Err("cannot connect"), peer_manager.accept_swap_request(&requester, &SwapMessage::new("127.0.0.2:9732".to_string(), "idtMyOwnPeerIdMyOwnPeerIdMyOwn1".to_string())));
+
+        peer_manager.reputation.report("127.0.0.3".parse().unwrap(), None, PeerBehavior::Timeout, Instant::now());
+        assert_eq!(Err("worse peer"), peer_manager.accept_swap_request(&requester, &SwapMessage::new("127.0.0.3:9732".to_string(), "idtOfferedOfferedOfferedOffere1".to_string())));
+
+        peer_manager.private_node = true;
+        assert_eq!(Err("private node"), peer_manager.accept_swap_request(&requester, &offered));
+        peer_manager.private_node = false;
+        assert!(peer_manager.swap_accepted_last.is_none());
+
+        // accepted request
+        assert_eq!(Ok("127.0.0.2:9732".parse().unwrap()), peer_manager.accept_swap_request(&requester, &offered));
+        assert!(peer_manager.swap_accepted_last.is_some());
+
+        // next request is refused for some time
+        assert_eq!(Err("recently swapped"), peer_manager.accept_swap_request(&requester, &offered));
+
+        // close
+        thread::sleep(Duration::from_secs(1));
+        let _ = actor_system.shutdown();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_swap_ack() -> Result<(), failure::Error> {
+        let log = create_logger(Level::Debug);
+        let storage = TmpStorage::create_to_out_dir("__test_resolve_swap_ack")?;
+
+        let tokio_runtime = create_tokio_runtime();
+        let actor_system = SystemBuilder::new().name("test_resolve_swap_ack").log(log.clone()).create().expect("Failed to create actor system");
+        let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+        let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+
+        let mut peer_manager = peer_manager(network_channel, shell_channel, &storage, &tokio_runtime);
+        let offered_peer = peer(&actor_system, &mut peer_manager, "127.0.0.1:9732", "idtOfferedPeerOfferedPeerOffer1", &tokio_runtime);
+        let other_peer = peer(&actor_system, &mut peer_manager, "127.0.0.2:9732", "idtOtherPeerOtherPeerOtherPeer1", &tokio_runtime);
+
+        let swap_ack = SwapMessage::new("127.0.0.3:9732".to_string(), "idtSwappedSwappedSwappedSwappe1".to_string());
+
+        // no swap was offered
+        assert_eq!(SwapAck::Unsolicited, peer_manager.resolve_swap_ack(&offered_peer, &swap_ack));
+
+        // swap was offered to another peer
+        peer_manager.swap_offered = Some((offered_peer.clone(), Instant::now()));
+        assert_eq!(SwapAck::Unsolicited, peer_manager.resolve_swap_ack(&other_peer, &swap_ack));
+        assert!(peer_manager.swap_offered.is_some());
+
+        // accepted offer
+        assert_eq!(SwapAck::Accepted("127.0.0.3:9732".parse().unwrap()), peer_manager.resolve_swap_ack(&offered_peer, &swap_ack));
+        assert!(peer_manager.swap_offered.is_none());
+
+        // the offer is answered just once
+        assert_eq!(SwapAck::Unsolicited, peer_manager.resolve_swap_ack(&offered_peer, &swap_ack));
+
+        // offered peer is already connected, so the acknowledgement is ignored
+        peer_manager.swap_offered = Some((offered_peer.clone(), Instant::now()));
+        assert_eq!(SwapAck::Ignored, peer_manager.resolve_swap_ack(&offered_peer, &SwapMessage::new("127.0.0.2:9732".to_string(), "idtOtherPeerOtherPeerOtherPeer1".to_string())));
+        assert!(peer_manager.swap_offered.is_none());
+
+        // invalid address is ignored too
+        peer_manager.swap_offered = Some((offered_peer.clone(), Instant::now()));
+        assert_eq!(SwapAck::Ignored, peer_manager.resolve_swap_ack(&offered_peer, &SwapMessage::new("invalid".to_string(), "idtSwappedSwappedSwappedSwappe1".to_string())));
+        assert!(peer_manager.swap_offered.is_none());
+
+        // close
+        thread::sleep(Duration::from_secs(1));
+        let _ = actor_system.shutdown();
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_potential_peers_are_written_to_address_book_in_batch() -> Result<(), failure::Error> {
+        let log = create_logger(Level::Debug);
+        let storage = TmpStorage::create_to_out_dir("__test_potential_peers_are_written_to_address_book_in_batch")?;
+
+        let tokio_runtime = create_tokio_runtime();
+        let actor_system = SystemBuilder::new().name("test_potential_peers_are_written_to_address_book_in_batch").log(log.clone()).create().expect("Failed to create actor system");
+        let shell_channel = ShellChannel::actor(&actor_system).expect("Failed to create shell channel");
+        let network_channel = NetworkChannel::actor(&actor_system).expect("Failed to create network channel");
+
+        let mut peer_manager = peer_manager(network_channel, shell_channel, &storage, &tokio_runtime);
+        let address_1: SocketAddr = "127.0.0.1:9732".parse().unwrap();
+        let address_2: SocketAddr = "127.0.0.2:9732".parse().unwrap();
+
+        peer_manager.process_potential_peers(&["127.0.0.1:9732".to_string(), "127.0.0.2:9732".to_string(), "invalid".to_string()]);
+        assert_eq!(2, peer_manager.potential_peers.len());
+        assert!(peer_manager.peer_storage.get(&address_1)?.is_none());
+
+        // addresses are written by the flush
+        peer_manager.flush_seen_addresses(&log);
+        assert!(peer_manager.seen_addresses.is_empty());
+        assert!(peer_manager.peer_storage.get(&address_1)?.is_some());
+        assert!(peer_manager.peer_storage.get(&address_2)?.is_some());
+
+        // close
+        thread::sleep(Duration::from_secs(1));
+        let _ = actor_system.shutdown();
+
+        Ok(())
+    }
 }
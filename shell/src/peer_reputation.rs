@@ -0,0 +1,187 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Reputation of the remote peers.
+//!
+//! Every reported [PeerBehavior] changes the score of the peer id and of the IP address of the peer.
+//! When a score drops to [BAN_SCORE], the peer id (or IP address) is banned for a limited time.
+//! Ban duration doubles with every repeated ban. Scores return back to neutral over time by [PeerReputation::decay].
+
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use networking::p2p::network_channel::PeerBehavior;
+use networking::p2p::peer::PeerId;
+
+/// Score, which bans the peer
+pub const BAN_SCORE: i32 = -100;
+/// Maximal score, so peer cannot collect credit for an unlimited misbehavior
+const MAX_SCORE: i32 = 100;
+/// Score change towards neutral by a single decay
+const DECAY_STEP: i32 = 10;
+/// Duration of the first ban
+const BAN_DURATION_BASE: Duration = Duration::from_secs(600);
+/// Maximal duration of a repeated ban
+const BAN_DURATION_MAX: Duration = Duration::from_secs(86_400);
+
+/// Score change caused by the behavior of the peer
+fn score_change(behavior: PeerBehavior) -> i32 {
+    match behavior {
+        PeerBehavior::InvalidBlock => -50,
+        PeerBehavior::InvalidOperation => -30,
+        PeerBehavior::DeserializationError => -20,
+        PeerBehavior::Timeout => -10,
+        PeerBehavior::UnsolicitedMessage => -5,
+        // the same as the blacklisting of the IP address before
+        PeerBehavior::BootstrapFailure => BAN_SCORE,
+        PeerBehavior::UsefulData => 1,
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Reputation {
+    score: i32,
+    /// Number of bans so far
+    bans: u32,
+    /// End of the last ban
+    banned_until: Option<Instant>,
+}
+
+impl Reputation {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.map_or(false, |banned_until| banned_until > now)
+    }
+
+    /// Apply score change, returns duration of the ban, if the score dropped to [BAN_SCORE]
+    fn update(&mut self, change: i32, now: Instant) -> Option<Duration> {
+        if self.is_banned(now) {
+            return None;
+        }
+
+        self.score = cmp::min(cmp::max(self.score + change, BAN_SCORE), MAX_SCORE);
+        if self.score > BAN_SCORE {
+            return None;
+        }
+
+        let duration = BAN_DURATION_BASE.checked_mul(1 << cmp::min(self.bans, 16))
+            .map_or(BAN_DURATION_MAX, |duration| cmp::min(duration, BAN_DURATION_MAX));
+        self.score = 0;
+        self.bans += 1;
+        self.banned_until = Some(now + duration);
+        Some(duration)
+    }
+
+    /// Move score towards neutral, returns `false` if reputation can be forgotten
+    fn decay(&mut self, now: Instant) -> bool {
+        if self.score > 0 {
+            self.score = cmp::max(self.score - DECAY_STEP, 0);
+        } else {
+            self.score = cmp::min(self.score + DECAY_STEP, 0);
+        }
+
+        // repeated offences are remembered for a while after the last ban
+        let remember_bans = self.banned_until.map_or(false, |banned_until| banned_until + BAN_DURATION_MAX > now);
+        self.score != 0 || remember_bans
+    }
+}
+
+/// Reputation of the remote peers by their peer id and IP address
+#[derive(Default)]
+pub struct PeerReputation {
+    by_peer_id: HashMap<PeerId, Reputation>,
+    by_ip: HashMap<IpAddr, Reputation>,
+}
+
+impl PeerReputation {
+    /// Record behavior of the peer, returns duration of the ban, if the peer (or its IP address) was banned
+    pub fn report(&mut self, ip: IpAddr, peer_id: Option<&PeerId>, behavior: PeerBehavior, now: Instant) -> Option<Duration> {
+        let change = score_change(behavior);
+        let ip_ban = self.by_ip.entry(ip).or_default().update(change, now);
+        let peer_id_ban = peer_id.and_then(|peer_id| self.by_peer_id.entry(peer_id.clone()).or_default().update(change, now));
+        cmp::max(ip_ban, peer_id_ban)
+    }
+
+    /// Ban the IP address until the given time, e.g. to restore the ban after restart
+    pub fn ban_ip(&mut self, ip: IpAddr, banned_until: Instant) {
+        let reputation = self.by_ip.entry(ip).or_default();
+        reputation.bans += 1;
+        reputation.banned_until = Some(banned_until);
+    }
+
+    pub fn is_ip_banned(&self, ip: &IpAddr, now: Instant) -> bool {
+        is_banned(&self.by_ip, ip, now)
+    }
+
+    pub fn is_peer_banned(&self, peer_id: &PeerId, now: Instant) -> bool {
+        is_banned(&self.by_peer_id, peer_id, now)
+    }
+
+    /// Score of the peer, the worse one of the peer id and IP address scores
+    pub fn score(&self, ip: &IpAddr, peer_id: Option<&PeerId>) -> i32 {
+        let ip_score = self.by_ip.get(ip).map_or(0, |reputation| reputation.score);
+        let peer_id_score = peer_id
+            .and_then(|peer_id| self.by_peer_id.get(peer_id))
+            .map_or(0, |reputation| reputation.score);
+        cmp::min(ip_score, peer_id_score)
+    }
+
+    /// Move all scores towards neutral and forget reputations, which are neutral and not banned recently
+    pub fn decay(&mut self, now: Instant) {
+        self.by_ip.retain(|_, reputation| reputation.decay(now));
+        self.by_peer_id.retain(|_, reputation| reputation.decay(now));
+    }
+}
+
+fn is_banned<K: Eq + Hash>(reputations: &HashMap<K, Reputation>, key: &K, now: Instant) -> bool {
+    reputations.get(key).map_or(false, |reputation| reputation.is_banned(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_graded_bans() {
+        let mut reputation = PeerReputation::default();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let peer_id: PeerId = "idrRoknJh9zwEePNswF3MPGFzmKaVp".to_string();
+        let now = Instant::now();
+
+        assert_eq!(None, reputation.report(ip, Some(&peer_id), PeerBehavior::InvalidBlock, now));
+        assert_eq!(-50, reputation.score(&ip, Some(&peer_id)));
+        assert_eq!(Some(BAN_DURATION_BASE), reputation.report(ip, Some(&peer_id), PeerBehavior::InvalidBlock, now));
+        assert!(reputation.is_ip_banned(&ip, now));
+        assert!(reputation.is_peer_banned(&peer_id, now));
+        assert!(!reputation.is_ip_banned(&"10.0.0.2".parse().unwrap(), now));
+
+        // ban is time-limited and a repeated ban is longer
+        let now = now + BAN_DURATION_BASE;
+        assert!(!reputation.is_ip_banned(&ip, now));
+        assert_eq!(Some(BAN_DURATION_BASE * 2), reputation.report(ip, None, PeerBehavior::BootstrapFailure, now));
+        assert!(reputation.is_ip_banned(&ip, now));
+        assert!(!reputation.is_peer_banned(&peer_id, now));
+    }
+
+    #[test]
+    fn test_score_decay() {
+        let mut reputation = PeerReputation::default();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            reputation.report(ip, None, PeerBehavior::UsefulData, now);
+        }
+        reputation.report("10.0.0.2".parse().unwrap(), None, PeerBehavior::DeserializationError, now);
+        assert_eq!(3, reputation.score(&ip, None));
+        assert_eq!(-20, reputation.score(&"10.0.0.2".parse().unwrap(), None));
+
+        reputation.decay(now);
+        assert_eq!(0, reputation.score(&ip, None));
+        assert_eq!(-10, reputation.score(&"10.0.0.2".parse().unwrap(), None));
+        reputation.decay(now);
+        assert!(reputation.by_ip.is_empty());
+    }
+}
@@ -42,6 +42,12 @@ pub struct BlockReceived {
     pub level: i32,
 }
 
+/// Message informing actors that protocol refused to apply a block
+#[derive(Clone, Debug)]
+pub struct BlockApplicationFailed {
+    pub hash: BlockHash,
+}
+
 /// Message informing actors about receiving all operations for a specific block
 #[derive(Clone, Debug)]
 pub struct AllBlockOperationsReceived {
@@ -81,6 +87,8 @@ pub enum ShellChannelMsg {
     /// This is not the same as NewCurrentHead, not every applied block is set as NewCurrentHead (reorg - several headers on same level, duplicate header ...)
     BlockApplied(BlockApplied),
     ApplyBlock(BlockHash),
+    /// Chain_feeder propagates if block was rejected by protocol
+    BlockApplicationFailed(BlockApplicationFailed),
     BlockReceived(BlockReceived),
     AllBlockOperationsReceived(AllBlockOperationsReceived),
     MempoolOperationReceived(MempoolOperationReceived),
@@ -101,6 +109,12 @@ impl From<BlockApplied> for ShellChannelMsg {
     }
 }
 
+impl From<BlockApplicationFailed> for ShellChannelMsg {
+    fn from(msg: BlockApplicationFailed) -> Self {
+        ShellChannelMsg::BlockApplicationFailed(msg)
+    }
+}
+
 impl From<MempoolOperationReceived> for ShellChannelMsg {
     fn from(msg: MempoolOperationReceived) -> Self {
         ShellChannelMsg::MempoolOperationReceived(msg)
@@ -9,7 +9,7 @@ use rand::Rng;
 use slog::Logger;
 
 use crypto::hash::{BlockHash, ChainId};
-use storage::{BlockHeaderWithHash, BlockMetaStorage, BlockStorage, BlockStorageReader, ChainMetaStorage, IteratorMode, StorageError};
+use storage::{BlockHeaderWithHash, BlockMetaStorage, BlockStorage, BlockStorageReader, IteratorMode, StorageError};
 use storage::persistent::PersistentStorage;
 use tezos_messages::Head;
 
@@ -22,8 +22,6 @@ pub struct BlockchainState {
     block_storage: BlockStorage,
     ///persistent block metadata storage
     block_meta_storage: BlockMetaStorage,
-    ///persistent chain metadata storage
-    chain_meta_storage: ChainMetaStorage,
     /// Current missing blocks.
     /// This represents a set of missing block we will try to retrieve in the future.
     /// Before we try to fetch missing block it is removed from this queue.
@@ -39,7 +37,6 @@ impl BlockchainState {
         BlockchainState {
             block_storage: BlockStorage::new(persistent_storage),
             block_meta_storage: BlockMetaStorage::new(persistent_storage),
-            chain_meta_storage: ChainMetaStorage::new(persistent_storage),
             missing_blocks: UniqueBlockData::new(),
             chain_id: chain_id.clone(),
         }
@@ -57,8 +54,7 @@ impl BlockchainState {
             level: block.header().header.level(),
         };
 
-        // set head to db
-        self.chain_meta_storage.set_current_head(&self.chain_id, &head)?;
+        // head is already stored, it is moved together with the apply result, see [storage::store_applied_block_result]
 
         Ok(Some(head))
     }
@@ -93,9 +89,15 @@ impl BlockchainState {
             .collect()
     }
 
+    /// Block is scheduled for download, if it is not stored and was not removed by the history pruning
     #[inline]
     pub fn push_missing_block(&mut self, missing_block: MissingBlock) -> Result<(), StorageError> {
-        if !self.block_storage.contains(&missing_block.block_hash)? {
+        if self.block_storage.contains(&missing_block.block_hash)? {
+            return Ok(());
+        }
+        let is_pruned = self.block_meta_storage.get(&missing_block.block_hash)?
+            .map_or(false, |meta| meta.is_pruned());
+        if !is_pruned {
             self.missing_blocks.push(missing_block);
         }
         Ok(())
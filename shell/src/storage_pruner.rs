@@ -0,0 +1,158 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Removes old chain data from storage according to the configured history mode.
+//! Pruning itself runs in a dedicated thread, which is woken up every time a new current head is set.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use failure::Error;
+use riker::actors::*;
+use slog::{info, Logger, warn};
+
+use storage::{ChainMetaStorage, StorageInitInfo};
+use storage::chain_meta_storage::ChainMetaStorageReader;
+use storage::history::{HistoryConfiguration, HistoryPruner};
+use storage::persistent::PersistentStorage;
+
+use crate::shell_channel::{ShellChannelMsg, ShellChannelRef};
+use crate::subscription::subscribe_to_shell_events;
+
+type SharedJoinHandle = Arc<Mutex<Option<JoinHandle<Result<(), Error>>>>>;
+
+/// How long does the pruner thread wait for a new current head, before it checks storage by itself
+const PRUNER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Prunes old blocks, operations and context from storage.
+#[actor(ShellChannelMsg)]
+pub struct StoragePruner {
+    /// All events from shell will be published to this channel
+    shell_channel: ShellChannelRef,
+    /// Thread where pruning is done will run until this is set to `false`
+    pruner_run: Arc<AtomicBool>,
+    /// Pruner thread
+    pruner_thread: SharedJoinHandle,
+}
+
+/// Reference to [storage pruner](StoragePruner) actor
+pub type StoragePrunerRef = ActorRef<StoragePrunerMsg>;
+
+impl StoragePruner {
+    /// Create new actor instance.
+    ///
+    /// This actor spawns a new thread, which prunes [`persistent_storage`](PersistentStorage)
+    /// every time the current head of the chain moves to a new cycle.
+    pub fn actor(
+        sys: &impl ActorRefFactory,
+        shell_channel: ShellChannelRef,
+        persistent_storage: &PersistentStorage,
+        init_storage_data: &StorageInitInfo,
+        history_cfg: HistoryConfiguration,
+        log: Logger) -> Result<StoragePrunerRef, CreateError> {
+        let pruner_run = Arc::new(AtomicBool::new(true));
+        let pruner_thread = {
+            let pruner_run = pruner_run.clone();
+            let persistent_storage = persistent_storage.clone();
+            let chain_id = init_storage_data.chain_id.clone();
+
+            thread::spawn(move || -> Result<(), Error> {
+                let chain_meta_storage = ChainMetaStorage::new(&persistent_storage);
+                let mut pruner = HistoryPruner::new(&persistent_storage, history_cfg);
+                info!(log, "Storage pruner started"; "history_mode" => pruner.cfg().mode.to_string(), "keep_cycles" => pruner.cfg().keep_cycles);
+
+                while pruner_run.load(Ordering::Acquire) {
+                    match chain_meta_storage.get_current_head(&chain_id) {
+                        Ok(Some(head)) => match pruner.prune(head.level) {
+                            Ok(Some(stats)) => info!(log, "Storage pruned";
+                                                     "level" => stats.level(),
+                                                     "blocks" => stats.blocks(),
+                                                     "operations" => stats.operations(),
+                                                     "context_actions" => stats.context_actions(),
+                                                     "context_nodes" => stats.context_nodes(),
+                                                     "context_blobs" => stats.context_blobs()),
+                            Ok(None) => (),
+                            Err(e) => warn!(log, "Failed to prune storage"; "reason" => e),
+                        }
+                        Ok(None) => (),
+                        Err(e) => warn!(log, "Failed to read current head"; "reason" => e),
+                    }
+
+                    thread::park_timeout(PRUNER_IDLE_TIMEOUT);
+                }
+
+                info!(log, "Storage pruner finished");
+                Ok(())
+            })
+        };
+
+        let myself = sys.actor_of_props::<StoragePruner>(
+            StoragePruner::name(),
+            Props::new_args((shell_channel, pruner_run, Arc::new(Mutex::new(Some(pruner_thread))))),
+        )?;
+
+        Ok(myself)
+    }
+
+    /// The `StoragePruner` is intended to serve as a singleton actor so that's why
+    /// we won't support multiple names per instance.
+    fn name() -> &'static str {
+        "storage-pruner"
+    }
+
+    fn process_shell_channel_message(&mut self, _ctx: &Context<StoragePrunerMsg>, msg: ShellChannelMsg) {
+        match msg {
+            ShellChannelMsg::NewCurrentHead(_, _) => {
+                if let Some(join_handle) = self.pruner_thread.lock().unwrap().as_ref() {
+                    join_handle.thread().unpark();
+                }
+            }
+            ShellChannelMsg::ShuttingDown(_) => {
+                self.pruner_run.store(false, Ordering::Release);
+            }
+            _ => ()
+        }
+    }
+}
+
+impl ActorFactoryArgs<(ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle)> for StoragePruner {
+    fn create_args((shell_channel, pruner_run, pruner_thread): (ShellChannelRef, Arc<AtomicBool>, SharedJoinHandle)) -> Self {
+        StoragePruner {
+            shell_channel,
+            pruner_run,
+            pruner_thread,
+        }
+    }
+}
+
+impl Actor for StoragePruner {
+    type Msg = StoragePrunerMsg;
+
+    fn pre_start(&mut self, ctx: &Context<Self::Msg>) {
+        subscribe_to_shell_events(&self.shell_channel, ctx.myself());
+    }
+
+    fn post_stop(&mut self) {
+        self.pruner_run.store(false, Ordering::Release);
+
+        let join_handle = self.pruner_thread.lock().unwrap()
+            .take().expect("Thread join handle is missing");
+        join_handle.thread().unpark();
+        let _ = join_handle.join().expect("Failed to join storage pruner thread");
+    }
+
+    fn recv(&mut self, ctx: &Context<Self::Msg>, msg: Self::Msg, sender: Sender) {
+        self.receive(ctx, msg, sender);
+    }
+}
+
+impl Receive<ShellChannelMsg> for StoragePruner {
+    type Msg = StoragePrunerMsg;
+
+    fn receive(&mut self, ctx: &Context<Self::Msg>, msg: ShellChannelMsg, _sender: Sender) {
+        self.process_shell_channel_message(ctx, msg);
+    }
+}
@@ -0,0 +1,388 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Online backup of the node data.
+//!
+//! Backup is created while the node runs and it is a directory with:
+//! * `db` - RocksDB checkpoint and copy of the commit logs, which replaces the `--bootstrap-db-path` on restore,
+//! * `tezos_data` - copy of the OCaml context directory (`--tezos-data-dir` without the database),
+//! * `backup.json` - [BackupManifest] with the current head at the time of the backup and all backed up files.
+//!
+//! Application of blocks is paused during the backup (see [ApplyLock](crate::persistent::ApplyLock)),
+//! so the protocol runner does not write to the OCaml context while it is copied.
+//! Current head is read first, so it is contained in the RocksDB checkpoint.
+//! Commit logs are copied after the checkpoint, so they contain all records referenced by the checkpoint.
+//! OCaml context is copied last and it contains exactly the context of the head.
+//! Manifest with the hashes of all files is written at the very end, directory without the manifest is not a valid backup.
+
+use std::{env, fs, io, process};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Fail;
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+
+use crypto::blake2b;
+use crypto::hash::{ChainId, HashType};
+
+use crate::{BlockMetaStorage, BlockStorage, BlockStorageReader, ChainMetaStorage, database_schemas, StorageError, SystemStorage};
+use crate::chain_meta_storage::ChainMetaStorageReader;
+use crate::persistent::{CommitLogSchema, DbConfiguration, DbTuning, open_cl_read_only, open_kv_secondary, PersistentStorage};
+
+/// Version of the backup format
+pub const BACKUP_VERSION: u16 = 2;
+
+const MANIFEST_FILE: &str = "backup.json";
+const DB_DIR: &str = "db";
+const TEZOS_DATA_DIR: &str = "tezos_data";
+
+/// Just one backup can be created at a time
+static BACKUP_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Possible errors for backup and restore
+#[derive(Debug, Fail)]
+pub enum BackupError {
+    #[fail(display = "Storage error: {}", error)]
+    StorageError {
+        error: StorageError
+    },
+    #[fail(display = "Backup I/O error: {}", error)]
+    IOError {
+        error: io::Error
+    },
+    #[fail(display = "Current head is not set, there is nothing to back up")]
+    MissingHead,
+    #[fail(display = "Another backup is in progress")]
+    BackupInProgress,
+    #[fail(display = "Backup directory {:?} already exists", path)]
+    BackupExists {
+        path: PathBuf
+    },
+    #[fail(display = "Target {:?} is not empty, backup can be restored just to the empty location", path)]
+    TargetNotEmpty {
+        path: PathBuf
+    },
+    #[fail(display = "Invalid backup: {}", reason)]
+    InvalidBackup {
+        reason: String
+    },
+}
+
+impl From<StorageError> for BackupError {
+    fn from(error: StorageError) -> Self {
+        BackupError::StorageError { error }
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(error: io::Error) -> Self {
+        BackupError::IOError { error }
+    }
+}
+
+impl slog::Value for BackupError {
+    fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+macro_rules! invalid_backup {
+    ($($arg:tt)*) => {{
+        BackupError::InvalidBackup { reason: format!($($arg)*) }
+    }}
+}
+
+/// Locations of the node data and of the backups created by the running node
+#[derive(Debug, Clone)]
+pub struct BackupConfiguration {
+    pub db_path: PathBuf,
+    pub tezos_data_dir: PathBuf,
+    /// Every backup is created in its own subdirectory
+    pub backup_dir: PathBuf,
+}
+
+/// Manifest describes the backup, it is stored as `backup.json` in the backup directory
+#[derive(Serialize, Deserialize, Debug, Clone, Getters, CopyGetters)]
+pub struct BackupManifest {
+    #[get_copy = "pub"]
+    version: u16,
+    /// Base58 encoded chain id
+    #[get = "pub"]
+    chain_id: String,
+    /// Base58 encoded hash of the current head at the time of the backup
+    #[get = "pub"]
+    head_hash: String,
+    #[get_copy = "pub"]
+    head_level: i32,
+    #[get_copy = "pub"]
+    database_version: Option<i64>,
+    /// Unix time in seconds
+    #[get_copy = "pub"]
+    created_at: u64,
+    #[get = "pub"]
+    files: Vec<BackupFile>,
+}
+
+impl BackupManifest {
+    /// Size of all backed up files in bytes
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+/// Backed up file with the path relative to the backup directory (`/` separated)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BackupFile {
+    pub path: String,
+    pub size: u64,
+    /// Hex encoded blake2b (256 bits) hash of the file content
+    pub hash: String,
+}
+
+struct BackupGuard;
+
+impl BackupGuard {
+    fn acquire() -> Result<Self, BackupError> {
+        BACKUP_IN_PROGRESS.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| BackupGuard)
+            .map_err(|_| BackupError::BackupInProgress)
+    }
+}
+
+impl Drop for BackupGuard {
+    fn drop(&mut self) {
+        BACKUP_IN_PROGRESS.store(false, Ordering::Release);
+    }
+}
+
+/// Create backup of the running node to the new directory `backup_name` in the `backup_dir`.
+///
+/// Backup is tagged with the current head of the chain `chain_id`. Incomplete backup directory is removed on failure.
+/// Application of blocks is paused until all data are copied, so this can take a while.
+pub fn create_backup(
+    persistent_storage: &PersistentStorage,
+    chain_id: &ChainId,
+    db_path: &Path,
+    tezos_data_dir: &Path,
+    backup_dir: &Path,
+    backup_name: &str,
+    log: &Logger) -> Result<BackupManifest, BackupError> {
+    let _guard = BackupGuard::acquire()?;
+    let backup_path = backup_dir.join(backup_name);
+    if backup_path.exists() {
+        return Err(BackupError::BackupExists { path: backup_path.clone() });
+    }
+
+    // protocol runner must not write to the OCaml context during the copy
+    let apply_lock = persistent_storage.apply_lock();
+    let apply_guard = apply_lock.write().expect("lock poisoning");
+
+    // head is read before the checkpoint, so the checkpoint contains the head with all its data
+    let head = ChainMetaStorage::new(persistent_storage)
+        .get_current_head(chain_id)?
+        .ok_or(BackupError::MissingHead)?;
+    let database_version = SystemStorage::new(persistent_storage.kv()).get_db_version()?;
+
+    fs::create_dir_all(&backup_path)?;
+    let copied = copy_node_data(persistent_storage, db_path, tezos_data_dir, backup_dir, &backup_path);
+    drop(apply_guard);
+    if let Err(e) = copied {
+        let _ = fs::remove_dir_all(&backup_path);
+        return Err(e);
+    }
+
+    let manifest = BackupManifest {
+        version: BACKUP_VERSION,
+        chain_id: HashType::ChainId.bytes_to_string(chain_id),
+        head_hash: HashType::BlockHash.bytes_to_string(&head.hash),
+        head_level: head.level,
+        database_version,
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0),
+        files: list_files(&backup_path)?,
+    };
+    let manifest_path = backup_path.join(MANIFEST_FILE);
+    fs::write(&manifest_path, serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?)?;
+
+    info!(log, "Backup created";
+               "head" => manifest.head_hash(),
+               "level" => manifest.head_level,
+               "files" => manifest.files.len(),
+               "size" => manifest.size(),
+               "path" => format!("{:?}", backup_path));
+
+    Ok(manifest)
+}
+
+fn copy_node_data(persistent_storage: &PersistentStorage, db_path: &Path, tezos_data_dir: &Path, backup_dir: &Path, backup_path: &Path) -> Result<(), BackupError> {
+    let db_backup_path = backup_path.join(DB_DIR);
+    persistent_storage.kv().checkpoint(&db_backup_path).map_err(StorageError::from)?;
+    persistent_storage.clog().copy_to(&db_backup_path).map_err(StorageError::from)?;
+
+    // database and backups (including the previous ones) are usually stored inside of the tezos data dir
+    let excluded = vec![fs::canonicalize(db_path)?, fs::canonicalize(backup_dir)?];
+    let tezos_data_backup_path = backup_path.join(TEZOS_DATA_DIR);
+    fs::create_dir_all(&tezos_data_backup_path)?;
+    if tezos_data_dir.exists() {
+        copy_dir(tezos_data_dir, &tezos_data_backup_path, &excluded)?;
+    }
+
+    Ok(())
+}
+
+/// Check, that the backup at `backup_path` is complete and that it contains applied head of the chain `expected_chain_id`.
+///
+/// Database of the backup is opened read-only, see [open_kv_secondary].
+pub fn verify_backup<P: AsRef<Path>>(backup_path: P, expected_chain_id: &ChainId, db_cfg: &DbConfiguration) -> Result<BackupManifest, BackupError> {
+    let backup_path = backup_path.as_ref();
+    let manifest: BackupManifest = match fs::read(backup_path.join(MANIFEST_FILE)) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| invalid_backup!("invalid manifest: {}", e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(invalid_backup!("manifest {} is missing, backup is not complete", MANIFEST_FILE)),
+        Err(e) => return Err(e.into()),
+    };
+    if manifest.version != BACKUP_VERSION {
+        return Err(invalid_backup!("unsupported version {}, expected {}", manifest.version, BACKUP_VERSION));
+    }
+    let expected_chain_id = HashType::ChainId.bytes_to_string(expected_chain_id);
+    if manifest.chain_id != expected_chain_id {
+        return Err(invalid_backup!("backup was created for chain {}, but expected chain is {}", manifest.chain_id, expected_chain_id));
+    }
+
+    for file in &manifest.files {
+        let path = relative_path(backup_path, &file.path);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() == file.size => (),
+            Ok(metadata) => return Err(invalid_backup!("file {} has size {}, but expected size is {}", file.path, metadata.len(), file.size)),
+            Err(_) => return Err(invalid_backup!("file {} is missing", file.path)),
+        }
+        if file_hash(&path)? != file.hash {
+            return Err(invalid_backup!("file {} does not match its hash", file.path));
+        }
+    }
+
+    // secondary instance keeps its info logs outside of the backup
+    let secondary_path = env::temp_dir().join(format!("tezedge_backup_verify_{}", process::id()));
+    let verified = verify_head(backup_path, &secondary_path, &manifest, db_cfg);
+    let _ = fs::remove_dir_all(&secondary_path);
+    verified.map(|_| manifest)
+}
+
+fn verify_head(backup_path: &Path, secondary_path: &Path, manifest: &BackupManifest, db_cfg: &DbConfiguration) -> Result<(), BackupError> {
+    let db_backup_path = backup_path.join(DB_DIR);
+    let db_tuning = DbTuning::new(db_cfg).map_err(StorageError::from)?;
+    let kv = open_kv_secondary(&db_backup_path, secondary_path, database_schemas(&db_tuning), db_cfg).map_err(StorageError::from)?;
+    let clog = open_cl_read_only(&db_backup_path, secondary_path.join("commit_logs"), vec![BlockStorage::descriptor()]).map_err(StorageError::from)?;
+    let persistent_storage = PersistentStorage::new_secondary(Arc::new(kv), Arc::new(clog));
+
+    let head_hash = HashType::BlockHash.string_to_bytes(&manifest.head_hash)
+        .map_err(|e| invalid_backup!("invalid head hash {}: {}", manifest.head_hash, e))?;
+    if BlockStorage::new(&persistent_storage).get(&head_hash)?.is_none() {
+        return Err(invalid_backup!("head {} is missing in the backup", manifest.head_hash));
+    }
+    match BlockMetaStorage::new(&persistent_storage).get(&head_hash)? {
+        Some(meta) if meta.is_applied() && meta.level() == manifest.head_level => (),
+        _ => return Err(invalid_backup!("head {} is not applied in the backup", manifest.head_hash)),
+    }
+
+    let chain_id = HashType::ChainId.string_to_bytes(&manifest.chain_id)
+        .map_err(|e| invalid_backup!("invalid chain id {}: {}", manifest.chain_id, e))?;
+    match ChainMetaStorage::new(&persistent_storage).get_current_head(&chain_id)? {
+        Some(head) if head.level >= manifest.head_level => Ok(()),
+        _ => Err(invalid_backup!("current head of the backup is older than the head {}", manifest.head_hash)),
+    }
+}
+
+/// Verify the backup at `backup_path` and copy its data to the `db_path` and `tezos_data_dir`.
+///
+/// Nothing is overwritten, database directory has to be empty and no backed up file can exist in the tezos data dir.
+pub fn restore_backup<P: AsRef<Path>>(
+    backup_path: P,
+    db_path: &Path,
+    tezos_data_dir: &Path,
+    expected_chain_id: &ChainId,
+    db_cfg: &DbConfiguration,
+    log: &Logger) -> Result<BackupManifest, BackupError> {
+    let backup_path = backup_path.as_ref();
+    let manifest = verify_backup(backup_path, expected_chain_id, db_cfg)?;
+
+    if db_path.exists() && fs::read_dir(db_path)?.next().is_some() {
+        return Err(BackupError::TargetNotEmpty { path: db_path.to_path_buf() });
+    }
+    let tezos_data_prefix = format!("{}/", TEZOS_DATA_DIR);
+    for file in &manifest.files {
+        if file.path.starts_with(&tezos_data_prefix) {
+            let target_path = relative_path(tezos_data_dir, &file.path[tezos_data_prefix.len()..]);
+            if target_path.exists() {
+                return Err(BackupError::TargetNotEmpty { path: target_path });
+            }
+        }
+    }
+
+    fs::create_dir_all(db_path)?;
+    copy_dir(&backup_path.join(DB_DIR), db_path, &[])?;
+    fs::create_dir_all(tezos_data_dir)?;
+    copy_dir(&backup_path.join(TEZOS_DATA_DIR), tezos_data_dir, &[])?;
+
+    info!(log, "Backup restored";
+               "head" => manifest.head_hash(),
+               "level" => manifest.head_level,
+               "files" => manifest.files.len(),
+               "size" => manifest.size());
+
+    Ok(manifest)
+}
+
+/// Copy content of the `source` directory recursively, `excluded` are canonical paths, which are not copied
+pub(crate) fn copy_dir(source: &Path, target: &Path, excluded: &[PathBuf]) -> Result<(), io::Error> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        if excluded.contains(&fs::canonicalize(entry.path())?) {
+            continue;
+        }
+
+        let target_path = target.join(entry.file_name());
+        if entry.metadata()?.is_dir() {
+            fs::create_dir_all(&target_path)?;
+            copy_dir(&entry.path(), &target_path, excluded)?;
+        } else {
+            fs::copy(entry.path(), &target_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// All files of the backup directory except of the manifest, sorted by their path
+fn list_files(backup_path: &Path) -> Result<Vec<BackupFile>, io::Error> {
+    fn collect(dir: &Path, prefix: &str, files: &mut Vec<BackupFile>) -> Result<(), io::Error> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                collect(&entry.path(), &format!("{}/", path), files)?;
+            } else if path != MANIFEST_FILE {
+                files.push(BackupFile { path, size: metadata.len(), hash: file_hash(&entry.path())? });
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    collect(backup_path, "", &mut files)?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+/// Hex encoded blake2b hash of the file content
+fn file_hash(path: &Path) -> Result<String, io::Error> {
+    let mut file = fs::File::open(path)?;
+    blake2b::digest_256_reader(&mut file).map(hex::encode)
+}
+
+#[inline]
+fn relative_path(base: &Path, path: &str) -> PathBuf {
+    path.split('/').fold(base.to_path_buf(), |path, component| path.join(component))
+}
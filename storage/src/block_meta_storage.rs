@@ -12,7 +12,7 @@ use tezos_messages::p2p::encoding::block_header::Level;
 
 use crate::{BlockHeaderWithHash, StorageError};
 use crate::num_from_slice;
-use crate::persistent::{Decoder, default_table_options, Encoder, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError};
+use crate::persistent::{DbTuning, Decoder, default_table_options, Encoder, KeyValueColumn, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError, WriteBatch};
 use crate::persistent::database::{IteratorMode, IteratorWithSchema};
 
 pub type BlockMetaStorageKV = dyn KeyValueStoreWithSchema<BlockMetaStorage> + Sync + Send;
@@ -61,6 +61,7 @@ impl BlockMetaStorage {
             None => {
                 let meta = Meta {
                     is_applied: false,
+                    is_pruned: false,
                     predecessor: Some(block_header.header.predecessor().clone()),
                     successor: None,
                     level: block_header.header.level(),
@@ -98,6 +99,7 @@ impl BlockMetaStorage {
             None => {
                 let meta = Meta {
                     is_applied: false,
+                    is_pruned: false,
                     predecessor: None,
                     successor: Some(block_header.hash.clone()),
                     level: block_header.header.level() - 1,
@@ -116,12 +118,39 @@ impl BlockMetaStorage {
             .map_err(StorageError::from)
     }
 
+    /// Same as `put`, but metadata are merged when the batch is written
+    #[inline]
+    pub fn put_to_batch(&self, batch: &mut WriteBatch, block_hash: &BlockHash, meta: &Meta) -> Result<(), StorageError> {
+        batch.merge::<BlockMetaStorage>(block_hash, meta)
+            .map_err(StorageError::from)
+    }
+
+    /// Write batch atomically, batch can contain operations of any storage sharing the same key-value store
+    #[inline]
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<(), StorageError> {
+        self.kv.write_batch(batch)
+            .map_err(StorageError::from)
+    }
+
     #[inline]
     pub fn get(&self, block_hash: &BlockHash) -> Result<Option<Meta>, StorageError> {
         self.kv.get(block_hash)
             .map_err(StorageError::from)
     }
 
+    /// Overwrite stored metadata, unlike `put` it does not merge values, so flags and links can be cleared
+    #[inline]
+    pub fn replace(&self, block_hash: &BlockHash, meta: &Meta) -> Result<(), StorageError> {
+        self.kv.put(block_hash, meta)
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn delete(&self, block_hash: &BlockHash) -> Result<(), StorageError> {
+        self.kv.delete(block_hash)
+            .map_err(StorageError::from)
+    }
+
     #[inline]
     pub fn iter(&self, mode: IteratorMode<Self>) -> Result<IteratorWithSchema<Self>, StorageError> {
         self.kv.iterator(mode)
@@ -152,6 +181,7 @@ const LEN_CHAIN_ID: usize = HashType::ChainId.size();
 const MASK_IS_APPLIED: u8 = 0b0000_0001;
 const MASK_HAS_SUCCESSOR: u8 = 0b0000_0010;
 const MASK_HAS_PREDECESSOR: u8 = 0b0000_0100;
+const MASK_IS_PRUNED: u8 = 0b0000_1000;
 
 const IDX_MASK: usize = 0;
 const IDX_PREDECESSOR: usize = IDX_MASK + 1;
@@ -166,6 +196,9 @@ const LEN_META: usize = std::mem::size_of::<u8>() + LEN_BLOCK_HASH + LEN_BLOCK_H
 macro_rules! is_applied {
     ($mask:expr) => {{ ($mask & MASK_IS_APPLIED) != 0 }}
 }
+macro_rules! is_pruned {
+    ($mask:expr) => {{ ($mask & MASK_IS_PRUNED) != 0 }}
+}
 macro_rules! has_predecessor {
     ($mask:expr) => {{ ($mask & MASK_HAS_PREDECESSOR) != 0 }}
 }
@@ -179,10 +212,15 @@ pub struct Meta {
     #[get = "pub"]
     predecessor: Option<BlockHash>,
     #[get = "pub"]
+    #[set = "pub"]
     successor: Option<BlockHash>,
     #[get_copy = "pub"]
     #[set = "pub"]
     is_applied: bool,
+    /// Block data were removed by the history pruning, only this metadata record is kept
+    #[get_copy = "pub"]
+    #[set = "pub"]
+    is_pruned: bool,
     #[get_copy = "pub"]
     level: Level,
     #[get = "pub"]
@@ -194,6 +232,7 @@ impl Meta {
     pub fn genesis_meta(genesis_hash: &BlockHash, genesis_chain_id: &ChainId, is_applied: bool) -> Self {
         Meta {
             is_applied,
+            is_pruned: false,
             predecessor: Some(genesis_hash.clone()), // this is what we want
             successor: None, // we do not know (yet) successor of the genesis
             level: 0,
@@ -211,6 +250,7 @@ impl Decoder for Meta {
             // mask
             let mask = bytes[IDX_MASK];
             let is_processed = is_applied!(mask);
+            let is_pruned = is_pruned!(mask);
             // predecessor
             let predecessor = if has_predecessor!(mask) {
                 let block_hash = bytes[IDX_PREDECESSOR..IDX_SUCCESSOR].to_vec();
@@ -232,7 +272,7 @@ impl Decoder for Meta {
             // chain_id
             let chain_id = bytes[IDX_CHAIN_ID..IDX_END].to_vec();
             assert_eq!(LEN_CHAIN_ID, chain_id.len(), "Chain ID expected length is {} but found {}", LEN_CHAIN_ID, chain_id.len());
-            Ok(Meta { predecessor, successor, is_applied: is_processed, level, chain_id })
+            Ok(Meta { predecessor, successor, is_applied: is_processed, is_pruned, level, chain_id })
         } else {
             Err(SchemaError::DecodeError)
         }
@@ -245,6 +285,9 @@ impl Encoder for Meta {
         if self.is_applied {
             mask |= MASK_IS_APPLIED;
         }
+        if self.is_pruned {
+            mask |= MASK_IS_PRUNED;
+        }
         if self.predecessor.is_some() {
             mask |= MASK_HAS_PREDECESSOR;
         }
@@ -274,12 +317,17 @@ impl KeyValueSchema for BlockMetaStorage {
     type Key = BlockHash;
     type Value = Meta;
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        let mut cf_opts = default_table_options();
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
         cf_opts.set_merge_operator("block_meta_storage_merge_operator", merge_meta_value, None);
         ColumnFamilyDescriptor::new(Self::name(), cf_opts)
     }
 
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name())
+            .with_merge_operator(|existing_val, operands| merge_meta_operands(existing_val, operands.iter().cloned()))
+    }
+
     #[inline]
     fn name() -> &'static str {
         "block_meta_storage"
@@ -287,6 +335,10 @@ impl KeyValueSchema for BlockMetaStorage {
 }
 
 fn merge_meta_value(_new_key: &[u8], existing_val: Option<&[u8]>, operands: &mut MergeOperands) -> Option<Vec<u8>> {
+    merge_meta_operands(existing_val, operands)
+}
+
+fn merge_meta_operands<'a, I: IntoIterator<Item=&'a [u8]>>(existing_val: Option<&[u8]>, operands: I) -> Option<Vec<u8>> {
     let mut result = existing_val.map(|v| v.to_vec());
 
     for op in operands {
@@ -325,7 +377,7 @@ mod tests {
 
     use crypto::hash::HashType;
 
-    use crate::persistent::{DbConfiguration, open_kv};
+    use crate::persistent::{DbConfiguration, DbTuning, open_kv};
     use crate::tests_common::TmpStorage;
 
     use super::*;
@@ -334,6 +386,7 @@ mod tests {
     fn block_meta_encoded_equals_decoded() -> Result<(), Error> {
         let expected = Meta {
             is_applied: false,
+            is_pruned: false,
             predecessor: Some(vec![98; 32]),
             successor: Some(vec![21; 32]),
             level: 34,
@@ -344,6 +397,32 @@ mod tests {
         Ok(assert_eq!(expected, decoded))
     }
 
+    #[test]
+    fn block_meta_pruned_flag_is_kept() -> Result<(), Error> {
+        let tmp_storage = TmpStorage::create("__blockmeta_prunedtest")?;
+
+        let k = vec![44; 32];
+        let mut v = Meta {
+            is_applied: true,
+            is_pruned: false,
+            predecessor: Some(vec![98; 32]),
+            successor: Some(vec![21; 32]),
+            level: 34,
+            chain_id: vec![44; 4],
+        };
+        let storage = BlockMetaStorage::new(tmp_storage.storage());
+        storage.put(&k, &v)?;
+        v.set_is_pruned(true);
+        storage.put(&k, &v)?;
+        v.set_is_pruned(false);
+        storage.put(&k, &v)?;
+
+        let value = storage.get(&k)?.expect("value not present");
+        assert!(value.is_pruned());
+        assert!(value.is_applied());
+        Ok(())
+    }
+
     #[test]
     fn genesis_block_initialized_success() -> Result<(), Error> {
         let tmp_storage = TmpStorage::create("__blockmeta_genesistest")?;
@@ -357,6 +436,7 @@ mod tests {
             Some(value) => {
                 let expected = Meta {
                     is_applied: true,
+                    is_pruned: false,
                     predecessor: Some(k.clone()),
                     successor: None,
                     level: 0,
@@ -377,6 +457,7 @@ mod tests {
         let k = vec![44; 32];
         let mut v = Meta {
             is_applied: false,
+            is_pruned: false,
             predecessor: None,
             successor: None,
             level: 1_245_762,
@@ -399,6 +480,7 @@ mod tests {
             Some(value) => {
                 let expected = Meta {
                     is_applied: true,
+                    is_pruned: false,
                     predecessor: Some(vec![98; 32]),
                     successor: Some(vec![21; 32]),
                     level: 1_245_762,
@@ -422,10 +504,11 @@ mod tests {
         }
 
         {
-            let db = open_kv(path, vec![BlockMetaStorage::descriptor()], &DbConfiguration::default()).unwrap();
+            let db = open_kv(path, vec![BlockMetaStorage::descriptor(&DbTuning::default())], &DbConfiguration::default()).unwrap();
             let k = vec![44; 32];
             let mut v = Meta {
                 is_applied: false,
+                is_pruned: false,
                 predecessor: None,
                 successor: None,
                 level: 2,
@@ -447,6 +530,7 @@ mod tests {
                 Ok(Some(value)) => {
                     let expected = Meta {
                         is_applied: true,
+                        is_pruned: false,
                         predecessor: Some(vec![98; 32]),
                         successor: Some(vec![21; 32]),
                         level: 2,
@@ -1,16 +1,23 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::collections::HashMap;
+use std::mem;
 use std::sync::Arc;
 
+use commitlog::Offset;
 use derive_builder::Builder;
 use getset::{CopyGetters, Getters};
+use rocksdb::{ColumnFamilyDescriptor, SliceTransform};
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{BlockHash, ContextHash, HashType};
+use crypto::hash::{BlockHash, ContextHash, HashType, ProtocolHash};
 
-use crate::{BlockHeaderWithHash, Direction, IteratorMode, StorageError};
-use crate::persistent::{BincodeEncoded, CommitLogSchema, CommitLogWithSchema, KeyValueSchema, KeyValueStoreWithSchema, Location, PersistentStorage};
+use crate::{BlockHeaderWithHash, Direction, IteratorMode, num_from_slice, StorageError};
+use crate::context_action_storage::{contract_id_to_contract_address_for_index, ContractAddress};
+use crate::migration::CommitLogReferences;
+use crate::operations_index_storage::{operations_index_delete_to_batch, operations_index_to_batch};
+use crate::persistent::{BincodeEncoded, CommitLogs, CommitLogSchema, CommitLogWithSchema, DbTuning, Decoder, default_table_options, Encoder, KeyValueColumn, KeyValueSchema, KeyValueStoreWithSchema, Location, PersistentStorage, SchemaError, WriteBatch};
 
 /// Store block header data in a key-value store and into commit log.
 /// The value is first inserted into commit log, which returns a location of the newly inserted value.
@@ -22,6 +29,8 @@ pub struct BlockStorage {
     primary_index: BlockPrimaryIndex,
     by_level_index: BlockByLevelIndex,
     by_context_hash_index: BlockByContextHashIndex,
+    by_baker_index: BlockByBakerIndex,
+    by_protocol_index: BlockByProtocolIndex,
     clog: Arc<BlockStorageCommitLog>,
 }
 
@@ -68,6 +77,18 @@ pub trait BlockStorageReader: Sync + Send {
 
     fn get_live_blocks(&self, level: i32, max_ttl: usize) -> Result<Option<Vec<String>>, StorageError>;
 
+    /// Blocks with timestamp in the range `[from, to]`, block timestamps are expected to grow with the level
+    fn get_by_timestamp_range(&self, from: i64, to: i64, limit: usize) -> Result<Vec<BlockHeaderWithHash>, StorageError>;
+
+    /// Blocks with the protocol level `proto`, proto level is expected to grow with the level
+    fn get_by_proto_level(&self, proto: u8, limit: usize) -> Result<Vec<BlockHeaderWithHash>, StorageError>;
+
+    /// Blocks applied by the protocol (as stored in the block metadata), starting from `from_level`
+    fn get_by_protocol(&self, protocol_hash: &ProtocolHash, from_level: BlockLevel, limit: usize) -> Result<Vec<BlockHeaderWithHash>, StorageError>;
+
+    /// Blocks baked by the baker (as stored in the block metadata), optionally just in the `cycle`
+    fn get_by_baker(&self, baker: &ContractAddress, cycle: Option<i32>, limit: usize) -> Result<Vec<BlockHeaderWithHash>, StorageError>;
+
     fn contains(&self, block_hash: &BlockHash) -> Result<bool, StorageError>;
 }
 
@@ -77,6 +98,8 @@ impl BlockStorage {
             primary_index: BlockPrimaryIndex::new(persistent_storage.kv()),
             by_level_index: BlockByLevelIndex::new(persistent_storage.kv()),
             by_context_hash_index: BlockByContextHashIndex::new(persistent_storage.kv()),
+            by_baker_index: BlockByBakerIndex::new(persistent_storage.kv()),
+            by_protocol_index: BlockByProtocolIndex::new(persistent_storage.kv()),
             clog: persistent_storage.clog(),
         }
     }
@@ -104,30 +127,36 @@ impl BlockStorage {
     }
 
     pub fn put_block_json_data(&self, block_hash: &BlockHash, json_data: BlockJsonData) -> Result<(), StorageError> {
-
-        let updated_column_location = {
-            let block_json_data_location = self.clog.append(&BlockStorageColumn::BlockJsonData(json_data))?;
-            let mut column_location = self.primary_index.get(block_hash)?.ok_or(StorageError::MissingKey)?;
-            column_location.block_json_data = Some(block_json_data_location);
-            column_location
-        };
-        let block_header = self.get_block_header_by_location(&updated_column_location)?;
-        // update indexes
-        self.primary_index.put(&block_header.hash, &updated_column_location)
-            .and(self.by_level_index.put(block_header.header.level(), &updated_column_location))
+        let mut batch = WriteBatch::new();
+        self.put_block_data_to_batch(&mut batch, block_hash, Some(json_data), None)?;
+        self.primary_index.write_batch(batch)
     }
 
     pub fn put_block_additional_data(&self, block_hash: &BlockHash, additional_data: BlockAdditionalData) -> Result<(), StorageError> {
-        let updated_column_location = {
-            let block_additional_data_location = self.clog.append(&BlockStorageColumn::BlockAdditionalData(additional_data))?;
-            let mut column_location = self.primary_index.get(block_hash)?.ok_or(StorageError::MissingKey)?;
-            column_location.block_additional_data = Some(block_additional_data_location);
-            column_location
-        };
-        let block_header = self.get_block_header_by_location(&updated_column_location)?;
+        let mut batch = WriteBatch::new();
+        self.put_block_data_to_batch(&mut batch, block_hash, None, Some(additional_data))?;
+        self.primary_index.write_batch(batch)
+    }
+
+    /// Appends json and/or additional data to the commit log and adds update of the block indexes to the batch.
+    /// Data are visible after the batch is written, records of the batch, which was never written, are truncated by the commit log recovery.
+    ///
+    /// Indexes are updated from the stored location, so data of one block should be put to the batch just once.
+    pub fn put_block_data_to_batch(&self, batch: &mut WriteBatch, block_hash: &BlockHash, json_data: Option<BlockJsonData>, additional_data: Option<BlockAdditionalData>) -> Result<(), StorageError> {
+        let mut column_location = self.primary_index.get(block_hash)?.ok_or(StorageError::MissingKey)?;
+        let block_header = self.get_block_header_by_location(&column_location)?;
+        if let Some(json_data) = json_data {
+            metadata_index_to_batch(batch, &block_header, &json_data)?;
+            operations_index_to_batch(batch, &block_header.hash, block_header.header.level(), json_data.operations_proto_metadata_json())?;
+            column_location.block_json_data = Some(self.clog.append(&BlockStorageColumn::BlockJsonData(json_data))?);
+        }
+        if let Some(additional_data) = additional_data {
+            column_location.block_additional_data = Some(self.clog.append(&BlockStorageColumn::BlockAdditionalData(additional_data))?);
+        }
         // update indexes
-        self.primary_index.put(&block_header.hash, &updated_column_location)
-            .and(self.by_level_index.put(block_header.header.level(), &updated_column_location))
+        batch.put::<BlockPrimaryIndex>(&block_header.hash, &column_location)?;
+        batch.put::<BlockByLevelIndex>(&block_header.header.level(), &column_location)?;
+        Ok(())
     }
 
     pub fn assign_to_context(&self, block_hash: &BlockHash, context_hash: &ContextHash) -> Result<(), StorageError> {
@@ -137,6 +166,217 @@ impl BlockStorage {
         }
     }
 
+    /// Remove context hash index entry of the block, e.g. when its context was pruned.
+    /// Entry is removed only if it still points to this block.
+    pub fn unassign_context(&self, block_hash: &BlockHash) -> Result<(), StorageError> {
+        let location = match self.primary_index.get(block_hash)? {
+            Some(location) => location,
+            None => return Ok(())
+        };
+        let context_hash = self.get_block_header_by_location(&location)?.header.context().clone();
+        if let Some(context_location) = self.by_context_hash_index.get(&context_hash)? {
+            if context_location.block_header == location.block_header {
+                self.by_context_hash_index.delete(&context_hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Headers of blocks from the level index with level in range `[from_level, to_level)`
+    pub fn get_by_level_range(&self, from_level: BlockLevel, to_level: BlockLevel) -> Result<Vec<BlockHeaderWithHash>, StorageError> {
+        self.by_level_index.get_blocks_in_range(from_level, to_level)?
+            .iter()
+            .map(|location| self.get_block_header_by_location(location))
+            .collect()
+    }
+
+    /// Check, that records referenced by indexes can be read from the commit log, e.g. after a crash.
+    /// Only records at `from_offset` and above are read, everything below is expected to be already verified.
+    ///
+    /// Blocks with unreadable header are removed from all indexes, unreadable json or additional data are
+    /// removed from the location, so they are stored again, when the block is applied.
+    pub fn verify_locations(&self, from_offset: Offset) -> Result<LocationsVerification, StorageError> {
+        let mut verification = LocationsVerification::default();
+        let is_readable = |location: &Location, verification: &mut LocationsVerification| {
+            if location.0 < from_offset {
+                true
+            } else {
+                verification.checked += 1;
+                self.clog.get(location).is_ok()
+            }
+        };
+
+        // secondary indexes hold copies of primary locations, identified by header location
+        let mut changed: HashMap<Offset, Option<BlockStorageColumnsLocation>> = HashMap::new();
+        let mut batch = WriteBatch::new();
+        for (block_hash, mut location) in self.primary_index.entries()? {
+            if !is_readable(&location.block_header, &mut verification) {
+                batch.delete::<BlockPrimaryIndex>(&block_hash)?;
+                changed.insert(location.block_header.0, None);
+                verification.removed_blocks += 1;
+                continue;
+            }
+
+            let mut cleared = false;
+            if location.block_json_data.map_or(false, |data| !is_readable(&data, &mut verification)) {
+                location.block_json_data = None;
+                cleared = true;
+                verification.cleared_records += 1;
+            }
+            if location.block_additional_data.map_or(false, |data| !is_readable(&data, &mut verification)) {
+                location.block_additional_data = None;
+                cleared = true;
+                verification.cleared_records += 1;
+            }
+            if cleared {
+                batch.put::<BlockPrimaryIndex>(&block_hash, &location)?;
+                changed.insert(location.block_header.0, Some(location.clone()));
+            }
+
+            verification.next_offset = location.locations()
+                .map(|location| location.0 + 1)
+                .fold(verification.next_offset, std::cmp::max);
+        }
+
+        if !changed.is_empty() {
+            for (level, location) in self.by_level_index.entries()? {
+                match changed.get(&location.block_header.0) {
+                    Some(Some(location)) => batch.put::<BlockByLevelIndex>(&level, location)?,
+                    Some(None) => batch.delete::<BlockByLevelIndex>(&level)?,
+                    None => (),
+                }
+            }
+            for (context_hash, location) in self.by_context_hash_index.entries()? {
+                match changed.get(&location.block_header.0) {
+                    Some(Some(location)) => batch.put::<BlockByContextHashIndex>(&context_hash, location)?,
+                    Some(None) => batch.delete::<BlockByContextHashIndex>(&context_hash)?,
+                    None => (),
+                }
+            }
+        }
+        // all indexes are updated at once, so a crash cannot leave secondary indexes pointing to removed blocks
+        self.primary_index.write_batch(batch)?;
+
+        Ok(verification)
+    }
+
+    /// Offset following the last record referenced by the primary index, it is greater than the next offset
+    /// of the commit log, if referenced records were lost (e.g. not flushed before a crash)
+    pub fn next_referenced_offset(&self) -> Result<Offset, StorageError> {
+        Ok(self.primary_index.entries()?
+            .iter()
+            .flat_map(|(_, location)| location.locations())
+            .map(|location| location.0 + 1)
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// Rewrite the commit log, so it contains only records referenced by indexes, and update all indexes
+    /// to the new locations. Must not run concurrently with other writes to the block storage.
+    /// Returns number of copied records.
+    pub fn compact(&self, commit_logs: &CommitLogs) -> Result<usize, StorageError> {
+        let mut compaction = commit_logs.begin_compaction(Self::name())?;
+        let mut journal = CompactionJournal::default();
+        let mut copied = 0;
+
+        let mut moved: HashMap<Offset, BlockStorageColumnsLocation> = HashMap::new();
+        for (block_hash, location) in self.primary_index.entries()? {
+            let new_location = BlockStorageColumnsLocation {
+                block_header: compaction.copy(&location.block_header)?,
+                block_json_data: location.block_json_data.map(|data| compaction.copy(&data)).transpose()?,
+                block_additional_data: location.block_additional_data.map(|data| compaction.copy(&data)).transpose()?,
+            };
+            copied += new_location.locations().count();
+            moved.insert(location.block_header.0, new_location.clone());
+            journal.primary.push((block_hash, new_location));
+        }
+        // secondary index entries without a primary entry would point to the removed records
+        for (level, location) in self.by_level_index.entries()? {
+            journal.by_level.push((level, moved.get(&location.block_header.0).cloned()));
+        }
+        for (context_hash, location) in self.by_context_hash_index.entries()? {
+            journal.by_context_hash.push((context_hash, moved.get(&location.block_header.0).cloned()));
+        }
+
+        commit_logs.commit_compaction(Self::name(), compaction, &journal.encode()?)?;
+        self.apply_compaction_journal(&journal)?;
+        commit_logs.finish_compaction(Self::name())?;
+
+        Ok(copied)
+    }
+
+    /// Finish compaction interrupted by a crash, or clean up, if it was not committed yet.
+    /// Returns `true`, if the compaction was finished.
+    pub fn recover_compaction(&self, commit_logs: &CommitLogs) -> Result<bool, StorageError> {
+        match commit_logs.compaction_journal(Self::name())? {
+            Some(journal) => {
+                self.apply_compaction_journal(&CompactionJournal::decode(&journal)?)?;
+                commit_logs.finish_compaction(Self::name())?;
+                Ok(true)
+            }
+            None => {
+                commit_logs.abort_compaction(Self::name())?;
+                Ok(false)
+            }
+        }
+    }
+
+    fn apply_compaction_journal(&self, journal: &CompactionJournal) -> Result<(), StorageError> {
+        for (block_hash, location) in &journal.primary {
+            self.primary_index.put(block_hash, location)?;
+        }
+        for (level, location) in &journal.by_level {
+            match location {
+                Some(location) => self.by_level_index.put(*level, location)?,
+                None => self.by_level_index.delete(level)?,
+            }
+        }
+        for (context_hash, location) in &journal.by_context_hash {
+            match location {
+                Some(location) => self.by_context_hash_index.put(context_hash, location)?,
+                None => self.by_context_hash_index.delete(context_hash)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes block from all indexes in one batch. Data already appended to the commit log are kept.
+    /// Level and context hash indexes are cleared only if they still point to this block.
+    pub fn delete(&self, block_hash: &BlockHash) -> Result<(), StorageError> {
+        let location = match self.primary_index.get(block_hash)? {
+            Some(location) => location,
+            None => return Ok(())
+        };
+        let block_header = self.get_block_header_by_location(&location)?;
+        let mut batch = WriteBatch::new();
+
+        let level = block_header.header.level();
+        if let Some(level_location) = self.by_level_index.get(&level)? {
+            if level_location.block_header == location.block_header {
+                batch.delete::<BlockByLevelIndex>(&level)?;
+            }
+        }
+        let context_hash = block_header.header.context();
+        if let Some(context_location) = self.by_context_hash_index.get(context_hash)? {
+            if context_location.block_header == location.block_header {
+                batch.delete::<BlockByContextHashIndex>(context_hash)?;
+            }
+        }
+        if let Some(json_data) = self.get_block_json_data_by_location(&location)? {
+            let (by_baker, by_protocol) = metadata_index_keys(&block_header, &json_data);
+            if let Some(key) = by_baker {
+                batch.delete::<BlockByBakerIndex>(&key)?;
+            }
+            if let Some(key) = by_protocol {
+                batch.delete::<BlockByProtocolIndex>(&key)?;
+            }
+            operations_index_delete_to_batch(&mut batch, block_hash, level, json_data.operations_proto_metadata_json())?;
+        }
+        batch.delete::<BlockPrimaryIndex>(block_hash)?;
+
+        self.primary_index.write_batch(batch)
+    }
+
     #[inline]
     fn get_block_header_by_location(&self, location: &BlockStorageColumnsLocation) -> Result<BlockHeaderWithHash, StorageError> {
         match self.clog.get(&location.block_header).map_err(StorageError::from)? {
@@ -167,6 +407,74 @@ impl BlockStorage {
         }
     }
 
+    /// First stored level, for which `predicate` holds. Predicate has to be monotonic in the block level,
+    /// i.e. once it holds for a level, it holds for all higher levels.
+    fn find_first_level<F>(&self, predicate: F) -> Result<Option<BlockLevel>, StorageError>
+        where
+            F: Fn(&BlockHeaderWithHash) -> bool
+    {
+        let (mut low, mut high) = match self.by_level_index.level_bounds()? {
+            Some((first, last)) => (first, last + 1),
+            None => return Ok(None),
+        };
+
+        // levels do not have to be continuous, so the first stored level from the middle is checked
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let (level, block_header) = match self.by_level_index.get_blocks_directed(middle, 1, Direction::Forward)?.pop() {
+                Some(location) => {
+                    let block_header = self.get_block_header_by_location(&location)?;
+                    (block_header.header.level(), block_header)
+                }
+                None => {
+                    high = middle;
+                    continue;
+                }
+            };
+            if level >= high || predicate(&block_header) {
+                high = middle;
+            } else {
+                low = level + 1;
+            }
+        }
+
+        Ok(self.by_level_index.get_blocks_directed(low, 1, Direction::Forward)?
+            .pop()
+            .map(|location| self.get_block_header_by_location(&location))
+            .transpose()?
+            .filter(|block_header| predicate(block_header))
+            .map(|block_header| block_header.header.level()))
+    }
+
+    /// Blocks from the level `from_level` upwards, while `predicate` holds
+    fn get_blocks_while<F>(&self, from_level: Option<BlockLevel>, limit: usize, predicate: F) -> Result<Vec<BlockHeaderWithHash>, StorageError>
+        where
+            F: Fn(&BlockHeaderWithHash) -> bool
+    {
+        let from_level = match from_level {
+            Some(from_level) => from_level,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut blocks = Vec::new();
+        for location in self.by_level_index.get_blocks_directed(from_level, limit, Direction::Forward)? {
+            let block_header = self.get_block_header_by_location(&location)?;
+            if !predicate(&block_header) {
+                break;
+            }
+            blocks.push(block_header);
+        }
+        Ok(blocks)
+    }
+
+    #[inline]
+    fn get_by_block_hashes(&self, block_hashes: Vec<BlockHash>) -> Result<Vec<BlockHeaderWithHash>, StorageError> {
+        // index entries of deleted blocks are skipped
+        block_hashes.iter()
+            .filter_map(|block_hash| self.get(block_hash).transpose())
+            .collect()
+    }
+
     #[inline]
     fn get_blocks_with_json_data_by_location<I>(&self, locations: I) -> Result<Vec<(BlockHeaderWithHash, BlockJsonData)>, StorageError>
         where
@@ -271,12 +579,65 @@ impl BlockStorageReader for BlockStorage {
         Ok(live_blocks)
     }
 
+    fn get_by_timestamp_range(&self, from: i64, to: i64, limit: usize) -> Result<Vec<BlockHeaderWithHash>, StorageError> {
+        let from_level = self.find_first_level(|block_header| block_header.header.timestamp() >= from)?;
+        self.get_blocks_while(from_level, limit, |block_header| block_header.header.timestamp() <= to)
+    }
+
+    fn get_by_proto_level(&self, proto: u8, limit: usize) -> Result<Vec<BlockHeaderWithHash>, StorageError> {
+        let from_level = self.find_first_level(|block_header| block_header.header.proto() >= proto)?;
+        self.get_blocks_while(from_level, limit, |block_header| block_header.header.proto() == proto)
+    }
+
+    #[inline]
+    fn get_by_protocol(&self, protocol_hash: &ProtocolHash, from_level: BlockLevel, limit: usize) -> Result<Vec<BlockHeaderWithHash>, StorageError> {
+        self.get_by_block_hashes(self.by_protocol_index.get_blocks(protocol_hash, from_level, limit)?)
+    }
+
+    #[inline]
+    fn get_by_baker(&self, baker: &ContractAddress, cycle: Option<i32>, limit: usize) -> Result<Vec<BlockHeaderWithHash>, StorageError> {
+        self.get_by_block_hashes(self.by_baker_index.get_blocks(baker, cycle, limit)?)
+    }
+
     #[inline]
     fn contains(&self, block_hash: &BlockHash) -> Result<bool, StorageError> {
         self.primary_index.contains(block_hash)
     }
 }
 
+/// Baker and protocol index keys of the block, taken from the block metadata.
+/// Metadata of genesis (and of blocks of the genesis protocol) contain no baker.
+pub(crate) fn metadata_index_keys(block_header: &BlockHeaderWithHash, json_data: &BlockJsonData) -> (Option<BlockByBakerKey>, Option<BlockByProtocolKey>) {
+    let metadata: serde_json::Value = match serde_json::from_str(json_data.block_header_proto_metadata_json()) {
+        Ok(metadata) => metadata,
+        Err(_) => return (None, None),
+    };
+    let level = block_header.header.level();
+
+    let by_baker = metadata["baker"].as_str()
+        .and_then(|baker| contract_id_to_contract_address_for_index(baker).ok())
+        .map(|baker| {
+            let cycle = metadata["level"]["cycle"].as_i64().unwrap_or(0) as i32;
+            BlockByBakerKey::new(&baker, cycle, level, block_header.hash.clone())
+        });
+    let by_protocol = metadata["protocol"].as_str()
+        .and_then(|protocol| HashType::ProtocolHash.string_to_bytes(protocol).ok())
+        .map(|protocol| BlockByProtocolKey::new(&protocol, level, block_header.hash.clone()));
+
+    (by_baker, by_protocol)
+}
+
+fn metadata_index_to_batch(batch: &mut WriteBatch, block_header: &BlockHeaderWithHash, json_data: &BlockJsonData) -> Result<(), StorageError> {
+    let (by_baker, by_protocol) = metadata_index_keys(block_header, json_data);
+    if let Some(key) = by_baker {
+        batch.put::<BlockByBakerIndex>(&key, &())?;
+    }
+    if let Some(key) = by_protocol {
+        batch.put::<BlockByProtocolIndex>(&key, &())?;
+    }
+    Ok(())
+}
+
 impl CommitLogSchema for BlockStorage {
     type Value = BlockStorageColumn;
 
@@ -297,15 +658,60 @@ pub enum BlockStorageColumn {
 impl BincodeEncoded for BlockStorageColumn {}
 
 /// Holds reference to all stored columns.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BlockStorageColumnsLocation {
     pub block_header: Location,
     pub block_json_data: Option<Location>,
     pub block_additional_data: Option<Location>,
 }
 
+impl BlockStorageColumnsLocation {
+    /// All stored locations
+    fn locations(&self) -> impl Iterator<Item=&Location> {
+        std::iter::once(&self.block_header)
+            .chain(self.block_json_data.iter())
+            .chain(self.block_additional_data.iter())
+    }
+}
+
+impl CommitLogReferences for BlockStorageColumnsLocation {
+    fn locations_mut(&mut self) -> Vec<&mut Location> {
+        std::iter::once(&mut self.block_header)
+            .chain(self.block_json_data.iter_mut())
+            .chain(self.block_additional_data.iter_mut())
+            .collect()
+    }
+}
+
 impl BincodeEncoded for BlockStorageColumnsLocation {}
 
+/// Summary of [BlockStorage::verify_locations]
+#[derive(Debug, Clone, Default, CopyGetters)]
+pub struct LocationsVerification {
+    /// Number of read records
+    #[get_copy = "pub"]
+    checked: usize,
+    /// Number of blocks removed, because of unreadable header
+    #[get_copy = "pub"]
+    removed_blocks: usize,
+    /// Number of unreadable json and additional data records removed from locations
+    #[get_copy = "pub"]
+    cleared_records: usize,
+    /// Offset following the last referenced record, everything from here can be truncated
+    #[get_copy = "pub"]
+    next_offset: Offset,
+}
+
+/// New index values written before they are applied, so interrupted compaction can be finished
+#[derive(Serialize, Deserialize, Default)]
+struct CompactionJournal {
+    primary: Vec<(BlockHash, BlockStorageColumnsLocation)>,
+    by_level: Vec<(BlockLevel, Option<BlockStorageColumnsLocation>)>,
+    by_context_hash: Vec<(ContextHash, Option<BlockStorageColumnsLocation>)>,
+}
+
+impl BincodeEncoded for CompactionJournal {}
+
 
 /// Index block data as `block_header_hash -> location`.
 #[derive(Clone)]
@@ -326,6 +732,12 @@ impl BlockPrimaryIndex {
             .map_err(StorageError::from)
     }
 
+    #[inline]
+    fn write_batch(&self, batch: WriteBatch) -> Result<(), StorageError> {
+        self.kv.write_batch(batch)
+            .map_err(StorageError::from)
+    }
+
     #[inline]
     fn get(&self, block_hash: &BlockHash) -> Result<Option<BlockStorageColumnsLocation>, StorageError> {
         self.kv.get(block_hash)
@@ -337,6 +749,18 @@ impl BlockPrimaryIndex {
         self.kv.contains(block_hash)
             .map_err(StorageError::from)
     }
+
+    #[inline]
+    fn delete(&self, block_hash: &BlockHash) -> Result<(), StorageError> {
+        self.kv.delete(block_hash)
+            .map_err(StorageError::from)
+    }
+
+    fn entries(&self) -> Result<Vec<(BlockHash, BlockStorageColumnsLocation)>, StorageError> {
+        self.kv.iterator(IteratorMode::Start)?
+            .map(|(block_hash, location)| -> Result<_, StorageError> { Ok((block_hash?, location?)) })
+            .collect()
+    }
 }
 
 impl KeyValueSchema for BlockPrimaryIndex {
@@ -372,6 +796,23 @@ impl BlockByLevelIndex {
         self.kv.get(level).map_err(StorageError::from)
     }
 
+    fn delete(&self, level: &BlockLevel) -> Result<(), StorageError> {
+        self.kv.delete(level).map_err(StorageError::from)
+    }
+
+    fn entries(&self) -> Result<Vec<(BlockLevel, BlockStorageColumnsLocation)>, StorageError> {
+        self.kv.iterator(IteratorMode::Start)?
+            .map(|(level, location)| -> Result<_, StorageError> { Ok((level?, location?)) })
+            .collect()
+    }
+
+    /// First and last stored level
+    fn level_bounds(&self) -> Result<Option<(BlockLevel, BlockLevel)>, StorageError> {
+        let first = self.kv.iterator(IteratorMode::Start)?.next().map(|(level, _)| level).transpose()?;
+        let last = self.kv.iterator(IteratorMode::End)?.next().map(|(level, _)| level).transpose()?;
+        Ok(first.and_then(|first| last.map(|last| (first, last))))
+    }
+
     fn get_blocks(&self, from_level: BlockLevel, limit: usize) -> Result<Vec<BlockStorageColumnsLocation>, StorageError> {
         self.kv.iterator(IteratorMode::From(&from_level, Direction::Reverse))?
             .take(limit)
@@ -386,6 +827,14 @@ impl BlockByLevelIndex {
             .collect()
     }
 
+    fn get_blocks_in_range(&self, from_level: BlockLevel, to_level: BlockLevel) -> Result<Vec<BlockStorageColumnsLocation>, StorageError> {
+        self.kv.iterator(IteratorMode::From(&from_level, Direction::Forward))?
+            .map(|(level, location)| -> Result<_, StorageError> { Ok((level?, location?)) })
+            .take_while(|entry| entry.as_ref().map_or(true, |(level, _)| *level < to_level))
+            .map(|entry| entry.map(|(_, location)| location))
+            .collect()
+    }
+
     fn get_blocks_by_nth_level(&self, every_nth: BlockLevel, from_level: BlockLevel, limit: usize) -> Result<Vec<BlockStorageColumnsLocation>, StorageError> {
         self.kv.iterator(IteratorMode::From(&from_level, Direction::Reverse))?
             .filter(|(level, _)| *level.as_ref().unwrap() % every_nth == 0)
@@ -427,6 +876,16 @@ impl BlockByContextHashIndex {
     fn get(&self, context_hash: &ContextHash) -> Result<Option<BlockStorageColumnsLocation>, StorageError> {
         self.kv.get(context_hash).map_err(StorageError::from)
     }
+
+    fn delete(&self, context_hash: &ContextHash) -> Result<(), StorageError> {
+        self.kv.delete(context_hash).map_err(StorageError::from)
+    }
+
+    fn entries(&self) -> Result<Vec<(ContextHash, BlockStorageColumnsLocation)>, StorageError> {
+        self.kv.iterator(IteratorMode::Start)?
+            .map(|(context_hash, location)| -> Result<_, StorageError> { Ok((context_hash?, location?)) })
+            .collect()
+    }
 }
 
 impl KeyValueSchema for BlockByContextHashIndex {
@@ -439,6 +898,200 @@ impl KeyValueSchema for BlockByContextHashIndex {
     }
 }
 
+/// Index blocks by the baker from the block metadata as `baker, cycle, level, block_hash -> ()`.
+#[derive(Clone)]
+pub struct BlockByBakerIndex {
+    kv: Arc<BlockByBakerIndexKV>,
+}
+
+pub type BlockByBakerIndexKV = dyn KeyValueStoreWithSchema<BlockByBakerIndex> + Sync + Send;
+
+impl BlockByBakerIndex {
+    fn new(kv: Arc<BlockByBakerIndexKV>) -> Self {
+        Self { kv }
+    }
+
+    fn get_blocks(&self, baker: &ContractAddress, cycle: Option<i32>, limit: usize) -> Result<Vec<BlockHash>, StorageError> {
+        let from_key = BlockByBakerKey::new(baker, cycle.unwrap_or(0), 0, Vec::new());
+        Ok(self.kv.prefix_iterator(&from_key)?
+            .filter_map(|(key, _)| key.ok())
+            .take_while(|key| cycle.map_or(true, |cycle| key.cycle == cycle))
+            .take(limit)
+            .map(|key| key.block_hash)
+            .collect())
+    }
+}
+
+impl KeyValueSchema for BlockByBakerIndex {
+    type Key = BlockByBakerKey;
+    type Value = ();
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(BlockByBakerKey::LEN_BAKER));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(BlockByBakerKey::LEN_BAKER)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "block_by_baker_storage"
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct BlockByBakerKey {
+    baker: ContractAddress,
+    cycle: i32,
+    level: BlockLevel,
+    block_hash: BlockHash,
+}
+
+impl BlockByBakerKey {
+    const LEN_BAKER: usize = 22;
+    const LEN_CYCLE: usize = mem::size_of::<i32>();
+    const LEN_LEVEL: usize = mem::size_of::<BlockLevel>();
+    const LEN_HASH: usize = HashType::BlockHash.size();
+
+    const IDX_CYCLE: usize = Self::LEN_BAKER;
+    const IDX_LEVEL: usize = Self::IDX_CYCLE + Self::LEN_CYCLE;
+    const IDX_HASH: usize = Self::IDX_LEVEL + Self::LEN_LEVEL;
+
+    /// Empty block hash can be used to get the first key of the level
+    pub fn new(baker: &[u8], cycle: i32, level: BlockLevel, block_hash: BlockHash) -> Self {
+        Self { baker: baker.to_vec(), cycle, level, block_hash }
+    }
+}
+
+/// * bytes layout `[baker(22)][cycle(4)][level(4)][block_hash(32)]`, hash is optional for range queries
+impl Encoder for BlockByBakerKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        if self.baker.len() == Self::LEN_BAKER {
+            let mut bytes = Vec::with_capacity(Self::IDX_HASH + Self::LEN_HASH);
+            bytes.extend(&self.baker);
+            bytes.extend(&self.cycle.to_be_bytes());
+            bytes.extend(&self.level.to_be_bytes());
+            bytes.extend(&self.block_hash);
+            Ok(bytes)
+        } else {
+            Err(SchemaError::EncodeError)
+        }
+    }
+}
+
+impl Decoder for BlockByBakerKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() == Self::IDX_HASH + Self::LEN_HASH {
+            Ok(Self {
+                baker: bytes[..Self::LEN_BAKER].to_vec(),
+                cycle: num_from_slice!(bytes, Self::IDX_CYCLE, i32),
+                level: num_from_slice!(bytes, Self::IDX_LEVEL, i32),
+                block_hash: bytes[Self::IDX_HASH..].to_vec(),
+            })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
+/// Index blocks by the protocol from the block metadata as `protocol_hash, level, block_hash -> ()`.
+#[derive(Clone)]
+pub struct BlockByProtocolIndex {
+    kv: Arc<BlockByProtocolIndexKV>,
+}
+
+pub type BlockByProtocolIndexKV = dyn KeyValueStoreWithSchema<BlockByProtocolIndex> + Sync + Send;
+
+impl BlockByProtocolIndex {
+    fn new(kv: Arc<BlockByProtocolIndexKV>) -> Self {
+        Self { kv }
+    }
+
+    fn get_blocks(&self, protocol_hash: &ProtocolHash, from_level: BlockLevel, limit: usize) -> Result<Vec<BlockHash>, StorageError> {
+        let from_key = BlockByProtocolKey::new(protocol_hash, from_level, Vec::new());
+        Ok(self.kv.prefix_iterator(&from_key)?
+            .filter_map(|(key, _)| key.ok())
+            .take(limit)
+            .map(|key| key.block_hash)
+            .collect())
+    }
+}
+
+impl KeyValueSchema for BlockByProtocolIndex {
+    type Key = BlockByProtocolKey;
+    type Value = ();
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(BlockByProtocolKey::LEN_PROTOCOL));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(BlockByProtocolKey::LEN_PROTOCOL)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "block_by_protocol_storage"
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct BlockByProtocolKey {
+    protocol_hash: ProtocolHash,
+    level: BlockLevel,
+    block_hash: BlockHash,
+}
+
+impl BlockByProtocolKey {
+    const LEN_PROTOCOL: usize = HashType::ProtocolHash.size();
+    const LEN_LEVEL: usize = mem::size_of::<BlockLevel>();
+    const LEN_HASH: usize = HashType::BlockHash.size();
+
+    const IDX_LEVEL: usize = Self::LEN_PROTOCOL;
+    const IDX_HASH: usize = Self::IDX_LEVEL + Self::LEN_LEVEL;
+
+    /// Empty block hash can be used to get the first key of the level
+    pub fn new(protocol_hash: &[u8], level: BlockLevel, block_hash: BlockHash) -> Self {
+        Self { protocol_hash: protocol_hash.to_vec(), level, block_hash }
+    }
+}
+
+/// * bytes layout `[protocol_hash(32)][level(4)][block_hash(32)]`, hash is optional for range queries
+impl Encoder for BlockByProtocolKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        if self.protocol_hash.len() == Self::LEN_PROTOCOL {
+            let mut bytes = Vec::with_capacity(Self::IDX_HASH + Self::LEN_HASH);
+            bytes.extend(&self.protocol_hash);
+            bytes.extend(&self.level.to_be_bytes());
+            bytes.extend(&self.block_hash);
+            Ok(bytes)
+        } else {
+            Err(SchemaError::EncodeError)
+        }
+    }
+}
+
+impl Decoder for BlockByProtocolKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() == Self::IDX_HASH + Self::LEN_HASH {
+            Ok(Self {
+                protocol_hash: bytes[..Self::LEN_PROTOCOL].to_vec(),
+                level: num_from_slice!(bytes, Self::IDX_LEVEL, i32),
+                block_hash: bytes[Self::IDX_HASH..].to_vec(),
+            })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -446,7 +1099,7 @@ mod tests {
 
     use failure::Error;
 
-    use crate::persistent::{DbConfiguration, open_kv};
+    use crate::persistent::{DbConfiguration, DbTuning, open_kv};
 
     use super::*;
 
@@ -460,7 +1113,7 @@ mod tests {
         }
 
         {
-            let db = open_kv(path, vec![BlockByLevelIndex::descriptor()], &DbConfiguration::default()).unwrap();
+            let db = open_kv(path, vec![BlockByLevelIndex::descriptor(&DbTuning::default())], &DbConfiguration::default()).unwrap();
             let index = BlockByLevelIndex::new(Arc::new(db));
 
             for i in vec![1161, 66441, 905, 66185, 649, 65929, 393, 65673] {
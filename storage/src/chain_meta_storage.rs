@@ -9,7 +9,7 @@ use serde::{Deserialize, Serialize};
 use crypto::hash::{ChainId, HashType};
 use tezos_messages::Head;
 
-use crate::persistent::{BincodeEncoded, Decoder, default_table_options, Encoder, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError};
+use crate::persistent::{BincodeEncoded, DbTuning, Decoder, default_table_options, Encoder, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError, WriteBatch};
 use crate::StorageError;
 
 pub type ChainMetaStorageKv = dyn KeyValueStoreWithSchema<ChainMetaStorage> + Sync + Send;
@@ -57,6 +57,14 @@ impl ChainMetaStorage {
             .map_err(StorageError::from)
     }
 
+    /// Same as `set_current_head`, but head is moved when the batch is written
+    #[inline]
+    pub fn set_current_head_to_batch(&self, batch: &mut WriteBatch, chain_id: &ChainId, head: &Head) -> Result<(), StorageError> {
+        batch.put::<ChainMetaStorage>(
+            &MetaKey::key_current_head(chain_id.clone()),
+            &MetadataValue::CurrentHead(head.clone()),
+        ).map_err(StorageError::from)
+    }
 
     #[inline]
     pub fn get_test_chain_id(&self, chain_id: &ChainId) -> Result<Option<ChainId>, StorageError> {
@@ -104,8 +112,8 @@ impl KeyValueSchema for ChainMetaStorage {
     type Key = MetaKey;
     type Value = MetadataValue;
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        let mut cf_opts = default_table_options();
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
         // 1 MB
         cf_opts.set_write_buffer_size(1024 * 1024);
         ColumnFamilyDescriptor::new(Self::name(), cf_opts)
@@ -0,0 +1,118 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Offline maintenance of the block storage commit log.
+//!
+//! * [recover_commit_log] runs on every startup. It finishes (or cleans up) a compaction interrupted by a crash,
+//!   checks that records appended since the last clean shutdown can be read and that no record behind the end
+//!   of the commit log is referenced, removes index entries pointing to unreadable (partially written or lost) records
+//!   and truncates the unreferenced tail of the commit log.
+//!   If some block data were removed, storage is repaired by [check_storage], so the blocks are applied again.
+//! * [compact_commit_log] rewrites the commit log, so it contains only records referenced by indexes
+//!   (e.g. data of blocks removed by history pruning are dropped).
+//!
+//! Both must not run while the node is running.
+
+use std::cmp::min;
+
+use getset::CopyGetters;
+use slog::{info, Logger, warn};
+
+use crate::{BlockStorage, StorageError, SystemStorage};
+use crate::fsck::check_storage;
+use crate::persistent::{CommitLogSchema, PersistentStorage};
+
+/// Summary of [recover_commit_log]
+#[derive(Debug, Clone, Default, CopyGetters)]
+pub struct RecoveryReport {
+    /// Interrupted compaction was finished
+    #[get_copy = "pub"]
+    compaction_finished: bool,
+    /// Number of read records
+    #[get_copy = "pub"]
+    checked_records: usize,
+    /// Number of blocks removed, because of unreadable header
+    #[get_copy = "pub"]
+    removed_blocks: usize,
+    /// Number of unreadable json and additional data records removed from locations
+    #[get_copy = "pub"]
+    cleared_records: usize,
+    /// Number of records truncated from the end of the commit log
+    #[get_copy = "pub"]
+    truncated_records: u64,
+}
+
+/// Summary of [compact_commit_log]
+#[derive(Debug, Clone, Default, CopyGetters)]
+pub struct CompactionReport {
+    /// Number of records kept in the commit log
+    #[get_copy = "pub"]
+    records: usize,
+    /// Size of the commit log before compaction in bytes
+    #[get_copy = "pub"]
+    size_before: u64,
+    /// Size of the commit log after compaction in bytes
+    #[get_copy = "pub"]
+    size_after: u64,
+}
+
+/// Bring block storage commit log and its indexes to a consistent state after a crash
+pub fn recover_commit_log(persistent_storage: &PersistentStorage, log: &Logger) -> Result<RecoveryReport, StorageError> {
+    let clog = persistent_storage.clog();
+    let block_storage = BlockStorage::new(persistent_storage);
+    let mut system_storage = SystemStorage::new(persistent_storage.kv());
+    let mut report = RecoveryReport::default();
+
+    report.compaction_finished = block_storage.recover_compaction(&clog)?;
+    if report.compaction_finished {
+        info!(log, "Interrupted commit log compaction was finished");
+        system_storage.set_block_storage_verified_offset(0)?;
+    }
+
+    let verified_offset = system_storage.get_block_storage_verified_offset()?.unwrap_or(0);
+    let next_offset = clog.next_offset(BlockStorage::name())?;
+    // indexes can reference records, which were lost by a crash (commit log is shorter than at the last verification)
+    let referenced_offset = block_storage.next_referenced_offset()?;
+    if verified_offset >= next_offset && referenced_offset <= next_offset {
+        // clean shutdown, nothing was appended since the last verification
+        return Ok(report);
+    }
+
+    let from_offset = min(verified_offset, next_offset);
+    info!(log, "Verifying block storage commit log"; "from_offset" => from_offset, "next_offset" => next_offset, "referenced_offset" => referenced_offset);
+    let verification = block_storage.verify_locations(from_offset)?;
+    report.checked_records = verification.checked();
+    report.removed_blocks = verification.removed_blocks();
+    report.cleared_records = verification.cleared_records();
+    report.truncated_records = clog.truncate(BlockStorage::name(), verification.next_offset())?;
+    system_storage.set_block_storage_verified_offset(clog.next_offset(BlockStorage::name())?)?;
+
+    if report.removed_blocks > 0 || report.cleared_records > 0 || report.truncated_records > 0 {
+        warn!(log, "Block storage commit log was recovered";
+                   "removed_blocks" => report.removed_blocks,
+                   "cleared_records" => report.cleared_records,
+                   "truncated_records" => report.truncated_records);
+    }
+    if report.removed_blocks > 0 || report.cleared_records > 0 {
+        // blocks with removed data have to be applied again
+        let fsck_report = check_storage(persistent_storage, true, log)?;
+        info!(log, "Storage repaired after commit log recovery"; "issues" => fsck_report.issues().len());
+    }
+
+    Ok(report)
+}
+
+/// Remove records not referenced by block storage indexes from the commit log
+pub fn compact_commit_log(persistent_storage: &PersistentStorage, log: &Logger) -> Result<CompactionReport, StorageError> {
+    let clog = persistent_storage.clog();
+    let block_storage = BlockStorage::new(persistent_storage);
+
+    let size_before = clog.size(BlockStorage::name())?;
+    info!(log, "Compacting block storage commit log"; "size" => size_before);
+    let records = block_storage.compact(&clog)?;
+    SystemStorage::new(persistent_storage.kv()).set_block_storage_verified_offset(clog.next_offset(BlockStorage::name())?)?;
+    let size_after = clog.size(BlockStorage::name())?;
+    info!(log, "Block storage commit log compacted"; "records" => records, "size_before" => size_before, "size_after" => size_after);
+
+    Ok(CompactionReport { records, size_before, size_after })
+}
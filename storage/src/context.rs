@@ -209,7 +209,8 @@ impl ContextApi for TezedgeContext {
     fn commit(&mut self, block_hash: &BlockHash, parent_context_hash: &Option<ContextHash>, new_context_hash: &ContextHash, context_diff: &ContextDiff) -> Result<(), ContextError> {
         ensure_eq_context_hash!(parent_context_hash, &context_diff);
 
-        // add to context
+        // add to context, lock is held until the context hash is assigned,
+        // so context garbage collection (under the read lock) sees either both or none of them
         let mut writer = self.storage.write().expect("lock poisoning");
         // TODO: push to correct index by context_hash found by block_hash
         writer.push(&context_diff.diff)?;
@@ -240,6 +241,7 @@ impl ContextApi for TezedgeContext {
                 )
             };
         }
+        drop(writer);
 
         Ok(())
     }
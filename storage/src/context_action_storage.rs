@@ -1,6 +1,7 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::mem;
 use std::ops::Range;
@@ -11,38 +12,53 @@ use failure::Fail;
 use rocksdb::{ColumnFamilyDescriptor, SliceTransform};
 use serde::{Deserialize, Serialize};
 
+use crypto::blake2b;
 use crypto::hash::{BlockHash, HashType};
 use tezos_context::channel::ContextAction;
 use tezos_messages::base::signature_public_key_hash::{ConversionError, SignaturePublicKeyHash};
 
 use crate::num_from_slice;
-use crate::persistent::{BincodeEncoded, Decoder, default_table_options, Encoder, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError};
+use crate::persistent::{BincodeEncoded, DBError, DbTuning, Decoder, default_table_options, Encoder, KeyValueColumn, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError, WriteBatch};
 use crate::persistent::codec::{range_from_idx_len, vec_from_slice};
-use crate::persistent::sequence::{SequenceGenerator, SequenceNumber};
+use crate::persistent::secondary_index::{Index, IndexedStore, SecondaryIndex};
+use crate::persistent::sequence::{SequenceError, SequenceGenerator, SequenceNumber};
 use crate::StorageError;
 
 pub enum ContextHashType {
     Block,
     Contract,
+    Operation,
 }
 
 pub struct ContextActionFilters {
     pub hash: (ContextHashType, Vec<u8>),
     pub action_type: Option<Vec<ContextActionType>>,
+    /// Action key (or one of the keys of the copy action) has to start with these path segments
+    pub key_prefix: Option<Vec<String>>,
+    /// Action has to start in the wall-clock range `[from, to)` (unix time in seconds), same as in
+    /// [ContextActionStorage::get_by_time_range]
+    pub time_range: Option<(f64, f64)>,
 }
 
 impl ContextActionFilters {
     pub fn with_block_hash(block_hash: Vec<u8>) -> Self {
-        Self {
-            hash: (ContextHashType::Block, block_hash),
-            action_type: None,
-        }
+        Self::with_hash(ContextHashType::Block, block_hash)
     }
 
     pub fn with_contract_id(contract_hash: Vec<u8>) -> Self {
+        Self::with_hash(ContextHashType::Contract, contract_hash)
+    }
+
+    pub fn with_operation_hash(operation_hash: Vec<u8>) -> Self {
+        Self::with_hash(ContextHashType::Operation, operation_hash)
+    }
+
+    fn with_hash(hash_type: ContextHashType, hash: Vec<u8>) -> Self {
         Self {
-            hash: (ContextHashType::Contract, contract_hash),
+            hash: (hash_type, hash),
             action_type: None,
+            key_prefix: None,
+            time_range: None,
         }
     }
 
@@ -51,8 +67,48 @@ impl ContextActionFilters {
         self
     }
 
+    pub fn with_key_prefix(mut self, key_prefix: Vec<String>) -> Self {
+        self.key_prefix = Some(key_prefix);
+        self
+    }
+
+    pub fn with_time_range(mut self, from: f64, to: f64) -> Self {
+        self.time_range = Some((from, to));
+        self
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.action_type.is_none()
+        self.action_type.is_none() && !self.has_action_filters()
+    }
+
+    /// Filters checked on the loaded actions: time range is not indexed and key prefix is indexed only
+    /// up to [ContextActionByKeyPrefixIndex::MAX_DEPTH] path segments
+    fn has_action_filters(&self) -> bool {
+        self.time_range.is_some()
+            || self.key_prefix.as_ref().map_or(false, |key_prefix| key_prefix.is_empty() || key_prefix.len() > ContextActionByKeyPrefixIndex::MAX_DEPTH)
+    }
+
+    fn matches(&self, action: &ContextAction) -> bool {
+        if let Some(key_prefix) = &self.key_prefix {
+            if !action_keys(action).iter().any(|key| key.starts_with(key_prefix)) {
+                return false;
+            }
+        }
+        if let Some((from, to)) = self.time_range {
+            match action_time(action) {
+                Some((start_time, _)) if start_time >= from && start_time < to => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Actions get ids in the order they were executed, so no action after this one can start in the time range
+    fn is_past_time_range(&self, action: &ContextAction) -> bool {
+        match (self.time_range, action_time(action)) {
+            (Some((_, to)), Some((start_time, _))) => start_time >= to,
+            _ => false,
+        }
     }
 }
 
@@ -64,7 +120,12 @@ pub struct ContextActionStorage {
     context_by_block_index: ContextActionByBlockHashIndex,
     context_by_contract_index: ContextActionByContractIndex,
     context_by_type_index: ContextActionByTypeIndex,
+    context_by_operation_index: Index<ContextActionByOperationIndex>,
+    context_by_time_index: Index<ContextActionByTimeIndex>,
+    context_by_key_prefix_index: Index<ContextActionByKeyPrefixIndex>,
     kv: Arc<ContextActionStorageKV>,
+    /// Actions with contract, type, operation, time and key prefix indexes, which are derived from the action itself
+    indexed: IndexedStore<ContextActionStorage>,
     generator: Arc<SequenceGenerator>,
 }
 
@@ -76,6 +137,15 @@ impl ContextActionStorage {
             context_by_block_index: ContextActionByBlockHashIndex::new(persistent_storage.kv()),
             context_by_contract_index: ContextActionByContractIndex::new(persistent_storage.kv()),
             context_by_type_index: ContextActionByTypeIndex::new(persistent_storage.kv()),
+            context_by_operation_index: Index::new(persistent_storage.kv()),
+            context_by_time_index: Index::new(persistent_storage.kv()),
+            context_by_key_prefix_index: Index::new(persistent_storage.kv()),
+            indexed: IndexedStore::new(persistent_storage.kv())
+                .with_index(Index::<ContextActionByContractIndex>::new(persistent_storage.kv()))
+                .with_index(Index::<ContextActionByTypeIndex>::new(persistent_storage.kv()))
+                .with_index(Index::<ContextActionByOperationIndex>::new(persistent_storage.kv()))
+                .with_index(Index::<ContextActionByTimeIndex>::new(persistent_storage.kv()))
+                .with_index(Index::<ContextActionByKeyPrefixIndex>::new(persistent_storage.kv())),
         }
     }
 
@@ -83,55 +153,75 @@ impl ContextActionStorage {
     pub fn put_action(&mut self, block_hash: &BlockHash, action: ContextAction) -> Result<(), StorageError> {
         // generate ID
         let id = self.generator.next()?;
-        let action = ContextActionRecordValue::new(action, id);
-        // Store action
-        self.kv.put(&id, &action)?;
-        // Populate indexes
-        self.context_by_block_index.put(&ContextActionByBlockHashKey::new(block_hash, id))?;
-
-        if let Some(action_type) = ContextActionType::extract_type(action.action()) {
-            self.context_by_type_index.put(&ContextActionByTypeIndexKey::new(action_type, id))?;
+        if self.generator.is_audited() && self.kv.contains(&id)? {
+            // overwriting the action would leave stale index entries, which break cursor paging
+            return Err(SequenceError::IdReused { name: self.generator.name().to_owned(), id }.into());
         }
-
-        extract_contract_addresses(&action).iter()
-            .map(|contract_address| self.context_by_contract_index.put(&ContextActionByContractIndexKey::new(contract_address, id)))
-            .collect::<Result<(), _>>()
+        let action = ContextActionRecordValue::new(action, id);
+        // Store action together with all index entries
+        let mut batch = WriteBatch::new();
+        self.indexed.insert_to_batch(&mut batch, &id, &action)?;
+        batch.put::<ContextActionByBlockHashIndex>(&ContextActionByBlockHashKey::new(block_hash, id), &())?;
+        self.indexed.write_batch(batch)
+            .map_err(StorageError::from)
     }
 
     #[inline]
     pub fn load_cursor(&self, cursor_id: Option<SequenceNumber>, limit: Option<usize>, cursor_filters: ContextActionFilters) -> Result<Vec<ContextActionRecordValue>, StorageError> {
-        let (addr_type, hash) = cursor_filters.hash;
-        if let ContextHashType::Block = addr_type {
-            let base_iterator = self.context_by_block_index.get_by_block_hash_iterator(&hash, cursor_id)?;
-            if let Some(action_type) = cursor_filters.action_type {
-                let type_iterator = self.context_by_type_index.get_by_action_types_iterator(&action_type, None)?;
-                let iterators: Vec<Box<dyn Iterator<Item=SequenceNumber>>> = vec![Box::new(base_iterator), Box::new(type_iterator)];
-                self.load_indexes(sorted_intersect::sorted_intersect(iterators, limit.unwrap_or(std::usize::MAX)).into_iter())
-            } else {
-                if let Some(limit) = limit {
-                    self.load_indexes(base_iterator.take(limit))
-                } else {
-                    self.load_indexes(base_iterator)
-                }
-            }
-        } else {
-            let mut base_iterator = self.context_by_contract_index.get_by_contract_address_iterator(&hash, cursor_id)?.peekable();
-            if let Some(action_type) = cursor_filters.action_type {
-                if let Some(index) = base_iterator.peek() {
-                    let type_iterator = self.context_by_type_index.get_by_action_types_iterator(&action_type, Some(*index))?;
-                    let iterators: Vec<Box<dyn Iterator<Item=SequenceNumber>>> = vec![Box::new(base_iterator), Box::new(type_iterator)];
-                    self.load_indexes(sorted_intersect::sorted_intersect(iterators, limit.unwrap_or(std::usize::MAX)).into_iter())
-                } else {
-                    Ok(Default::default())
-                }
-            } else {
-                if let Some(limit) = limit {
-                    self.load_indexes(base_iterator.take(limit))
-                } else {
-                    self.load_indexes(base_iterator)
+        let (addr_type, hash) = &cursor_filters.hash;
+        // actions get ids in the order they were executed, so skip the ones started before the time range
+        let cursor_id = match cursor_filters.time_range {
+            Some((from, _)) => match self.context_by_time_index.range(&ContextActionByTimeIndexKey::new(from, 0), &ContextActionByTimeIndexKey::new(std::f64::MAX, 0))?.next() {
+                Some(first_id) => {
+                    let first_id = first_id?;
+                    Some(cursor_id.map_or(first_id, |cursor_id| cursor_id.max(first_id)))
                 }
+                None => return Ok(Vec::new()),
+            },
+            None => cursor_id,
+        };
+        // the first index entry, which cannot be decoded, stops the index iteration and its error is returned
+        let index_error = RefCell::new(None);
+        let until_error = |id: Result<SequenceNumber, DBError>| match id {
+            Ok(id) => Some(id),
+            Err(e) => {
+                index_error.borrow_mut().get_or_insert(e);
+                None
             }
+        };
+        let check_index_error = |actions: Vec<ContextActionRecordValue>| match index_error.borrow_mut().take() {
+            Some(e) => Err(StorageError::from(e)),
+            None => Ok(actions),
+        };
+
+        let base_iterator: Box<dyn Iterator<Item=SequenceNumber>> = match addr_type {
+            ContextHashType::Block => Box::new(self.context_by_block_index.get_by_block_hash_iterator(hash, cursor_id)?),
+            ContextHashType::Contract => Box::new(self.context_by_contract_index.get_by_contract_address_iterator(hash, cursor_id)?),
+            ContextHashType::Operation => Box::new(self.context_by_operation_index.prefix(&ContextActionByOperationIndexKey::new(hash, cursor_id.unwrap_or(0)))?
+                .scan((), |_, id| until_error(id))),
+        };
+        let limit = limit.unwrap_or(std::usize::MAX);
+
+        if cursor_filters.action_type.is_none() && cursor_filters.key_prefix.is_none() {
+            return check_index_error(self.load_filtered(base_iterator, &cursor_filters, limit));
         }
+
+        let mut base_iterator = base_iterator.peekable();
+        let from_id = match base_iterator.peek() {
+            Some(index) => *index,
+            None => return check_index_error(Default::default()),
+        };
+        let mut iterators: Vec<Box<dyn Iterator<Item=SequenceNumber>>> = vec![Box::new(base_iterator)];
+        if let Some(action_type) = &cursor_filters.action_type {
+            iterators.push(Box::new(self.context_by_type_index.get_by_action_types_iterator(action_type, Some(from_id))?));
+        }
+        if let Some(key_prefix) = cursor_filters.key_prefix.as_ref().filter(|key_prefix| !key_prefix.is_empty()) {
+            iterators.push(Box::new(self.context_by_key_prefix_index.prefix(&ContextActionByKeyPrefixIndexKey::new(key_prefix, from_id))?
+                .scan((), |_, id| until_error(id))));
+        }
+        // intersection is lazy, so only the actions needed to fill the limit after filtering are loaded
+        let ids = sorted_intersect::sorted_intersect(iterators);
+        check_index_error(self.load_filtered(ids, &cursor_filters, limit))
     }
 
     #[inline]
@@ -146,11 +236,80 @@ impl ContextActionStorage {
             .and_then(|idx| self.load_indexes(idx.into_iter()))
     }
 
+    /// Actions started in the wall-clock range `[from, to)` (unix time in seconds), ordered by the start time
+    pub fn get_by_time_range(&self, from: f64, to: f64, limit: usize) -> Result<Vec<ContextActionRecordValue>, StorageError> {
+        let ids = self.context_by_time_index
+            .range(&ContextActionByTimeIndexKey::new(from, 0), &ContextActionByTimeIndexKey::new(to, 0))?
+            .take(limit)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.load_indexes(ids.into_iter())
+    }
+
+    /// Count and total duration of the actions of the block, grouped by the action type
+    pub fn aggregate_by_block_hash(&self, block_hash: &BlockHash) -> Result<Vec<ContextActionTypeStats>, StorageError> {
+        let mut stats: Vec<ContextActionTypeStats> = vec![];
+        for id in self.context_by_block_index.get_by_block_hash_iterator(block_hash, None)? {
+            let action = match self.kv.get(&id)? {
+                Some(value) => value.into_action(),
+                None => continue,
+            };
+            let action_type = match ContextActionType::extract_type(&action) {
+                Some(action_type) => action_type,
+                None => continue,
+            };
+            let duration = action_time(&action).map_or(0.0, |(start_time, end_time)| end_time - start_time);
+            match stats.iter_mut().find(|stats| stats.action_type == action_type) {
+                Some(stats) => {
+                    stats.count += 1;
+                    stats.total_duration += duration;
+                }
+                None => stats.push(ContextActionTypeStats { action_type, count: 1, total_duration: duration }),
+            }
+        }
+        stats.sort_by_key(|stats| stats.action_type as u16);
+        Ok(stats)
+    }
+
+    /// Remove all actions stored for the block together with their index entries.
+    /// Returns number of removed actions.
+    #[inline]
+    pub fn delete_by_block_hash(&mut self, block_hash: &BlockHash) -> Result<usize, StorageError> {
+        let ids = self.context_by_block_index.get_by_block_hash(block_hash)?;
+        let mut batch = WriteBatch::new();
+        for id in &ids {
+            self.indexed.delete_to_batch(&mut batch, id)?;
+            batch.delete::<ContextActionByBlockHashIndex>(&ContextActionByBlockHashKey::new(block_hash, *id))?;
+        }
+        self.indexed.write_batch(batch)?;
+
+        Ok(ids.len())
+    }
+
+    /// Create contract, type, operation, time and key prefix indexes again from the stored actions.
+    /// Returns number of index entries.
+    pub fn rebuild_indexes(&self) -> Result<usize, StorageError> {
+        let contract_entries = Index::<ContextActionByContractIndex>::new(self.context_by_contract_index.kv.clone()).rebuild(self.kv.as_ref())?;
+        let type_entries = Index::<ContextActionByTypeIndex>::new(self.context_by_type_index.kv.clone()).rebuild(self.kv.as_ref())?;
+        let operation_entries = self.context_by_operation_index.rebuild(self.kv.as_ref())?;
+        let time_entries = self.context_by_time_index.rebuild(self.kv.as_ref())?;
+        let key_prefix_entries = self.context_by_key_prefix_index.rebuild(self.kv.as_ref())?;
+        Ok(contract_entries + type_entries + operation_entries + time_entries + key_prefix_entries)
+    }
+
     fn load_indexes<'a, Idx: Iterator<Item=u64> + 'a>(&'a self, indexes: Idx) -> Result<Vec<ContextActionRecordValue>, StorageError> {
         Ok(indexes.filter_map(|id| {
             self.kv.get(&id).ok().flatten()
         }).collect())
     }
+
+    fn load_filtered<'a, Idx: Iterator<Item=u64> + 'a>(&'a self, indexes: Idx, filters: &ContextActionFilters, limit: usize) -> Vec<ContextActionRecordValue> {
+        indexes
+            .filter_map(|id| self.kv.get(&id).ok().flatten())
+            .take_while(|value| !filters.is_past_time_range(value.action()))
+            .filter(|value| filters.matches(value.action()))
+            .take(limit)
+            .collect()
+    }
 }
 
 impl KeyValueSchema for ContextActionStorage {
@@ -205,6 +364,61 @@ impl From<ContextActionRecordValue> for ContextActionJson {
     }
 }
 
+/// Aggregated actions of one type
+#[derive(Serialize, Debug, Clone)]
+pub struct ContextActionTypeStats {
+    pub action_type: ContextActionType,
+    pub count: u64,
+    /// Sum of the action durations in seconds
+    pub total_duration: f64,
+}
+
+/// Keys accessed by the action
+fn action_keys(action: &ContextAction) -> Vec<&Vec<String>> {
+    match action {
+        ContextAction::Set { key, .. }
+        | ContextAction::Delete { key, .. }
+        | ContextAction::RemoveRecursively { key, .. }
+        | ContextAction::Mem { key, .. }
+        | ContextAction::DirMem { key, .. }
+        | ContextAction::Get { key, .. }
+        | ContextAction::Fold { key, .. } => vec![key],
+        ContextAction::Copy { from_key, to_key, .. } => vec![from_key, to_key],
+        _ => vec![]
+    }
+}
+
+/// Wall-clock start and end time of the action
+fn action_time(action: &ContextAction) -> Option<(f64, f64)> {
+    match action {
+        ContextAction::Set { start_time, end_time, .. }
+        | ContextAction::Delete { start_time, end_time, .. }
+        | ContextAction::RemoveRecursively { start_time, end_time, .. }
+        | ContextAction::Copy { start_time, end_time, .. }
+        | ContextAction::Checkout { start_time, end_time, .. }
+        | ContextAction::Commit { start_time, end_time, .. }
+        | ContextAction::Mem { start_time, end_time, .. }
+        | ContextAction::DirMem { start_time, end_time, .. }
+        | ContextAction::Get { start_time, end_time, .. }
+        | ContextAction::Fold { start_time, end_time, .. } => Some((*start_time, *end_time)),
+        ContextAction::Shutdown => None,
+    }
+}
+
+fn extract_operation_hash(action: &ContextAction) -> Option<&Vec<u8>> {
+    match action {
+        ContextAction::Set { operation_hash, .. }
+        | ContextAction::Delete { operation_hash, .. }
+        | ContextAction::RemoveRecursively { operation_hash, .. }
+        | ContextAction::Copy { operation_hash, .. }
+        | ContextAction::Mem { operation_hash, .. }
+        | ContextAction::DirMem { operation_hash, .. }
+        | ContextAction::Get { operation_hash, .. }
+        | ContextAction::Fold { operation_hash, .. } => operation_hash.as_ref(),
+        _ => None
+    }
+}
+
 fn extract_contract_addresses(value: &ContextActionRecordValue) -> Vec<ContractAddress> {
     let contract_addresses = match &value.action {
         ContextAction::Set { key, .. }
@@ -278,12 +492,6 @@ impl ContextActionByBlockHashIndex {
         Self { kv }
     }
 
-    #[inline]
-    fn put(&mut self, key: &ContextActionByBlockHashKey) -> Result<(), StorageError> {
-        self.kv.put(key, &())
-            .map_err(StorageError::from)
-    }
-
     #[inline]
     fn get_by_block_hash(&self, block_hash: &BlockHash) -> Result<Vec<SequenceNumber>, StorageError> {
         Ok(self.get_by_block_hash_iterator(block_hash, None)?.collect())
@@ -304,13 +512,17 @@ impl KeyValueSchema for ContextActionByBlockHashIndex {
     type Key = ContextActionByBlockHashKey;
     type Value = ();
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        let mut cf_opts = default_table_options();
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
         cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(ContextActionByBlockHashKey::LEN_BLOCK_HASH));
         cf_opts.set_memtable_prefix_bloom_ratio(0.2);
         ColumnFamilyDescriptor::new(Self::name(), cf_opts)
     }
 
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(ContextActionByBlockHashKey::LEN_BLOCK_HASH)
+    }
+
     fn name() -> &'static str {
         "context_action_block_hash_index"
     }
@@ -396,11 +608,6 @@ impl ContextActionByContractIndex {
         Self { kv }
     }
 
-    #[inline]
-    fn put(&mut self, key: &ContextActionByContractIndexKey) -> Result<(), StorageError> {
-        self.kv.put(key, &()).map_err(StorageError::from)
-    }
-
     #[inline]
     fn get_by_contract_address(&self, contract_address: &ContractAddress, from_id: Option<SequenceNumber>, limit: usize) -> Result<Vec<SequenceNumber>, StorageError> {
         Ok(self.get_by_contract_address_iterator(contract_address, from_id)?.take(limit).collect())
@@ -420,19 +627,37 @@ impl KeyValueSchema for ContextActionByContractIndex {
     type Key = ContextActionByContractIndexKey;
     type Value = ();
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        let mut cf_opts = default_table_options();
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
         cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(ContextActionByContractIndexKey::LEN_CONTRACT_ADDRESS));
         cf_opts.set_memtable_prefix_bloom_ratio(0.2);
         // cf_opts.set_comparator("reverse_id", ContextActionByContractIndexKey::reverse_id_comparator);
         ColumnFamilyDescriptor::new(Self::name(), cf_opts)
     }
 
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(ContextActionByContractIndexKey::LEN_CONTRACT_ADDRESS)
+    }
+
     fn name() -> &'static str {
         "context_by_contract_storage"
     }
 }
 
+impl SecondaryIndex for ContextActionByContractIndex {
+    type Primary = ContextActionStorage;
+
+    fn index_keys(id: &SequenceNumber, action: &ContextActionRecordValue) -> Vec<Self::Key> {
+        extract_contract_addresses(action).iter()
+            .map(|contract_address| ContextActionByContractIndexKey::new(contract_address, *id))
+            .collect()
+    }
+
+    fn primary_key(index_key: &Self::Key) -> SequenceNumber {
+        index_key.id
+    }
+}
+
 /// Key for a specific action stored in a database.
 #[derive(PartialEq, Debug)]
 pub struct ContextActionByContractIndexKey {
@@ -564,6 +789,291 @@ pub fn contract_id_to_contract_address_for_index(contract_id: &str) -> Result<Co
     Ok(contract_address)
 }
 
+/// Index data as `operation_hash -> location`.
+///
+/// Index is composed from:
+/// * operation hash
+/// * auto increment ID
+///
+/// This allows for fast search of context actions executed by an operation.
+pub struct ContextActionByOperationIndex;
+
+impl KeyValueSchema for ContextActionByOperationIndex {
+    type Key = ContextActionByOperationIndexKey;
+    type Value = ();
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(ContextActionByOperationIndexKey::LEN_OPERATION_HASH));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(ContextActionByOperationIndexKey::LEN_OPERATION_HASH)
+    }
+
+    fn name() -> &'static str {
+        "context_by_operation_storage"
+    }
+}
+
+impl SecondaryIndex for ContextActionByOperationIndex {
+    type Primary = ContextActionStorage;
+
+    fn index_keys(id: &SequenceNumber, action: &ContextActionRecordValue) -> Vec<Self::Key> {
+        extract_operation_hash(action.action()).into_iter()
+            .filter(|operation_hash| operation_hash.len() == ContextActionByOperationIndexKey::LEN_OPERATION_HASH)
+            .map(|operation_hash| ContextActionByOperationIndexKey::new(operation_hash, *id))
+            .collect()
+    }
+
+    fn primary_key(index_key: &Self::Key) -> SequenceNumber {
+        index_key.id
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ContextActionByOperationIndexKey {
+    operation_hash: Vec<u8>,
+    id: SequenceNumber,
+}
+
+impl ContextActionByOperationIndexKey {
+    const LEN_OPERATION_HASH: usize = HashType::OperationHash.size();
+    const LEN_ID: usize = mem::size_of::<SequenceNumber>();
+    const LEN_TOTAL: usize = Self::LEN_OPERATION_HASH + Self::LEN_ID;
+
+    const IDX_OPERATION_HASH: usize = 0;
+    const IDX_ID: usize = Self::IDX_OPERATION_HASH + Self::LEN_OPERATION_HASH;
+
+    pub fn new(operation_hash: &[u8], id: SequenceNumber) -> Self {
+        Self {
+            operation_hash: operation_hash.to_vec(),
+            id,
+        }
+    }
+}
+
+/// Decoder for `ContextActionByOperationIndexKey`
+///
+/// * bytes layout `[operation_hash(32)][id(8)]`
+impl Decoder for ContextActionByOperationIndexKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if Self::LEN_TOTAL == bytes.len() {
+            let operation_hash = vec_from_slice(bytes, Self::IDX_OPERATION_HASH, Self::LEN_OPERATION_HASH);
+            let id = num_from_slice!(bytes, Self::IDX_ID, SequenceNumber);
+            Ok(Self { operation_hash, id })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
+/// Encoder for `ContextActionByOperationIndexKey`
+///
+/// * bytes layout `[operation_hash(32)][id(8)]`
+impl Encoder for ContextActionByOperationIndexKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        if self.operation_hash.len() != Self::LEN_OPERATION_HASH {
+            return Err(SchemaError::EncodeError);
+        }
+        let mut result = Vec::with_capacity(Self::LEN_TOTAL);
+        result.extend(&self.operation_hash);
+        result.extend(&self.id.to_be_bytes());
+        Ok(result)
+    }
+}
+
+/// Index data as `start_time -> location`.
+///
+/// Index is composed from:
+/// * wall-clock start time of the action in microseconds
+/// * auto increment ID
+///
+/// This allows for range queries of context actions executed in a time window.
+pub struct ContextActionByTimeIndex;
+
+impl KeyValueSchema for ContextActionByTimeIndex {
+    type Key = ContextActionByTimeIndexKey;
+    type Value = ();
+
+    fn name() -> &'static str {
+        "context_by_time_storage"
+    }
+}
+
+impl SecondaryIndex for ContextActionByTimeIndex {
+    type Primary = ContextActionStorage;
+
+    fn index_keys(id: &SequenceNumber, action: &ContextActionRecordValue) -> Vec<Self::Key> {
+        action_time(action.action()).into_iter()
+            .map(|(start_time, _)| ContextActionByTimeIndexKey::new(start_time, *id))
+            .collect()
+    }
+
+    fn primary_key(index_key: &Self::Key) -> SequenceNumber {
+        index_key.id
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ContextActionByTimeIndexKey {
+    start_time: u64,
+    id: SequenceNumber,
+}
+
+impl ContextActionByTimeIndexKey {
+    const LEN_START_TIME: usize = mem::size_of::<u64>();
+    const LEN_ID: usize = mem::size_of::<SequenceNumber>();
+    const LEN_TOTAL: usize = Self::LEN_START_TIME + Self::LEN_ID;
+
+    const IDX_START_TIME: usize = 0;
+    const IDX_ID: usize = Self::IDX_START_TIME + Self::LEN_START_TIME;
+
+    /// # Arguments
+    /// * `start_time` - unix time in seconds, negative times are stored as zero
+    pub fn new(start_time: f64, id: SequenceNumber) -> Self {
+        Self {
+            start_time: (start_time.max(0.0) * 1_000_000.0) as u64,
+            id,
+        }
+    }
+}
+
+/// Decoder for `ContextActionByTimeIndexKey`
+///
+/// * bytes layout `[start_time(8)][id(8)]`
+impl Decoder for ContextActionByTimeIndexKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if Self::LEN_TOTAL == bytes.len() {
+            let start_time = num_from_slice!(bytes, Self::IDX_START_TIME, u64);
+            let id = num_from_slice!(bytes, Self::IDX_ID, SequenceNumber);
+            Ok(Self { start_time, id })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
+/// Encoder for `ContextActionByTimeIndexKey`
+///
+/// * bytes layout `[start_time(8)][id(8)]`
+impl Encoder for ContextActionByTimeIndexKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut result = Vec::with_capacity(Self::LEN_TOTAL);
+        result.extend(&self.start_time.to_be_bytes());
+        result.extend(&self.id.to_be_bytes());
+        Ok(result)
+    }
+}
+
+/// Index data as `key_prefix -> location`.
+///
+/// Index is composed from:
+/// * digest of the first path segments of the action key
+/// * auto increment ID
+///
+/// Every prefix of the action key (of both keys of the copy action) up to [ContextActionByKeyPrefixIndex::MAX_DEPTH]
+/// segments is indexed, so actions accessing a subtree of the context (e.g. `data/contracts/index`) are found
+/// without loading all actions of the block. Longer prefixes are looked up by the indexed part and filtered after load.
+pub struct ContextActionByKeyPrefixIndex;
+
+impl ContextActionByKeyPrefixIndex {
+    /// Maximal number of the path segments of the indexed key prefix
+    pub const MAX_DEPTH: usize = 4;
+}
+
+impl KeyValueSchema for ContextActionByKeyPrefixIndex {
+    type Key = ContextActionByKeyPrefixIndexKey;
+    type Value = ();
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(ContextActionByKeyPrefixIndexKey::LEN_KEY_PREFIX_HASH));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(ContextActionByKeyPrefixIndexKey::LEN_KEY_PREFIX_HASH)
+    }
+
+    fn name() -> &'static str {
+        "context_by_key_prefix_storage"
+    }
+}
+
+impl SecondaryIndex for ContextActionByKeyPrefixIndex {
+    type Primary = ContextActionStorage;
+
+    fn index_keys(id: &SequenceNumber, action: &ContextActionRecordValue) -> Vec<Self::Key> {
+        let mut keys: Vec<Self::Key> = action_keys(action.action()).into_iter()
+            .flat_map(|key| (1..=key.len().min(Self::MAX_DEPTH)).map(move |depth| ContextActionByKeyPrefixIndexKey::new(&key[..depth], *id)))
+            .collect();
+        // both keys of the copy action usually share the first segments
+        keys.sort_by(|a, b| a.key_prefix_hash.cmp(&b.key_prefix_hash));
+        keys.dedup();
+        keys
+    }
+
+    fn primary_key(index_key: &Self::Key) -> SequenceNumber {
+        index_key.id
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct ContextActionByKeyPrefixIndexKey {
+    key_prefix_hash: Vec<u8>,
+    id: SequenceNumber,
+}
+
+impl ContextActionByKeyPrefixIndexKey {
+    const LEN_KEY_PREFIX_HASH: usize = 16;
+    const LEN_ID: usize = mem::size_of::<SequenceNumber>();
+    const LEN_TOTAL: usize = Self::LEN_KEY_PREFIX_HASH + Self::LEN_ID;
+
+    const IDX_KEY_PREFIX_HASH: usize = 0;
+    const IDX_ID: usize = Self::IDX_KEY_PREFIX_HASH + Self::LEN_KEY_PREFIX_HASH;
+
+    /// # Arguments
+    /// * `key_prefix` - path segments of the key, only first [ContextActionByKeyPrefixIndex::MAX_DEPTH] segments are used
+    pub fn new(key_prefix: &[String], id: SequenceNumber) -> Self {
+        let depth = key_prefix.len().min(ContextActionByKeyPrefixIndex::MAX_DEPTH);
+        Self {
+            key_prefix_hash: blake2b::digest_128(key_prefix[..depth].join("/").as_bytes()),
+            id,
+        }
+    }
+}
+
+/// Decoder for `ContextActionByKeyPrefixIndexKey`
+///
+/// * bytes layout `[key_prefix_hash(16)][id(8)]`
+impl Decoder for ContextActionByKeyPrefixIndexKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if Self::LEN_TOTAL == bytes.len() {
+            let key_prefix_hash = vec_from_slice(bytes, Self::IDX_KEY_PREFIX_HASH, Self::LEN_KEY_PREFIX_HASH);
+            let id = num_from_slice!(bytes, Self::IDX_ID, SequenceNumber);
+            Ok(Self { key_prefix_hash, id })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
+/// Encoder for `ContextActionByKeyPrefixIndexKey`
+///
+/// * bytes layout `[key_prefix_hash(16)][id(8)]`
+impl Encoder for ContextActionByKeyPrefixIndexKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut result = Vec::with_capacity(Self::LEN_TOTAL);
+        result.extend(&self.key_prefix_hash);
+        result.extend(&self.id.to_be_bytes());
+        Ok(result)
+    }
+}
+
 /// Type index
 pub struct ContextActionByTypeIndex {
     kv: Arc<ContextActionByTypeIndexKV>,
@@ -576,11 +1086,6 @@ impl ContextActionByTypeIndex {
         Self { kv }
     }
 
-    #[inline]
-    fn put(&self, key: &ContextActionByTypeIndexKey) -> Result<(), StorageError> {
-        self.kv.put(key, &()).map_err(StorageError::from)
-    }
-
     #[inline]
     fn get_by_action_type_iterator<'a>(&'a self, action_type: ContextActionType, cursor_id: Option<SequenceNumber>) -> Result<impl Iterator<Item=u64> + 'a, StorageError> {
         let iterate_from_key = cursor_id.map_or_else(
@@ -599,7 +1104,8 @@ impl ContextActionByTypeIndex {
         for action_type in action_types {
             ret.push(self.get_by_action_type_iterator(*action_type, cursor_id)?);
         }
-        let cmp = |a: &u64, b: &u64| a > b;
+        // index iterators are ordered by ascending id
+        let cmp = |a: &u64, b: &u64| a < b;
         Ok(kmerge_by(ret.into_iter(), cmp))
     }
 }
@@ -608,19 +1114,37 @@ impl KeyValueSchema for ContextActionByTypeIndex {
     type Key = ContextActionByTypeIndexKey;
     type Value = ();
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        let mut cf_opts = default_table_options();
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
         cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(mem::size_of::<ContextActionType>()));
         cf_opts.set_memtable_prefix_bloom_ratio(0.2);
         // cf_opts.set_comparator("reverse_id", ContextActionByTypeIndexKey::reverse_id_comparator);
         ColumnFamilyDescriptor::new(Self::name(), cf_opts)
     }
 
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(mem::size_of::<ContextActionType>())
+    }
+
     fn name() -> &'static str {
         "context_by_type_storage"
     }
 }
 
+impl SecondaryIndex for ContextActionByTypeIndex {
+    type Primary = ContextActionStorage;
+
+    fn index_keys(id: &SequenceNumber, action: &ContextActionRecordValue) -> Vec<Self::Key> {
+        ContextActionType::extract_type(action.action()).into_iter()
+            .map(|action_type| ContextActionByTypeIndexKey::new(action_type, *id))
+            .collect()
+    }
+
+    fn primary_key(index_key: &Self::Key) -> SequenceNumber {
+        index_key.id
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub struct ContextActionByTypeIndexKey {
     pub action_type: ContextActionType,
@@ -774,94 +1298,65 @@ impl FromStr for ContextActionType {
 }
 
 pub mod sorted_intersect {
-    use std::cmp::Ordering;
-
-    pub fn sorted_intersect<I>(mut iters: Vec<I>, limit: usize) -> Vec<I::Item>
+    /// Lazy intersection of iterators sorted in ascending order
+    pub fn sorted_intersect<I>(iters: Vec<I>) -> SortedIntersect<I>
         where
             I: Iterator,
             I::Item: Ord,
     {
-        let mut ret = Default::default();
-        if iters.is_empty() {
-            return ret;
-        } else if iters.len() == 1 {
-            let iter = iters.iter_mut().next().unwrap();
-            ret.extend(iter.take(limit));
-            return ret;
-        }
-        let mut heap = Vec::with_capacity(iters.len());
-        // Fill the heap with values
-        if !fill_heap(iters.iter_mut(), &mut heap) {
-            // Hit an exhausted iterator, finish
-            return ret;
-        }
-
-        while ret.len() < limit {
-            if is_hit(&heap) {
-                // We hit intersected item
-                if let Some((item, _)) = heap.pop() {
-                    // Push it into the intersect values
-                    ret.push(item);
-                    // Clear the rest of the heap
-                    heap.clear();
-                    // Build a new heap from new values
-                    if !fill_heap(iters.iter_mut(), &mut heap) {
-                        // Hit an exhausted iterator, finish
-                        return ret;
-                    }
-                } else {
-                    // Hit an exhausted iterator, finish
-                    return ret;
-                }
-            } else {
-                // Remove max element from the heap
-                if let Some((_, iter_num)) = heap.pop() {
-                    if let Some(item) = iters[iter_num].next() {
-                        // Insert replacement from the corresponding iterator to heap
-                        heap.push((item, iter_num));
-                        heapify(&mut heap);
-                    } else {
-                        // Hit an exhausted iterator, finish
-                        return ret;
-                    }
-                } else {
-                    // Hit an exhausted iterator, finish
-                    return ret;
-                }
-            }
-        }
-
-        ret
+        SortedIntersect { iters, finished: false }
     }
 
-    fn heapify<Item: Ord>(heap: &mut Vec<(Item, usize)>) {
-        heap.sort_by(|(a, _), (b, _)| b.cmp(a));
+    pub struct SortedIntersect<I> {
+        iters: Vec<I>,
+        finished: bool,
     }
 
-    fn fill_heap<'a, Item: Ord, Inner: 'a + Iterator<Item=Item>, Outer: Iterator<Item=&'a mut Inner>>(iters: Outer, heap: &mut Vec<(Inner::Item, usize)>) -> bool {
-        for (i, iter) in iters.enumerate() {
-            let value = iter.next();
-            if let Some(value) = value {
-                heap.push((value, i))
-            } else {
-                return false;
+    impl<I> Iterator for SortedIntersect<I>
+        where
+            I: Iterator,
+            I::Item: Ord,
+    {
+        type Item = I::Item;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.finished || self.iters.is_empty() {
+                return None;
+            }
+            let mut current = Vec::with_capacity(self.iters.len());
+            for iter in self.iters.iter_mut() {
+                match iter.next() {
+                    Some(item) => current.push(item),
+                    None => return self.finish(),
+                }
+            }
+            loop {
+                let max = (0..current.len()).max_by(|a, b| current[*a].cmp(&current[*b]))?;
+                let mut hit = true;
+                for i in 0..current.len() {
+                    // catch up with the greatest current value
+                    while current[i] < current[max] {
+                        match self.iters[i].next() {
+                            Some(item) => current[i] = item,
+                            None => return self.finish(),
+                        }
+                    }
+                    if current[i] > current[max] {
+                        hit = false;
+                    }
+                }
+                if hit {
+                    return current.pop();
+                }
             }
         }
-        heapify(heap);
-        true
     }
 
-    fn is_hit<Item: Ord>(heap: &Vec<(Item, usize)>) -> bool {
-        let value = heap.iter().next().map(|(value, _)|
-            heap.iter().fold((value, true), |(a, eq), (b, _)| {
-                (b, eq & (a.cmp(b) == Ordering::Equal))
-            })
-        );
-
-        if let Some((_, true)) = value {
-            true
-        } else {
-            false
+    impl<I> SortedIntersect<I> {
+        fn finish<T>(&mut self) -> Option<T> {
+            // an exhausted iterator ends the intersection
+            self.finished = true;
+            None
         }
     }
 }
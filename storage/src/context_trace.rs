@@ -0,0 +1,462 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Context action traces for the deterministic replay of the context storage without the OCaml protocol.
+//!
+//! Trace is a sequence of [TraceRecord]s in the order, in which the context received them: `Checkout` of the predecessor
+//! context, actions of the block and `Commit` of the new context. Header of the block has to precede the commit of its context,
+//! so the trace can be replayed to the empty storage (context is looked up by the block level).
+//!
+//! Trace can be recorded by the `ContextListener` or exported by [export_context_trace] from the actions stored
+//! in the [ContextActionStorage] (these are stored without checkouts and commits, so they are derived from the block headers).
+//!
+//! * binary format: `[magic(8)][record_len(4)][record]...`, where record is bincode encoded [TraceRecord]
+//! * json lines format: one json encoded [TraceRecord] per line
+//!
+//! Recording appends to the existing trace, so the trace continues after the node restart.
+//!
+//! [ContextReplayer] applies the trace to the [TezedgeContext]. Trace has to start at the genesis, or the storage has to contain
+//! contexts of all the preceding blocks.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use getset::CopyGetters;
+use serde::{Deserialize, Serialize};
+use slog::{Logger, warn};
+
+use tezos_context::channel::ContextAction;
+
+use crate::{BlockHeaderWithHash, BlockStorage, BlockStorageReader, ContextActionStorage, StorageError};
+use crate::context::{ContextApi, ContextDiff, ContextError, TezedgeContext};
+use crate::persistent::{BincodeEncoded, Decoder, Encoder, PersistentStorage, SchemaError};
+
+const TRACE_MAGIC: &[u8; 8] = b"TZEDGECT";
+/// Limit of the binary record length, so the corrupted length does not allocate the whole memory
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+
+/// Possible errors for trace export/replay
+#[derive(Debug, Fail)]
+pub enum ContextTraceError {
+    #[fail(display = "Storage error: {}", error)]
+    StorageError {
+        error: StorageError
+    },
+    #[fail(display = "Context error: {}", error)]
+    ContextError {
+        error: ContextError
+    },
+    #[fail(display = "Trace I/O error: {}", error)]
+    IOError {
+        error: io::Error
+    },
+    #[fail(display = "Trace schema error: {}", error)]
+    SchemaError {
+        error: SchemaError
+    },
+    #[fail(display = "Trace json error: {}", error)]
+    JsonError {
+        error: serde_json::Error
+    },
+    #[fail(display = "Block at level {} was not found in storage", level)]
+    MissingBlock {
+        level: i32
+    },
+    #[fail(display = "Trace record length {} exceeds the limit {}", len, MAX_RECORD_LEN)]
+    RecordTooLarge {
+        len: usize
+    },
+    #[fail(display = "Existing trace has format {:?}, but {:?} is expected", found, expected)]
+    FormatMismatch {
+        expected: TraceFormat,
+        found: TraceFormat,
+    },
+}
+
+impl From<StorageError> for ContextTraceError {
+    fn from(error: StorageError) -> Self {
+        ContextTraceError::StorageError { error }
+    }
+}
+
+impl From<ContextError> for ContextTraceError {
+    fn from(error: ContextError) -> Self {
+        ContextTraceError::ContextError { error }
+    }
+}
+
+impl From<io::Error> for ContextTraceError {
+    fn from(error: io::Error) -> Self {
+        ContextTraceError::IOError { error }
+    }
+}
+
+impl From<SchemaError> for ContextTraceError {
+    fn from(error: SchemaError) -> Self {
+        ContextTraceError::SchemaError { error }
+    }
+}
+
+impl From<serde_json::Error> for ContextTraceError {
+    fn from(error: serde_json::Error) -> Self {
+        ContextTraceError::JsonError { error }
+    }
+}
+
+impl slog::Value for ContextTraceError {
+    fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+/// Encoding of the trace file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Binary,
+    JsonLines,
+}
+
+#[derive(Debug, Clone, Fail)]
+#[fail(display = "invalid trace format: {}, expected binary or json", _0)]
+pub struct ParseTraceFormat(String);
+
+impl FromStr for TraceFormat {
+    type Err = ParseTraceFormat;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "binary" => Ok(TraceFormat::Binary),
+            "json" => Ok(TraceFormat::JsonLines),
+            x => Err(ParseTraceFormat(x.to_string()))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TraceRecord {
+    /// Header of the block, whose context is committed later in the trace
+    Block(BlockHeaderWithHash),
+    Action(ContextAction),
+}
+
+impl BincodeEncoded for TraceRecord {}
+
+/// Writes trace records to the underlying writer
+pub struct ContextTraceWriter<W: Write> {
+    writer: W,
+    format: TraceFormat,
+    records: usize,
+}
+
+impl ContextTraceWriter<BufWriter<File>> {
+    /// Create new trace file at `path`, existing file is overwritten
+    pub fn create<P: AsRef<Path>>(path: P, format: TraceFormat) -> Result<Self, ContextTraceError> {
+        Self::new(BufWriter::new(File::create(path)?), format)
+    }
+
+    /// Append to the trace file at `path`, new file is created if it does not exist yet.
+    /// Record partially written before the crash is truncated, so the trace stays readable.
+    pub fn append<P: AsRef<Path>>(path: P, format: TraceFormat) -> Result<Self, ContextTraceError> {
+        let path = path.as_ref();
+        if !path.exists() || path.metadata()?.len() == 0 {
+            return Self::create(path, format);
+        }
+
+        let found = ContextTraceReader::open(path)?.format();
+        if found != format {
+            return Err(ContextTraceError::FormatMismatch { expected: format, found });
+        }
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let complete_len = complete_records_len(&mut file, format)?;
+        file.set_len(complete_len)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self { writer: BufWriter::new(file), format, records: 0 })
+    }
+}
+
+/// Length of the trace file up to the end of the last complete record
+fn complete_records_len(file: &mut File, format: TraceFormat) -> Result<u64, ContextTraceError> {
+    let file_len = file.metadata()?.len();
+    match format {
+        TraceFormat::Binary => {
+            let mut reader = BufReader::new(&mut *file);
+            let mut position = TRACE_MAGIC.len() as u64;
+            reader.seek(SeekFrom::Start(position))?;
+            let mut len_bytes = [0u8; 4];
+            while position + len_bytes.len() as u64 <= file_len {
+                reader.read_exact(&mut len_bytes)?;
+                let record_end = position + len_bytes.len() as u64 + u64::from(u32::from_be_bytes(len_bytes));
+                if record_end > file_len {
+                    break;
+                }
+                reader.seek(SeekFrom::Start(record_end))?;
+                position = record_end;
+            }
+            Ok(position)
+        }
+        TraceFormat::JsonLines => {
+            // find the end of the last line from the end of the file
+            let mut buffer = [0u8; 4096];
+            let mut end = file_len;
+            while end > 0 {
+                let start = end.saturating_sub(buffer.len() as u64);
+                let chunk = &mut buffer[..(end - start) as usize];
+                file.seek(SeekFrom::Start(start))?;
+                file.read_exact(chunk)?;
+                if let Some(newline) = chunk.iter().rposition(|byte| *byte == b'\n') {
+                    return Ok(start + newline as u64 + 1);
+                }
+                end = start;
+            }
+            Ok(0)
+        }
+    }
+}
+
+impl<W: Write> ContextTraceWriter<W> {
+    pub fn new(mut writer: W, format: TraceFormat) -> Result<Self, ContextTraceError> {
+        if format == TraceFormat::Binary {
+            writer.write_all(TRACE_MAGIC)?;
+        }
+        Ok(Self { writer, format, records: 0 })
+    }
+
+    pub fn write(&mut self, record: &TraceRecord) -> Result<(), ContextTraceError> {
+        match self.format {
+            TraceFormat::Binary => {
+                let bytes = Encoder::encode(record)?;
+                self.writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+                self.writer.write_all(&bytes)?;
+            }
+            TraceFormat::JsonLines => {
+                serde_json::to_writer(&mut self.writer, record)?;
+                self.writer.write_all(b"\n")?;
+            }
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Flush the trace and return number of written records
+    pub fn finish(mut self) -> Result<usize, ContextTraceError> {
+        self.writer.flush()?;
+        Ok(self.records)
+    }
+}
+
+/// Reads trace records, format is detected from the beginning of the trace
+pub struct ContextTraceReader<R: BufRead> {
+    reader: R,
+    format: TraceFormat,
+}
+
+impl ContextTraceReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ContextTraceError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> ContextTraceReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, ContextTraceError> {
+        let format = if reader.fill_buf()?.starts_with(TRACE_MAGIC) {
+            reader.consume(TRACE_MAGIC.len());
+            TraceFormat::Binary
+        } else {
+            TraceFormat::JsonLines
+        };
+        Ok(Self { reader, format })
+    }
+
+    #[inline]
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    fn read_record(&mut self) -> Result<Option<TraceRecord>, ContextTraceError> {
+        match self.format {
+            TraceFormat::Binary => {
+                if self.reader.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let mut len_bytes = [0u8; 4];
+                self.reader.read_exact(&mut len_bytes)?;
+                let len = u32::from_be_bytes(len_bytes) as usize;
+                if len > MAX_RECORD_LEN {
+                    return Err(ContextTraceError::RecordTooLarge { len });
+                }
+                let mut bytes = vec![0u8; len];
+                self.reader.read_exact(&mut bytes)?;
+                Ok(Some(<TraceRecord as Decoder>::decode(&bytes)?))
+            }
+            TraceFormat::JsonLines => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if self.reader.read_line(&mut line)? == 0 {
+                        return Ok(None);
+                    }
+                    if !line.trim().is_empty() {
+                        return Ok(Some(serde_json::from_str(&line)?));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for ContextTraceReader<R> {
+    type Item = Result<TraceRecord, ContextTraceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Export actions of the blocks in the level range `[from_level, to_level]` stored in the [ContextActionStorage].
+///
+/// Context actions have to be stored (`--store-context-actions`), otherwise just the checkouts and commits are exported
+/// and a warning is logged. Returns number of exported blocks.
+pub fn export_context_trace<W: Write>(
+    persistent_storage: &PersistentStorage,
+    from_level: i32,
+    to_level: i32,
+    writer: &mut ContextTraceWriter<W>,
+    log: &Logger) -> Result<usize, ContextTraceError> {
+    let block_storage = BlockStorage::new(persistent_storage);
+    let context_action_storage = ContextActionStorage::new(persistent_storage);
+
+    let mut blocks = 0;
+    let mut empty_commits = vec![];
+    for level in from_level..=to_level {
+        let block = block_storage.get_by_block_level(level)?
+            .ok_or(ContextTraceError::MissingBlock { level })?;
+        // genesis is committed to the empty context
+        let predecessor_context_hash = if level > 0 {
+            let predecessor = block_storage.get(block.header.predecessor())?
+                .ok_or(ContextTraceError::MissingBlock { level: level - 1 })?;
+            Some(predecessor.header.context().clone())
+        } else {
+            None
+        };
+
+        writer.write(&TraceRecord::Block(block.clone()))?;
+        if let Some(context_hash) = &predecessor_context_hash {
+            writer.write(&TraceRecord::Action(ContextAction::Checkout { context_hash: context_hash.clone(), start_time: 0.0, end_time: 0.0 }))?;
+        }
+        let actions = context_action_storage.get_by_block_hash(&block.hash)?;
+        if actions.is_empty() {
+            empty_commits.push(level);
+        }
+        for action in actions {
+            writer.write(&TraceRecord::Action(action.into_action()))?;
+        }
+        writer.write(&TraceRecord::Action(ContextAction::Commit {
+            parents: predecessor_context_hash.iter().cloned().collect(),
+            parent_context_hash: predecessor_context_hash,
+            block_hash: Some(block.hash.clone()),
+            new_context_hash: block.header.context().clone(),
+            author: String::new(),
+            message: String::new(),
+            date: block.header.timestamp(),
+            start_time: 0.0,
+            end_time: 0.0,
+        }))?;
+        blocks += 1;
+    }
+
+    if let (Some(first_level), Some(last_level)) = (empty_commits.first(), empty_commits.last()) {
+        warn!(log, "Exported commits without context actions, replay of them does not change the context, check that context actions are stored (--store-context-actions)";
+                   "empty_commits" => empty_commits.len(), "first_level" => first_level, "last_level" => last_level);
+    }
+
+    Ok(blocks)
+}
+
+/// Summary of the replayed trace
+#[derive(Debug, Clone, Default, CopyGetters)]
+pub struct ReplayReport {
+    /// Number of applied context actions, including checkouts and commits
+    #[get_copy = "pub"]
+    actions: usize,
+    /// Number of committed contexts
+    #[get_copy = "pub"]
+    commits: usize,
+    /// Time spent in the context operations, reading of the trace is not included
+    #[get_copy = "pub"]
+    elapsed: Duration,
+}
+
+/// Applies trace records to the [TezedgeContext], the same way as the `ContextListener` applies actions received from the protocol
+pub struct ContextReplayer {
+    block_storage: BlockStorage,
+    context: TezedgeContext,
+    context_diff: ContextDiff,
+    report: ReplayReport,
+}
+
+impl ContextReplayer {
+    pub fn new(persistent_storage: &PersistentStorage) -> Self {
+        let context = TezedgeContext::new(BlockStorage::new(persistent_storage), persistent_storage.context_storage());
+        Self {
+            block_storage: BlockStorage::new(persistent_storage),
+            context_diff: context.init_from_start(),
+            context,
+            report: ReplayReport::default(),
+        }
+    }
+
+    /// Apply all records and return summary of the whole replay
+    pub fn replay<I: IntoIterator<Item=Result<TraceRecord, ContextTraceError>>>(mut self, records: I) -> Result<ReplayReport, ContextTraceError> {
+        for record in records {
+            self.apply(&record?)?;
+        }
+        Ok(self.report)
+    }
+
+    pub fn apply(&mut self, record: &TraceRecord) -> Result<(), ContextTraceError> {
+        let start = Instant::now();
+        match record {
+            TraceRecord::Block(block) => self.block_storage.put_block_header(block)?,
+            TraceRecord::Action(action) => {
+                self.apply_action(action)?;
+                self.report.actions += 1;
+            }
+        }
+        self.report.elapsed += start.elapsed();
+        Ok(())
+    }
+
+    fn apply_action(&mut self, action: &ContextAction) -> Result<(), ContextError> {
+        match action {
+            ContextAction::Set { key, value, context_hash, ignored, .. } =>
+                if !ignored {
+                    self.context_diff.set(context_hash, key, value)?;
+                }
+            ContextAction::Copy { to_key: key, from_key, context_hash, ignored, .. } =>
+                if !ignored {
+                    self.context.copy_to_diff(context_hash, from_key, key, &mut self.context_diff)?;
+                }
+            ContextAction::Delete { key, context_hash, ignored, .. } =>
+                if !ignored {
+                    self.context.delete_to_diff(context_hash, key, &mut self.context_diff)?;
+                }
+            ContextAction::RemoveRecursively { key, context_hash, ignored, .. } =>
+                if !ignored {
+                    self.context.remove_recursively_to_diff(context_hash, key, &mut self.context_diff)?;
+                }
+            ContextAction::Commit { parent_context_hash, new_context_hash, block_hash: Some(block_hash), .. } => {
+                self.context.commit(block_hash, parent_context_hash, new_context_hash, &self.context_diff)?;
+                self.report.commits += 1;
+            }
+            ContextAction::Checkout { context_hash, .. } => {
+                self.context_diff = self.context.checkout(context_hash)?;
+            }
+            _ => (),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Offline storage integrity check.
+//!
+//! Walks all blocks in `BlockMetaStorage` and checks, that:
+//! * block metadata can be decoded,
+//! * successor of the block exists and points back to the block,
+//! * applied block has header, json data, complete operations and its context is stored in the skip list
+//!   (unless it was removed by the history pruning),
+//! * all validation passes marked as downloaded in `OperationsMetaStorage` are present in `OperationsStorage`,
+//! * current head points to an applied block.
+//!
+//! Found issues can be repaired, so the node can continue with bootstrap:
+//! dangling links are removed, blocks with missing data are marked as not applied (to be applied again)
+//! and missing operations are marked as not downloaded (to be requested from peers again).
+//! Check must not run while the node is running.
+
+use std::fmt;
+
+use getset::{CopyGetters, Getters};
+use slog::{info, Logger, warn};
+
+use crypto::hash::{BlockHash, ChainId, ContextHash, HashType};
+use tezos_messages::Head;
+
+use crate::{BlockMetaStorage, BlockStorage, BlockStorageReader, ChainMetaStorage, IteratorMode, OperationKey, OperationsMetaStorage, OperationsStorage, OperationsStorageReader, StorageError, SystemStorage};
+use crate::block_meta_storage::Meta;
+use crate::chain_meta_storage::ChainMetaStorageReader;
+use crate::persistent::{DBError, PersistentStorage};
+use crate::skip_list::SkipList;
+
+/// Storage inconsistency found by [check_storage]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    /// Block metadata cannot be decoded
+    InvalidBlockMeta {
+        block_hash: BlockHash,
+    },
+    /// Block points to the successor, which is not stored or which has different predecessor
+    DanglingSuccessor {
+        block_hash: BlockHash,
+        successor: BlockHash,
+    },
+    /// Block is marked as applied, but its header is not stored
+    AppliedWithoutHeader {
+        block_hash: BlockHash,
+    },
+    /// Block is marked as applied, but result of the application (json data) is not stored
+    AppliedWithoutJsonData {
+        block_hash: BlockHash,
+    },
+    /// Block is marked as applied, but not all its operations were downloaded
+    AppliedWithIncompleteOperations {
+        block_hash: BlockHash,
+    },
+    /// Validation passes are marked as downloaded, but operations are not stored
+    MissingOperations {
+        block_hash: BlockHash,
+        validation_passes: Vec<u8>,
+    },
+    /// Block is marked as applied, but there is no context (skip list level) for it
+    MissingContext {
+        block_hash: BlockHash,
+        context_hash: ContextHash,
+        level: i32,
+    },
+    /// Block is marked as applied, but its context hash is not indexed
+    MissingContextIndex {
+        block_hash: BlockHash,
+        context_hash: ContextHash,
+    },
+    /// Current head is missing or not applied
+    InvalidCurrentHead {
+        block_hash: BlockHash,
+    },
+}
+
+impl Issue {
+    /// Returns `false` for issues, which can be just reported
+    pub fn is_repairable(&self) -> bool {
+        match self {
+            Issue::InvalidBlockMeta { .. } | Issue::AppliedWithIncompleteOperations { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let block = |block_hash: &BlockHash| HashType::BlockHash.bytes_to_string(block_hash);
+        match self {
+            Issue::InvalidBlockMeta { block_hash } =>
+                write!(f, "block {} has invalid metadata", block(block_hash)),
+            Issue::DanglingSuccessor { block_hash, successor } =>
+                write!(f, "block {} has dangling successor {}", block(block_hash), block(successor)),
+            Issue::AppliedWithoutHeader { block_hash } =>
+                write!(f, "block {} is applied, but its header is missing", block(block_hash)),
+            Issue::AppliedWithoutJsonData { block_hash } =>
+                write!(f, "block {} is applied, but its json data are missing", block(block_hash)),
+            Issue::AppliedWithIncompleteOperations { block_hash } =>
+                write!(f, "block {} is applied, but its operations are not complete", block(block_hash)),
+            Issue::MissingOperations { block_hash, validation_passes } =>
+                write!(f, "block {} has missing operations for validation passes {:?}", block(block_hash), validation_passes),
+            Issue::MissingContext { block_hash, context_hash, level } =>
+                write!(f, "block {} is applied, but context {} for level {} is missing", block(block_hash), HashType::ContextHash.bytes_to_string(context_hash), level),
+            Issue::MissingContextIndex { block_hash, context_hash } =>
+                write!(f, "block {} is applied, but context {} is not indexed", block(block_hash), HashType::ContextHash.bytes_to_string(context_hash)),
+            Issue::InvalidCurrentHead { block_hash } =>
+                write!(f, "current head {} is not applied", block(block_hash)),
+        }
+    }
+}
+
+/// Result of the storage check
+#[derive(Debug, Clone, Default, Getters, CopyGetters)]
+pub struct FsckReport {
+    #[get_copy = "pub"]
+    blocks_checked: usize,
+    #[get = "pub"]
+    issues: Vec<Issue>,
+    #[get_copy = "pub"]
+    repaired: usize,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Storages used by the check
+struct Storages {
+    block_storage: BlockStorage,
+    block_meta_storage: BlockMetaStorage,
+    operations_storage: OperationsStorage,
+    operations_meta_storage: OperationsMetaStorage,
+    chain_meta_storage: ChainMetaStorage,
+}
+
+/// Check integrity of the block, operations and context data and optionally repair found issues
+pub fn check_storage(persistent_storage: &PersistentStorage, repair: bool, log: &Logger) -> Result<FsckReport, StorageError> {
+    let storages = Storages {
+        block_storage: BlockStorage::new(persistent_storage),
+        block_meta_storage: BlockMetaStorage::new(persistent_storage),
+        operations_storage: OperationsStorage::new(persistent_storage),
+        operations_meta_storage: OperationsMetaStorage::new(persistent_storage),
+        chain_meta_storage: ChainMetaStorage::new(persistent_storage),
+    };
+    let context_len = {
+        let context = persistent_storage.context_storage();
+        let context = context.read().expect("lock poisoning");
+        context.len()
+    };
+    // context of levels below this level was removed by the history pruning
+    let history_pruned_level = SystemStorage::new(persistent_storage.kv()).get_history_pruned_level()?.unwrap_or(0);
+
+    let mut report = FsckReport::default();
+    for (block_hash, meta) in storages.block_meta_storage.iter(IteratorMode::Start)? {
+        let block_hash = block_hash?;
+        report.blocks_checked += 1;
+        match meta {
+            Ok(meta) => check_block(&storages, &block_hash, &meta, context_len, history_pruned_level, &mut report.issues)?,
+            Err(_) => report.issues.push(Issue::InvalidBlockMeta { block_hash }),
+        }
+    }
+
+    let chain_id = SystemStorage::new(persistent_storage.kv()).get_chain_id()?;
+    if let Some(chain_id) = &chain_id {
+        check_current_head(&storages, chain_id, &mut report.issues)?;
+    }
+
+    for issue in &report.issues {
+        warn!(log, "Storage issue found"; "issue" => issue.to_string());
+    }
+
+    if repair {
+        for issue in &report.issues {
+            if repair_issue(&storages, issue)? {
+                report.repaired += 1;
+            }
+        }
+        // blocks could be marked as not applied, so current head needs to be checked again
+        let invalid_current_head = report.issues.iter().any(|issue| matches!(issue, Issue::InvalidCurrentHead { .. }));
+        if let Some(chain_id) = &chain_id {
+            if (report.repaired > 0 || invalid_current_head) && repair_current_head(&storages, chain_id, log)? && invalid_current_head {
+                report.repaired += 1;
+            }
+        }
+    }
+
+    info!(log, "Storage check finished"; "blocks_checked" => report.blocks_checked, "issues" => report.issues.len(), "repaired" => report.repaired);
+    Ok(report)
+}
+
+fn check_block(storages: &Storages, block_hash: &BlockHash, meta: &Meta, context_len: usize, history_pruned_level: i32, issues: &mut Vec<Issue>) -> Result<(), StorageError> {
+    if let Some(successor) = meta.successor() {
+        let is_linked = match storages.block_meta_storage.get(successor) {
+            Ok(successor_meta) => successor_meta.map_or(false, |successor_meta| successor_meta.predecessor().as_ref() == Some(block_hash)),
+            // invalid metadata of the successor are reported for the successor itself
+            Err(StorageError::DBError { error: DBError::SchemaError { .. } }) => true,
+            Err(error) => return Err(error),
+        };
+        if !is_linked {
+            issues.push(Issue::DanglingSuccessor { block_hash: block_hash.clone(), successor: successor.clone() });
+        }
+    }
+
+    // data of pruned blocks were removed by the history pruning on purpose
+    if meta.is_pruned() {
+        return Ok(());
+    }
+
+    if let Some(operations_meta) = storages.operations_meta_storage.get(block_hash)? {
+        let mut missing = vec![];
+        for validation_pass in 0..operations_meta.validation_passes() {
+            if operations_meta.is_validation_pass_present(validation_pass)
+                && storages.operations_storage.get(&OperationKey::new(block_hash, validation_pass))?.is_none() {
+                missing.push(validation_pass);
+            }
+        }
+        if !missing.is_empty() {
+            issues.push(Issue::MissingOperations { block_hash: block_hash.clone(), validation_passes: missing });
+        }
+    }
+
+    if !meta.is_applied() {
+        return Ok(());
+    }
+
+    let header = match storages.block_storage.get(block_hash)? {
+        Some(header) => header,
+        None => {
+            issues.push(Issue::AppliedWithoutHeader { block_hash: block_hash.clone() });
+            return Ok(());
+        }
+    };
+    if storages.block_storage.get_with_json_data(block_hash)?.is_none() {
+        issues.push(Issue::AppliedWithoutJsonData { block_hash: block_hash.clone() });
+    }
+    if !storages.operations_meta_storage.is_complete(block_hash)? {
+        issues.push(Issue::AppliedWithIncompleteOperations { block_hash: block_hash.clone() });
+    }
+
+    // context of the genesis block is not stored by the context listener
+    let context_hash = header.header.context();
+    if header.header.level() == 0 || header.header.level() < history_pruned_level {
+        return Ok(());
+    }
+    if header.header.level() as usize >= context_len {
+        issues.push(Issue::MissingContext { block_hash: block_hash.clone(), context_hash: context_hash.clone(), level: header.header.level() });
+    } else if storages.block_storage.get_by_context_hash(context_hash)?.is_none() {
+        issues.push(Issue::MissingContextIndex { block_hash: block_hash.clone(), context_hash: context_hash.clone() });
+    }
+
+    Ok(())
+}
+
+fn check_current_head(storages: &Storages, chain_id: &ChainId, issues: &mut Vec<Issue>) -> Result<(), StorageError> {
+    if let Some(head) = storages.chain_meta_storage.get_current_head(chain_id)? {
+        let is_applied = get_valid_meta(storages, &head.hash)?
+            .map_or(false, |meta| meta.is_applied());
+        if !is_applied {
+            issues.push(Issue::InvalidCurrentHead { block_hash: head.hash });
+        }
+    }
+    Ok(())
+}
+
+/// Returns `true`, if issue was repaired
+fn repair_issue(storages: &Storages, issue: &Issue) -> Result<bool, StorageError> {
+    match issue {
+        Issue::DanglingSuccessor { block_hash, .. } => {
+            update_block_meta(storages, block_hash, |meta| meta.set_successor(None))
+        }
+        Issue::AppliedWithoutHeader { block_hash }
+        | Issue::AppliedWithoutJsonData { block_hash }
+        | Issue::MissingContext { block_hash, .. } => {
+            update_block_meta(storages, block_hash, |meta| meta.set_is_applied(false))
+        }
+        Issue::MissingOperations { block_hash, validation_passes } => {
+            match storages.operations_meta_storage.get(block_hash)? {
+                Some(mut operations_meta) => {
+                    validation_passes.iter().for_each(|validation_pass| operations_meta.set_validation_pass_missing(*validation_pass));
+                    storages.operations_meta_storage.replace(block_hash, &operations_meta)?;
+                    Ok(true)
+                }
+                None => Ok(false)
+            }
+        }
+        Issue::MissingContextIndex { block_hash, context_hash } => {
+            storages.block_storage.assign_to_context(block_hash, context_hash)?;
+            Ok(true)
+        }
+        // repaired separately by `repair_current_head`, after all blocks are repaired
+        Issue::InvalidCurrentHead { .. } => Ok(false),
+        Issue::InvalidBlockMeta { .. } | Issue::AppliedWithIncompleteOperations { .. } => Ok(false),
+    }
+}
+
+fn update_block_meta<F: FnOnce(&mut Meta)>(storages: &Storages, block_hash: &BlockHash, update: F) -> Result<bool, StorageError> {
+    match storages.block_meta_storage.get(block_hash)? {
+        Some(mut meta) => {
+            update(&mut meta);
+            storages.block_meta_storage.replace(block_hash, &meta)?;
+            Ok(true)
+        }
+        None => Ok(false)
+    }
+}
+
+/// Block metadata, which cannot be decoded, are handled as missing
+fn get_valid_meta(storages: &Storages, block_hash: &BlockHash) -> Result<Option<Meta>, StorageError> {
+    match storages.block_meta_storage.get(block_hash) {
+        Err(StorageError::DBError { error: DBError::SchemaError { .. } }) => Ok(None),
+        result => result,
+    }
+}
+
+/// Move current head back to the first applied predecessor, returns `true`, if current head was moved
+fn repair_current_head(storages: &Storages, chain_id: &ChainId, log: &Logger) -> Result<bool, StorageError> {
+    let head = match storages.chain_meta_storage.get_current_head(chain_id)? {
+        Some(head) => head,
+        None => return Ok(false),
+    };
+
+    let mut block_hash = head.hash.clone();
+    while let Some(meta) = get_valid_meta(storages, &block_hash)? {
+        if meta.is_applied() {
+            if block_hash == head.hash {
+                return Ok(false);
+            }
+            info!(log, "Current head was moved"; "block_hash" => HashType::BlockHash.bytes_to_string(&block_hash), "level" => meta.level());
+            storages.chain_meta_storage.set_current_head(chain_id, &Head { hash: block_hash, level: meta.level() })?;
+            return Ok(true);
+        }
+        match meta.predecessor() {
+            Some(predecessor) if predecessor != &block_hash => block_hash = predecessor.clone(),
+            _ => break,
+        }
+    }
+
+    warn!(log, "No applied block was found for the current head");
+    Ok(false)
+}
@@ -0,0 +1,258 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! History modes define, how much of the chain history is kept in the storage.
+//!
+//! * `Archive` - everything is kept (default)
+//! * `Full` - context actions and context (skip list) data older than configured number of cycles are removed,
+//!   blocks and operations are kept
+//! * `Rolling` - same as `Full`, but also blocks (indexes) and operations older than configured number of cycles are removed.
+//!   Block metadata are kept and marked as pruned, so predecessor/successor links stay valid and pruned blocks are not downloaded again
+//!
+//! Pruning is done by [HistoryPruner] always by whole cycles. The level, below which data were already pruned,
+//! is persisted in `SystemStorage`, so pruning continues where it stopped after the restart.
+//! Only levels between the already pruned level and the new prune level are visited, blocks are found by the block level index,
+//! so fork blocks, which are not referenced by the level index, are not pruned.
+//!
+//! Context (skip list) nodes are removed by ranges of levels, just nodes needed to rebuild the context from the prune level on are kept.
+//! Context hashes of blocks below the prune level are unassigned, so reading of their (pruned) context fails as not found.
+//! After pruning, deduplicated context values, which are not referenced anymore, are collected.
+
+use std::cmp::min;
+use std::fmt;
+use std::str::FromStr;
+
+use failure::Fail;
+use getset::CopyGetters;
+
+use crate::{BlockMetaStorage, BlockStorage, ContextActionStorage, OperationsMetaStorage, OperationsStorage, StorageError, SystemStorage};
+use crate::persistent::{ContextList, PersistentStorage};
+use crate::skip_list::{BlobCollector, SkipList, SkipListError};
+
+/// How many context (skip list) indexes are pruned at once, while holding the context lock
+const CONTEXT_PRUNE_BATCH_SIZE: usize = 4096;
+
+/// Possible errors for history pruning
+#[derive(Debug, Fail)]
+pub enum HistoryError {
+    #[fail(display = "Storage error: {}", error)]
+    StorageError {
+        error: StorageError
+    },
+    #[fail(display = "Context storage error: {}", error)]
+    ContextError {
+        error: SkipListError
+    },
+    #[fail(display = "Context storage lock is poisoned")]
+    ContextLockPoisoned,
+}
+
+impl From<StorageError> for HistoryError {
+    fn from(error: StorageError) -> Self {
+        HistoryError::StorageError { error }
+    }
+}
+
+impl From<SkipListError> for HistoryError {
+    fn from(error: SkipListError) -> Self {
+        HistoryError::ContextError { error }
+    }
+}
+
+impl slog::Value for HistoryError {
+    fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryMode {
+    Archive,
+    Full,
+    Rolling,
+}
+
+#[derive(Debug, Clone, Fail)]
+#[fail(display = "invalid history mode: {}, supported values are: archive, full, rolling", _0)]
+pub struct ParseHistoryModeError(String);
+
+impl FromStr for HistoryMode {
+    type Err = ParseHistoryModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "archive" => Ok(HistoryMode::Archive),
+            "full" => Ok(HistoryMode::Full),
+            "rolling" => Ok(HistoryMode::Rolling),
+            _ => Err(ParseHistoryModeError(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for HistoryMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryMode::Archive => write!(f, "archive"),
+            HistoryMode::Full => write!(f, "full"),
+            HistoryMode::Rolling => write!(f, "rolling"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryConfiguration {
+    pub mode: HistoryMode,
+    /// How many cycles (before the cycle of the current head) are kept
+    pub keep_cycles: i32,
+    pub blocks_per_cycle: i32,
+}
+
+impl HistoryConfiguration {
+    /// Level, below which data should be pruned, when chain reaches `head_level`.
+    /// Level is always aligned to the start of the cycle.
+    pub fn prune_level(&self, head_level: i32) -> Option<i32> {
+        if self.mode == HistoryMode::Archive || self.blocks_per_cycle <= 0 {
+            return None;
+        }
+
+        let level = head_level - self.keep_cycles * self.blocks_per_cycle;
+        let level = level - level.rem_euclid(self.blocks_per_cycle);
+        if level > 0 {
+            Some(level)
+        } else {
+            None
+        }
+    }
+}
+
+/// Summary of one pruning run
+#[derive(Debug, Clone, Default, CopyGetters)]
+pub struct PruneStats {
+    /// Everything below this level is pruned
+    #[get_copy = "pub"]
+    level: i32,
+    #[get_copy = "pub"]
+    blocks: usize,
+    #[get_copy = "pub"]
+    operations: usize,
+    #[get_copy = "pub"]
+    context_actions: usize,
+    #[get_copy = "pub"]
+    context_nodes: usize,
+    #[get_copy = "pub"]
+    context_blobs: usize,
+}
+
+/// Removes old data from the storage according to the configured [HistoryMode]
+pub struct HistoryPruner {
+    cfg: HistoryConfiguration,
+    block_storage: BlockStorage,
+    block_meta_storage: BlockMetaStorage,
+    operations_storage: OperationsStorage,
+    operations_meta_storage: OperationsMetaStorage,
+    context_action_storage: ContextActionStorage,
+    system_storage: SystemStorage,
+    context: ContextList,
+    blob_collector: BlobCollector,
+}
+
+impl HistoryPruner {
+    pub fn new(persistent_storage: &PersistentStorage, cfg: HistoryConfiguration) -> Self {
+        Self {
+            cfg,
+            block_storage: BlockStorage::new(persistent_storage),
+            block_meta_storage: BlockMetaStorage::new(persistent_storage),
+            operations_storage: OperationsStorage::new(persistent_storage),
+            operations_meta_storage: OperationsMetaStorage::new(persistent_storage),
+            context_action_storage: ContextActionStorage::new(persistent_storage),
+            system_storage: SystemStorage::new(persistent_storage.kv()),
+            context: persistent_storage.context_storage(),
+            blob_collector: BlobCollector::new(persistent_storage.kv(), persistent_storage.kv()),
+        }
+    }
+
+    #[inline]
+    pub fn cfg(&self) -> &HistoryConfiguration {
+        &self.cfg
+    }
+
+    /// Level, below which data were already pruned
+    #[inline]
+    pub fn pruned_level(&self) -> Result<i32, HistoryError> {
+        Ok(self.system_storage.get_history_pruned_level()?.unwrap_or(0))
+    }
+
+    /// Remove data, which are not needed anymore, when chain reached `head_level`.
+    /// Returns `None`, if there was nothing to prune.
+    pub fn prune(&mut self, head_level: i32) -> Result<Option<PruneStats>, HistoryError> {
+        let prune_level = match self.cfg.prune_level(head_level) {
+            Some(prune_level) => prune_level,
+            None => return Ok(None),
+        };
+        let pruned_level = self.pruned_level()?;
+        if prune_level <= pruned_level {
+            return Ok(None);
+        }
+
+        let mut stats = PruneStats { level: prune_level, ..Default::default() };
+
+        // only levels, which were not pruned yet, are visited
+        for block_header in self.block_storage.get_by_level_range(pruned_level, prune_level)? {
+            let block_hash = &block_header.hash;
+            stats.context_actions += self.context_action_storage.delete_by_block_hash(block_hash)?;
+
+            // genesis is always kept, it is needed to recognize already initialized storage
+            if block_header.header.level() == 0 {
+                continue;
+            }
+            if self.cfg.mode == HistoryMode::Full {
+                // context of the block is pruned
+                self.block_storage.unassign_context(block_hash)?;
+            } else if self.cfg.mode == HistoryMode::Rolling {
+                // metadata are kept, so the chain stays linked and the block is not scheduled for download again
+                if let Some(mut meta) = self.block_meta_storage.get(block_hash)? {
+                    meta.set_is_pruned(true);
+                    self.block_meta_storage.put(block_hash, &meta)?;
+                }
+                stats.operations += self.operations_storage.delete_operations(block_hash)?;
+                self.operations_meta_storage.delete(block_hash)?;
+                self.block_storage.delete(block_hash)?;
+                stats.blocks += 1;
+            }
+        }
+
+        stats.context_nodes = self.prune_context(pruned_level as usize, prune_level as usize)?;
+        stats.context_blobs = self.collect_context_garbage()?;
+
+        self.system_storage.set_history_pruned_level(prune_level)?;
+        Ok(Some(stats))
+    }
+
+    /// Context is pruned in batches, so block application is not blocked for the whole pruning
+    fn prune_context(&self, from_index: usize, to_index: usize) -> Result<usize, HistoryError> {
+        let mut removed = 0;
+        let mut batch_start = from_index;
+        while batch_start < to_index {
+            let batch_end = min(batch_start + CONTEXT_PRUNE_BATCH_SIZE, to_index);
+            let context = self.context.read().map_err(|_| HistoryError::ContextLockPoisoned)?;
+            removed += context.prune(batch_start, batch_end)?;
+            batch_start = batch_end;
+        }
+        Ok(removed)
+    }
+
+    /// Remove deduplicated context values, which are not referenced anymore, returns number of removed values.
+    ///
+    /// Values are scanned without holding the context lock, it is read locked just to take the checkpoint
+    /// and to remove the values, so nothing can be pushed to the context in the meantime (see [BlobCollector]).
+    pub fn collect_context_garbage(&self) -> Result<usize, HistoryError> {
+        let checkpoint = {
+            let _context = self.context.read().map_err(|_| HistoryError::ContextLockPoisoned)?;
+            self.blob_collector.checkpoint()?
+        };
+        let unreferenced = self.blob_collector.find_unreferenced()?;
+
+        let _context = self.context.read().map_err(|_| HistoryError::ContextLockPoisoned)?;
+        Ok(self.blob_collector.remove(checkpoint, unreferenced)?)
+    }
+}
@@ -7,6 +7,7 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use failure::Fail;
+use rocksdb::ColumnFamilyDescriptor;
 use serde::{Deserialize, Serialize};
 use slog::{error, info, Logger};
 
@@ -22,24 +23,85 @@ pub use crate::block_storage::{BlockAdditionalData, BlockAdditionalDataBuilder,
 pub use crate::chain_meta_storage::ChainMetaStorage;
 pub use crate::context_action_storage::{ContextActionByBlockHashKey, ContextActionRecordValue, ContextActionStorage};
 pub use crate::mempool_storage::{MempoolStorage, MempoolStorageKV};
+pub use crate::operations_index_storage::OperationsIndexStorage;
 pub use crate::operations_meta_storage::{OperationsMetaStorage, OperationsMetaStorageKV};
 pub use crate::operations_storage::{OperationKey, OperationsStorage, OperationsStorageKV, OperationsStorageReader};
-use crate::persistent::{CommitLogError, DBError, Decoder, Encoder, SchemaError};
+pub use crate::peer_storage::PeerStorage;
+use crate::migration::Migrator;
+use crate::persistent::{CommitLogError, DBError, DbTuning, Decoder, Encoder, KeyValueStore, SchemaError, WriteBatch};
 pub use crate::persistent::database::{Direction, IteratorMode};
 use crate::persistent::sequence::SequenceError;
+use crate::skip_list::SkipListError;
 pub use crate::system_storage::SystemStorage;
 
 pub mod persistent;
 pub mod operations_storage;
 pub mod operations_meta_storage;
+pub mod operations_index_storage;
 pub mod block_storage;
 pub mod block_meta_storage;
 pub mod context_action_storage;
+pub mod context_trace;
 pub mod mempool_storage;
 pub mod system_storage;
 pub mod skip_list;
 pub mod context;
 pub mod chain_meta_storage;
+pub mod snapshot;
+pub mod backup;
+pub mod history;
+pub mod migration;
+pub mod fsck;
+pub mod merkle_storage;
+pub mod commit_log_maintenance;
+pub mod sequence_recovery;
+pub mod peer_storage;
+
+/// All key-value schemas, `$f` is the name of the schema function (`descriptor` or `column`) called with `$args`
+macro_rules! schemas {
+    ($f:ident $(, $arg:expr)*) => {
+        vec![
+            $crate::block_storage::BlockPrimaryIndex::$f($($arg),*),
+            $crate::block_storage::BlockByLevelIndex::$f($($arg),*),
+            $crate::block_storage::BlockByContextHashIndex::$f($($arg),*),
+            $crate::block_storage::BlockByBakerIndex::$f($($arg),*),
+            $crate::block_storage::BlockByProtocolIndex::$f($($arg),*),
+            $crate::BlockMetaStorage::$f($($arg),*),
+            $crate::OperationsStorage::$f($($arg),*),
+            $crate::OperationsMetaStorage::$f($($arg),*),
+            $crate::operations_index_storage::OperationByHashIndex::$f($($arg),*),
+            $crate::operations_index_storage::OperationByAccountIndex::$f($($arg),*),
+            $crate::operations_index_storage::OperationByKindIndex::$f($($arg),*),
+            $crate::operations_index_storage::OperationByAccountKindIndex::$f($($arg),*),
+            $crate::context_action_storage::ContextActionByBlockHashIndex::$f($($arg),*),
+            $crate::context_action_storage::ContextActionByContractIndex::$f($($arg),*),
+            $crate::context_action_storage::ContextActionByTypeIndex::$f($($arg),*),
+            $crate::context_action_storage::ContextActionByOperationIndex::$f($($arg),*),
+            $crate::context_action_storage::ContextActionByTimeIndex::$f($($arg),*),
+            $crate::context_action_storage::ContextActionByKeyPrefixIndex::$f($($arg),*),
+            $crate::SystemStorage::$f($($arg),*),
+            $crate::persistent::sequence::Sequences::$f($($arg),*),
+            $crate::skip_list::DatabaseBackedSkipList::$f($($arg),*),
+            $crate::skip_list::Lane::$f($($arg),*),
+            $crate::skip_list::ListValue::$f($($arg),*),
+            $crate::skip_list::ListValueBlob::$f($($arg),*),
+            $crate::MempoolStorage::$f($($arg),*),
+            $crate::mempool_storage::MempoolHistoryStorage::$f($($arg),*),
+            $crate::mempool_storage::MempoolHistoryByTimeIndex::$f($($arg),*),
+            $crate::mempool_storage::MempoolHistoryBySourceIndex::$f($($arg),*),
+            $crate::ContextActionStorage::$f($($arg),*),
+            $crate::ChainMetaStorage::$f($($arg),*),
+            $crate::merkle_storage::MerkleStorage::$f($($arg),*),
+            $crate::PeerStorage::$f($($arg),*),
+        ]
+    }
+}
+
+/// Descriptors of all column families of the database, primary and secondary instances have to be opened with the same descriptors,
+/// otherwise e.g. columns with merge operator cannot be read.
+pub fn database_schemas(tuning: &DbTuning) -> Vec<ColumnFamilyDescriptor> {
+    schemas!(descriptor, tuning)
+}
 
 /// Extension of block header with block hash
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
@@ -104,6 +166,10 @@ pub enum StorageError {
     MessageHashError {
         error: MessageHashError
     },
+    #[fail(display = "Context storage error: {}", error)]
+    ContextStorageError {
+        error: SkipListError
+    },
 }
 
 impl From<DBError> for StorageError {
@@ -142,6 +208,12 @@ impl From<TezosEnvironmentError> for StorageError {
     }
 }
 
+impl From<SkipListError> for StorageError {
+    fn from(error: SkipListError) -> Self {
+        StorageError::ContextStorageError { error }
+    }
+}
+
 impl slog::Value for StorageError {
     fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
         serializer.emit_arguments(key, &format_args!("{}", self))
@@ -184,10 +256,13 @@ pub fn resolve_storage_init_chain_data(tezos_env: &TezosEnvironmentConfiguration
     Ok(init_data)
 }
 
-/// Stores apply result to storage and mark block as applied, if everythnig is ok.
+/// Stores apply result to storage, marks block as applied and moves current head to the block.
+/// Everything is written in one batch, so after a crash the block is either applied (and head moved), or not at all.
 pub fn store_applied_block_result(
     block_storage: &BlockStorage,
     block_meta_storage: &BlockMetaStorage,
+    chain_meta_storage: &ChainMetaStorage,
+    chain_id: &ChainId,
     block_hash: &BlockHash,
     block_result: ApplyBlockResponse,
     block_metadata: &mut block_meta_storage::Meta) -> Result<(BlockJsonData, BlockAdditionalData), StorageError> {
@@ -198,21 +273,29 @@ pub fn store_applied_block_result(
         .block_header_proto_metadata_json(block_result.block_header_proto_metadata_json)
         .operations_proto_metadata_json(block_result.operations_proto_metadata_json)
         .build().unwrap();
-    block_storage.put_block_json_data(&block_hash, block_json_data.clone())?;
 
     // store additional data
     let block_additional_data = BlockAdditionalDataBuilder::default()
         .max_operations_ttl(block_result.max_operations_ttl.try_into().unwrap())
         .last_allowed_fork_level(block_result.last_allowed_fork_level)
         .build().unwrap();
-    block_storage.put_block_additional_data(&block_hash, block_additional_data.clone())?;
 
-    // TODO: check context checksum or context_hash
+    let mut batch = WriteBatch::new();
+    block_storage.put_block_data_to_batch(&mut batch, &block_hash, Some(block_json_data.clone()), Some(block_additional_data.clone()))?;
+
+    // context hash is verified by the context listener against the merkle context (if enabled), see [merkle_storage]
 
     // if everything is stored and ok, we can considere this block as applied
     // mark current head as applied
-    block_metadata.set_is_applied(true);
-    block_meta_storage.put(&block_hash, &block_metadata)?;
+    let mut applied_metadata = block_metadata.clone();
+    applied_metadata.set_is_applied(true);
+    block_meta_storage.put_to_batch(&mut batch, &block_hash, &applied_metadata)?;
+
+    // applied block becomes current head
+    chain_meta_storage.set_current_head_to_batch(&mut batch, chain_id, &Head { hash: block_hash.clone(), level: applied_metadata.level() })?;
+
+    block_meta_storage.write_batch(batch)?;
+    *block_metadata = applied_metadata;
 
     Ok((block_json_data, block_additional_data))
 }
@@ -231,11 +314,13 @@ pub fn store_commit_genesis_result(
     // store data for genesis
     let genesis_block_hash = &init_storage_data.genesis_block_header_hash;
     let chain_id = &init_storage_data.chain_id;
+    let genesis = block_storage.get(&genesis_block_hash)?.ok_or(StorageError::MissingKey)?;
+    let mut batch = WriteBatch::new();
 
     // if everything is stored and ok, we can considere genesis block as applied
     // if storage is empty, initialize with genesis
-    block_meta_storage.put(&genesis_block_hash, &block_meta_storage::Meta::genesis_meta(&genesis_block_hash, chain_id, true))?;
-    operations_meta_storage.put(&genesis_block_hash, &operations_meta_storage::Meta::genesis_meta(chain_id))?;
+    block_meta_storage.put_to_batch(&mut batch, &genesis_block_hash, &block_meta_storage::Meta::genesis_meta(&genesis_block_hash, chain_id, true))?;
+    operations_meta_storage.put_to_batch(&mut batch, &genesis_block_hash, &operations_meta_storage::Meta::genesis_meta(chain_id))?;
 
     // store result data - json and additional data
     let block_json_data = BlockJsonDataBuilder::default()
@@ -243,23 +328,20 @@ pub fn store_commit_genesis_result(
         .block_header_proto_metadata_json(bock_result.block_header_proto_metadata_json)
         .operations_proto_metadata_json(bock_result.operations_proto_metadata_json)
         .build().unwrap();
-    block_storage.put_block_json_data(&genesis_block_hash, block_json_data.clone())?;
+    block_storage.put_block_data_to_batch(&mut batch, &genesis_block_hash, Some(block_json_data.clone()), None)?;
 
     // set genesis as current head - it is empty storage
-    match block_storage.get(&genesis_block_hash)? {
-        Some(genesis) => {
-            chain_meta_storage.set_current_head(
-                &chain_id,
-                &Head {
-                    hash: genesis.hash.clone(),
-                    level: genesis.header.level(),
-                },
-            )?;
+    chain_meta_storage.set_current_head_to_batch(
+        &mut batch,
+        &chain_id,
+        &Head {
+            hash: genesis.hash.clone(),
+            level: genesis.header.level(),
+        },
+    )?;
 
-            Ok(block_json_data)
-        }
-        None => return Err(StorageError::MissingKey)
-    }
+    block_meta_storage.write_batch(batch)?;
+    Ok(block_json_data)
 }
 
 /// Genesis block needs extra handling because predecessor of the genesis block is genesis itself.
@@ -307,21 +389,29 @@ pub fn initialize_storage_with_genesis_block(
 }
 
 pub fn check_database_compatibility(
-    db: Arc<rocksdb::DB>,
+    db: Arc<KeyValueStore>,
     expected_database_version: i64,
+    migrator: &Migrator,
     tezos_env: &TezosEnvironmentConfiguration,
     log: &Logger) -> Result<bool, StorageError> {
     let mut system_info = SystemStorage::new(db);
     let db_version_ok = match system_info.get_db_version()? {
-        Some(db_version) => db_version == expected_database_version,
+        Some(db_version) if db_version == expected_database_version => true,
+        Some(db_version) => match migrator.check(db_version, expected_database_version) {
+            Ok(()) => {
+                info!(log, "Database will be migrated"; "db_version" => db_version, "expected_version" => expected_database_version);
+                true
+            }
+            Err(e) => {
+                error!(log, "Incompatible database version found and it cannot be migrated. Please re-sync your node to empty storage - see configuration!"; "reason" => e);
+                false
+            }
+        },
         None => {
             system_info.set_db_version(expected_database_version)?;
             true
         }
     };
-    if !db_version_ok {
-        error!(log, "Incompatible database version found. Please re-sync your node to empty storage - see configuration!");
-    }
 
     let tezos_env_main_chain_id = tezos_env.main_chain_id().map_err(|e| StorageError::TezosEnvironmentError { error: e })?;
     let tezos_env_main_chain_name = &tezos_env.version;
@@ -358,17 +448,18 @@ pub fn check_database_compatibility(
 
 pub mod tests_common {
     use std::{env, fs};
+    use std::collections::BTreeMap;
     use std::path::{Path, PathBuf};
     use std::sync::Arc;
 
     use failure::Error;
 
-    use crate::block_storage;
-    use crate::chain_meta_storage::ChainMetaStorage;
-    use crate::mempool_storage::MempoolStorage;
+    use tezos_messages::p2p::encoding::operations_for_blocks;
+    use tezos_messages::p2p::encoding::prelude::{BlockHeaderBuilder, OperationsForBlock, OperationsForBlocksMessage};
+
+    use crate::merkle_storage::{CommitInfo, MerkleStorage};
     use crate::persistent::*;
-    use crate::persistent::sequence::Sequences;
-    use crate::skip_list::{DatabaseBackedSkipList, Lane, ListValue};
+    use crate::skip_list::Bucket;
 
     use super::*;
 
@@ -386,34 +477,30 @@ pub mod tests_common {
             Self::create(path)
         }
 
+        /// Create storage backed by RocksDB
         pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+            let path = Self::prepare_dir(path);
+            let kv = open_kv(&path, database_schemas(&DbTuning::default()), &DbConfiguration::default())?;
+            Self::with_kv(kv, path)
+        }
+
+        /// Create storage backed by in-memory key-value store, just commit logs are stored in the `path`
+        pub fn create_in_memory<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+            let path = Self::prepare_dir(path);
+            let kv = open_in_memory_kv(schemas!(column));
+            Self::with_kv(kv, path)
+        }
+
+        fn prepare_dir<P: AsRef<Path>>(path: P) -> PathBuf {
             let path = path.as_ref().to_path_buf();
             // remove previous data if exists
             if Path::new(&path).exists() {
                 fs::remove_dir_all(&path).unwrap();
             }
+            path
+        }
 
-            let cfg = DbConfiguration::default();
-
-            let kv = open_kv(&path, vec![
-                block_storage::BlockPrimaryIndex::descriptor(),
-                block_storage::BlockByLevelIndex::descriptor(),
-                block_storage::BlockByContextHashIndex::descriptor(),
-                BlockMetaStorage::descriptor(),
-                OperationsStorage::descriptor(),
-                OperationsMetaStorage::descriptor(),
-                context_action_storage::ContextActionByBlockHashIndex::descriptor(),
-                context_action_storage::ContextActionByContractIndex::descriptor(),
-                context_action_storage::ContextActionByTypeIndex::descriptor(),
-                SystemStorage::descriptor(),
-                Sequences::descriptor(),
-                DatabaseBackedSkipList::descriptor(),
-                Lane::descriptor(),
-                ListValue::descriptor(),
-                MempoolStorage::descriptor(),
-                ContextActionStorage::descriptor(),
-                ChainMetaStorage::descriptor(),
-            ], &cfg)?;
+        fn with_kv(kv: KeyValueStore, path: PathBuf) -> Result<Self, Error> {
             let clog = open_cl(&path, vec![
                 BlockStorage::descriptor(),
             ])?;
@@ -435,4 +522,100 @@ pub mod tests_common {
             let _ = fs::remove_dir_all(&self.path);
         }
     }
+
+    /// Stores chain of applied blocks with levels `1..=count` (with json data, operations and context) and sets the last one as current head,
+    /// every level adds one key to the context, see [context_diff]. Context is committed to the merkle storage as well,
+    /// so blocks have real context hashes.
+    pub fn store_test_chain(persistent_storage: &PersistentStorage, chain_id: &ChainId, count: i32, log: &Logger) -> Result<Vec<BlockHeaderWithHash>, Error> {
+        let block_storage = BlockStorage::new(persistent_storage);
+        let block_meta_storage = BlockMetaStorage::new(persistent_storage);
+        let chain_meta_storage = ChainMetaStorage::new(persistent_storage);
+        let operations_storage = OperationsStorage::new(persistent_storage);
+        let operations_meta_storage = OperationsMetaStorage::new(persistent_storage);
+        let context = persistent_storage.context_storage();
+        let merkle_storage = MerkleStorage::new(persistent_storage);
+        SystemStorage::new(persistent_storage.kv()).set_chain_id(chain_id)?;
+
+        // context for level 0
+        context.write().unwrap().push(&context_diff(0))?;
+        let mut context_hash = merkle_storage.commit_diff(&None, &context_diff(0), &CommitInfo { parents: &[], date: 5_635_634, author: "Tezos", message: "Genesis" })?;
+
+        let mut predecessor = HashType::BlockHash.string_to_bytes("BLockGenesisGenesisGenesisGenesisGenesisb83baZgbyZe")?;
+        let mut blocks = vec![];
+        for level in 1..=count {
+            let parents = [context_hash.clone()];
+            context_hash = merkle_storage.commit_diff(&Some(context_hash), &context_diff(level), &CommitInfo { parents: &parents, date: 5_635_634 + level as i64, author: "Tezos", message: "" })?;
+            let block = BlockHeaderWithHash::new(
+                BlockHeaderBuilder::default()
+                    .level(level)
+                    .proto(1)
+                    .predecessor(predecessor.clone())
+                    .timestamp(5_635_634 + level as i64)
+                    .validation_pass(1)
+                    .operations_hash(HashType::OperationListListHash.string_to_bytes("LLoaGLRPRx3Zf8kB4ACtgku8F4feeBiskeb41J1ciwfcXB3KzHKXc")?)
+                    .fitness(vec![])
+                    .context(context_hash.clone())
+                    .protocol_data(vec![])
+                    .build().unwrap()
+            )?;
+
+            block_storage.put_block_header(&block)?;
+            block_meta_storage.put_block_header(&block, chain_id, log)?;
+            operations_meta_storage.put_block_header(&block, chain_id)?;
+
+            let operations = OperationsForBlocksMessage::new(OperationsForBlock::new(block.hash.clone(), 0), operations_for_blocks::Path::Op, vec![]);
+            operations_storage.put_operations(&operations)?;
+            operations_meta_storage.put_operations(&operations)?;
+
+            let mut meta = block_meta_storage.get(&block.hash)?.unwrap();
+            store_applied_block_result(&block_storage, &block_meta_storage, &chain_meta_storage, chain_id, &block.hash, apply_block_response(&block), &mut meta)?;
+
+            context.write().unwrap().push(&context_diff(level))?;
+            block_storage.assign_to_context(&block.hash, block.header.context())?;
+
+            predecessor = block.hash.clone();
+            blocks.push(block);
+        }
+
+        Ok(blocks)
+    }
+
+    /// Context diff of the test chain, it adds key `data/level/{level}`
+    pub fn context_diff(level: i32) -> ContextMap {
+        let mut diff = BTreeMap::new();
+        diff.insert(format!("data/level/{}", level), Bucket::Exists(vec![level as u8]));
+        diff
+    }
+
+    /// Block header with given `level` and `predecessor`, context hash is derived from the level
+    pub fn test_block(level: i32, predecessor: &BlockHash) -> Result<BlockHeaderWithHash, Error> {
+        Ok(BlockHeaderWithHash::new(
+            BlockHeaderBuilder::default()
+                .level(level)
+                .proto(1)
+                .predecessor(predecessor.clone())
+                .timestamp(5_635_634 + level as i64)
+                .validation_pass(1)
+                .operations_hash(HashType::OperationListListHash.string_to_bytes("LLoaGLRPRx3Zf8kB4ACtgku8F4feeBiskeb41J1ciwfcXB3KzHKXc")?)
+                .fitness(vec![])
+                .context(vec![level as u8 + 1; HashType::ContextHash.size()])
+                .protocol_data(vec![])
+                .build().unwrap()
+        )?)
+    }
+
+    /// Protocol response for the block of the test chain, with valid (empty) json data
+    pub fn apply_block_response(block: &BlockHeaderWithHash) -> ApplyBlockResponse {
+        ApplyBlockResponse {
+            last_allowed_fork_level: 0,
+            max_operations_ttl: 60,
+            context_hash: block.header.context().clone(),
+            block_header_proto_json: "{}".to_string(),
+            block_header_proto_metadata_json: "{}".to_string(),
+            operations_proto_metadata_json: "[]".to_string(),
+            validation_result_message: "applied".to_string(),
+            forking_testchain: false,
+            forking_testchain_data: None,
+        }
+    }
 }
@@ -3,17 +3,22 @@
 
 use std::fmt;
 use std::fmt::Formatter;
+use std::mem;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use getset::{CopyGetters, Getters};
+use rocksdb::{ColumnFamilyDescriptor, SliceTransform};
 use serde::{Deserialize, Serialize};
 
-use crypto::hash::{HashType, OperationHash};
+use crypto::hash::{BlockHash, HashType, OperationHash};
 use tezos_messages::p2p::binary_message::MessageHash;
 use tezos_messages::p2p::encoding::operation::OperationMessage;
 
 use crate::{IteratorMode, num_from_slice, StorageError};
-use crate::persistent::{BincodeEncoded, Decoder, Encoder, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError};
+use crate::context_action_storage::{contract_id_to_contract_address_for_index, ContractAddress};
+use crate::persistent::{BincodeEncoded, DBError, DbTuning, Decoder, default_table_options, Encoder, KeyValueColumn, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError, WriteBatch};
+use crate::persistent::secondary_index::{Index, IndexedStore, SecondaryIndex};
 
 /// Convenience type for operation meta storage database
 pub type MempoolStorageKV = dyn KeyValueStoreWithSchema<MempoolStorage> + Sync + Send;
@@ -52,15 +57,30 @@ impl fmt::Display for MempoolOperationTypeParseError {
     }
 }
 
-/// Operation metadata storage
+/// Operation metadata storage.
+///
+/// Besides the current mempool operations, it keeps history of all operations seen in the mempool
+/// (see [MempoolHistoryRecord]), which is kept after the operation is removed from the mempool.
 #[derive(Clone)]
 pub struct MempoolStorage {
-    kv: Arc<MempoolStorageKV>
+    kv: Arc<MempoolStorageKV>,
+    history: Arc<IndexedStore<MempoolHistoryStorage>>,
+    history_by_time_index: Arc<Index<MempoolHistoryByTimeIndex>>,
+    history_by_source_index: Arc<Index<MempoolHistoryBySourceIndex>>,
 }
 
 impl MempoolStorage {
     pub fn new(persistent_storage: &PersistentStorage) -> Self {
-        Self { kv: persistent_storage.kv() }
+        Self {
+            kv: persistent_storage.kv(),
+            history: Arc::new(
+                IndexedStore::new(persistent_storage.kv())
+                    .with_index(Index::<MempoolHistoryByTimeIndex>::new(persistent_storage.kv()))
+                    .with_index(Index::<MempoolHistoryBySourceIndex>::new(persistent_storage.kv()))
+            ),
+            history_by_time_index: Arc::new(Index::new(persistent_storage.kv())),
+            history_by_source_index: Arc::new(Index::new(persistent_storage.kv())),
+        }
     }
 
     #[inline]
@@ -137,6 +157,103 @@ impl MempoolStorage {
         }
         Ok(operations)
     }
+
+    /// Record, that the operation was received, only the first reception is kept.
+    ///
+    /// # Arguments
+    /// * `peer` - address of the peer, which sent the operation, `None` for operations injected by RPC
+    pub fn record_received(&self, operation_hash: &OperationHash, peer: Option<String>, received_at: SystemTime) -> Result<(), StorageError> {
+        if self.history.get(operation_hash)?.is_none() {
+            self.history.put(operation_hash, &MempoolHistoryRecord::new(operation_hash.clone(), unix_millis(received_at), peer))?;
+        }
+        Ok(())
+    }
+
+    /// Record result of the operation prevalidation together with source addresses of the operation contents
+    pub fn record_prevalidation(&self, operation_hash: &OperationHash, prevalidation: MempoolPrevalidation, sources: Vec<String>) -> Result<(), StorageError> {
+        self.update_history(operation_hash, |record| {
+            record.prevalidation = Some(prevalidation);
+            if !sources.is_empty() {
+                record.sources = sources;
+            }
+        })
+    }
+
+    /// Record, that the operation was included in the block
+    pub fn record_included(&self, operation_hash: &OperationHash, block_hash: &BlockHash, included_at: SystemTime) -> Result<(), StorageError> {
+        let outcome = MempoolOperationOutcome::Included { block_hash: block_hash.clone(), at: unix_millis(included_at) };
+        self.update_history(operation_hash, |record| record.outcome = Some(outcome))
+    }
+
+    /// Record, that the operation was removed from the mempool without being included in a block
+    pub fn record_dropped(&self, operation_hash: &OperationHash, reason: String, dropped_at: SystemTime) -> Result<(), StorageError> {
+        let outcome = MempoolOperationOutcome::Dropped { reason, at: unix_millis(dropped_at) };
+        self.update_history(operation_hash, |record| {
+            // inclusion is final
+            if !record.is_included() {
+                record.outcome = Some(outcome)
+            }
+        })
+    }
+
+    /// Updates are done only for operations, which were already received.
+    /// Operations are received and processed sequentially, so there is no concurrent update of the same record.
+    fn update_history<F: FnOnce(&mut MempoolHistoryRecord)>(&self, operation_hash: &OperationHash, update: F) -> Result<(), StorageError> {
+        if let Some(mut record) = self.history.get(operation_hash)? {
+            update(&mut record);
+            self.history.put(operation_hash, &record)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn get_history(&self, operation_hash: &OperationHash) -> Result<Option<MempoolHistoryRecord>, StorageError> {
+        self.history.get(operation_hash)
+            .map_err(StorageError::from)
+    }
+
+    /// History of operations with given source address, ordered by the time of reception.
+    /// Address is expected in the index format, see [contract_id_to_contract_address_for_index].
+    pub fn find_history_by_source(&self, source: &ContractAddress, limit: usize) -> Result<Vec<MempoolHistoryRecord>, StorageError> {
+        let operation_hashes = self.history_by_source_index
+            .prefix(&MempoolHistoryBySourceKey::new(source, 0, OperationHash::new()))?
+            .take(limit);
+        self.load_history(operation_hashes)
+    }
+
+    /// History of operations received in the time range `[from, to)`, ordered by the time of reception
+    pub fn find_history_by_time(&self, from: SystemTime, to: SystemTime, limit: usize) -> Result<Vec<MempoolHistoryRecord>, StorageError> {
+        let operation_hashes = self.history_by_time_index
+            .range(&MempoolHistoryByTimeKey::new(unix_millis(from), OperationHash::new()), &MempoolHistoryByTimeKey::new(unix_millis(to), OperationHash::new()))?
+            .take(limit);
+        self.load_history(operation_hashes)
+    }
+
+    /// Remove history of operations received before `before`.
+    /// Returns number of removed records.
+    pub fn prune_history(&self, before: SystemTime) -> Result<usize, StorageError> {
+        let operation_hashes = self.history_by_time_index
+            .range(&MempoolHistoryByTimeKey::new(0, OperationHash::new()), &MempoolHistoryByTimeKey::new(unix_millis(before), OperationHash::new()))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut batch = WriteBatch::new();
+        for operation_hash in &operation_hashes {
+            self.history.delete_to_batch(&mut batch, operation_hash)?;
+        }
+        self.history.write_batch(batch)?;
+
+        Ok(operation_hashes.len())
+    }
+
+    fn load_history<I: Iterator<Item=Result<OperationHash, DBError>>>(&self, operation_hashes: I) -> Result<Vec<MempoolHistoryRecord>, StorageError> {
+        let mut records = vec![];
+        for operation_hash in operation_hashes {
+            if let Some(record) = self.history.get(&operation_hash?)? {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
 }
 
 impl KeyValueSchema for MempoolStorage {
@@ -197,4 +314,254 @@ pub struct MempoolValue {
     time_to_live: SystemTime,
 }
 
-impl BincodeEncoded for MempoolValue {}
\ No newline at end of file
+impl BincodeEncoded for MempoolValue {}
+
+#[inline]
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Result of the operation prevalidation
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PrevalidationStatus {
+    Applied,
+    Refused,
+    BranchRefused,
+    BranchDelayed,
+    /// Protocol failed to validate the operation
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Getters, CopyGetters)]
+pub struct MempoolPrevalidation {
+    #[get_copy = "pub"]
+    status: PrevalidationStatus,
+    /// Unix time in milliseconds
+    #[get_copy = "pub"]
+    validated_at: u64,
+    /// Error returned by the protocol (json) or reason of the failure
+    #[get = "pub"]
+    error: Option<String>,
+}
+
+impl MempoolPrevalidation {
+    pub fn new(status: PrevalidationStatus, validated_at: SystemTime, error: Option<String>) -> Self {
+        Self { status, validated_at: unix_millis(validated_at), error }
+    }
+}
+
+/// How the operation left the mempool, times are unix times in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum MempoolOperationOutcome {
+    Included {
+        block_hash: BlockHash,
+        at: u64,
+    },
+    Dropped {
+        reason: String,
+        at: u64,
+    },
+}
+
+/// History of the operation in the mempool
+#[derive(Serialize, Deserialize, Debug, Clone, Getters, CopyGetters)]
+pub struct MempoolHistoryRecord {
+    #[get = "pub"]
+    operation_hash: OperationHash,
+    /// Unix time in milliseconds, when the operation was received first time
+    #[get_copy = "pub"]
+    first_seen: u64,
+    /// Address of the peer, which sent the operation, `None` for operations injected by RPC
+    #[get = "pub"]
+    peer: Option<String>,
+    /// Source addresses of the operation contents, known after the prevalidation
+    #[get = "pub"]
+    sources: Vec<String>,
+    /// Result of the last prevalidation
+    #[get = "pub"]
+    prevalidation: Option<MempoolPrevalidation>,
+    #[get = "pub"]
+    outcome: Option<MempoolOperationOutcome>,
+}
+
+impl MempoolHistoryRecord {
+    fn new(operation_hash: OperationHash, first_seen: u64, peer: Option<String>) -> Self {
+        Self {
+            operation_hash,
+            first_seen,
+            peer,
+            sources: vec![],
+            prevalidation: None,
+            outcome: None,
+        }
+    }
+
+    pub fn is_included(&self) -> bool {
+        matches!(self.outcome, Some(MempoolOperationOutcome::Included { .. }))
+    }
+}
+
+impl BincodeEncoded for MempoolHistoryRecord {}
+
+/// Mempool history as `operation_hash -> history_record`
+pub struct MempoolHistoryStorage;
+
+impl KeyValueSchema for MempoolHistoryStorage {
+    type Key = OperationHash;
+    type Value = MempoolHistoryRecord;
+
+    #[inline]
+    fn name() -> &'static str {
+        "mempool_history_storage"
+    }
+}
+
+/// Index of the mempool history by the time of reception
+pub struct MempoolHistoryByTimeIndex;
+
+impl KeyValueSchema for MempoolHistoryByTimeIndex {
+    type Key = MempoolHistoryByTimeKey;
+    type Value = ();
+
+    #[inline]
+    fn name() -> &'static str {
+        "mempool_history_by_time_index"
+    }
+}
+
+impl SecondaryIndex for MempoolHistoryByTimeIndex {
+    type Primary = MempoolHistoryStorage;
+
+    fn index_keys(operation_hash: &OperationHash, record: &MempoolHistoryRecord) -> Vec<Self::Key> {
+        vec![MempoolHistoryByTimeKey::new(record.first_seen, operation_hash.clone())]
+    }
+
+    fn primary_key(index_key: &Self::Key) -> OperationHash {
+        index_key.operation_hash.clone()
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct MempoolHistoryByTimeKey {
+    first_seen: u64,
+    operation_hash: OperationHash,
+}
+
+impl MempoolHistoryByTimeKey {
+    const LEN_TIME: usize = mem::size_of::<u64>();
+    const LEN_HASH: usize = HashType::OperationHash.size();
+
+    const IDX_TIME: usize = 0;
+    const IDX_HASH: usize = Self::IDX_TIME + Self::LEN_TIME;
+
+    /// Empty operation hash can be used to get the first key with the time
+    pub fn new(first_seen: u64, operation_hash: OperationHash) -> Self {
+        Self { first_seen, operation_hash }
+    }
+}
+
+/// * bytes layout `[first_seen(8)][operation_hash(32)]`, hash is optional for range queries
+impl Encoder for MempoolHistoryByTimeKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut bytes = Vec::with_capacity(Self::LEN_TIME + Self::LEN_HASH);
+        bytes.extend(&self.first_seen.to_be_bytes());
+        bytes.extend(&self.operation_hash);
+        Ok(bytes)
+    }
+}
+
+impl Decoder for MempoolHistoryByTimeKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() == Self::LEN_TIME + Self::LEN_HASH {
+            let first_seen = num_from_slice!(bytes, Self::IDX_TIME, u64);
+            let operation_hash = bytes[Self::IDX_HASH..].to_vec();
+            Ok(Self { first_seen, operation_hash })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
+/// Index of the mempool history by the source address of the operation contents
+pub struct MempoolHistoryBySourceIndex;
+
+impl KeyValueSchema for MempoolHistoryBySourceIndex {
+    type Key = MempoolHistoryBySourceKey;
+    type Value = ();
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(MempoolHistoryBySourceKey::LEN_SOURCE));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(MempoolHistoryBySourceKey::LEN_SOURCE)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "mempool_history_by_source_index"
+    }
+}
+
+impl SecondaryIndex for MempoolHistoryBySourceIndex {
+    type Primary = MempoolHistoryStorage;
+
+    fn index_keys(operation_hash: &OperationHash, record: &MempoolHistoryRecord) -> Vec<Self::Key> {
+        record.sources.iter()
+            .filter_map(|source| contract_id_to_contract_address_for_index(source).ok())
+            .map(|source| MempoolHistoryBySourceKey::new(&source, record.first_seen, operation_hash.clone()))
+            .collect()
+    }
+
+    fn primary_key(index_key: &Self::Key) -> OperationHash {
+        index_key.time_key.operation_hash.clone()
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct MempoolHistoryBySourceKey {
+    source: ContractAddress,
+    time_key: MempoolHistoryByTimeKey,
+}
+
+impl MempoolHistoryBySourceKey {
+    const LEN_SOURCE: usize = 22;
+
+    pub fn new(source: &[u8], first_seen: u64, operation_hash: OperationHash) -> Self {
+        Self {
+            source: source.to_vec(),
+            time_key: MempoolHistoryByTimeKey::new(first_seen, operation_hash),
+        }
+    }
+}
+
+/// * bytes layout `[source(22)][first_seen(8)][operation_hash(32)]`
+impl Encoder for MempoolHistoryBySourceKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        if self.source.len() == Self::LEN_SOURCE {
+            let mut bytes = self.source.clone();
+            bytes.extend(self.time_key.encode()?);
+            Ok(bytes)
+        } else {
+            Err(SchemaError::EncodeError)
+        }
+    }
+}
+
+impl Decoder for MempoolHistoryBySourceKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() > Self::LEN_SOURCE {
+            Ok(Self {
+                source: bytes[..Self::LEN_SOURCE].to_vec(),
+                time_key: MempoolHistoryByTimeKey::decode(&bytes[Self::LEN_SOURCE..])?,
+            })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
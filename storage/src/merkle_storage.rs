@@ -0,0 +1,437 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Content-addressed merkle context storage.
+//!
+//! Context is stored as a tree of blobs (values) and trees (directories) like in irmin, which is used by the OCaml node.
+//! Every entry is stored under its blake2b hash, so the hash of the commit is the same as the context hash computed by the protocol.
+//! This allows to verify, that context diffs received from the protocol runner produce the same context as in the OCaml context.
+//!
+//! Hash encoding (all numbers are big-endian):
+//! * blob: `[len(value): u64][value]`
+//! * tree: `[count(entries): u64]` and for every entry (sorted by name) `[kind: u64][len(name): LEB128][name][len(hash): u64][hash]`
+//! * commit: `[len(hash): u64][root hash][count(parents): u64]`, for every parent `[len(hash): u64][hash]`,
+//!   then `[date: u64][len(author): u64][author][len(message): u64][message]`
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::Arc;
+
+use failure::Fail;
+use rocksdb::ColumnFamilyDescriptor;
+use serde::{Deserialize, Serialize};
+
+use crypto::blake2b;
+use crypto::hash::{ContextHash, HashType};
+
+use crate::persistent::{BincodeEncoded, DBError, DbTuning, default_table_options, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage};
+use crate::persistent::ContextMap;
+use crate::skip_list::Bucket;
+
+pub type EntryHash = Vec<u8>;
+pub type MerkleStorageKV = dyn KeyValueStoreWithSchema<MerkleStorage> + Sync + Send;
+
+/// Key of the context is split to the tree path by this separator
+const KEY_SEPARATOR: char = '/';
+
+/// Possible errors for merkle storage
+#[derive(Debug, Fail)]
+pub enum MerkleError {
+    #[fail(display = "Merkle storage database error: {}", error)]
+    DBError {
+        error: DBError
+    },
+    #[fail(display = "Entry {} not found in merkle storage", hash)]
+    EntryNotFound {
+        hash: String
+    },
+    #[fail(display = "Entry {} is not a {}", hash, expected)]
+    InvalidEntry {
+        hash: String,
+        expected: &'static str,
+    },
+}
+
+impl From<DBError> for MerkleError {
+    fn from(error: DBError) -> Self {
+        MerkleError::DBError { error }
+    }
+}
+
+impl slog::Value for MerkleError {
+    fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum NodeKind {
+    Leaf,
+    NonLeaf,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub node_kind: NodeKind,
+    pub entry_hash: EntryHash,
+}
+
+pub type Tree = BTreeMap<String, Node>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Commit {
+    pub parents: Vec<EntryHash>,
+    pub root_hash: EntryHash,
+    pub date: i64,
+    pub author: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Entry {
+    Tree(Tree),
+    Blob(Vec<u8>),
+    Commit(Commit),
+}
+
+impl BincodeEncoded for Entry {}
+
+/// Commit metadata, which are part of the commit hash
+pub struct CommitInfo<'a> {
+    pub parents: &'a [ContextHash],
+    pub date: i64,
+    pub author: &'a str,
+    pub message: &'a str,
+}
+
+/// Tree, which is modified by the context diff. Unchanged subtrees are loaded from the storage lazily.
+enum WorkingNode {
+    Stored(Node),
+    Blob(Vec<u8>),
+    Tree(BTreeMap<String, WorkingNode>),
+}
+
+#[derive(Clone)]
+pub struct MerkleStorage {
+    kv: Arc<MerkleStorageKV>
+}
+
+impl MerkleStorage {
+    pub fn new(persistent_storage: &PersistentStorage) -> Self {
+        Self { kv: persistent_storage.kv() }
+    }
+
+    /// Apply context diff to the context `parent_context_hash` (or to the empty context, if there is none),
+    /// store new entries and return hash of the new commit, which is the new context hash.
+    pub fn commit_diff(&self, parent_context_hash: &Option<ContextHash>, diff: &ContextMap, info: &CommitInfo) -> Result<ContextHash, MerkleError> {
+        let mut root = match parent_context_hash {
+            Some(parent) => WorkingNode::Stored(Node { node_kind: NodeKind::NonLeaf, entry_hash: self.get_commit(parent)?.root_hash }),
+            None => WorkingNode::Tree(BTreeMap::new()),
+        };
+
+        for (key, bucket) in diff {
+            let path: Vec<&str> = key.split(KEY_SEPARATOR).filter(|step| !step.is_empty()).collect();
+            if path.is_empty() {
+                continue;
+            }
+            match bucket {
+                Bucket::Exists(value) => self.set(&mut root, &path, value.clone())?,
+                Bucket::Deleted => self.delete(&mut root, &path)?,
+            }
+        }
+
+        let mut entries = vec![];
+        let root_hash = hash_node(root, &mut entries).entry_hash;
+        let commit = Commit {
+            parents: info.parents.to_vec(),
+            root_hash,
+            date: info.date,
+            author: info.author.to_string(),
+            message: info.message.to_string(),
+        };
+        let commit_hash = hash_commit(&commit);
+        entries.push((commit_hash.clone(), Entry::Commit(commit)));
+
+        // entries are content-addressed, so already stored entries are just rewritten by the same value
+        for (hash, entry) in entries {
+            self.kv.put(&hash, &entry)?;
+        }
+
+        Ok(commit_hash)
+    }
+
+    /// Read value stored under the `key` in the context identified by `context_hash`
+    pub fn get(&self, context_hash: &ContextHash, key: &[String]) -> Result<Option<Vec<u8>>, MerkleError> {
+        let mut hash = self.get_commit(context_hash)?.root_hash;
+        for (idx, step) in key.iter().enumerate() {
+            let node = match self.get_tree(&hash)?.remove(step) {
+                Some(node) => node,
+                None => return Ok(None),
+            };
+            match node.node_kind {
+                NodeKind::NonLeaf => hash = node.entry_hash,
+                NodeKind::Leaf if idx == key.len() - 1 => return match self.get_entry(&node.entry_hash)? {
+                    Entry::Blob(value) => Ok(Some(value)),
+                    _ => Err(MerkleError::InvalidEntry { hash: hex::encode(&node.entry_hash), expected: "blob" }),
+                },
+                NodeKind::Leaf => return Ok(None),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Check, if context with the `context_hash` is stored
+    #[inline]
+    pub fn contains_commit(&self, context_hash: &ContextHash) -> Result<bool, MerkleError> {
+        match self.kv.get(context_hash)? {
+            Some(Entry::Commit(_)) => Ok(true),
+            _ => Ok(false),
+        }
+    }
+
+    fn get_entry(&self, hash: &EntryHash) -> Result<Entry, MerkleError> {
+        self.kv.get(hash)?
+            .ok_or_else(|| MerkleError::EntryNotFound { hash: hex::encode(hash) })
+    }
+
+    /// Read commit of the context identified by `context_hash`
+    pub fn get_commit(&self, context_hash: &ContextHash) -> Result<Commit, MerkleError> {
+        match self.kv.get(context_hash)? {
+            Some(Entry::Commit(commit)) => Ok(commit),
+            Some(_) => Err(MerkleError::InvalidEntry { hash: HashType::ContextHash.bytes_to_string(context_hash), expected: "commit" }),
+            None => Err(MerkleError::EntryNotFound { hash: HashType::ContextHash.bytes_to_string(context_hash) }),
+        }
+    }
+
+    fn get_tree(&self, hash: &EntryHash) -> Result<Tree, MerkleError> {
+        match self.get_entry(hash)? {
+            Entry::Tree(tree) => Ok(tree),
+            _ => Err(MerkleError::InvalidEntry { hash: hex::encode(hash), expected: "tree" }),
+        }
+    }
+
+    /// Make sure, that node is loaded tree, blob is replaced by an empty tree
+    fn load_tree<'a>(&self, node: &'a mut WorkingNode) -> Result<&'a mut BTreeMap<String, WorkingNode>, MerkleError> {
+        let loaded = match node {
+            WorkingNode::Tree(_) => None,
+            WorkingNode::Stored(Node { node_kind: NodeKind::NonLeaf, entry_hash }) => Some(
+                self.get_tree(entry_hash)?
+                    .into_iter()
+                    .map(|(name, node)| (name, WorkingNode::Stored(node)))
+                    .collect()
+            ),
+            WorkingNode::Stored(_) | WorkingNode::Blob(_) => Some(BTreeMap::new()),
+        };
+        if let Some(loaded) = loaded {
+            *node = WorkingNode::Tree(loaded);
+        }
+
+        match node {
+            WorkingNode::Tree(children) => Ok(children),
+            _ => unreachable!("Node was loaded as a tree"),
+        }
+    }
+
+    fn set(&self, node: &mut WorkingNode, path: &[&str], value: Vec<u8>) -> Result<(), MerkleError> {
+        let children = self.load_tree(node)?;
+        match path {
+            [name] => {
+                children.insert(name.to_string(), WorkingNode::Blob(value));
+                Ok(())
+            }
+            [name, rest @ ..] => {
+                let child = children.entry(name.to_string()).or_insert_with(|| WorkingNode::Tree(BTreeMap::new()));
+                self.set(child, rest, value)
+            }
+            [] => Ok(()),
+        }
+    }
+
+    /// Delete entry (blob or whole subtree), empty trees are removed like in irmin
+    fn delete(&self, node: &mut WorkingNode, path: &[&str]) -> Result<(), MerkleError> {
+        let is_tree = match node {
+            WorkingNode::Tree(_) | WorkingNode::Stored(Node { node_kind: NodeKind::NonLeaf, .. }) => true,
+            _ => false,
+        };
+        if !is_tree {
+            return Ok(());
+        }
+
+        let children = self.load_tree(node)?;
+        match path {
+            [name] => {
+                children.remove(*name);
+            }
+            [name, rest @ ..] => {
+                if let Some(child) = children.get_mut(*name) {
+                    self.delete(child, rest)?;
+                    let is_empty = match child {
+                        WorkingNode::Tree(child_children) => child_children.is_empty(),
+                        _ => false,
+                    };
+                    if is_empty {
+                        children.remove(*name);
+                    }
+                }
+            }
+            [] => (),
+        }
+        Ok(())
+    }
+
+}
+
+impl KeyValueSchema for MerkleStorage {
+    type Key = EntryHash;
+    type Value = Entry;
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let cf_opts = default_table_options(tuning, Self::name());
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "merkle_storage"
+    }
+}
+
+/// Calculate context hash of the whole `context` (e.g. restored from a snapshot) without storing anything.
+/// Deleted keys are skipped, so the result is the same as the hash of the commit of this context by [MerkleStorage::commit_diff].
+pub fn hash_context(context: &ContextMap, info: &CommitInfo) -> ContextHash {
+    let mut root = BTreeMap::new();
+    for (key, bucket) in context {
+        if let Bucket::Exists(value) = bucket {
+            let path: Vec<&str> = key.split(KEY_SEPARATOR).filter(|step| !step.is_empty()).collect();
+            insert_blob(&mut root, &path, value.clone());
+        }
+    }
+
+    let root_hash = hash_node(WorkingNode::Tree(root), &mut vec![]).entry_hash;
+    hash_commit(&Commit {
+        parents: info.parents.to_vec(),
+        root_hash,
+        date: info.date,
+        author: info.author.to_string(),
+        message: info.message.to_string(),
+    })
+}
+
+/// Insert value to the tree, which is built in memory, blob on the path is replaced by a tree
+fn insert_blob(children: &mut BTreeMap<String, WorkingNode>, path: &[&str], value: Vec<u8>) {
+    match path {
+        [name] => {
+            children.insert(name.to_string(), WorkingNode::Blob(value));
+        }
+        [name, rest @ ..] => {
+            let child = children.entry(name.to_string()).or_insert_with(|| WorkingNode::Tree(BTreeMap::new()));
+            if let WorkingNode::Tree(grandchildren) = child {
+                insert_blob(grandchildren, rest, value);
+            } else {
+                let mut grandchildren = BTreeMap::new();
+                insert_blob(&mut grandchildren, rest, value);
+                *child = WorkingNode::Tree(grandchildren);
+            }
+        }
+        [] => (),
+    }
+}
+
+/// Calculate hashes of all changed nodes and collect them as entries to be stored
+fn hash_node(node: WorkingNode, entries: &mut Vec<(EntryHash, Entry)>) -> Node {
+    match node {
+        WorkingNode::Stored(node) => node,
+        WorkingNode::Blob(value) => {
+            let entry_hash = hash_blob(&value);
+            entries.push((entry_hash.clone(), Entry::Blob(value)));
+            Node { node_kind: NodeKind::Leaf, entry_hash }
+        }
+        WorkingNode::Tree(children) => {
+            let tree: Tree = children.into_iter()
+                .map(|(name, child)| (name, hash_node(child, entries)))
+                .collect();
+            let entry_hash = hash_tree(&tree);
+            entries.push((entry_hash.clone(), Entry::Tree(tree)));
+            Node { node_kind: NodeKind::NonLeaf, entry_hash }
+        }
+    }
+}
+
+fn hash_blob(value: &[u8]) -> EntryHash {
+    let mut bytes = Vec::with_capacity(mem::size_of::<u64>() + value.len());
+    bytes.extend_from_slice(&(value.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(value);
+    blake2b::digest_256(&bytes)
+}
+
+fn hash_tree(tree: &Tree) -> EntryHash {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(&(tree.len() as u64).to_be_bytes());
+    for (name, node) in tree {
+        bytes.extend_from_slice(&encode_node_kind(node.node_kind));
+        encode_leb128(name.len(), &mut bytes);
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&(node.entry_hash.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&node.entry_hash);
+    }
+    blake2b::digest_256(&bytes)
+}
+
+fn hash_commit(commit: &Commit) -> EntryHash {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(&(commit.root_hash.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(&commit.root_hash);
+    bytes.extend_from_slice(&(commit.parents.len() as u64).to_be_bytes());
+    for parent in &commit.parents {
+        bytes.extend_from_slice(&(parent.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(parent);
+    }
+    bytes.extend_from_slice(&(commit.date as u64).to_be_bytes());
+    bytes.extend_from_slice(&(commit.author.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(commit.author.as_bytes());
+    bytes.extend_from_slice(&(commit.message.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(commit.message.as_bytes());
+    blake2b::digest_256(&bytes)
+}
+
+fn encode_node_kind(node_kind: NodeKind) -> [u8; 8] {
+    match node_kind {
+        NodeKind::NonLeaf => [0, 0, 0, 0, 0, 0, 0, 0],
+        NodeKind::Leaf => [255, 0, 0, 0, 0, 0, 0, 0],
+    }
+}
+
+fn encode_leb128(mut value: usize, bytes: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            break;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_leb128() {
+        let encode = |value| {
+            let mut bytes = vec![];
+            encode_leb128(value, &mut bytes);
+            bytes
+        };
+        assert_eq!(vec![0], encode(0));
+        assert_eq!(vec![127], encode(127));
+        assert_eq!(vec![0x80, 0x01], encode(128));
+        assert_eq!(vec![0xe5, 0x8e, 0x26], encode(624_485));
+    }
+
+    #[test]
+    fn test_hash_blob() {
+        assert_eq!(blake2b::digest_256(&[0, 0, 0, 0, 0, 0, 0, 3, 1, 2, 3]), hash_blob(&[1, 2, 3]));
+    }
+}
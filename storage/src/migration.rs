@@ -0,0 +1,639 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Versioned database migrations.
+//!
+//! Every [Migration] step upgrades the database by exactly one version (from `version() - 1` to `version()`).
+//! [Migrator] runs all steps needed to reach the expected version one by one, and after each finished step
+//! it stores the new version in `SystemStorage`.
+//!
+//! Step can be interrupted at any time (crash, kill, ...), so:
+//! * every step must be idempotent - it can be run again on partially migrated data,
+//! * long running steps should store their progress with [MigrationContext::save_checkpoint]
+//!   (this is done automatically by [MigrationContext::rewrite] and [MigrationContext::rewrite_commit_log]
+//!   for every column family / commit log of the step), so after restart the step continues
+//!   from the last checkpoint instead of from the beginning.
+//!
+//! Commit logs are append only, rewritten commit log records are appended and the column family referencing them
+//! is updated, replaced records are reclaimed by the commit log compaction.
+//!
+//! In dry-run mode nothing is written to the database, steps just report, what would be changed.
+//! Dry-run of more than one step reports every step against the current (not migrated) data.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use failure::Fail;
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+
+use crate::{BlockStorage, BlockStorageReader, Direction, IteratorMode, StorageError, SystemStorage};
+use crate::block_storage::{BlockByBakerIndex, BlockByProtocolIndex, BlockPrimaryIndex, metadata_index_keys};
+use crate::context_action_storage::{ContextActionByKeyPrefixIndex, ContextActionByOperationIndex, ContextActionByTimeIndex, ContextActionStorage};
+use crate::operations_index_storage::{OperationByAccountKindIndex, OperationByHashIndex, operations_account_kind_index_to_batch, operations_index_to_batch};
+use crate::persistent::{BincodeEncoded, CommitLogSchema, CommitLogWithSchema, Decoder, Encoder, KeyValueSchema, KeyValueStoreWithSchema, Location, PersistentStorage, SchemaError, WriteBatch};
+use crate::persistent::secondary_index::SecondaryIndex;
+use crate::skip_list::{escape_legacy_value, ListValue};
+use crate::system_storage::DbVersion;
+
+/// After how many processed records is checkpoint stored by [MigrationContext::rewrite]
+const REWRITE_CHECKPOINT_INTERVAL: usize = 1024;
+
+/// Possible errors for database migration
+#[derive(Debug, Fail)]
+pub enum MigrationError {
+    #[fail(display = "Storage error: {}", error)]
+    StorageError {
+        error: StorageError
+    },
+    #[fail(display = "Migration schema error: {}", error)]
+    SchemaError {
+        error: SchemaError
+    },
+    #[fail(display = "Database version is not set")]
+    MissingDbVersion,
+    #[fail(display = "Database version {} cannot be migrated to version {}, no migration step for version {}", from_version, to_version, missing_version)]
+    MissingMigration {
+        from_version: DbVersion,
+        to_version: DbVersion,
+        missing_version: DbVersion,
+    },
+    #[fail(display = "Database version {} is newer than expected version {}, downgrade is not supported", db_version, expected_version)]
+    UnsupportedDowngrade {
+        db_version: DbVersion,
+        expected_version: DbVersion,
+    },
+    #[fail(display = "Invalid migration steps: {}", reason)]
+    InvalidMigrations {
+        reason: String
+    },
+    #[fail(display = "Migration step to version {} failed: {}", version, reason)]
+    StepFailed {
+        version: DbVersion,
+        reason: String,
+    },
+}
+
+impl From<StorageError> for MigrationError {
+    fn from(error: StorageError) -> Self {
+        MigrationError::StorageError { error }
+    }
+}
+
+impl From<SchemaError> for MigrationError {
+    fn from(error: SchemaError) -> Self {
+        MigrationError::SchemaError { error }
+    }
+}
+
+impl slog::Value for MigrationError {
+    fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+/// One migration step, which upgrades database from version `version() - 1` to `version()`.
+pub trait Migration: Send + Sync {
+    /// Database version after this step is applied
+    fn version(&self) -> DbVersion;
+
+    /// Short human readable description of changes
+    fn description(&self) -> &'static str;
+
+    /// Migrate data. Must be idempotent, because after a crash the step is run again (from the last checkpoint).
+    /// In dry-run mode (see [MigrationContext::is_dry_run]) nothing can be written.
+    fn migrate(&self, ctx: &mut MigrationContext) -> Result<(), MigrationError>;
+}
+
+/// What should happen with the record processed by [MigrationContext::rewrite]
+pub enum Rewrite<V> {
+    Keep,
+    Put(V),
+    Delete,
+}
+
+/// Value of the column family referencing commit log records, which can be rewritten by [MigrationContext::rewrite_commit_log]
+pub trait CommitLogReferences {
+    /// Locations of all referenced commit log records
+    fn locations_mut(&mut self) -> Vec<&mut Location>;
+}
+
+/// Counts of changed records in one column family / commit log
+#[derive(Debug, Clone, Default, PartialEq, CopyGetters)]
+pub struct ChangeCount {
+    #[get_copy = "pub"]
+    updated: usize,
+    #[get_copy = "pub"]
+    deleted: usize,
+}
+
+/// What was (or in dry-run would be) changed by one migration step
+#[derive(Debug, Clone, Getters, CopyGetters)]
+pub struct MigrationStepReport {
+    #[get_copy = "pub"]
+    version: DbVersion,
+    #[get_copy = "pub"]
+    description: &'static str,
+    /// Step was resumed from a checkpoint stored by previous (interrupted) run
+    #[get_copy = "pub"]
+    resumed: bool,
+    #[get = "pub"]
+    changes: BTreeMap<&'static str, ChangeCount>,
+}
+
+impl fmt::Display for MigrationStepReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "version {}: {}", self.version, self.description)?;
+        if self.resumed {
+            write!(f, " (resumed)")?;
+        }
+        for (name, count) in &self.changes {
+            write!(f, ", {}: {} updated/{} deleted", name, count.updated, count.deleted)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Getters, CopyGetters)]
+pub struct MigrationReport {
+    #[get_copy = "pub"]
+    dry_run: bool,
+    #[get = "pub"]
+    steps: Vec<MigrationStepReport>,
+}
+
+/// Progress of the running migration step, persisted in `SystemStorage`
+#[derive(Serialize, Deserialize)]
+struct MigrationCheckpoint {
+    version: DbVersion,
+    /// Progress stored by the step itself, see [MigrationContext::save_checkpoint]
+    data: Option<Vec<u8>>,
+    /// Progress of every column family rewritten by the step
+    rewrites: BTreeMap<String, RewriteProgress>,
+}
+
+impl MigrationCheckpoint {
+    fn new(version: DbVersion) -> Self {
+        Self { version, data: None, rewrites: BTreeMap::new() }
+    }
+}
+
+/// Progress of [MigrationContext::rewrite] of one column family
+#[derive(Serialize, Deserialize, Clone)]
+enum RewriteProgress {
+    /// Encoded key of the last processed record
+    LastKey(Vec<u8>),
+    Finished,
+}
+
+impl BincodeEncoded for MigrationCheckpoint {}
+
+/// Access to the storage for a running migration step
+pub struct MigrationContext<'a> {
+    persistent_storage: &'a PersistentStorage,
+    system_storage: SystemStorage,
+    dry_run: bool,
+    checkpoint: MigrationCheckpoint,
+    report: MigrationStepReport,
+    log: &'a Logger,
+}
+
+impl<'a> MigrationContext<'a> {
+    #[inline]
+    pub fn persistent_storage(&self) -> &PersistentStorage {
+        self.persistent_storage
+    }
+
+    #[inline]
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    #[inline]
+    pub fn log(&self) -> &Logger {
+        self.log
+    }
+
+    /// Checkpoint stored by previous (interrupted) run of the same step
+    #[inline]
+    pub fn checkpoint(&self) -> Option<&[u8]> {
+        self.checkpoint.data.as_deref()
+    }
+
+    /// Persist progress of the step. Checkpoint is ignored in dry-run mode.
+    pub fn save_checkpoint(&mut self, data: Vec<u8>) -> Result<(), MigrationError> {
+        if !self.dry_run {
+            self.checkpoint.data = Some(data);
+            self.store_checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn save_rewrite_progress(&mut self, name: &'static str, progress: RewriteProgress) -> Result<(), MigrationError> {
+        if !self.dry_run {
+            self.checkpoint.rewrites.insert(name.to_string(), progress);
+            self.store_checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn store_checkpoint(&mut self) -> Result<(), MigrationError> {
+        self.system_storage.set_migration_checkpoint(Encoder::encode(&self.checkpoint)?)
+            .map_err(MigrationError::from)
+    }
+
+    /// Record change done by the step, to be included in the report
+    pub fn record_update(&mut self, name: &'static str) {
+        self.report.changes.entry(name).or_default().updated += 1;
+    }
+
+    /// Record multiple changes of the same column family done by the step
+    pub fn record_updates(&mut self, name: &'static str, count: usize) {
+        if count > 0 {
+            self.report.changes.entry(name).or_default().updated += count;
+        }
+    }
+
+    /// Record removal done by the step, to be included in the report
+    pub fn record_delete(&mut self, name: &'static str) {
+        self.report.changes.entry(name).or_default().deleted += 1;
+    }
+
+    /// Go through all records of the column family `S` and update/delete them according to the `rewrite` function.
+    /// Progress is stored as a checkpoint of the column family, so interrupted rewrite continues from the last stored key
+    /// and already finished rewrite is skipped. Step can rewrite more column families one after another.
+    /// Returns number of processed records.
+    pub fn rewrite<S, F>(&mut self, mut rewrite: F) -> Result<usize, MigrationError>
+        where
+            S: KeyValueSchema,
+            F: FnMut(&S::Key, S::Value) -> Result<Rewrite<S::Value>, MigrationError>
+    {
+        let last_key_bytes = match self.checkpoint.rewrites.get(S::name()) {
+            Some(RewriteProgress::Finished) => return Ok(0),
+            Some(RewriteProgress::LastKey(last_key_bytes)) => Some(last_key_bytes.clone()),
+            None => None,
+        };
+        let kv = self.persistent_storage.kv();
+        let last_key = last_key_bytes.as_ref()
+            .map(|last_key_bytes| <S::Key as Decoder>::decode(last_key_bytes))
+            .transpose()?;
+        let mode = match &last_key {
+            Some(last_key) => IteratorMode::From(last_key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+
+        let mut processed = 0;
+        for (key, value) in KeyValueStoreWithSchema::<S>::iterator(kv.as_ref(), mode).map_err(StorageError::from)? {
+            let key = key?;
+            let key_bytes = Encoder::encode(&key)?;
+            if last_key_bytes.as_ref().map_or(false, |last_key_bytes| last_key_bytes == &key_bytes) {
+                // already processed before the checkpoint was stored
+                continue;
+            }
+
+            match rewrite(&key, value?)? {
+                Rewrite::Keep => (),
+                Rewrite::Put(value) => {
+                    if !self.dry_run {
+                        KeyValueStoreWithSchema::<S>::put(kv.as_ref(), &key, &value).map_err(StorageError::from)?;
+                    }
+                    self.record_update(S::name());
+                }
+                Rewrite::Delete => {
+                    if !self.dry_run {
+                        KeyValueStoreWithSchema::<S>::delete(kv.as_ref(), &key).map_err(StorageError::from)?;
+                    }
+                    self.record_delete(S::name());
+                }
+            }
+
+            processed += 1;
+            if processed % REWRITE_CHECKPOINT_INTERVAL == 0 {
+                self.save_rewrite_progress(S::name(), RewriteProgress::LastKey(key_bytes))?;
+            }
+        }
+        self.save_rewrite_progress(S::name(), RewriteProgress::Finished)?;
+
+        Ok(processed)
+    }
+
+    /// Go through all records of the commit log `C` referenced by the column family `S` and replace them
+    /// according to the `rewrite` function (`None` keeps the record as it is).
+    /// Replacement is appended to the commit log and the referencing record of `S` is updated after the commit log is flushed,
+    /// so the column family never references a missing record. Replaced records are reclaimed by the commit log compaction.
+    /// Progress is stored in the same way as by [MigrationContext::rewrite].
+    /// Returns number of processed records of `S`.
+    pub fn rewrite_commit_log<S, C, F>(&mut self, mut rewrite: F) -> Result<usize, MigrationError>
+        where
+            S: KeyValueSchema,
+            S::Value: CommitLogReferences,
+            C: CommitLogSchema,
+            F: FnMut(&S::Key, C::Value) -> Result<Option<C::Value>, MigrationError>
+    {
+        let clog = self.persistent_storage.clog();
+        let dry_run = self.dry_run;
+
+        let mut rewritten = 0;
+        let processed = self.rewrite::<S, _>(|key, mut value| {
+            let mut changed = false;
+            for location in value.locations_mut() {
+                let record = CommitLogWithSchema::<C>::get(clog.as_ref(), location).map_err(StorageError::from)?;
+                if let Some(record) = rewrite(key, record)? {
+                    if !dry_run {
+                        *location = CommitLogWithSchema::<C>::append(clog.as_ref(), &record).map_err(StorageError::from)?;
+                    }
+                    rewritten += 1;
+                    changed = true;
+                }
+            }
+            if changed {
+                if !dry_run {
+                    clog.flush().map_err(StorageError::from)?;
+                }
+                Ok(Rewrite::Put(value))
+            } else {
+                Ok(Rewrite::Keep)
+            }
+        })?;
+        self.record_updates(C::name(), rewritten);
+
+        Ok(processed)
+    }
+}
+
+/// Runs ordered migration steps
+pub struct Migrator {
+    migrations: Vec<Box<dyn Migration>>,
+}
+
+impl Migrator {
+    /// Steps must be ordered by version, every version can be present just once
+    pub fn new(migrations: Vec<Box<dyn Migration>>) -> Result<Self, MigrationError> {
+        for pair in migrations.windows(2) {
+            if pair[1].version() != pair[0].version() + 1 {
+                return Err(MigrationError::InvalidMigrations {
+                    reason: format!("step for version {} is followed by step for version {}", pair[0].version(), pair[1].version())
+                });
+            }
+        }
+        Ok(Self { migrations })
+    }
+
+    /// Check, that database with `db_version` can be migrated to `expected_version`
+    pub fn check(&self, db_version: DbVersion, expected_version: DbVersion) -> Result<(), MigrationError> {
+        if db_version > expected_version {
+            return Err(MigrationError::UnsupportedDowngrade { db_version, expected_version });
+        }
+        for version in (db_version + 1)..=expected_version {
+            if self.step(version).is_none() {
+                return Err(MigrationError::MissingMigration { from_version: db_version, to_version: expected_version, missing_version: version });
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&self, version: DbVersion) -> Option<&dyn Migration> {
+        self.migrations.iter()
+            .find(|migration| migration.version() == version)
+            .map(|migration| migration.as_ref())
+    }
+
+    /// Migrate database to the `expected_version`. In dry-run mode nothing is written.
+    pub fn migrate(&self, persistent_storage: &PersistentStorage, expected_version: DbVersion, dry_run: bool, log: &Logger) -> Result<MigrationReport, MigrationError> {
+        let mut system_storage = SystemStorage::new(persistent_storage.kv());
+        let mut db_version = system_storage.get_db_version()?.ok_or(MigrationError::MissingDbVersion)?;
+        self.check(db_version, expected_version)?;
+
+        let mut report = MigrationReport { dry_run, steps: vec![] };
+        while db_version < expected_version {
+            let step = self.step(db_version + 1).expect("Migration step availability was already checked");
+
+            let checkpoint = match system_storage.get_migration_checkpoint()? {
+                Some(checkpoint) => Some(<MigrationCheckpoint as Decoder>::decode(&checkpoint)?)
+                    .filter(|checkpoint| checkpoint.version == step.version()),
+                None => None
+            };
+            let resumed = checkpoint.is_some();
+
+            info!(log, "Running database migration step"; "version" => step.version(), "description" => step.description(), "dry_run" => dry_run, "resumed" => resumed);
+            let mut ctx = MigrationContext {
+                persistent_storage,
+                system_storage: system_storage.clone(),
+                dry_run,
+                report: MigrationStepReport {
+                    version: step.version(),
+                    description: step.description(),
+                    resumed,
+                    changes: BTreeMap::new(),
+                },
+                checkpoint: checkpoint.unwrap_or_else(|| MigrationCheckpoint::new(step.version())),
+                log,
+            };
+            step.migrate(&mut ctx)?;
+
+            if !dry_run {
+                // checkpoint is bound to the version, so even if we crash between these two writes, it is not used by the next step
+                system_storage.set_db_version(step.version())?;
+                system_storage.clear_migration_checkpoint()?;
+            }
+            info!(log, "Database migration step finished"; "report" => ctx.report.to_string());
+
+            report.steps.push(ctx.report);
+            db_version = step.version();
+        }
+
+        Ok(report)
+    }
+}
+
+/// Ordered list of all known migration steps. Every change of the database format, which increases
+/// the database version, should add a step here, so existing databases do not need to be re-synced.
+pub fn database_migrations() -> Vec<Box<dyn Migration>> {
+    vec![
+        Box::new(ContextValueBlobs),
+        Box::new(BlockMetadataIndexes),
+        Box::new(OperationIndexes),
+        Box::new(ContextActionIndexes),
+        Box::new(OperationAccountKindIndex),
+    ]
+}
+
+/// Large context values are stored just once in the `skip_list_value_blobs` column and skip list values
+/// reference them. Values stored before are readable as they are, unless they start with a tag of the new values,
+/// so every stored value is checked and such values are escaped (see [escape_legacy_value]).
+struct ContextValueBlobs;
+
+impl Migration for ContextValueBlobs {
+    fn version(&self) -> DbVersion {
+        16
+    }
+
+    fn description(&self) -> &'static str {
+        "deduplicated large context values"
+    }
+
+    fn migrate(&self, ctx: &mut MigrationContext) -> Result<(), MigrationError> {
+        ctx.rewrite::<ListValue, _>(|_, stored| match escape_legacy_value(&stored) {
+            Some(escaped) => Ok(Rewrite::Put(escaped)),
+            None => Ok(Rewrite::Keep),
+        })?;
+        Ok(())
+    }
+}
+
+/// Blocks are indexed by the baker and the protocol from their metadata, entries of already stored blocks are added.
+struct BlockMetadataIndexes;
+
+impl Migration for BlockMetadataIndexes {
+    fn version(&self) -> DbVersion {
+        17
+    }
+
+    fn description(&self) -> &'static str {
+        "block indexes by baker and protocol"
+    }
+
+    fn migrate(&self, ctx: &mut MigrationContext) -> Result<(), MigrationError> {
+        let block_storage = BlockStorage::new(ctx.persistent_storage());
+        let kv = ctx.persistent_storage().kv();
+        let dry_run = ctx.is_dry_run();
+
+        let (mut by_baker_count, mut by_protocol_count) = (0, 0);
+        ctx.rewrite::<BlockPrimaryIndex, _>(|block_hash, _| {
+            if let Some((block_header, json_data)) = block_storage.get_with_json_data(block_hash)? {
+                let (by_baker, by_protocol) = metadata_index_keys(&block_header, &json_data);
+                if let Some(key) = by_baker {
+                    if !dry_run {
+                        KeyValueStoreWithSchema::<BlockByBakerIndex>::put(kv.as_ref(), &key, &()).map_err(StorageError::from)?;
+                    }
+                    by_baker_count += 1;
+                }
+                if let Some(key) = by_protocol {
+                    if !dry_run {
+                        KeyValueStoreWithSchema::<BlockByProtocolIndex>::put(kv.as_ref(), &key, &()).map_err(StorageError::from)?;
+                    }
+                    by_protocol_count += 1;
+                }
+            }
+            Ok(Rewrite::Keep)
+        })?;
+        ctx.record_updates(BlockByBakerIndex::name(), by_baker_count);
+        ctx.record_updates(BlockByProtocolIndex::name(), by_protocol_count);
+
+        Ok(())
+    }
+}
+
+/// Applied operations are indexed by the hash, account and kind, entries of operations of already stored blocks are added.
+struct OperationIndexes;
+
+impl Migration for OperationIndexes {
+    fn version(&self) -> DbVersion {
+        18
+    }
+
+    fn description(&self) -> &'static str {
+        "operation indexes by hash, account and kind"
+    }
+
+    fn migrate(&self, ctx: &mut MigrationContext) -> Result<(), MigrationError> {
+        let block_storage = BlockStorage::new(ctx.persistent_storage());
+        let kv = ctx.persistent_storage().kv();
+        let dry_run = ctx.is_dry_run();
+
+        let mut operation_count = 0;
+        ctx.rewrite::<BlockPrimaryIndex, _>(|block_hash, _| {
+            if let Some((block_header, json_data)) = block_storage.get_with_json_data(block_hash)? {
+                let mut batch = WriteBatch::new();
+                operation_count += operations_index_to_batch(&mut batch, block_hash, block_header.header.level(), json_data.operations_proto_metadata_json())?;
+                if !dry_run {
+                    KeyValueStoreWithSchema::<OperationByHashIndex>::write_batch(kv.as_ref(), batch).map_err(StorageError::from)?;
+                }
+            }
+            Ok(Rewrite::Keep)
+        })?;
+        ctx.record_updates(OperationByHashIndex::name(), operation_count);
+
+        Ok(())
+    }
+}
+
+/// Context actions are indexed by the operation, start time and key prefix, entries of already stored actions are added.
+struct ContextActionIndexes;
+
+impl Migration for ContextActionIndexes {
+    fn version(&self) -> DbVersion {
+        19
+    }
+
+    fn description(&self) -> &'static str {
+        "context action indexes by operation, time and key prefix"
+    }
+
+    fn migrate(&self, ctx: &mut MigrationContext) -> Result<(), MigrationError> {
+        let kv = ctx.persistent_storage().kv();
+        let dry_run = ctx.is_dry_run();
+
+        let (mut by_operation_count, mut by_time_count, mut by_key_prefix_count) = (0, 0, 0);
+        ctx.rewrite::<ContextActionStorage, _>(|id, action| {
+            let mut batch = WriteBatch::new();
+            for key in ContextActionByOperationIndex::index_keys(id, &action) {
+                batch.put::<ContextActionByOperationIndex>(&key, &()).map_err(StorageError::from)?;
+                by_operation_count += 1;
+            }
+            for key in ContextActionByTimeIndex::index_keys(id, &action) {
+                batch.put::<ContextActionByTimeIndex>(&key, &()).map_err(StorageError::from)?;
+                by_time_count += 1;
+            }
+            for key in ContextActionByKeyPrefixIndex::index_keys(id, &action) {
+                batch.put::<ContextActionByKeyPrefixIndex>(&key, &()).map_err(StorageError::from)?;
+                by_key_prefix_count += 1;
+            }
+            if !dry_run {
+                KeyValueStoreWithSchema::<ContextActionStorage>::write_batch(kv.as_ref(), batch).map_err(StorageError::from)?;
+            }
+            Ok(Rewrite::Keep)
+        })?;
+        ctx.record_updates(ContextActionByOperationIndex::name(), by_operation_count);
+        ctx.record_updates(ContextActionByTimeIndex::name(), by_time_count);
+        ctx.record_updates(ContextActionByKeyPrefixIndex::name(), by_key_prefix_count);
+
+        Ok(())
+    }
+}
+
+/// Operations of the account are indexed also together with the kind, so the account operations of one kind are read without a scan,
+/// entries of operations of already stored blocks are added.
+struct OperationAccountKindIndex;
+
+impl Migration for OperationAccountKindIndex {
+    fn version(&self) -> DbVersion {
+        20
+    }
+
+    fn description(&self) -> &'static str {
+        "operation index by account and kind"
+    }
+
+    fn migrate(&self, ctx: &mut MigrationContext) -> Result<(), MigrationError> {
+        let block_storage = BlockStorage::new(ctx.persistent_storage());
+        let kv = ctx.persistent_storage().kv();
+        let dry_run = ctx.is_dry_run();
+
+        let mut entry_count = 0;
+        ctx.rewrite::<BlockPrimaryIndex, _>(|block_hash, _| {
+            if let Some((block_header, json_data)) = block_storage.get_with_json_data(block_hash)? {
+                let mut batch = WriteBatch::new();
+                entry_count += operations_account_kind_index_to_batch(&mut batch, block_hash, block_header.header.level(), json_data.operations_proto_metadata_json())?;
+                if !dry_run {
+                    KeyValueStoreWithSchema::<OperationByAccountKindIndex>::write_batch(kv.as_ref(), batch).map_err(StorageError::from)?;
+                }
+            }
+            Ok(Rewrite::Keep)
+        })?;
+        ctx.record_updates(OperationByAccountKindIndex::name(), entry_count);
+
+        Ok(())
+    }
+}
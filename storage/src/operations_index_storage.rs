@@ -0,0 +1,653 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Indexes of the applied operations.
+//!
+//! Operations are indexed from the operations metadata (json) of the applied block, which is stored together
+//! with the block by [BlockStorage::put_block_json_data](crate::BlockStorage::put_block_json_data), so the indexes
+//! cover exactly the blocks with stored json data. Indexes contain:
+//! * operation hash -> position of the operation in the block (operation can be included in blocks of several branches)
+//! * account -> operations, where the account is a source, destination, delegate or an originated contract,
+//!   including the internal operations
+//! * operation kind -> operations
+//! * account, operation kind -> operations, so operations of the account can be filtered by the kind without a scan
+//!
+//! Entries of one account (or kind) are ordered by the block level.
+//!
+//! Operations of the blocks, which were replaced by a reorg, are still indexed, as the block is still stored.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use failure::Fail;
+use getset::{CopyGetters, Getters};
+use rocksdb::{ColumnFamilyDescriptor, SliceTransform};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crypto::hash::{BlockHash, HashType, OperationHash};
+
+use crate::{num_from_slice, StorageError};
+use crate::block_storage::BlockLevel;
+use crate::context_action_storage::{contract_id_to_contract_address_for_index, ContractAddress};
+use crate::persistent::{BincodeEncoded, DbTuning, Decoder, default_table_options, Encoder, KeyValueColumn, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError, WriteBatch};
+
+pub type OperationByHashIndexKV = dyn KeyValueStoreWithSchema<OperationByHashIndex> + Sync + Send;
+pub type OperationByAccountIndexKV = dyn KeyValueStoreWithSchema<OperationByAccountIndex> + Sync + Send;
+pub type OperationByKindIndexKV = dyn KeyValueStoreWithSchema<OperationByKindIndex> + Sync + Send;
+pub type OperationByAccountKindIndexKV = dyn KeyValueStoreWithSchema<OperationByAccountKindIndex> + Sync + Send;
+
+/// Queries over the indexes of the applied operations
+#[derive(Clone)]
+pub struct OperationsIndexStorage {
+    by_hash: Arc<OperationByHashIndexKV>,
+    by_account: Arc<OperationByAccountIndexKV>,
+    by_kind: Arc<OperationByKindIndexKV>,
+    by_account_kind: Arc<OperationByAccountKindIndexKV>,
+}
+
+impl OperationsIndexStorage {
+    pub fn new(persistent_storage: &PersistentStorage) -> Self {
+        Self {
+            by_hash: persistent_storage.kv(),
+            by_account: persistent_storage.kv(),
+            by_kind: persistent_storage.kv(),
+            by_account_kind: persistent_storage.kv(),
+        }
+    }
+
+    /// All blocks, which include the operation
+    pub fn get_by_hash(&self, operation_hash: &OperationHash) -> Result<Vec<IndexedOperation>, StorageError> {
+        self.by_hash.prefix_iterator(&OperationByHashKey::new(operation_hash.clone(), Vec::new()))?
+            .map(|(_, operation)| operation.map_err(StorageError::from))
+            .collect()
+    }
+
+    /// Operations touching the account from the level `from_level` ordered by level, optionally just operations of the `kind`.
+    /// Account is expected in the index format, see [contract_id_to_contract_address_for_index].
+    pub fn get_by_account(&self, account: &ContractAddress, kind: Option<OperationKind>, from_level: BlockLevel, limit: usize) -> Result<Vec<IndexedOperation>, StorageError> {
+        match kind {
+            Some(kind) => {
+                let from_key = OperationByAccountKindKey::new(account, kind, OperationPosition::first_of_level(from_level));
+                self.by_account_kind.prefix_iterator(&from_key)?
+                    .take(limit)
+                    .map(|(_, operation)| operation.map_err(StorageError::from))
+                    .collect()
+            }
+            None => {
+                let from_key = OperationByAccountKey::new(account, OperationPosition::first_of_level(from_level));
+                self.by_account.prefix_iterator(&from_key)?
+                    .take(limit)
+                    .map(|(_, operation)| operation.map_err(StorageError::from))
+                    .collect()
+            }
+        }
+    }
+
+    /// Operations of the `kind` from the level `from_level` ordered by level
+    pub fn get_by_kind(&self, kind: OperationKind, from_level: BlockLevel, limit: usize) -> Result<Vec<IndexedOperation>, StorageError> {
+        let from_key = OperationByKindKey::new(kind, OperationPosition::first_of_level(from_level));
+        self.by_kind.prefix_iterator(&from_key)?
+            .take(limit)
+            .map(|(_, operation)| operation.map_err(StorageError::from))
+            .collect()
+    }
+}
+
+/// Adds index entries of all operations of the block to the batch, returns number of indexed operations
+pub(crate) fn operations_index_to_batch(batch: &mut WriteBatch, block_hash: &BlockHash, level: BlockLevel, operations_json: &str) -> Result<usize, StorageError> {
+    let entries = operation_index_entries(block_hash, level, operations_json);
+    for entry in &entries {
+        let operation = &entry.operation;
+        batch.put::<OperationByHashIndex>(&OperationByHashKey::new(operation.operation_hash.clone(), block_hash.clone()), operation)?;
+        for kind in &operation.kinds {
+            batch.put::<OperationByKindIndex>(&OperationByKindKey::new(*kind, operation.position()), operation)?;
+        }
+        for (account, roles) in &entry.accounts {
+            let operation = IndexedOperation { roles: roles.clone(), ..operation.clone() };
+            batch.put::<OperationByAccountIndex>(&OperationByAccountKey::new(account, operation.position()), &operation)?;
+        }
+        account_kind_index_to_batch(batch, entry)?;
+    }
+    Ok(entries.len())
+}
+
+/// Adds just the account and kind index entries of all operations of the block to the batch, returns number of the entries
+pub(crate) fn operations_account_kind_index_to_batch(batch: &mut WriteBatch, block_hash: &BlockHash, level: BlockLevel, operations_json: &str) -> Result<usize, StorageError> {
+    let mut count = 0;
+    for entry in &operation_index_entries(block_hash, level, operations_json) {
+        count += account_kind_index_to_batch(batch, entry)?;
+    }
+    Ok(count)
+}
+
+fn account_kind_index_to_batch(batch: &mut WriteBatch, entry: &OperationIndexEntry) -> Result<usize, StorageError> {
+    let mut count = 0;
+    for (account, roles) in &entry.accounts {
+        let operation = IndexedOperation { roles: roles.clone(), ..entry.operation.clone() };
+        for kind in &operation.kinds {
+            batch.put::<OperationByAccountKindIndex>(&OperationByAccountKindKey::new(account, *kind, operation.position()), &operation)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Adds removal of index entries of all operations of the block to the batch
+pub(crate) fn operations_index_delete_to_batch(batch: &mut WriteBatch, block_hash: &BlockHash, level: BlockLevel, operations_json: &str) -> Result<(), StorageError> {
+    for entry in operation_index_entries(block_hash, level, operations_json) {
+        let operation = &entry.operation;
+        batch.delete::<OperationByHashIndex>(&OperationByHashKey::new(operation.operation_hash.clone(), block_hash.clone()))?;
+        for kind in &operation.kinds {
+            batch.delete::<OperationByKindIndex>(&OperationByKindKey::new(*kind, operation.position()))?;
+        }
+        for account in entry.accounts.keys() {
+            batch.delete::<OperationByAccountIndex>(&OperationByAccountKey::new(account, operation.position()))?;
+            for kind in &operation.kinds {
+                batch.delete::<OperationByAccountKindIndex>(&OperationByAccountKindKey::new(account, *kind, operation.position()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Index entries of one operation
+struct OperationIndexEntry {
+    operation: IndexedOperation,
+    accounts: BTreeMap<ContractAddress, Vec<AccountRole>>,
+}
+
+/// Operations json is a list of validation passes, every pass is a list of operations with `hash` and `contents`.
+/// Operations without a valid hash are not indexed.
+fn operation_index_entries(block_hash: &BlockHash, level: BlockLevel, operations_json: &str) -> Vec<OperationIndexEntry> {
+    let validation_passes: Vec<Vec<Value>> = match serde_json::from_str(operations_json) {
+        Ok(validation_passes) => validation_passes,
+        Err(_) => return vec![],
+    };
+
+    let mut entries = vec![];
+    for (validation_pass, operations) in validation_passes.iter().enumerate() {
+        for (index, operation) in operations.iter().enumerate() {
+            let operation_hash = match operation["hash"].as_str().and_then(|hash| HashType::OperationHash.string_to_bytes(hash).ok()) {
+                Some(operation_hash) => operation_hash,
+                None => continue,
+            };
+
+            let mut kinds = vec![];
+            let mut accounts = BTreeMap::new();
+            for content in operation["contents"].as_array().into_iter().flatten() {
+                if let Some(kind) = content["kind"].as_str().and_then(|kind| kind.parse().ok()) {
+                    if !kinds.contains(&kind) {
+                        kinds.push(kind);
+                    }
+                }
+                let metadata = &content["metadata"];
+                collect_accounts(content, &metadata["operation_result"], &mut accounts);
+                // endorsements have the delegate in the metadata
+                collect_accounts(metadata, &Value::Null, &mut accounts);
+                for internal in metadata["internal_operation_results"].as_array().into_iter().flatten() {
+                    collect_accounts(internal, &internal["result"], &mut accounts);
+                }
+            }
+
+            entries.push(OperationIndexEntry {
+                operation: IndexedOperation {
+                    operation_hash,
+                    block_hash: block_hash.clone(),
+                    level,
+                    validation_pass: validation_pass as u8,
+                    index: index as u32,
+                    kinds,
+                    roles: vec![],
+                },
+                accounts,
+            });
+        }
+    }
+    entries
+}
+
+fn collect_accounts(content: &Value, result: &Value, accounts: &mut BTreeMap<ContractAddress, Vec<AccountRole>>) {
+    let mut add = |address: &str, role: AccountRole| {
+        if let Ok(address) = contract_id_to_contract_address_for_index(address) {
+            let roles = accounts.entry(address).or_insert_with(Vec::new);
+            if !roles.contains(&role) {
+                roles.push(role);
+            }
+        }
+    };
+
+    // activated account is the source of the account activation
+    for (field, role) in &[("source", AccountRole::Source), ("pkh", AccountRole::Source), ("destination", AccountRole::Destination), ("delegate", AccountRole::Delegate)] {
+        if let Some(address) = content[*field].as_str() {
+            add(address, *role);
+        }
+    }
+    for address in result["originated_contracts"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+        add(address, AccountRole::Originated);
+    }
+}
+
+/// Kind of the operation content
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    Endorsement = 0,
+    SeedNonceRevelation = 1,
+    DoubleEndorsementEvidence = 2,
+    DoubleBakingEvidence = 3,
+    ActivateAccount = 4,
+    Proposals = 5,
+    Ballot = 6,
+    Reveal = 7,
+    Transaction = 8,
+    Origination = 9,
+    Delegation = 10,
+}
+
+impl OperationKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Endorsement),
+            1 => Some(Self::SeedNonceRevelation),
+            2 => Some(Self::DoubleEndorsementEvidence),
+            3 => Some(Self::DoubleBakingEvidence),
+            4 => Some(Self::ActivateAccount),
+            5 => Some(Self::Proposals),
+            6 => Some(Self::Ballot),
+            7 => Some(Self::Reveal),
+            8 => Some(Self::Transaction),
+            9 => Some(Self::Origination),
+            10 => Some(Self::Delegation),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Fail)]
+#[fail(display = "invalid operation kind: {}", _0)]
+pub struct ParseOperationKind(String);
+
+/// Parses the kind as used in the operation json, e.g. `transaction` or `seed_nonce_revelation`
+impl FromStr for OperationKind {
+    type Err = ParseOperationKind;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "endorsement" => Ok(Self::Endorsement),
+            "seed_nonce_revelation" => Ok(Self::SeedNonceRevelation),
+            "double_endorsement_evidence" => Ok(Self::DoubleEndorsementEvidence),
+            "double_baking_evidence" => Ok(Self::DoubleBakingEvidence),
+            "activate_account" => Ok(Self::ActivateAccount),
+            "proposals" => Ok(Self::Proposals),
+            "ballot" => Ok(Self::Ballot),
+            "reveal" => Ok(Self::Reveal),
+            "transaction" => Ok(Self::Transaction),
+            "origination" => Ok(Self::Origination),
+            "delegation" => Ok(Self::Delegation),
+            x => Err(ParseOperationKind(x.to_string()))
+        }
+    }
+}
+
+/// How the account takes part in the operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountRole {
+    Source,
+    Destination,
+    Delegate,
+    Originated,
+}
+
+/// Applied operation and its position in the block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Getters, CopyGetters)]
+pub struct IndexedOperation {
+    #[get = "pub"]
+    operation_hash: OperationHash,
+    #[get = "pub"]
+    block_hash: BlockHash,
+    #[get_copy = "pub"]
+    level: BlockLevel,
+    #[get_copy = "pub"]
+    validation_pass: u8,
+    /// Index of the operation in the validation pass
+    #[get_copy = "pub"]
+    index: u32,
+    /// Kinds of the operation contents
+    #[get = "pub"]
+    kinds: Vec<OperationKind>,
+    /// Roles of the account, if the operation was found by the account
+    #[get = "pub"]
+    roles: Vec<AccountRole>,
+}
+
+impl IndexedOperation {
+    fn position(&self) -> OperationPosition {
+        OperationPosition {
+            level: self.level,
+            block_hash: self.block_hash.clone(),
+            validation_pass: self.validation_pass,
+            index: self.index,
+        }
+    }
+}
+
+impl BincodeEncoded for IndexedOperation {}
+
+/// Index operations by the operation hash as `operation_hash, block_hash -> operation`
+pub struct OperationByHashIndex;
+
+impl KeyValueSchema for OperationByHashIndex {
+    type Key = OperationByHashKey;
+    type Value = IndexedOperation;
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(OperationByHashKey::LEN_OPERATION_HASH));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(OperationByHashKey::LEN_OPERATION_HASH)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "operation_by_hash_index"
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct OperationByHashKey {
+    operation_hash: OperationHash,
+    block_hash: BlockHash,
+}
+
+impl OperationByHashKey {
+    const LEN_OPERATION_HASH: usize = HashType::OperationHash.size();
+    const LEN_BLOCK_HASH: usize = HashType::BlockHash.size();
+
+    /// Empty block hash can be used to get the first key of the operation
+    pub fn new(operation_hash: OperationHash, block_hash: BlockHash) -> Self {
+        Self { operation_hash, block_hash }
+    }
+}
+
+/// * bytes layout `[operation_hash(32)][block_hash(32)]`, block hash is optional for prefix queries
+impl Encoder for OperationByHashKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        if self.operation_hash.len() == Self::LEN_OPERATION_HASH {
+            let mut bytes = Vec::with_capacity(Self::LEN_OPERATION_HASH + Self::LEN_BLOCK_HASH);
+            bytes.extend(&self.operation_hash);
+            bytes.extend(&self.block_hash);
+            Ok(bytes)
+        } else {
+            Err(SchemaError::EncodeError)
+        }
+    }
+}
+
+impl Decoder for OperationByHashKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() == Self::LEN_OPERATION_HASH + Self::LEN_BLOCK_HASH {
+            Ok(Self {
+                operation_hash: bytes[..Self::LEN_OPERATION_HASH].to_vec(),
+                block_hash: bytes[Self::LEN_OPERATION_HASH..].to_vec(),
+            })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
+/// Position of the operation, which defines order of the operations in the account and kind indexes
+#[derive(PartialEq, Debug)]
+pub struct OperationPosition {
+    level: BlockLevel,
+    block_hash: BlockHash,
+    validation_pass: u8,
+    index: u32,
+}
+
+impl OperationPosition {
+    const LEN_LEVEL: usize = mem::size_of::<BlockLevel>();
+    const LEN_BLOCK_HASH: usize = HashType::BlockHash.size();
+    const LEN_VALIDATION_PASS: usize = mem::size_of::<u8>();
+    const LEN_INDEX: usize = mem::size_of::<u32>();
+    const LEN_POSITION: usize = Self::LEN_LEVEL + Self::LEN_BLOCK_HASH + Self::LEN_VALIDATION_PASS + Self::LEN_INDEX;
+
+    const IDX_LEVEL: usize = 0;
+    const IDX_BLOCK_HASH: usize = Self::IDX_LEVEL + Self::LEN_LEVEL;
+    const IDX_VALIDATION_PASS: usize = Self::IDX_BLOCK_HASH + Self::LEN_BLOCK_HASH;
+    const IDX_INDEX: usize = Self::IDX_VALIDATION_PASS + Self::LEN_VALIDATION_PASS;
+
+    /// Position used to seek to the first operation of the level, it is encoded just as the level
+    pub fn first_of_level(level: BlockLevel) -> Self {
+        Self { level, block_hash: Vec::new(), validation_pass: 0, index: 0 }
+    }
+}
+
+/// * bytes layout `[level(4)][block_hash(32)][validation_pass(1)][index(4)]`, everything after the level is omitted for the empty block hash
+impl Encoder for OperationPosition {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut bytes = Vec::with_capacity(Self::LEN_POSITION);
+        bytes.extend(&self.level.to_be_bytes());
+        if !self.block_hash.is_empty() {
+            bytes.extend(&self.block_hash);
+            bytes.push(self.validation_pass);
+            bytes.extend(&self.index.to_be_bytes());
+        }
+        Ok(bytes)
+    }
+}
+
+impl Decoder for OperationPosition {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() == Self::LEN_POSITION {
+            Ok(Self {
+                level: num_from_slice!(bytes, Self::IDX_LEVEL, i32),
+                block_hash: bytes[Self::IDX_BLOCK_HASH..Self::IDX_VALIDATION_PASS].to_vec(),
+                validation_pass: bytes[Self::IDX_VALIDATION_PASS],
+                index: num_from_slice!(bytes, Self::IDX_INDEX, u32),
+            })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
+/// Index operations by the account as `account, level, block_hash, validation_pass, index -> operation`
+pub struct OperationByAccountIndex;
+
+impl KeyValueSchema for OperationByAccountIndex {
+    type Key = OperationByAccountKey;
+    type Value = IndexedOperation;
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(OperationByAccountKey::LEN_ACCOUNT));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(OperationByAccountKey::LEN_ACCOUNT)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "operation_by_account_index"
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct OperationByAccountKey {
+    account: ContractAddress,
+    position: OperationPosition,
+}
+
+impl OperationByAccountKey {
+    const LEN_ACCOUNT: usize = 22;
+
+    pub fn new(account: &[u8], position: OperationPosition) -> Self {
+        Self { account: account.to_vec(), position }
+    }
+}
+
+/// * bytes layout `[account(22)][level(4)][block_hash(32)][validation_pass(1)][index(4)]`
+impl Encoder for OperationByAccountKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        if self.account.len() == Self::LEN_ACCOUNT {
+            let mut bytes = self.account.clone();
+            bytes.extend(self.position.encode()?);
+            Ok(bytes)
+        } else {
+            Err(SchemaError::EncodeError)
+        }
+    }
+}
+
+impl Decoder for OperationByAccountKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() > Self::LEN_ACCOUNT {
+            Ok(Self {
+                account: bytes[..Self::LEN_ACCOUNT].to_vec(),
+                position: OperationPosition::decode(&bytes[Self::LEN_ACCOUNT..])?,
+            })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
+/// Index operations by the kind of their contents as `kind, level, block_hash, validation_pass, index -> operation`
+pub struct OperationByKindIndex;
+
+impl KeyValueSchema for OperationByKindIndex {
+    type Key = OperationByKindKey;
+    type Value = IndexedOperation;
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(OperationByKindKey::LEN_KIND));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(OperationByKindKey::LEN_KIND)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "operation_by_kind_index"
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct OperationByKindKey {
+    kind: OperationKind,
+    position: OperationPosition,
+}
+
+impl OperationByKindKey {
+    const LEN_KIND: usize = mem::size_of::<u8>();
+
+    pub fn new(kind: OperationKind, position: OperationPosition) -> Self {
+        Self { kind, position }
+    }
+}
+
+/// * bytes layout `[kind(1)][level(4)][block_hash(32)][validation_pass(1)][index(4)]`
+impl Encoder for OperationByKindKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut bytes = vec![self.kind as u8];
+        bytes.extend(self.position.encode()?);
+        Ok(bytes)
+    }
+}
+
+impl Decoder for OperationByKindKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() > Self::LEN_KIND {
+            Ok(Self {
+                kind: OperationKind::from_u8(bytes[0]).ok_or(SchemaError::DecodeError)?,
+                position: OperationPosition::decode(&bytes[Self::LEN_KIND..])?,
+            })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
+
+/// Index operations by the account and the kind of their contents as `account, kind, level, block_hash, validation_pass, index -> operation`
+pub struct OperationByAccountKindIndex;
+
+impl KeyValueSchema for OperationByAccountKindIndex {
+    type Key = OperationByAccountKindKey;
+    type Value = IndexedOperation;
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(OperationByAccountKindKey::LEN_ACCOUNT_KIND));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(OperationByAccountKindKey::LEN_ACCOUNT_KIND)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "operation_by_account_kind_index"
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub struct OperationByAccountKindKey {
+    account: ContractAddress,
+    kind: OperationKind,
+    position: OperationPosition,
+}
+
+impl OperationByAccountKindKey {
+    const LEN_ACCOUNT_KIND: usize = OperationByAccountKey::LEN_ACCOUNT + OperationByKindKey::LEN_KIND;
+
+    pub fn new(account: &[u8], kind: OperationKind, position: OperationPosition) -> Self {
+        Self { account: account.to_vec(), kind, position }
+    }
+}
+
+/// * bytes layout `[account(22)][kind(1)][level(4)][block_hash(32)][validation_pass(1)][index(4)]`
+impl Encoder for OperationByAccountKindKey {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        if self.account.len() == OperationByAccountKey::LEN_ACCOUNT {
+            let mut bytes = self.account.clone();
+            bytes.push(self.kind as u8);
+            bytes.extend(self.position.encode()?);
+            Ok(bytes)
+        } else {
+            Err(SchemaError::EncodeError)
+        }
+    }
+}
+
+impl Decoder for OperationByAccountKindKey {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() > Self::LEN_ACCOUNT_KIND {
+            Ok(Self {
+                account: bytes[..OperationByAccountKey::LEN_ACCOUNT].to_vec(),
+                kind: OperationKind::from_u8(bytes[OperationByAccountKey::LEN_ACCOUNT]).ok_or(SchemaError::DecodeError)?,
+                position: OperationPosition::decode(&bytes[Self::LEN_ACCOUNT_KIND..])?,
+            })
+        } else {
+            Err(SchemaError::DecodeError)
+        }
+    }
+}
@@ -11,7 +11,7 @@ use tezos_messages::p2p::encoding::prelude::*;
 
 use crate::{BlockHeaderWithHash, StorageError};
 use crate::num_from_slice;
-use crate::persistent::{Decoder, default_table_options, Encoder, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError};
+use crate::persistent::{DbTuning, Decoder, default_table_options, Encoder, KeyValueColumn, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError, WriteBatch};
 use crate::persistent::database::{IteratorMode, IteratorWithSchema};
 
 /// Convenience type for operation meta storage database
@@ -73,6 +73,13 @@ impl OperationsMetaStorage {
             .map_err(StorageError::from)
     }
 
+    /// Same as `put`, but metadata are merged when the batch is written
+    #[inline]
+    pub fn put_to_batch(&self, batch: &mut WriteBatch, block_hash: &BlockHash, meta: &Meta) -> Result<(), StorageError> {
+        batch.merge::<OperationsMetaStorage>(block_hash, meta)
+            .map_err(StorageError::from)
+    }
+
     #[inline]
     pub fn get(&self, block_hash: &BlockHash) -> Result<Option<Meta>, StorageError> {
         self.kv.get(block_hash)
@@ -85,6 +92,19 @@ impl OperationsMetaStorage {
             .map_err(StorageError::from)
     }
 
+    /// Overwrite stored metadata, unlike `put` it does not merge values, so flags can be cleared
+    #[inline]
+    pub fn replace(&self, block_hash: &BlockHash, meta: &Meta) -> Result<(), StorageError> {
+        self.kv.put(block_hash, meta)
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn delete(&self, block_hash: &BlockHash) -> Result<(), StorageError> {
+        self.kv.delete(block_hash)
+            .map_err(StorageError::from)
+    }
+
     #[inline]
     pub fn iter(&self, mode: IteratorMode<Self>) -> Result<IteratorWithSchema<Self>, StorageError> {
         self.kv.iterator(mode)
@@ -96,12 +116,17 @@ impl KeyValueSchema for OperationsMetaStorage {
     type Key = BlockHash;
     type Value = Meta;
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        let mut cf_opts = default_table_options();
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
         cf_opts.set_merge_operator("operations_meta_storage_merge_operator", merge_meta_value, None);
         ColumnFamilyDescriptor::new(Self::name(), cf_opts)
     }
 
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name())
+            .with_merge_operator(|existing_val, operands| merge_meta_operands(existing_val, operands.iter().cloned()))
+    }
+
     #[inline]
     fn name() -> &'static str {
         "operations_meta_storage"
@@ -109,6 +134,10 @@ impl KeyValueSchema for OperationsMetaStorage {
 }
 
 fn merge_meta_value(_new_key: &[u8], existing_val: Option<&[u8]>, operands: &mut MergeOperands) -> Option<Vec<u8>> {
+    merge_meta_operands(existing_val, operands)
+}
+
+fn merge_meta_operands<'a, I: IntoIterator<Item=&'a [u8]>>(existing_val: Option<&[u8]>, operands: I) -> Option<Vec<u8>> {
     let mut result = existing_val.map(|v| v.to_vec());
 
     for op in operands {
@@ -170,6 +199,25 @@ impl Meta {
         }
     }
 
+    #[inline]
+    pub fn validation_passes(&self) -> u8 {
+        self.validation_passes
+    }
+
+    #[inline]
+    pub fn is_validation_pass_present(&self, validation_pass: u8) -> bool {
+        self.is_validation_pass_present.get(validation_pass as usize)
+            .map_or(false, |is_present| *is_present == (true as u8))
+    }
+
+    /// Mark validation pass as not downloaded yet, so it is requested again
+    pub fn set_validation_pass_missing(&mut self, validation_pass: u8) {
+        if let Some(is_present) = self.is_validation_pass_present.get_mut(validation_pass as usize) {
+            *is_present = false as u8;
+            self.is_complete = false;
+        }
+    }
+
     #[inline]
     pub fn level(&self) -> i32 {
         self.level
@@ -241,7 +289,7 @@ mod tests {
 
     use crypto::hash::HashType;
 
-    use crate::persistent::{DbConfiguration, open_kv};
+    use crate::persistent::{DbConfiguration, DbTuning, open_kv};
     use crate::tests_common::TmpStorage;
 
     use super::*;
@@ -337,7 +385,7 @@ mod tests {
             let t = true as u8;
             let f = false as u8;
 
-            let db = open_kv(path, vec![OperationsMetaStorage::descriptor()], &DbConfiguration::default())?;
+            let db = open_kv(path, vec![OperationsMetaStorage::descriptor(&DbTuning::default())], &DbConfiguration::default())?;
             let k = vec![3, 1, 3, 3, 7];
             let mut v = Meta {
                 is_complete: false,
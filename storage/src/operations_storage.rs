@@ -9,7 +9,7 @@ use crypto::hash::{BlockHash, HashType};
 use tezos_messages::p2p::binary_message::BinaryMessage;
 use tezos_messages::p2p::encoding::prelude::*;
 
-use crate::persistent::{Decoder, default_table_options, Encoder, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError};
+use crate::persistent::{DbTuning, Decoder, default_table_options, Encoder, KeyValueColumn, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, SchemaError};
 use crate::StorageError;
 
 pub type OperationsStorageKV = dyn KeyValueStoreWithSchema<OperationsStorage> + Sync + Send;
@@ -44,6 +44,24 @@ impl OperationsStorage {
         self.kv.put(key, value)
             .map_err(StorageError::from)
     }
+
+    /// Remove operations of all validation passes stored for the block, returns number of removed validation passes
+    #[inline]
+    pub fn delete_operations(&self, block_hash: &BlockHash) -> Result<usize, StorageError> {
+        let key = OperationKey {
+            block_hash: block_hash.clone(),
+            validation_pass: 0,
+        };
+
+        let keys = self.kv.prefix_iterator(&key)?
+            .map(|(key, _)| key)
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in &keys {
+            self.kv.delete(key)?;
+        }
+
+        Ok(keys.len())
+    }
 }
 
 impl OperationsStorageReader for OperationsStorage {
@@ -75,13 +93,17 @@ impl KeyValueSchema for OperationsStorage {
     type Key = OperationKey;
     type Value = OperationsForBlocksMessage;
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        let mut cf_opts = default_table_options();
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
         cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(HashType::BlockHash.size()));
         cf_opts.set_memtable_prefix_bloom_ratio(0.2);
         ColumnFamilyDescriptor::new(Self::name(), cf_opts)
     }
 
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(HashType::BlockHash.size())
+    }
+
     #[inline]
     fn name() -> &'static str {
         "operations_storage"
@@ -0,0 +1,214 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Address book of the remote peers.
+//!
+//! Addresses of the peers are kept across restarts, so the node can reconnect to the peers it already knows
+//! instead of waiting for the DNS bootstrap or the peers configured with `--peers`.
+
+use std::cmp::Ordering;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+use getset::{CopyGetters, Getters};
+use rocksdb::ColumnFamilyDescriptor;
+use serde::{Deserialize, Serialize};
+
+use crate::IteratorMode;
+use crate::persistent::{BincodeEncoded, DbTuning, default_table_options, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage, WriteBatch};
+use crate::StorageError;
+
+pub type PeerStorageKV = dyn KeyValueStoreWithSchema<PeerStorage> + Sync + Send;
+
+/// Maximal number of addresses in the address book, advertised addresses are not added above it
+pub const MAX_PEER_RECORDS: usize = 4_096;
+
+/// What we know about the peer at an address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Getters, CopyGetters)]
+pub struct PeerRecord {
+    /// Last time the address was advertised to us or we connected to it
+    #[get_copy = "pub"]
+    last_seen: SystemTime,
+    /// Last time the connection to the peer succeeded
+    #[get_copy = "pub"]
+    last_connected: Option<SystemTime>,
+    /// Peer id of the last successful connection
+    #[get = "pub"]
+    peer_id: Option<String>,
+    /// Number of failed connections since the last successful one
+    #[get_copy = "pub"]
+    failures: u32,
+    /// Peer is banned until this time
+    #[get_copy = "pub"]
+    banned_until: Option<SystemTime>,
+}
+
+impl PeerRecord {
+    fn new(last_seen: SystemTime) -> Self {
+        PeerRecord {
+            last_seen,
+            last_connected: None,
+            peer_id: None,
+            failures: 0,
+            banned_until: None,
+        }
+    }
+
+    pub fn is_banned(&self, now: SystemTime) -> bool {
+        self.banned_until.map_or(false, |banned_until| banned_until > now)
+    }
+
+    /// Order by reliability, the most reliable first: the fewest failures, then the most recently connected and seen
+    fn cmp_reliability(&self, other: &PeerRecord) -> Ordering {
+        self.failures.cmp(&other.failures)
+            .then_with(|| other.last_connected.cmp(&self.last_connected))
+            .then_with(|| other.last_seen.cmp(&self.last_seen))
+    }
+}
+
+impl BincodeEncoded for PeerRecord {}
+
+impl BincodeEncoded for SocketAddr {}
+
+/// Persistent address book of the remote peers, keyed by the address the peer listens at.
+#[derive(Clone)]
+pub struct PeerStorage {
+    kv: Arc<PeerStorageKV>,
+    /// Number of stored addresses, counted on the first use and then kept up to date by the writes of this storage and its clones
+    count: Arc<Mutex<Option<usize>>>,
+}
+
+impl PeerStorage {
+    pub fn new(persistent_storage: &PersistentStorage) -> Self {
+        Self { kv: persistent_storage.kv(), count: Arc::new(Mutex::new(None)) }
+    }
+
+    #[inline]
+    pub fn get(&self, address: &SocketAddr) -> Result<Option<PeerRecord>, StorageError> {
+        self.kv.get(address)
+            .map_err(StorageError::from)
+    }
+
+    /// Record, that the address was advertised to us
+    pub fn seen(&self, address: &SocketAddr, now: SystemTime) -> Result<(), StorageError> {
+        self.seen_all(std::iter::once(address), now)
+    }
+
+    /// Record, that the addresses were advertised to us, all in one batch.
+    /// New addresses are added only while the address book has less than [MAX_PEER_RECORDS] addresses.
+    pub fn seen_all<'a, I: IntoIterator<Item=&'a SocketAddr>>(&self, addresses: I, now: SystemTime) -> Result<(), StorageError> {
+        let mut stored_count = self.lock_count();
+        let mut count = match *stored_count {
+            Some(count) => count,
+            None => self.kv.iterator(IteratorMode::Start)?.count(),
+        };
+        let mut batch = WriteBatch::new();
+        for address in addresses {
+            let record = match self.get(address)? {
+                Some(record) => PeerRecord { last_seen: now, ..record },
+                None if count < MAX_PEER_RECORDS => {
+                    count += 1;
+                    PeerRecord::new(now)
+                }
+                None => continue,
+            };
+            batch.put::<PeerStorage>(address, &record)?;
+        }
+        self.kv.write_batch(batch)?;
+        *stored_count = Some(count);
+        Ok(())
+    }
+
+    /// Record successful connection to the peer
+    pub fn connected(&self, address: &SocketAddr, peer_id: &str, now: SystemTime) -> Result<(), StorageError> {
+        self.update(address, now, |record| {
+            record.last_seen = now;
+            record.last_connected = Some(now);
+            record.peer_id = Some(peer_id.to_string());
+            record.failures = 0;
+        })
+    }
+
+    /// Record failed connection to the peer, only addresses already in the address book are updated
+    pub fn failed(&self, address: &SocketAddr) -> Result<(), StorageError> {
+        match self.get(address)? {
+            Some(record) => self.kv.put(address, &PeerRecord { failures: record.failures.saturating_add(1), ..record })
+                .map_err(StorageError::from),
+            None => Ok(()),
+        }
+    }
+
+    /// Record ban of all known addresses with the IP address
+    pub fn ban_ip(&self, ip: &IpAddr, banned_until: SystemTime) -> Result<(), StorageError> {
+        for (address, record) in self.records()? {
+            if &address.ip() == ip {
+                self.kv.put(&address, &PeerRecord { banned_until: Some(banned_until), ..record })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All known addresses, the most reliable first
+    pub fn records(&self) -> Result<Vec<(SocketAddr, PeerRecord)>, StorageError> {
+        let mut records = Vec::new();
+        for (key, value) in self.kv.iterator(IteratorMode::Start)? {
+            records.push((key?, value?));
+        }
+        records.sort_by(|(_, a), (_, b)| a.cmp_reliability(b));
+        Ok(records)
+    }
+
+    /// Remove addresses, which were not seen since `seen_before` and are not banned anymore,
+    /// and the least reliable addresses above [MAX_PEER_RECORDS]. Returns number of removed addresses.
+    pub fn prune(&self, seen_before: SystemTime, now: SystemTime) -> Result<usize, StorageError> {
+        let mut stored_count = self.lock_count();
+        let mut batch = WriteBatch::new();
+        let mut kept = 0;
+        for (address, record) in self.records()? {
+            if record.is_banned(now) || (record.last_seen >= seen_before && kept < MAX_PEER_RECORDS) {
+                kept += 1;
+            } else {
+                batch.delete::<PeerStorage>(&address)?;
+            }
+        }
+        let removed = batch.len();
+        self.kv.write_batch(batch)?;
+        *stored_count = Some(kept);
+        Ok(removed)
+    }
+
+    fn update<F: FnOnce(&mut PeerRecord)>(&self, address: &SocketAddr, now: SystemTime, f: F) -> Result<(), StorageError> {
+        let mut stored_count = self.lock_count();
+        let (mut record, added) = match self.get(address)? {
+            Some(record) => (record, false),
+            None => (PeerRecord::new(now), true),
+        };
+        f(&mut record);
+        self.kv.put(address, &record)?;
+        if let (true, Some(count)) = (added, stored_count.as_mut()) {
+            *count += 1;
+        }
+        Ok(())
+    }
+
+    /// Lock of the address count, a panic of the other holder does not make it unusable
+    fn lock_count(&self) -> MutexGuard<Option<usize>> {
+        self.count.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl KeyValueSchema for PeerStorage {
+    type Key = SocketAddr;
+    type Value = PeerRecord;
+
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let cf_opts = default_table_options(tuning, Self::name());
+        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+    }
+
+    #[inline]
+    fn name() -> &'static str {
+        "peer_storage"
+    }
+}
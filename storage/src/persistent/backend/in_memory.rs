@@ -0,0 +1,250 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! In-memory backend, every column is stored in a separate `BTreeMap`.
+//! Nothing is persisted, so it is intended for tests and benchmarks.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::persistent::backend::{BackendIterator, BackendIteratorMode, BatchOperation, KeyValueStoreBackend};
+use crate::persistent::database::{DBError, Direction};
+use crate::persistent::metrics::ColumnProperties;
+use crate::persistent::schema::KeyValueColumn;
+
+type ColumnData = BTreeMap<Vec<u8>, Vec<u8>>;
+type Entry = (Box<[u8]>, Box<[u8]>);
+
+/// How many entries are read by the iterator at once, while the column is locked
+const ITERATOR_CHUNK_SIZE: usize = 256;
+
+struct Column {
+    cfg: KeyValueColumn,
+    data: RwLock<ColumnData>,
+}
+
+/// Key-value store backend, which keeps all data in memory
+pub struct InMemoryBackend {
+    columns: HashMap<&'static str, Column>,
+}
+
+impl InMemoryBackend {
+    /// Create empty store with given columns
+    pub fn new<I>(columns: I) -> Self
+        where
+            I: IntoIterator<Item=KeyValueColumn>
+    {
+        Self {
+            columns: columns.into_iter()
+                .map(|cfg| (cfg.name(), Column { cfg, data: RwLock::new(BTreeMap::new()) }))
+                .collect()
+        }
+    }
+
+    fn column(&self, column: &'static str) -> Result<&Column, DBError> {
+        self.columns.get(column)
+            .ok_or(DBError::MissingColumnFamily { name: column })
+    }
+
+    fn read(&self, column: &'static str) -> Result<(KeyValueColumn, RwLockReadGuard<ColumnData>), DBError> {
+        let column = self.column(column)?;
+        let data = column.data.read()
+            .map_err(|_| DBError::BackendError { reason: format!("lock of column {} is poisoned", column.cfg.name()) })?;
+        Ok((column.cfg, data))
+    }
+
+    fn write(&self, column: &'static str) -> Result<(KeyValueColumn, RwLockWriteGuard<ColumnData>), DBError> {
+        let column = self.column(column)?;
+        let data = column.data.write()
+            .map_err(|_| DBError::BackendError { reason: format!("lock of column {} is poisoned", column.cfg.name()) })?;
+        Ok((column.cfg, data))
+    }
+}
+
+impl KeyValueStoreBackend for InMemoryBackend {
+    fn put(&self, column: &'static str, key: &[u8], value: &[u8]) -> Result<(), DBError> {
+        let (_, mut data) = self.write(column)?;
+        data.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, column: &'static str, key: &[u8]) -> Result<(), DBError> {
+        let (_, mut data) = self.write(column)?;
+        data.remove(key);
+        Ok(())
+    }
+
+    fn merge(&self, column: &'static str, key: &[u8], value: &[u8]) -> Result<(), DBError> {
+        let (cfg, mut data) = self.write(column)?;
+        merge(&cfg, &mut data, key, value)
+    }
+
+    fn get(&self, column: &'static str, key: &[u8]) -> Result<Option<Vec<u8>>, DBError> {
+        let (_, data) = self.read(column)?;
+        Ok(data.get(key).cloned())
+    }
+
+    /// Entries are read lazily, see [ColumnIterator]
+    fn iterator<'a>(&'a self, column: &'static str, mode: BackendIteratorMode) -> Result<BackendIterator<'a>, DBError> {
+        let column = self.column(column)?;
+
+        let iterator = match mode {
+            BackendIteratorMode::Start => ColumnIterator::new(column, Bound::Unbounded, Direction::Forward, None),
+            BackendIteratorMode::End => ColumnIterator::new(column, Bound::Unbounded, Direction::Reverse, None),
+            BackendIteratorMode::From(key, direction) => ColumnIterator::new(column, Bound::Included(key.to_vec()), direction, None),
+        };
+
+        Ok(Box::new(iterator))
+    }
+
+    /// Same as with RocksDB, if column has no prefix defined, all entries from the key to the end are returned
+    fn prefix_iterator<'a>(&'a self, column: &'static str, key: &[u8]) -> Result<BackendIterator<'a>, DBError> {
+        let column = self.column(column)?;
+
+        let prefix = match column.cfg.prefix_len() {
+            Some(prefix_len) if prefix_len <= key.len() => Some(key[..prefix_len].to_vec()),
+            _ => None,
+        };
+
+        Ok(Box::new(ColumnIterator::new(column, Bound::Included(key.to_vec()), Direction::Forward, prefix)))
+    }
+
+    fn contains(&self, column: &'static str, key: &[u8]) -> Result<bool, DBError> {
+        let (_, data) = self.read(column)?;
+        Ok(data.contains_key(key))
+    }
+
+    /// All involved columns are locked for the whole batch, so readers see either none or all changes.
+    /// Changes are prepared first and applied only if all operations (e.g. merges) succeed.
+    fn write_batch(&self, batch: Vec<BatchOperation>) -> Result<(), DBError> {
+        // columns are always locked in the same order to avoid deadlocks
+        let columns: BTreeSet<&'static str> = batch.iter()
+            .map(|operation| match operation {
+                BatchOperation::Put { column, .. } | BatchOperation::Delete { column, .. } | BatchOperation::Merge { column, .. } => *column,
+            })
+            .collect();
+        let mut columns = columns.into_iter()
+            .map(|column| Ok((column, self.write(column)?)))
+            .collect::<Result<HashMap<_, _>, DBError>>()?;
+
+        // new value of every changed key, `None` for the deleted ones
+        let mut changes: HashMap<(&'static str, Vec<u8>), Option<Vec<u8>>> = HashMap::new();
+        for operation in batch {
+            match operation {
+                BatchOperation::Put { column, key, value } => {
+                    changes.insert((column, key), Some(value));
+                }
+                BatchOperation::Delete { column, key } => {
+                    changes.insert((column, key), None);
+                }
+                BatchOperation::Merge { column, key, value } => {
+                    let (cfg, data) = columns.get(column).expect("Column is locked");
+                    let merged_value = match changes.get(&(column, key.clone())) {
+                        Some(changed) => merged(cfg, changed.as_deref(), &value)?,
+                        None => merged(cfg, data.get(&key).map(|value| value.as_slice()), &value)?,
+                    };
+                    changes.insert((column, key), Some(merged_value));
+                }
+            }
+        }
+
+        for ((column, key), value) in changes {
+            let (_, data) = columns.get_mut(column).expect("Column is locked");
+            match value {
+                Some(value) => data.insert(key, value),
+                None => data.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), DBError> {
+        Ok(())
+    }
+
+    fn column_properties(&self, column: &'static str) -> Result<ColumnProperties, DBError> {
+        let (_, data) = self.read(column)?;
+        Ok(ColumnProperties {
+            estimated_keys: Some(data.len() as u64),
+            ..ColumnProperties::default()
+        })
+    }
+}
+
+fn merge(cfg: &KeyValueColumn, data: &mut ColumnData, key: &[u8], value: &[u8]) -> Result<(), DBError> {
+    let merged = merged(cfg, data.get(key).map(|value| value.as_slice()), value)?;
+    data.insert(key.to_vec(), merged);
+    Ok(())
+}
+
+/// Result of the merge operator of the column applied to the existing value
+fn merged(cfg: &KeyValueColumn, existing: Option<&[u8]>, value: &[u8]) -> Result<Vec<u8>, DBError> {
+    let merge_operator = cfg.merge_operator()
+        .ok_or_else(|| DBError::BackendError { reason: format!("column {} has no merge operator", cfg.name()) })?;
+
+    merge_operator(existing, &[value])
+        .ok_or_else(|| DBError::BackendError { reason: format!("merge operator of column {} failed", cfg.name()) })
+}
+
+/// Iterator over the column, which reads entries in chunks of [ITERATOR_CHUNK_SIZE].
+/// Column is locked just while the chunk is read, so the column can be modified during the iteration
+/// (changes after the last read chunk are visible to the iterator), but the whole column is never copied.
+struct ColumnIterator<'a> {
+    column: &'a Column,
+    direction: Direction,
+    /// Bound of the next chunk, it starts after the last returned key
+    next: Bound<Vec<u8>>,
+    /// Iteration ends at the first key without this prefix
+    prefix: Option<Vec<u8>>,
+    chunk: std::vec::IntoIter<Entry>,
+    finished: bool,
+}
+
+impl<'a> ColumnIterator<'a> {
+    fn new(column: &'a Column, start: Bound<Vec<u8>>, direction: Direction, prefix: Option<Vec<u8>>) -> Self {
+        Self { column, direction, next: start, prefix, chunk: Vec::new().into_iter(), finished: false }
+    }
+
+    fn read_chunk(&mut self) {
+        // poisoned lock is still readable, iterator cannot return an error
+        let data = self.column.data.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let prefix = self.prefix.as_deref();
+        let has_prefix = |(key, _): &(&Vec<u8>, &Vec<u8>)| prefix.map_or(true, |prefix| key.starts_with(prefix));
+
+        let entries: Vec<Entry> = match self.direction {
+            Direction::Forward => data.range::<Vec<u8>, _>((self.next.clone(), Bound::Unbounded))
+                .take_while(has_prefix)
+                .take(ITERATOR_CHUNK_SIZE)
+                .map(|(key, value)| (key.clone().into_boxed_slice(), value.clone().into_boxed_slice()))
+                .collect(),
+            Direction::Reverse => data.range::<Vec<u8>, _>((Bound::Unbounded, self.next.clone()))
+                .rev()
+                .take_while(has_prefix)
+                .take(ITERATOR_CHUNK_SIZE)
+                .map(|(key, value)| (key.clone().into_boxed_slice(), value.clone().into_boxed_slice()))
+                .collect(),
+        };
+
+        self.finished = entries.len() < ITERATOR_CHUNK_SIZE;
+        if let Some((key, _)) = entries.last() {
+            self.next = Bound::Excluded(key.to_vec());
+        }
+        self.chunk = entries.into_iter();
+    }
+}
+
+impl<'a> Iterator for ColumnIterator<'a> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.chunk.next() {
+            return Some(entry);
+        }
+        if self.finished {
+            return None;
+        }
+        self.read_chunk();
+        self.chunk.next()
+    }
+}
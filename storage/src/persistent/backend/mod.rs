@@ -0,0 +1,86 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Backends of the [KeyValueStore](crate::persistent::KeyValueStore).
+//!
+//! Backend works just with raw bytes, encoding and decoding of keys and values is done by the
+//! [KeyValueStoreWithSchema](crate::persistent::KeyValueStoreWithSchema) implementation of the store.
+//! Data of every [schema](crate::persistent::KeyValueSchema) are stored in a separate column
+//! identified by the schema name.
+
+use std::path::Path;
+
+pub use in_memory::InMemoryBackend;
+
+use crate::persistent::database::{DBError, Direction};
+use crate::persistent::metrics::{CacheStats, ColumnProperties};
+
+pub mod in_memory;
+pub mod rocks_db;
+
+/// Iterator over raw keys and values of the column
+pub type BackendIterator<'a> = Box<dyn Iterator<Item=(Box<[u8]>, Box<[u8]>)> + 'a>;
+
+/// Backend iterator mode, from start to end, from end to start or from specific key to end/start
+pub enum BackendIteratorMode<'a> {
+    Start,
+    End,
+    From(&'a [u8], Direction),
+}
+
+/// Operation of the [write batch](KeyValueStoreBackend::write_batch)
+pub enum BatchOperation {
+    Put { column: &'static str, key: Vec<u8>, value: Vec<u8> },
+    Delete { column: &'static str, key: Vec<u8> },
+    Merge { column: &'static str, key: Vec<u8>, value: Vec<u8> },
+}
+
+/// Raw key-value store, which can be used as a backend of the [KeyValueStore](crate::persistent::KeyValueStore)
+pub trait KeyValueStoreBackend: Send + Sync {
+    /// Insert key value pair into the column, overriding existing value if exists.
+    fn put(&self, column: &'static str, key: &[u8], value: &[u8]) -> Result<(), DBError>;
+
+    /// Delete value associated with given key from the column.
+    fn delete(&self, column: &'static str, key: &[u8]) -> Result<(), DBError>;
+
+    /// Merge value with the existing value by the merge operator of the column.
+    fn merge(&self, column: &'static str, key: &[u8], value: &[u8]) -> Result<(), DBError>;
+
+    /// Read value associated with given key, if exists.
+    fn get(&self, column: &'static str, key: &[u8]) -> Result<Option<Vec<u8>>, DBError>;
+
+    /// Read all entries of the column in given mode.
+    fn iterator<'a>(&'a self, column: &'static str, mode: BackendIteratorMode) -> Result<BackendIterator<'a>, DBError>;
+
+    /// Starting from given key, read all entries with the same prefix (as defined by the column).
+    fn prefix_iterator<'a>(&'a self, column: &'static str, key: &[u8]) -> Result<BackendIterator<'a>, DBError>;
+
+    /// Check, if column contains given key
+    fn contains(&self, column: &'static str, key: &[u8]) -> Result<bool, DBError>;
+
+    /// Apply all operations atomically, either all of them are written or none
+    fn write_batch(&self, batch: Vec<BatchOperation>) -> Result<(), DBError>;
+
+    /// Flush all buffered data to the persistent storage
+    fn flush(&self) -> Result<(), DBError>;
+
+    /// Make data written by the primary instance visible, only secondary instances have to catch up
+    fn try_catch_up_with_primary(&self) -> Result<(), DBError> {
+        Ok(())
+    }
+
+    /// Size estimates of the column, must be cheap (no scan of the data)
+    fn column_properties(&self, _column: &'static str) -> Result<ColumnProperties, DBError> {
+        Ok(ColumnProperties::default())
+    }
+
+    /// Block cache statistics, if the backend has a block cache
+    fn cache_stats(&self) -> Result<Option<CacheStats>, DBError> {
+        Ok(None)
+    }
+
+    /// Create consistent copy of the whole store in the new directory at `path`, while the store is used
+    fn checkpoint(&self, _path: &Path) -> Result<(), DBError> {
+        Err(DBError::BackendError { reason: "checkpoint is not supported by the backend".to_string() })
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! RocksDB backend, every schema is stored in its own column family.
+
+use std::path::Path;
+
+use rocksdb::{DB, DBRawIterator, WriteBatch, WriteOptions};
+use rocksdb::checkpoint::Checkpoint;
+
+use crate::persistent::backend::{BackendIterator, BackendIteratorMode, BatchOperation, KeyValueStoreBackend};
+use crate::persistent::database::DBError;
+use crate::persistent::metrics::{CacheStats, ColumnProperties};
+
+impl KeyValueStoreBackend for DB {
+    fn put(&self, column: &'static str, key: &[u8], value: &[u8]) -> Result<(), DBError> {
+        let cf = self.cf_handle(column)
+            .ok_or(DBError::MissingColumnFamily { name: column })?;
+
+        self.put_cf_opt(cf, key, value, &default_write_options())
+            .map_err(DBError::from)
+    }
+
+    fn delete(&self, column: &'static str, key: &[u8]) -> Result<(), DBError> {
+        let cf = self.cf_handle(column)
+            .ok_or(DBError::MissingColumnFamily { name: column })?;
+
+        self.delete_cf_opt(cf, key, &default_write_options())
+            .map_err(DBError::from)
+    }
+
+    fn merge(&self, column: &'static str, key: &[u8], value: &[u8]) -> Result<(), DBError> {
+        let cf = self.cf_handle(column)
+            .ok_or(DBError::MissingColumnFamily { name: column })?;
+
+        self.merge_cf_opt(cf, key, value, &default_write_options())
+            .map_err(DBError::from)
+    }
+
+    fn get(&self, column: &'static str, key: &[u8]) -> Result<Option<Vec<u8>>, DBError> {
+        let cf = self.cf_handle(column)
+            .ok_or(DBError::MissingColumnFamily { name: column })?;
+
+        self.get_cf(cf, key)
+            .map_err(DBError::from)
+    }
+
+    fn iterator<'a>(&'a self, column: &'static str, mode: BackendIteratorMode) -> Result<BackendIterator<'a>, DBError> {
+        let cf = self.cf_handle(column)
+            .ok_or(DBError::MissingColumnFamily { name: column })?;
+
+        let iter = match mode {
+            BackendIteratorMode::Start => self.iterator_cf(cf, rocksdb::IteratorMode::Start),
+            BackendIteratorMode::End => self.iterator_cf(cf, rocksdb::IteratorMode::End),
+            BackendIteratorMode::From(key, direction) => self.iterator_cf(cf, rocksdb::IteratorMode::From(key, direction.into()))
+        };
+
+        Ok(Box::new(iter))
+    }
+
+    fn prefix_iterator<'a>(&'a self, column: &'static str, key: &[u8]) -> Result<BackendIterator<'a>, DBError> {
+        let cf = self.cf_handle(column)
+            .ok_or(DBError::MissingColumnFamily { name: column })?;
+
+        Ok(Box::new(self.prefix_iterator_cf(cf, key)))
+    }
+
+    fn contains(&self, column: &'static str, key: &[u8]) -> Result<bool, DBError> {
+        let cf = self.cf_handle(column)
+            .ok_or(DBError::MissingColumnFamily { name: column })?;
+
+        let iter = self.iterator_cf(cf, rocksdb::IteratorMode::From(key, rocksdb::Direction::Forward));
+        let contains = if iter.valid() {
+            let iter: DBRawIterator = iter.into();
+            match iter.key() {
+                Some(key_from_db) => key_from_db == key,
+                None => false
+            }
+        } else {
+            false
+        };
+
+        Ok(contains)
+    }
+
+    fn write_batch(&self, batch: Vec<BatchOperation>) -> Result<(), DBError> {
+        let mut write_batch = WriteBatch::default();
+        for operation in batch {
+            match operation {
+                BatchOperation::Put { column, key, value } => {
+                    let cf = self.cf_handle(column)
+                        .ok_or(DBError::MissingColumnFamily { name: column })?;
+                    write_batch.put_cf(cf, key, value);
+                }
+                BatchOperation::Delete { column, key } => {
+                    let cf = self.cf_handle(column)
+                        .ok_or(DBError::MissingColumnFamily { name: column })?;
+                    write_batch.delete_cf(cf, key);
+                }
+                BatchOperation::Merge { column, key, value } => {
+                    let cf = self.cf_handle(column)
+                        .ok_or(DBError::MissingColumnFamily { name: column })?;
+                    write_batch.merge_cf(cf, key, value);
+                }
+            }
+        }
+
+        self.write_opt(write_batch, &default_write_options())
+            .map_err(DBError::from)
+    }
+
+    fn flush(&self) -> Result<(), DBError> {
+        DB::flush(self)
+            .map_err(DBError::from)
+    }
+
+    fn try_catch_up_with_primary(&self) -> Result<(), DBError> {
+        DB::try_catch_up_with_primary(self)
+            .map_err(DBError::from)
+    }
+
+    fn column_properties(&self, column: &'static str) -> Result<ColumnProperties, DBError> {
+        let cf = self.cf_handle(column)
+            .ok_or(DBError::MissingColumnFamily { name: column })?;
+
+        Ok(ColumnProperties {
+            estimated_keys: self.property_int_value_cf(cf, "rocksdb.estimate-num-keys")?,
+            sst_size: self.property_int_value_cf(cf, "rocksdb.total-sst-files-size")?,
+            memtable_size: self.property_int_value_cf(cf, "rocksdb.cur-size-all-mem-tables")?,
+        })
+    }
+
+    fn cache_stats(&self) -> Result<Option<CacheStats>, DBError> {
+        // available only if statistics are enabled in the database options
+        let statistics = match self.property_value("rocksdb.options-statistics")? {
+            Some(statistics) => statistics,
+            None => return Ok(None),
+        };
+
+        match (statistics_ticker(&statistics, "rocksdb.block.cache.hit"), statistics_ticker(&statistics, "rocksdb.block.cache.miss")) {
+            (Some(hits), Some(misses)) => Ok(Some(CacheStats::new(hits, misses))),
+            _ => Ok(None)
+        }
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<(), DBError> {
+        // sst files are hard-linked (if possible), memtables are flushed before
+        Checkpoint::new(self)?
+            .create_checkpoint(path)
+            .map_err(DBError::from)
+    }
+}
+
+/// Parse ticker value from the statistics dump, ticker line looks like `rocksdb.block.cache.hit COUNT : 42`
+fn statistics_ticker(statistics: &str, name: &str) -> Option<u64> {
+    statistics.lines()
+        .find(|line| line.split_whitespace().next() == Some(name))
+        .and_then(|line| line.rsplit(':').next())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn default_write_options() -> WriteOptions {
+    let mut opts = WriteOptions::default();
+    opts.set_sync(false);
+    opts
+}
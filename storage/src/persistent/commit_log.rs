@@ -1,8 +1,9 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{fmt, io};
+use std::{fmt, fs, io};
 use std::collections::HashMap;
+use std::io::{Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
@@ -13,10 +14,14 @@ use serde::{Deserialize, Serialize};
 
 use crate::persistent::BincodeEncoded;
 use crate::persistent::codec::{Decoder, Encoder, SchemaError};
+use crate::persistent::metrics::{CommitLogStats, LatencyHistogram, StorageMetrics};
 use crate::persistent::schema::{CommitLogDescriptor, CommitLogSchema};
 
 pub type CommitLogRef = Arc<RwLock<CommitLog>>;
 
+/// Maximal size of one record
+const MESSAGE_MAX_BYTES: usize = 10_000_000;
+
 /// Possible errors for commit log
 #[derive(Debug, Fail)]
 pub enum CommitLogError {
@@ -41,6 +46,10 @@ pub enum CommitLogError {
     MissingCommitLog {
         name: &'static str
     },
+    #[fail(display = "Commit log {} is opened read-only", name)]
+    ReadOnly {
+        name: &'static str
+    },
 }
 
 impl From<SchemaError> for CommitLogError {
@@ -65,7 +74,7 @@ type ByteLimit = usize;
 type ItemCount = u32;
 
 /// Precisely identifies location of a record in a commit log.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Location(pub Offset, pub ByteLimit);
 
 impl Location {
@@ -102,11 +111,12 @@ pub trait CommitLogWithSchema<S: CommitLogSchema> {
 
 impl<S: CommitLogSchema> CommitLogWithSchema<S> for CommitLogs {
     fn append(&self, value: &S::Value) -> Result<Location, CommitLogError> {
+        self.check_writable(S::name())?;
         let cl = self.cl_handle(S::name())
             .ok_or(CommitLogError::MissingCommitLog { name: S::name() })?;
         let mut cl = cl.write().expect("Write lock failed");
         let bytes = value.encode()?;
-        let offset = cl.append_msg(&bytes)
+        let offset = self.metrics.write(S::name(), || cl.append_msg(&bytes))
             .map_err(|error| CommitLogError::AppendError { error })?;
 
         Ok(Location(offset, bytes.len()))
@@ -116,7 +126,7 @@ impl<S: CommitLogSchema> CommitLogWithSchema<S> for CommitLogs {
         let cl = self.cl_handle(S::name())
             .ok_or(CommitLogError::MissingCommitLog { name: S::name() })?;
         let cl = cl.read().expect("Read lock failed");
-        let msg_buf = cl.read(location.0, fit_read_limit(location.1))
+        let msg_buf = self.metrics.read(S::name(), || cl.read(location.0, fit_read_limit(location.1)))
             .map_err(|error| CommitLogError::ReadError { error, location: *location })?;
         let bytes = msg_buf.iter().next().ok_or(CommitLogError::ReadError { error: ReadError::CorruptLog, location: *location })?;
         let value = S::Value::decode(bytes.payload())?;
@@ -128,7 +138,7 @@ impl<S: CommitLogSchema> CommitLogWithSchema<S> for CommitLogs {
         let cl = self.cl_handle(S::name())
             .ok_or(CommitLogError::MissingCommitLog { name: S::name() })?;
         let cl = cl.read().expect("Read lock failed");
-        let msg_buf = cl.read(range.0, fit_batch_read_limit(range.1, range.2))
+        let msg_buf = self.metrics.read(S::name(), || cl.read(range.0, fit_batch_read_limit(range.1, range.2)))
             .map_err(|error| CommitLogError::ReadError { error, location: Location(range.0, range.1) })?;
         msg_buf.iter()
             .take(range.2 as usize)
@@ -176,6 +186,9 @@ pub fn fold_consecutive_locations(locations: &[Location]) -> Vec<Range> {
 pub struct CommitLogs {
     base_path: PathBuf,
     commit_log_map: RwLock<HashMap<String, CommitLogRef>>,
+    metrics: StorageMetrics,
+    /// Read-only commit logs are opened from the copy in this directory, so files of the writing process are never modified
+    mirror_path: Option<PathBuf>,
 }
 
 impl CommitLogs {
@@ -183,10 +196,34 @@ impl CommitLogs {
         where
             P: AsRef<Path>,
             I: IntoIterator<Item=CommitLogDescriptor>,
+    {
+        Self::open(path, cfs, None)
+    }
+
+    /// Open commit logs written by another process, records cannot be appended
+    /// and new records are visible after [catch_up](CommitLogs::catch_up).
+    ///
+    /// Commit log library opens its files for writing and repairs them, so files of the writing process at `path`
+    /// are just copied to the `mirror_path` and commit logs are opened from there.
+    pub(crate) fn new_read_only<P, M, I>(path: P, mirror_path: M, cfs: I) -> Result<Self, CommitLogError>
+        where
+            P: AsRef<Path>,
+            M: AsRef<Path>,
+            I: IntoIterator<Item=CommitLogDescriptor>,
+    {
+        Self::open(path, cfs, Some(mirror_path.as_ref().into()))
+    }
+
+    fn open<P, I>(path: P, cfs: I, mirror_path: Option<PathBuf>) -> Result<Self, CommitLogError>
+        where
+            P: AsRef<Path>,
+            I: IntoIterator<Item=CommitLogDescriptor>,
     {
         let myself = Self {
             base_path: path.as_ref().into(),
             commit_log_map: RwLock::new(HashMap::new()),
+            metrics: StorageMetrics::default(),
+            mirror_path,
         };
 
         for descriptor in cfs.into_iter() {
@@ -198,14 +235,7 @@ impl CommitLogs {
 
     /// Register a new commit log.
     fn register(&self, name: &str) -> Result<(), CommitLogError> {
-        let path = self.base_path.join(name);
-        if !Path::new(&path).exists() {
-            std::fs::create_dir_all(&path)?;
-        }
-
-        let mut opts = LogOptions::new(&path);
-        opts.message_max_bytes(10_000_000);
-        let log = CommitLog::new(opts)?;
+        let log = self.open_log(name)?;
 
         let mut commit_log_map = self.commit_log_map.write().unwrap();
         commit_log_map.insert(name.into(), Arc::new(RwLock::new(log)));
@@ -220,8 +250,218 @@ impl CommitLogs {
         commit_log_map.get(name).cloned()
     }
 
+    #[inline]
+    fn cl_handle_or_err(&self, name: &'static str) -> Result<CommitLogRef, CommitLogError> {
+        self.cl_handle(name).ok_or(CommitLogError::MissingCommitLog { name })
+    }
+
+    #[inline]
+    fn check_writable(&self, name: &'static str) -> Result<(), CommitLogError> {
+        if self.is_read_only() {
+            Err(CommitLogError::ReadOnly { name })
+        } else {
+            Ok(())
+        }
+    }
+
+    #[inline]
+    pub fn is_read_only(&self) -> bool {
+        self.mirror_path.is_some()
+    }
+
+    /// Open commit log, read-only commit log is synchronized to the mirror directory at first
+    fn open_log(&self, name: &str) -> Result<CommitLog, CommitLogError> {
+        match &self.mirror_path {
+            Some(mirror_path) => {
+                let mirror_path = mirror_path.join(name);
+                sync_dir(&self.base_path.join(name), &mirror_path)?;
+                open_log(&mirror_path)
+            }
+            None => open_log(&self.base_path.join(name)),
+        }
+    }
+
+    /// Open all registered commit logs again, so records appended by the writing process are visible.
+    /// Intended for commit logs opened by [open_cl_read_only](crate::persistent::open_cl_read_only).
+    pub fn catch_up(&self) -> Result<(), CommitLogError> {
+        let commit_log_map = self.commit_log_map.read().unwrap();
+        for (name, commit_log) in commit_log_map.iter() {
+            let log = self.open_log(name)?;
+            *commit_log.write().expect("Write lock failed") = log;
+        }
+
+        Ok(())
+    }
+
+    /// Offset, which will be assigned to the next appended record
+    pub fn next_offset(&self, name: &'static str) -> Result<Offset, CommitLogError> {
+        let cl = self.cl_handle_or_err(name)?;
+        let cl = cl.read().expect("Read lock failed");
+        Ok(cl.next_offset())
+    }
+
+    /// Size of all files of the commit log in bytes
+    pub fn size(&self, name: &'static str) -> Result<u64, CommitLogError> {
+        dir_size(&self.base_path.join(name))
+    }
+
+    /// Statistics of all registered commit logs
+    pub fn stats(&self) -> Result<Vec<CommitLogStats>, CommitLogError> {
+        let metrics: HashMap<_, _> = self.metrics.columns().into_iter().collect();
+        let mut names: Vec<String> = self.commit_log_map.read().unwrap().keys().cloned().collect();
+        names.sort();
+
+        names.into_iter()
+            .filter_map(|name| self.cl_handle(&name).map(|cl| (name, cl)))
+            .map(|(name, cl)| {
+                let records = cl.read().expect("Read lock failed").next_offset();
+                let (reads, writes) = match metrics.get(name.as_str()) {
+                    Some(metrics) => (metrics.reads().stats(), metrics.writes().stats()),
+                    None => (LatencyHistogram::new().stats(), LatencyHistogram::new().stats()),
+                };
+                Ok(CommitLogStats {
+                    size: dir_size(&self.base_path.join(&name))?,
+                    name,
+                    records,
+                    reads,
+                    writes,
+                })
+            })
+            .collect()
+    }
+
+    /// Remove all records starting at `next_offset` (e.g. partially written records after a crash).
+    /// Returns number of removed records.
+    pub fn truncate(&self, name: &'static str, next_offset: Offset) -> Result<u64, CommitLogError> {
+        self.check_writable(name)?;
+        let cl = self.cl_handle_or_err(name)?;
+        let mut cl = cl.write().expect("Write lock failed");
+        let current_next_offset = cl.next_offset();
+        if next_offset >= current_next_offset {
+            return Ok(0);
+        }
+
+        if next_offset == 0 {
+            // commit log cannot be truncated to be empty, so it is created again
+            let path = self.base_path.join(name);
+            fs::remove_dir_all(&path)?;
+            *cl = open_log(&path)?;
+        } else {
+            cl.truncate(next_offset - 1)?;
+        }
+        cl.flush()?;
+
+        Ok(current_next_offset - next_offset)
+    }
+
+    fn compaction_path(&self, name: &str) -> PathBuf {
+        self.base_path.join(format!("{}.compacting", name))
+    }
+
+    fn compaction_old_path(&self, name: &str) -> PathBuf {
+        self.base_path.join(format!("{}.old", name))
+    }
+
+    fn compaction_journal_path(&self, name: &str) -> PathBuf {
+        self.base_path.join(format!("{}.compaction_journal", name))
+    }
+
+    /// Start compaction of the commit log. Records are copied by [CommitLogCompaction::copy] to a new commit log,
+    /// which replaces the current one in [finish_compaction](CommitLogs::finish_compaction).
+    ///
+    /// Compaction is done in these steps, so it can be recovered after a crash:
+    /// 1. records are copied to the new commit log
+    /// 2. [commit_compaction](CommitLogs::commit_compaction) stores journal - from now on, compaction has to be finished
+    /// 3. caller updates locations according to the journal (this has to be idempotent)
+    /// 4. [finish_compaction](CommitLogs::finish_compaction) replaces the commit log and removes the journal
+    ///
+    /// After restart, [compaction_journal](CommitLogs::compaction_journal) tells, if steps 3. and 4. have to be repeated,
+    /// otherwise [abort_compaction](CommitLogs::abort_compaction) cleans up an unfinished compaction.
+    pub fn begin_compaction(&self, name: &'static str) -> Result<CommitLogCompaction, CommitLogError> {
+        self.check_writable(name)?;
+        self.abort_compaction(name)?;
+        Ok(CommitLogCompaction {
+            source: self.cl_handle_or_err(name)?,
+            target: open_log(&self.compaction_path(name))?,
+        })
+    }
+
+    /// Persist the journal needed to finish the compaction, see [begin_compaction](CommitLogs::begin_compaction)
+    pub fn commit_compaction(&self, name: &'static str, compaction: CommitLogCompaction, journal: &[u8]) -> Result<(), CommitLogError> {
+        let CommitLogCompaction { mut target, .. } = compaction;
+        target.flush()?;
+        drop(target);
+
+        // journal is written at once by rename, so it is either complete or missing
+        let journal_path = self.compaction_journal_path(name);
+        let tmp_path = journal_path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(journal)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &journal_path)?;
+
+        Ok(())
+    }
+
+    /// Journal of the committed, but not finished compaction
+    pub fn compaction_journal(&self, name: &'static str) -> Result<Option<Vec<u8>>, CommitLogError> {
+        let journal_path = self.compaction_journal_path(name);
+        if journal_path.exists() {
+            Ok(Some(fs::read(journal_path)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Replace the commit log by the compacted one, see [begin_compaction](CommitLogs::begin_compaction).
+    /// Can be called repeatedly, if it was interrupted.
+    pub fn finish_compaction(&self, name: &'static str) -> Result<(), CommitLogError> {
+        self.check_writable(name)?;
+        let cl = self.cl_handle_or_err(name)?;
+        let mut cl = cl.write().expect("Write lock failed");
+
+        let path = self.base_path.join(name);
+        let compaction_path = self.compaction_path(name);
+        let old_path = self.compaction_old_path(name);
+        if compaction_path.exists() {
+            if old_path.exists() {
+                fs::remove_dir_all(&old_path)?;
+            }
+            if path.exists() {
+                fs::rename(&path, &old_path)?;
+            }
+            fs::rename(&compaction_path, &path)?;
+            *cl = open_log(&path)?;
+        }
+
+        let journal_path = self.compaction_journal_path(name);
+        if journal_path.exists() {
+            fs::remove_file(journal_path)?;
+        }
+        if old_path.exists() {
+            fs::remove_dir_all(&old_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove leftovers of the compaction, which was not committed
+    pub fn abort_compaction(&self, name: &'static str) -> Result<(), CommitLogError> {
+        self.check_writable(name)?;
+        for path in &[self.compaction_path(name), self.compaction_old_path(name)] {
+            if path.exists() {
+                fs::remove_dir_all(path)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Flush all registered commit logs.
     pub fn flush(&self) -> Result<(), CommitLogError> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+
         let commit_log_map = self.commit_log_map.read().unwrap();
         for commit_log in commit_log_map.values() {
             let mut commit_log = commit_log.write().unwrap();
@@ -230,6 +470,118 @@ impl CommitLogs {
 
         Ok(())
     }
+
+    /// Copy all registered commit logs to the directory at `path`, every commit log to its own subdirectory.
+    /// Commit log is locked during its copy, so it is copied with all records appended before the copy started.
+    pub fn copy_to(&self, path: &Path) -> Result<(), CommitLogError> {
+        let commit_log_map = self.commit_log_map.read().unwrap();
+        for (name, commit_log) in commit_log_map.iter() {
+            let mut commit_log = commit_log.write().expect("Write lock failed");
+            if !self.is_read_only() {
+                commit_log.flush()?;
+            }
+
+            let target_path = path.join(name);
+            fs::create_dir_all(&target_path)?;
+            for entry in fs::read_dir(self.base_path.join(name))? {
+                let entry = entry?;
+                if entry.metadata()?.is_file() {
+                    fs::copy(entry.path(), target_path.join(entry.file_name()))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Commit log being compacted, see [CommitLogs::begin_compaction]
+pub struct CommitLogCompaction {
+    source: CommitLogRef,
+    target: CommitLog,
+}
+
+impl CommitLogCompaction {
+    /// Copy record from the current commit log to the compacted one, returns new location of the record
+    pub fn copy(&mut self, location: &Location) -> Result<Location, CommitLogError> {
+        let source = self.source.read().expect("Read lock failed");
+        let msg_buf = source.read(location.0, fit_read_limit(location.1))
+            .map_err(|error| CommitLogError::ReadError { error, location: *location })?;
+        let message = msg_buf.iter().next()
+            .filter(|message| message.offset() == location.0)
+            .ok_or(CommitLogError::ReadError { error: ReadError::CorruptLog, location: *location })?;
+        let offset = self.target.append_msg(message.payload())
+            .map_err(|error| CommitLogError::AppendError { error })?;
+
+        Ok(Location(offset, message.payload().len()))
+    }
+}
+
+fn open_log(path: &Path) -> Result<CommitLog, CommitLogError> {
+    if !path.exists() {
+        fs::create_dir_all(path)?;
+    }
+
+    let mut opts = LogOptions::new(path);
+    opts.message_max_bytes(MESSAGE_MAX_BYTES);
+    Ok(CommitLog::new(opts)?)
+}
+
+/// Copy files of the `source` directory, which are missing or differ in the `target` directory, and remove files,
+/// which are not in the `source` anymore (e.g. after compaction). Files of the `source` are opened just for reading.
+///
+/// Segments are only appended to, so just the appended part is copied to a shorter `target` file. Files of the same
+/// length (indexes are preallocated and updated in place) or longer than the `source` (truncated) are copied whole.
+fn sync_dir(source: &Path, target: &Path) -> Result<(), CommitLogError> {
+    fs::create_dir_all(target)?;
+    let mut source_files = Vec::new();
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let source_metadata = entry.metadata()?;
+        if !source_metadata.is_file() {
+            continue;
+        }
+        let target_path = target.join(entry.file_name());
+        match fs::metadata(&target_path) {
+            Ok(target_metadata) if target_metadata.len() < source_metadata.len() => {
+                append_tail(&entry.path(), &target_path, target_metadata.len())?;
+            }
+            Ok(target_metadata) if target_metadata.len() == source_metadata.len()
+                && target_metadata.modified()? >= source_metadata.modified()? => (),
+            _ => {
+                fs::copy(entry.path(), &target_path)?;
+            }
+        }
+        source_files.push(entry.file_name());
+    }
+
+    for entry in fs::read_dir(target)? {
+        let entry = entry?;
+        if !source_files.contains(&entry.file_name()) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Append the content of the `source` file after the `offset` to the `target` file
+fn append_tail(source: &Path, target: &Path, offset: u64) -> Result<(), CommitLogError> {
+    let mut source = fs::File::open(source)?;
+    source.seek(SeekFrom::Start(offset))?;
+    let mut target = fs::OpenOptions::new().append(true).open(target)?;
+    io::copy(&mut source, &mut target)?;
+    Ok(())
+}
+
+fn dir_size(path: &Path) -> Result<u64, CommitLogError> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
 }
 
 impl Drop for CommitLogs {
@@ -287,4 +639,33 @@ mod tests {
             Range(7, 30, 3),
             Range(6, 10, 1)], ranges);
     }
+
+    #[test]
+    fn test_sync_dir_appends_and_removes() -> Result<(), failure::Error> {
+        let source = std::env::temp_dir().join("__commit_log_sync_dir_source");
+        let target = std::env::temp_dir().join("__commit_log_sync_dir_target");
+        for dir in &[&source, &target] {
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+            }
+        }
+        fs::create_dir_all(&source)?;
+        fs::write(source.join("0.log"), vec![1, 2, 3])?;
+        fs::write(source.join("0.index"), vec![0; 4])?;
+        sync_dir(&source, &target)?;
+        assert_eq!(vec![1, 2, 3], fs::read(target.join("0.log"))?);
+
+        // appended segment, truncated index and removed file
+        fs::OpenOptions::new().append(true).open(source.join("0.log"))?.write_all(&[4, 5])?;
+        fs::write(source.join("0.index"), vec![7; 2])?;
+        fs::write(target.join("1.log"), vec![9])?;
+        sync_dir(&source, &target)?;
+        assert_eq!(vec![1, 2, 3, 4, 5], fs::read(target.join("0.log"))?);
+        assert_eq!(vec![7; 2], fs::read(target.join("0.index"))?);
+        assert!(!target.join("1.log").exists());
+
+        fs::remove_dir_all(&source)?;
+        fs::remove_dir_all(&target)?;
+        Ok(())
+    }
 }
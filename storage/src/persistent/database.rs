@@ -2,11 +2,16 @@
 // SPDX-License-Identifier: MIT
 
 use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use failure::Fail;
-use rocksdb::{DB, DBIterator, DBRawIterator, Error, WriteOptions};
+use rocksdb::Error;
 
+use crate::persistent::backend::{BackendIterator, BackendIteratorMode, BatchOperation, KeyValueStoreBackend};
 use crate::persistent::codec::{Decoder, Encoder, SchemaError};
+use crate::persistent::metrics::{CacheStats, ColumnStats, OperationMetrics, StorageMetrics};
 use crate::persistent::schema::KeyValueSchema;
 
 /// Possible errors for schema
@@ -24,6 +29,10 @@ pub enum DBError {
     MissingColumnFamily {
         name: &'static str
     },
+    #[fail(display = "Key-value store backend error: {}", reason)]
+    BackendError {
+        reason: String
+    },
 }
 
 impl From<SchemaError> for DBError {
@@ -38,7 +47,7 @@ impl From<Error> for DBError {
     }
 }
 
-/// Custom trait extending RocksDB to better handle and enforce database schema
+/// Custom trait extending key-value store to better handle and enforce database schema
 pub trait KeyValueStoreWithSchema<S: KeyValueSchema> {
     /// Insert new key value pair into the database. If key already exists, method will fail
     ///
@@ -84,99 +93,182 @@ pub trait KeyValueStoreWithSchema<S: KeyValueSchema> {
     /// # Arguments
     /// * `key` - Key (specified by schema), to be checked for existence
     fn contains(&self, key: &S::Key) -> Result<bool, DBError>;
+
+    /// Write all operations of the batch atomically. Batch can contain operations of any schema.
+    ///
+    /// # Arguments
+    /// * `batch` - Operations to be written
+    fn write_batch(&self, batch: WriteBatch) -> Result<(), DBError>;
+}
+
+/// Operations of multiple schemas, which are written atomically by [KeyValueStoreWithSchema::write_batch]
+#[derive(Default)]
+pub struct WriteBatch {
+    operations: Vec<BatchOperation>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert key value pair, overriding existing value if exists
+    pub fn put<S: KeyValueSchema>(&mut self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        self.operations.push(BatchOperation::Put { column: S::name(), key: key.encode()?, value: value.encode()? });
+        Ok(())
+    }
+
+    /// Delete value associated with given key
+    pub fn delete<S: KeyValueSchema>(&mut self, key: &S::Key) -> Result<(), DBError> {
+        self.operations.push(BatchOperation::Delete { column: S::name(), key: key.encode()? });
+        Ok(())
+    }
+
+    /// Merge value with the existing value by the merge operator of the schema
+    pub fn merge<S: KeyValueSchema>(&mut self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
+        self.operations.push(BatchOperation::Merge { column: S::name(), key: key.encode()?, value: value.encode()? });
+        Ok(())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+/// Key-value store used by all storages, data are stored by the pluggable [backend](KeyValueStoreBackend)
+pub struct KeyValueStore {
+    backend: Box<dyn KeyValueStoreBackend>,
+    metrics: StorageMetrics,
 }
 
-impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for DB {
+impl KeyValueStore {
+    pub fn new<B: KeyValueStoreBackend + 'static>(backend: B) -> Self {
+        Self { backend: Box::new(backend), metrics: StorageMetrics::default() }
+    }
+
+    /// Flush all buffered data to the persistent storage
+    #[inline]
+    pub fn flush(&self) -> Result<(), DBError> {
+        self.backend.flush()
+    }
+
+    /// Make data written by the primary instance visible, see [open_kv_secondary](crate::persistent::open_kv_secondary)
+    #[inline]
+    pub fn try_catch_up_with_primary(&self) -> Result<(), DBError> {
+        self.backend.try_catch_up_with_primary()
+    }
+
+    /// Statistics of all columns used since the start
+    pub fn column_stats(&self) -> Result<Vec<ColumnStats>, DBError> {
+        self.metrics.columns().into_iter()
+            .map(|(name, metrics)| Ok(ColumnStats {
+                name,
+                properties: self.backend.column_properties(name)?,
+                reads: metrics.reads().stats(),
+                writes: metrics.writes().stats(),
+            }))
+            .collect()
+    }
+
+    /// Block cache statistics of the backend
+    #[inline]
+    pub fn cache_stats(&self) -> Result<Option<CacheStats>, DBError> {
+        self.backend.cache_stats()
+    }
+
+    /// Create consistent copy of the store in the new directory at `path`, see [KeyValueStoreBackend::checkpoint]
+    #[inline]
+    pub fn checkpoint(&self, path: &Path) -> Result<(), DBError> {
+        self.backend.checkpoint(path)
+    }
+}
+
+impl<S: KeyValueSchema> KeyValueStoreWithSchema<S> for KeyValueStore {
     fn put(&self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
         let key = key.encode()?;
         let value = value.encode()?;
-        let cf = self.cf_handle(S::name())
-            .ok_or(DBError::MissingColumnFamily { name: S::name() })?;
 
-        self.put_cf_opt(cf, &key, &value, &default_write_options())
-            .map_err(DBError::from)
+        self.metrics.write(S::name(), || self.backend.put(S::name(), &key, &value))
     }
 
     fn delete(&self, key: &S::Key) -> Result<(), DBError> {
         let key = key.encode()?;
-        let cf = self.cf_handle(S::name())
-            .ok_or(DBError::MissingColumnFamily { name: S::name() })?;
 
-        self.delete_cf_opt(cf, &key, &default_write_options())
-            .map_err(DBError::from)
+        self.metrics.write(S::name(), || self.backend.delete(S::name(), &key))
     }
 
     fn merge(&self, key: &S::Key, value: &S::Value) -> Result<(), DBError> {
         let key = key.encode()?;
         let value = value.encode()?;
-        let cf = self.cf_handle(S::name())
-            .ok_or(DBError::MissingColumnFamily { name: S::name() })?;
 
-        self.merge_cf_opt(cf, &key, &value, &default_write_options())
-            .map_err(DBError::from)
+        self.metrics.write(S::name(), || self.backend.merge(S::name(), &key, &value))
     }
 
     fn get(&self, key: &S::Key) -> Result<Option<S::Value>, DBError> {
         let key = key.encode()?;
-        let cf = self.cf_handle(S::name())
-            .ok_or(DBError::MissingColumnFamily { name: S::name() })?;
 
-        self.get_cf(cf, &key)
-            .map_err(DBError::from)?
+        self.metrics.read(S::name(), || self.backend.get(S::name(), &key))?
             .map(|value| S::Value::decode(&value))
             .transpose()
             .map_err(DBError::from)
     }
 
     fn iterator(&self, mode: IteratorMode<S>) -> Result<IteratorWithSchema<S>, DBError> {
-        let cf = self.cf_handle(S::name())
-            .ok_or(DBError::MissingColumnFamily { name: S::name() })?;
-
+        let start = Instant::now();
         let iter = match mode {
-            IteratorMode::Start => self.iterator_cf(cf, rocksdb::IteratorMode::Start),
-            IteratorMode::End => self.iterator_cf(cf, rocksdb::IteratorMode::End),
-            IteratorMode::From(key, direction) => self.iterator_cf(cf, rocksdb::IteratorMode::From(&key.encode()?, direction.into()))
+            IteratorMode::Start => self.backend.iterator(S::name(), BackendIteratorMode::Start)?,
+            IteratorMode::End => self.backend.iterator(S::name(), BackendIteratorMode::End)?,
+            IteratorMode::From(key, direction) => self.backend.iterator(S::name(), BackendIteratorMode::From(&key.encode()?, direction))?,
         };
 
-        Ok(IteratorWithSchema(iter, PhantomData))
+        Ok(IteratorWithSchema::new(iter, self.metrics.column(S::name()), start.elapsed()))
     }
 
     fn prefix_iterator(&self, key: &S::Key) -> Result<IteratorWithSchema<S>, DBError> {
         let key = key.encode()?;
-        let cf = self.cf_handle(S::name())
-            .ok_or(DBError::MissingColumnFamily { name: S::name() })?;
 
-        Ok(IteratorWithSchema(self.prefix_iterator_cf(cf, key), PhantomData))
+        let start = Instant::now();
+        let iter = self.backend.prefix_iterator(S::name(), &key)?;
+
+        Ok(IteratorWithSchema::new(iter, self.metrics.column(S::name()), start.elapsed()))
     }
 
     fn contains(&self, key: &S::Key) -> Result<bool, DBError> {
-        let cf = self.cf_handle(S::name())
-            .ok_or(DBError::MissingColumnFamily { name: S::name() })?;
-
         let key = key.encode()?;
-        let iter = self.iterator_cf(cf, rocksdb::IteratorMode::From(&key, rocksdb::Direction::Forward));
-        let contains = if iter.valid() {
-            let iter: DBRawIterator = iter.into();
-            match iter.key() {
-                Some(key_from_db) => key_from_db == &key[..],
-                None => false
-            }
-        } else {
-            false
-        };
 
-        Ok(contains)
+        self.metrics.read(S::name(), || self.backend.contains(S::name(), &key))
     }
-}
 
-fn default_write_options() -> WriteOptions {
-    let mut opts = WriteOptions::default();
-    opts.set_sync(false);
-    opts
+    fn write_batch(&self, batch: WriteBatch) -> Result<(), DBError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.backend.write_batch(batch.operations)
+    }
 }
 
 /// Database iterator extended by specific schema
-pub struct IteratorWithSchema<'a, S: KeyValueSchema>(DBIterator<'a>, PhantomData<S>);
+///
+/// Time spent in the backend is recorded as a single read of the column, when the iterator is dropped.
+pub struct IteratorWithSchema<'a, S: KeyValueSchema> {
+    iter: BackendIterator<'a>,
+    metrics: Arc<OperationMetrics>,
+    elapsed: Duration,
+    schema: PhantomData<S>,
+}
+
+impl<'a, S: KeyValueSchema> IteratorWithSchema<'a, S> {
+    fn new(iter: BackendIterator<'a>, metrics: Arc<OperationMetrics>, elapsed: Duration) -> Self {
+        Self { iter, metrics, elapsed, schema: PhantomData }
+    }
+}
 
 impl<'a, S: KeyValueSchema> Iterator for IteratorWithSchema<'a, S>
 {
@@ -184,12 +276,22 @@ impl<'a, S: KeyValueSchema> Iterator for IteratorWithSchema<'a, S>
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.next()
-            .map(|(k, v)| (S::Key::decode(&k), S::Value::decode(&v)))
+        let start = Instant::now();
+        let next = self.iter.next();
+        self.elapsed += start.elapsed();
+
+        next.map(|(k, v)| (S::Key::decode(&k), S::Value::decode(&v)))
+    }
+}
+
+impl<'a, S: KeyValueSchema> Drop for IteratorWithSchema<'a, S> {
+    fn drop(&mut self) {
+        self.metrics.reads().record(self.elapsed);
     }
 }
 
 /// Database iterator direction
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Direction {
     Forward,
     Reverse,
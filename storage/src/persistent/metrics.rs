@@ -0,0 +1,232 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Storage metrics collected continuously while the storage is used.
+//!
+//! Every read and write of the [KeyValueStore](crate::persistent::KeyValueStore) and [CommitLogs](crate::persistent::CommitLogs)
+//! is timed and recorded to a latency histogram of the column (or commit log). Iteration is recorded as a single read,
+//! which spans the whole life of the iterator. Recording needs just a shared lock
+//! and atomic increments, so it is cheap enough to be always enabled. Sizes and key counts are estimates provided by the backend
+//! (e.g. RocksDB properties), so collecting of [StorageStats] never scans the data.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Number of histogram buckets, bucket `i` counts latencies below `2^i` microseconds, the last one counts the rest
+const LATENCY_BUCKETS: usize = 24;
+
+/// Histogram of operation latencies with exponential buckets
+pub struct LatencyHistogram {
+    buckets: Vec<AtomicU64>,
+    total_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: (0..LATENCY_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            total_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        let bucket = (64 - micros.leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Summary of recorded latencies, percentiles are upper bounds of the buckets
+    pub fn stats(&self) -> LatencyStats {
+        let buckets: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        let count: u64 = buckets.iter().sum();
+        let percentile = |percentile: u64| {
+            let threshold = (count * percentile + 99) / 100;
+            let mut seen = 0;
+            for (bucket, bucket_count) in buckets.iter().enumerate() {
+                seen += bucket_count;
+                if seen >= threshold && *bucket_count > 0 {
+                    return 1u64 << bucket as u64;
+                }
+            }
+            0
+        };
+
+        LatencyStats {
+            count,
+            mean_micros: if count > 0 { self.total_micros.load(Ordering::Relaxed) as f64 / count as f64 } else { 0.0 },
+            p50_micros: percentile(50),
+            p99_micros: percentile(99),
+            histogram: buckets,
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Summary of the [LatencyHistogram]
+#[derive(Serialize, Debug, Clone)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub mean_micros: f64,
+    pub p50_micros: u64,
+    pub p99_micros: u64,
+    /// Counts of latencies below `2^i` microseconds
+    pub histogram: Vec<u64>,
+}
+
+/// Latencies of reads and writes of one column (or commit log)
+#[derive(Default)]
+pub struct OperationMetrics {
+    reads: LatencyHistogram,
+    writes: LatencyHistogram,
+}
+
+impl OperationMetrics {
+    #[inline]
+    pub fn reads(&self) -> &LatencyHistogram {
+        &self.reads
+    }
+
+    #[inline]
+    pub fn writes(&self) -> &LatencyHistogram {
+        &self.writes
+    }
+}
+
+/// Operation metrics of all used columns
+#[derive(Default)]
+pub struct StorageMetrics {
+    columns: RwLock<HashMap<&'static str, Arc<OperationMetrics>>>,
+}
+
+impl StorageMetrics {
+    /// Measure read operation of the column
+    #[inline]
+    pub fn read<T, F: FnOnce() -> T>(&self, column: &'static str, operation: F) -> T {
+        let start = Instant::now();
+        let result = operation();
+        self.column(column).reads.record(start.elapsed());
+        result
+    }
+
+    /// Measure write operation of the column
+    #[inline]
+    pub fn write<T, F: FnOnce() -> T>(&self, column: &'static str, operation: F) -> T {
+        let start = Instant::now();
+        let result = operation();
+        self.column(column).writes.record(start.elapsed());
+        result
+    }
+
+    /// Metrics of the column, e.g. for operations, which cannot be measured by a single closure
+    pub fn column(&self, column: &'static str) -> Arc<OperationMetrics> {
+        if let Some(metrics) = self.columns.read().expect("Read lock failed").get(column) {
+            return metrics.clone();
+        }
+        self.columns.write().expect("Write lock failed")
+            .entry(column)
+            .or_default()
+            .clone()
+    }
+
+    /// Metrics of all columns used since the start, sorted by name
+    pub fn columns(&self) -> Vec<(&'static str, Arc<OperationMetrics>)> {
+        let mut columns: Vec<_> = self.columns.read().expect("Read lock failed")
+            .iter()
+            .map(|(name, metrics)| (*name, metrics.clone()))
+            .collect();
+        columns.sort_by_key(|(name, _)| *name);
+        columns
+    }
+}
+
+/// Backend estimates of the column size
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ColumnProperties {
+    pub estimated_keys: Option<u64>,
+    /// Size of all SST files in bytes
+    pub sst_size: Option<u64>,
+    /// Size of all memtables in bytes
+    pub memtable_size: Option<u64>,
+}
+
+/// Hits and misses of the backend block cache
+#[derive(Serialize, Debug, Clone)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_ratio: f64,
+}
+
+impl CacheStats {
+    pub fn new(hits: u64, misses: u64) -> Self {
+        let total = hits + misses;
+        Self {
+            hits,
+            misses,
+            hit_ratio: if total > 0 { hits as f64 / total as f64 } else { 0.0 },
+        }
+    }
+}
+
+/// Statistics of one key-value store column
+#[derive(Serialize, Debug, Clone)]
+pub struct ColumnStats {
+    pub name: &'static str,
+    pub properties: ColumnProperties,
+    pub reads: LatencyStats,
+    pub writes: LatencyStats,
+}
+
+/// Statistics of one commit log
+#[derive(Serialize, Debug, Clone)]
+pub struct CommitLogStats {
+    pub name: String,
+    /// Number of appended records
+    pub records: u64,
+    /// Size of the commit log files in bytes
+    pub size: u64,
+    pub reads: LatencyStats,
+    pub writes: LatencyStats,
+}
+
+/// Statistics of the whole storage
+#[derive(Serialize, Debug, Clone)]
+pub struct StorageStats {
+    pub columns: Vec<ColumnStats>,
+    pub commit_logs: Vec<CommitLogStats>,
+    /// Available only if statistics are enabled by the database configuration
+    pub block_cache: Option<CacheStats>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::new();
+        assert_eq!(0, histogram.stats().p99_micros);
+
+        for _ in 0..98 {
+            histogram.record(Duration::from_micros(3));
+        }
+        histogram.record(Duration::from_micros(100));
+        histogram.record(Duration::from_secs(3600));
+
+        let stats = histogram.stats();
+        assert_eq!(100, stats.count);
+        assert_eq!(4, stats.p50_micros);
+        assert_eq!(128, stats.p99_micros);
+        assert_eq!(1, stats.histogram[LATENCY_BUCKETS - 1]);
+    }
+}
@@ -1,8 +1,8 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::collections::BTreeMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
 use derive_builder::Builder;
@@ -10,24 +10,58 @@ use rocksdb::{BlockBasedOptions, ColumnFamilyDescriptor, DB, Options};
 
 pub use codec::{BincodeEncoded, Codec, Decoder, Encoder, SchemaError};
 pub use commit_log::{CommitLogError, CommitLogRef, CommitLogs, CommitLogWithSchema, Location};
-pub use database::{DBError, KeyValueStoreWithSchema};
-pub use schema::{CommitLogDescriptor, CommitLogSchema, KeyValueSchema};
+pub use database::{DBError, KeyValueStore, KeyValueStoreWithSchema, WriteBatch};
+pub use schema::{CommitLogDescriptor, CommitLogSchema, KeyValueColumn, KeyValueSchema, MergeOperator};
+pub use tuning::{ColumnTuning, DbCompression, DbProfile, DbTuning};
 
+use crate::{BlockStorage, StorageError, SystemStorage};
+use crate::persistent::backend::InMemoryBackend;
+use crate::persistent::metrics::StorageStats;
 use crate::persistent::sequence::Sequences;
-use crate::skip_list::{Bucket, DatabaseBackedSkipList, TypedSkipList};
+use crate::skip_list::{Bucket, DatabaseBackedSkipList, SkipList, TypedSkipList};
 
 pub mod sequence;
 pub mod codec;
 pub mod schema;
 pub mod database;
+pub mod backend;
 pub mod commit_log;
+pub mod metrics;
+pub mod secondary_index;
+pub mod secondary;
+pub mod tuning;
 
-/// Rocksdb database system configuration
-/// - [max_num_of_threads] - if not set, num of cpus is used
+/// Rocksdb database system configuration, see [tuning] for how the column family options are resolved
 #[derive(Builder, Debug, Clone)]
 pub struct DbConfiguration {
+    /// If not set, num of cpus is used
     #[builder(default = "None")]
     max_threads: Option<usize>,
+    /// Preset of the tuning, explicitly configured options have priority over the preset
+    #[builder(default = "DbProfile::Default")]
+    profile: DbProfile,
+    /// Size of the block cache in bytes shared by all column families.
+    /// If not set by the configuration nor by the profile, RocksDB creates 8MB cache for every column family.
+    #[builder(default = "None")]
+    block_cache_size: Option<usize>,
+    /// Options of all column families
+    #[builder(default)]
+    column_tuning: ColumnTuning,
+    /// Options of the column families by their name, see [KeyValueSchema::name]
+    #[builder(default)]
+    column_overrides: HashMap<String, ColumnTuning>,
+    /// Limit of the flush and compaction writes in bytes per second
+    #[builder(default = "None")]
+    rate_limit: Option<usize>,
+    /// Directory of the write-ahead log, if not set, the log is in the database directory
+    #[builder(default = "None")]
+    wal_dir: Option<PathBuf>,
+    /// When the write-ahead log gets bigger, column families with the oldest data in the log are flushed
+    #[builder(default = "None")]
+    max_total_wal_size: Option<u64>,
+    /// Collect RocksDB statistics, needed for the block cache hit ratio in storage stats, but they add some overhead to every operation
+    #[builder(default = "false")]
+    statistics: bool,
 }
 
 impl Default for DbConfiguration {
@@ -43,15 +77,54 @@ impl Default for DbConfiguration {
 /// # Arguments
 /// * `path` - Path to open RocksDB
 /// * `cfs` - Iterator of Column Family descriptors
-pub fn open_kv<P, I>(path: P, cfs: I, cfg: &DbConfiguration) -> Result<DB, DBError>
+pub fn open_kv<P, I>(path: P, cfs: I, cfg: &DbConfiguration) -> Result<KeyValueStore, DBError>
     where
         P: AsRef<Path>,
         I: IntoIterator<Item=ColumnFamilyDescriptor>,
 {
     DB::open_cf_descriptors(&default_kv_options(cfg), path, cfs)
+        .map(KeyValueStore::new)
         .map_err(DBError::from)
 }
 
+/// Open RocksDB database of another process (usually running light node) as a secondary instance.
+/// Secondary instance is read-only and sees data written by the primary instance
+/// only after [KeyValueStore::try_catch_up_with_primary], see https://github.com/facebook/rocksdb/wiki/Secondary-instance
+///
+/// # Arguments
+/// * `primary_path` - Path of RocksDB opened by the primary instance
+/// * `secondary_path` - Path, where secondary instance keeps its own info logs
+/// * `cfs` - Iterator of Column Family descriptors, the same as used by the primary instance (usually [database_schemas](crate::database_schemas)),
+///           otherwise columns with merge operator or prefix extractor cannot be read
+pub fn open_kv_secondary<P, S, I>(primary_path: P, secondary_path: S, cfs: I, cfg: &DbConfiguration) -> Result<KeyValueStore, DBError>
+    where
+        P: AsRef<Path>,
+        S: AsRef<Path>,
+        I: IntoIterator<Item=ColumnFamilyDescriptor>,
+{
+    let mut db_opts = default_kv_options(cfg);
+    db_opts.create_if_missing(false);
+    db_opts.create_missing_column_families(false);
+    // secondary instance has to keep all files open, otherwise it could miss files deleted by the primary compaction
+    db_opts.set_max_open_files(-1);
+
+    DB::open_cf_descriptors_as_secondary(&db_opts, primary_path.as_ref(), secondary_path.as_ref(), cfs)
+        .map(KeyValueStore::new)
+        .map_err(DBError::from)
+}
+
+/// Create empty in-memory key-value store with specified columns.
+/// Data are not persisted, so this is intended mainly for tests.
+///
+/// # Arguments
+/// * `columns` - Iterator of column descriptions, usually [KeyValueSchema::column]
+pub fn open_in_memory_kv<I>(columns: I) -> KeyValueStore
+    where
+        I: IntoIterator<Item=KeyValueColumn>,
+{
+    KeyValueStore::new(InMemoryBackend::new(columns))
+}
+
 /// Create default database configuration options,
 /// based on recommended setting: https://github.com/facebook/rocksdb/wiki/Setup-Options-and-Basic-Tuning#other-general-options
 fn default_kv_options(cfg: &DbConfiguration) -> Options {
@@ -65,6 +138,9 @@ fn default_kv_options(cfg: &DbConfiguration) -> Options {
     db_opts.set_level_compaction_dynamic_level_bytes(true);
     db_opts.set_max_background_compactions(4);
     db_opts.set_max_background_flushes(2);
+    if cfg.statistics {
+        db_opts.enable_statistics();
+    }
 
     // resolve thread count to use
     let num_of_threads = match cfg.max_threads {
@@ -76,25 +152,62 @@ fn default_kv_options(cfg: &DbConfiguration) -> Options {
         db_opts.increase_parallelism(num_of_threads as i32);
     }
 
+    if let Some(rate_limit) = cfg.rate_limit {
+        // refill every 100ms with the default fairness
+        db_opts.set_ratelimiter(rate_limit as i64, 100_000, 10);
+    }
+    if let Some(wal_dir) = &cfg.wal_dir {
+        db_opts.set_wal_dir(wal_dir);
+    }
+    if let Some(max_total_wal_size) = cfg.max_total_wal_size {
+        db_opts.set_max_total_wal_size(max_total_wal_size);
+    }
+    if let Some(readahead_size) = cfg.profile.compaction_readahead_size() {
+        db_opts.set_compaction_readahead_size(readahead_size);
+    }
+
     db_opts
 }
 
-/// Create default database configuration options,
+/// Create default column family options with the tuning of the column family,
 /// based on recommended setting:
 ///     https://github.com/facebook/rocksdb/wiki/Setup-Options-and-Basic-Tuning#other-general-options
 ///     https://rocksdb.org/blog/2019/03/08/format-version-4.html
-pub fn default_table_options() -> Options {
+///
+/// # Arguments
+/// * `tuning` - Tuning of the database, see [DbTuning::new]
+/// * `name` - Name of the column family, usually [KeyValueSchema::name]
+pub fn default_table_options(tuning: &DbTuning, name: &str) -> Options {
+    let column_tuning = tuning.column_tuning(name);
+
     // default db options
     let mut db_opts = Options::default();
 
     // https://github.com/facebook/rocksdb/wiki/Setup-Options-and-Basic-Tuning#other-general-options
     db_opts.set_level_compaction_dynamic_level_bytes(true);
 
+    if let Some(compression) = column_tuning.compression {
+        db_opts.set_compression_type(compression.into());
+    }
+    if let Some(write_buffer_size) = column_tuning.write_buffer_size {
+        db_opts.set_write_buffer_size(write_buffer_size);
+    }
+    if let Some(max_write_buffer_number) = column_tuning.max_write_buffer_number {
+        db_opts.set_max_write_buffer_number(max_write_buffer_number);
+    }
+
     // block table options
     let mut table_options = BlockBasedOptions::default();
-    table_options.set_block_size(16 * 1024);
+    table_options.set_block_size(column_tuning.block_size.unwrap_or(16 * 1024));
     table_options.set_cache_index_and_filter_blocks(true);
     table_options.set_pin_l0_filter_and_index_blocks_in_cache(true);
+    if let Some(block_cache) = tuning.block_cache() {
+        table_options.set_block_cache(block_cache);
+    }
+    if let Some(bloom_filter_bits) = column_tuning.bloom_filter_bits.filter(|bits| *bits > 0) {
+        // full filter, block based filter is deprecated
+        table_options.set_bloom_filter(bloom_filter_bits, false);
+    }
 
     // set format_version 4 https://rocksdb.org/blog/2019/03/08/format-version-4.html
     table_options.set_format_version(4);
@@ -114,36 +227,66 @@ pub fn open_cl<P, I>(path: P, cfs: I) -> Result<CommitLogs, CommitLogError>
     CommitLogs::new(path, cfs)
 }
 
+/// Open commit log written by another process at a given path, see [CommitLogs::catch_up].
+/// Files of the commit log are copied to the `mirror_path` and opened from there, so `path` is never modified.
+pub fn open_cl_read_only<P, M, I>(path: P, mirror_path: M, cfs: I) -> Result<CommitLogs, CommitLogError>
+    where
+        P: AsRef<Path>,
+        M: AsRef<Path>,
+        I: IntoIterator<Item=CommitLogDescriptor>
+{
+    CommitLogs::new_read_only(path, mirror_path, cfs)
+}
+
 
 pub type ContextMap = BTreeMap<String, Bucket<Vec<u8>>>;
 pub type ContextList = Arc<RwLock<dyn TypedSkipList<String, Bucket<Vec<u8>>> + Sync + Send>>;
+/// Blocks are applied (protocol context is written) under the read lock,
+/// write lock pauses the application of blocks, e.g. to copy the protocol context consistently
+pub type ApplyLock = Arc<RwLock<()>>;
 
 /// Groups all components required for correct permanent storage functioning
 #[derive(Clone)]
 pub struct PersistentStorage {
     /// key-value store
-    kv: Arc<DB>,
+    kv: Arc<KeyValueStore>,
     /// commit log store
     clog: Arc<CommitLogs>,
     /// autoincrement  id generators
     seq: Arc<Sequences>,
     /// skip list backed context storage
     cs: ContextList,
+    /// storage only reads data of the primary instance running in another process
+    secondary: bool,
+    /// see [ApplyLock]
+    apply_lock: ApplyLock,
 }
 
 impl PersistentStorage {
-    pub fn new(kv: Arc<DB>, clog: Arc<CommitLogs>) -> Self {
+    pub fn new(kv: Arc<KeyValueStore>, clog: Arc<CommitLogs>) -> Self {
+        Self::create(kv, clog, false)
+    }
+
+    /// Storage reading data of the light node running in another process, `kv` is opened by [open_kv_secondary]
+    /// and `clog` by [open_cl_read_only]. New data are visible after [catch_up_with_primary](PersistentStorage::catch_up_with_primary).
+    pub fn new_secondary(kv: Arc<KeyValueStore>, clog: Arc<CommitLogs>) -> Self {
+        Self::create(kv, clog, true)
+    }
+
+    fn create(kv: Arc<KeyValueStore>, clog: Arc<CommitLogs>, secondary: bool) -> Self {
         let seq = Arc::new(Sequences::new(kv.clone(), 1000));
         Self {
             clog,
             kv: kv.clone(),
-            cs: Arc::new(RwLock::new(DatabaseBackedSkipList::new(0, kv, seq.generator("skip_list")).expect("failed to initialize context storage"))),
+            cs: Arc::new(RwLock::new(DatabaseBackedSkipList::new(0, kv, seq.generator(DatabaseBackedSkipList::name())).expect("failed to initialize context storage"))),
             seq,
+            secondary,
+            apply_lock: Arc::new(RwLock::new(())),
         }
     }
 
     #[inline]
-    pub fn kv(&self) -> Arc<DB> {
+    pub fn kv(&self) -> Arc<KeyValueStore> {
         self.kv.clone()
     }
 
@@ -159,11 +302,49 @@ impl PersistentStorage {
 
     #[inline]
     pub fn context_storage(&self) -> ContextList { self.cs.clone() }
+
+    #[inline]
+    pub fn apply_lock(&self) -> ApplyLock {
+        self.apply_lock.clone()
+    }
+
+    #[inline]
+    pub fn is_secondary(&self) -> bool {
+        self.secondary
+    }
+
+    /// Make data written by the primary instance visible in the secondary storage.
+    /// Key-value store catches up first, so its records never reference commit log records, which are not visible yet.
+    pub fn catch_up_with_primary(&self) -> Result<(), StorageError> {
+        self.kv.try_catch_up_with_primary()?;
+        self.clog.catch_up()?;
+        self.cs.write().expect("lock poisoning").reload()?;
+        Ok(())
+    }
+
+    /// Current statistics of the key-value store and commit logs, collected without scanning the data
+    pub fn stats(&self) -> Result<StorageStats, StorageError> {
+        Ok(StorageStats {
+            columns: self.kv.column_stats()?,
+            commit_logs: self.clog.stats()?,
+            block_cache: self.kv.cache_stats()?,
+        })
+    }
 }
 
 impl Drop for PersistentStorage {
     fn drop(&mut self) {
+        if self.secondary {
+            // nothing was written, data belong to the primary instance
+            return;
+        }
+
+        // records appended before the flush do not need to be verified after the restart
+        let verified_offset = self.clog.next_offset(BlockStorage::name());
         self.clog.flush().expect("Failed to flush commit logs");
+        if let Ok(verified_offset) = verified_offset {
+            let _ = SystemStorage::new(self.kv()).set_block_storage_verified_offset(verified_offset);
+        }
         self.kv.flush().expect("Failed to flush database");
     }
 }
\ No newline at end of file
@@ -1,23 +1,60 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use getset::CopyGetters;
 use rocksdb::ColumnFamilyDescriptor;
 
 use crate::persistent::codec::Codec;
-use crate::persistent::default_table_options;
+use crate::persistent::{DbTuning, default_table_options};
 
 /// This trait extends basic column family by introducing Codec types safety and enforcement
 pub trait KeyValueSchema {
     type Key: Codec;
     type Value: Codec;
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        ColumnFamilyDescriptor::new(Self::name(), default_table_options())
+    /// RocksDB column family with options resolved by the `tuning`
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(Self::name(), default_table_options(tuning, Self::name()))
+    }
+
+    /// Backend independent description of the column, used by non-RocksDB backends
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name())
     }
 
     fn name() -> &'static str;
 }
 
+/// Merge operator called with the existing value (if any) and all merged operands (oldest first).
+/// Returning `None` means, that merge failed.
+pub type MergeOperator = fn(existing_value: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>>;
+
+/// Backend independent counterpart of the RocksDB [ColumnFamilyDescriptor]
+#[derive(Clone, Copy, CopyGetters)]
+pub struct KeyValueColumn {
+    #[get_copy = "pub"]
+    name: &'static str,
+    /// Length of the fixed key prefix, which is used by the prefix iterator
+    #[get_copy = "pub"]
+    prefix_len: Option<usize>,
+    #[get_copy = "pub"]
+    merge_operator: Option<MergeOperator>,
+}
+
+impl KeyValueColumn {
+    pub fn new(name: &'static str) -> Self {
+        Self { name, prefix_len: None, merge_operator: None }
+    }
+
+    pub fn with_prefix_len(self, prefix_len: usize) -> Self {
+        Self { prefix_len: Some(prefix_len), ..self }
+    }
+
+    pub fn with_merge_operator(self, merge_operator: MergeOperator) -> Self {
+        Self { merge_operator: Some(merge_operator), ..self }
+    }
+}
+
 pub struct CommitLogDescriptor {
     name: String,
 }
@@ -0,0 +1,73 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Periodic catch-up of the secondary storage with the light node writing to the same database.
+//!
+//! Secondary [PersistentStorage] is created by [PersistentStorage::new_secondary] from the key-value store opened
+//! by [open_kv_secondary](crate::persistent::open_kv_secondary) and commit logs opened by
+//! [open_cl_read_only](crate::persistent::open_cl_read_only). Storage readers (RPC, analytics) do not compete
+//! with the writer, they just see new data with a delay given by the catch-up interval.
+//!
+//! Light node runs as a read-only RPC replica with `--replica-path`.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use slog::{Logger, warn};
+
+use crate::persistent::PersistentStorage;
+
+/// Running catch-up of the secondary storage, catch-up is stopped when the handle is dropped
+pub struct CatchUpHandle {
+    run: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CatchUpHandle {
+    /// Stop the catch-up and wait for the catch-up thread to finish
+    pub fn stop(&mut self) {
+        self.run.store(false, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for CatchUpHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Catch up the secondary storage with the primary instance every `interval` in a separate thread,
+/// `on_catch_up` is called after every successful catch-up (e.g. to notify readers about the new current head).
+/// Failed catch-up is just logged and repeated after the next interval.
+pub fn spawn_catch_up<F>(persistent_storage: &PersistentStorage, interval: Duration, log: Logger, mut on_catch_up: F) -> Result<CatchUpHandle, io::Error>
+    where
+        F: FnMut() + Send + 'static
+{
+    let run = Arc::new(AtomicBool::new(true));
+    let thread = {
+        let run = run.clone();
+        let persistent_storage = persistent_storage.clone();
+
+        thread::Builder::new()
+            .name("storage-catch-up".to_string())
+            .spawn(move || {
+                while run.load(Ordering::Acquire) {
+                    match persistent_storage.catch_up_with_primary() {
+                        Ok(()) => on_catch_up(),
+                        Err(e) => warn!(log, "Failed to catch up with the primary storage"; "reason" => e),
+                    }
+                    thread::park_timeout(interval);
+                }
+            })?
+    };
+
+    Ok(CatchUpHandle { run, thread: Some(thread) })
+}
@@ -0,0 +1,191 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Secondary indexes maintained together with the primary schema.
+//!
+//! Index is a [KeyValueSchema] with empty values, whose keys are derived from the primary records by [SecondaryIndex::index_keys].
+//! Index key has to contain the primary key, so it is unique and the primary key can be extracted from it
+//! by [SecondaryIndex::primary_key]. Key encoding defines the order of the index, so prefix and range queries
+//! work over the encoded keys.
+//!
+//! Primary records written through [IndexedStore] update all registered indexes in the same [WriteBatch].
+//! If index is added later (or index keys change), it can be filled by [Index::rebuild].
+
+use std::sync::Arc;
+
+use crate::persistent::{DBError, Encoder, KeyValueSchema, KeyValueStoreWithSchema, WriteBatch};
+use crate::{Direction, IteratorMode};
+
+/// How many operations are written in one batch during the index rebuild
+const REBUILD_BATCH_SIZE: usize = 10_000;
+
+pub type PrimaryKey<I> = <<I as SecondaryIndex>::Primary as KeyValueSchema>::Key;
+pub type PrimaryValue<I> = <<I as SecondaryIndex>::Primary as KeyValueSchema>::Value;
+
+/// Declaration of the secondary index over the primary schema
+pub trait SecondaryIndex: KeyValueSchema<Value=()> {
+    type Primary: KeyValueSchema;
+
+    /// Index entries of the primary record, record does not need to be indexed at all
+    fn index_keys(key: &PrimaryKey<Self>, value: &PrimaryValue<Self>) -> Vec<Self::Key>;
+
+    /// Primary key referenced by the index entry
+    fn primary_key(index_key: &Self::Key) -> PrimaryKey<Self>;
+}
+
+/// Maintenance of the index, when records of the primary schema `P` are written
+pub trait IndexUpdate<P: KeyValueSchema>: Send + Sync {
+    /// Add index entries of the new record to the batch
+    fn put(&self, batch: &mut WriteBatch, key: &P::Key, value: &P::Value) -> Result<(), DBError>;
+
+    /// Add removal of index entries of the deleted record to the batch
+    fn delete(&self, batch: &mut WriteBatch, key: &P::Key, value: &P::Value) -> Result<(), DBError>;
+}
+
+/// Queries over the secondary index `I`
+pub struct Index<I: SecondaryIndex> {
+    kv: Arc<dyn KeyValueStoreWithSchema<I> + Sync + Send>,
+}
+
+impl<I: SecondaryIndex> Index<I> {
+    pub fn new(kv: Arc<dyn KeyValueStoreWithSchema<I> + Sync + Send>) -> Self {
+        Self { kv }
+    }
+
+    /// Primary keys of index entries with the same prefix as `key` (as defined by the prefix length of the schema),
+    /// starting from `key`. Index entries, which cannot be decoded, are returned as errors.
+    pub fn prefix<'a>(&'a self, key: &I::Key) -> Result<impl Iterator<Item=Result<PrimaryKey<I>, DBError>> + 'a, DBError> {
+        Ok(self.kv.prefix_iterator(key)?
+            .map(|(index_key, _)| index_key
+                .map(|index_key| I::primary_key(&index_key))
+                .map_err(DBError::from)))
+    }
+
+    /// Primary keys of index entries in the range `[from, to)` of the encoded index keys.
+    /// Index entries, which cannot be decoded, are returned as errors.
+    pub fn range<'a>(&'a self, from: &I::Key, to: &I::Key) -> Result<impl Iterator<Item=Result<PrimaryKey<I>, DBError>> + 'a, DBError> {
+        let to = to.encode()?;
+        Ok(self.kv.iterator(IteratorMode::From(from, Direction::Forward))?
+            .map(|(index_key, _)| index_key.map_err(DBError::from))
+            .take_while(move |index_key| match index_key {
+                Ok(index_key) => index_key.encode().map_or(false, |encoded| encoded < to),
+                Err(_) => true,
+            })
+            .map(|index_key| index_key.map(|index_key| I::primary_key(&index_key))))
+    }
+
+    /// Remove all index entries and create them again from the primary records.
+    /// Returns number of index entries.
+    pub fn rebuild(&self, primary: &(dyn KeyValueStoreWithSchema<I::Primary> + Sync + Send)) -> Result<usize, DBError> {
+        let mut batch = WriteBatch::new();
+        for (index_key, _) in self.kv.iterator(IteratorMode::Start)? {
+            batch.delete::<I>(&index_key?)?;
+            if batch.len() >= REBUILD_BATCH_SIZE {
+                self.kv.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        self.kv.write_batch(std::mem::take(&mut batch))?;
+
+        let mut count = 0;
+        for (key, value) in primary.iterator(IteratorMode::Start)? {
+            for index_key in I::index_keys(&key?, &value?) {
+                batch.put::<I>(&index_key, &())?;
+                count += 1;
+            }
+            if batch.len() >= REBUILD_BATCH_SIZE {
+                self.kv.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        self.kv.write_batch(batch)?;
+
+        Ok(count)
+    }
+}
+
+impl<I: SecondaryIndex> IndexUpdate<I::Primary> for Index<I> {
+    fn put(&self, batch: &mut WriteBatch, key: &PrimaryKey<I>, value: &PrimaryValue<I>) -> Result<(), DBError> {
+        for index_key in I::index_keys(key, value) {
+            batch.put::<I>(&index_key, &())?;
+        }
+        Ok(())
+    }
+
+    fn delete(&self, batch: &mut WriteBatch, key: &PrimaryKey<I>, value: &PrimaryValue<I>) -> Result<(), DBError> {
+        for index_key in I::index_keys(key, value) {
+            batch.delete::<I>(&index_key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records of the primary schema `P` with automatically maintained secondary indexes
+pub struct IndexedStore<P: KeyValueSchema> {
+    kv: Arc<dyn KeyValueStoreWithSchema<P> + Sync + Send>,
+    indexes: Vec<Arc<dyn IndexUpdate<P>>>,
+}
+
+impl<P: KeyValueSchema> IndexedStore<P> {
+    pub fn new(kv: Arc<dyn KeyValueStoreWithSchema<P> + Sync + Send>) -> Self {
+        Self { kv, indexes: vec![] }
+    }
+
+    /// Register index maintained by this store
+    pub fn with_index<I: IndexUpdate<P> + 'static>(mut self, index: I) -> Self {
+        self.indexes.push(Arc::new(index));
+        self
+    }
+
+    #[inline]
+    pub fn get(&self, key: &P::Key) -> Result<Option<P::Value>, DBError> {
+        self.kv.get(key)
+    }
+
+    /// Add new record (key is expected not to be stored yet) and its index entries to the batch
+    pub fn insert_to_batch(&self, batch: &mut WriteBatch, key: &P::Key, value: &P::Value) -> Result<(), DBError> {
+        batch.put::<P>(key, value)?;
+        for index in &self.indexes {
+            index.put(batch, key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Store record together with its index entries, index entries of the replaced record are removed
+    pub fn put(&self, key: &P::Key, value: &P::Value) -> Result<(), DBError> {
+        let mut batch = WriteBatch::new();
+        if let Some(old_value) = self.kv.get(key)? {
+            for index in &self.indexes {
+                index.delete(&mut batch, key, &old_value)?;
+            }
+        }
+        self.insert_to_batch(&mut batch, key, value)?;
+        self.kv.write_batch(batch)
+    }
+
+    /// Add removal of the record and its index entries to the batch.
+    /// Returns `false`, if record is not stored.
+    pub fn delete_to_batch(&self, batch: &mut WriteBatch, key: &P::Key) -> Result<bool, DBError> {
+        match self.kv.get(key)? {
+            Some(value) => {
+                for index in &self.indexes {
+                    index.delete(batch, key, &value)?;
+                }
+                batch.delete::<P>(key)?;
+                Ok(true)
+            }
+            None => Ok(false)
+        }
+    }
+
+    /// Remove record together with its index entries
+    pub fn delete(&self, key: &P::Key) -> Result<bool, DBError> {
+        let mut batch = WriteBatch::new();
+        let deleted = self.delete_to_batch(&mut batch, key)?;
+        self.kv.write_batch(batch)?;
+        Ok(deleted)
+    }
+
+    #[inline]
+    pub fn write_batch(&self, batch: WriteBatch) -> Result<(), DBError> {
+        self.kv.write_batch(batch)
+    }
+}
@@ -3,7 +3,7 @@
 
 use std::collections::HashMap;
 use std::sync::{Arc, Condvar, Mutex, PoisonError};
-use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
 
 use failure::Fail;
 
@@ -22,6 +22,8 @@ pub struct Sequences {
     seq_batch_size: u16,
     /// Map of all loaded generators
     generators: Arc<Mutex<HashMap<String, Arc<SequenceGenerator>>>>,
+    /// Audit mode shared by all generators
+    audit: Arc<AtomicBool>,
 }
 
 pub type SequenceNumber = u64;
@@ -35,6 +37,7 @@ impl Sequences {
             db,
             seq_batch_size,
             generators: Arc::new(Mutex::new(HashMap::new())),
+            audit: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -44,12 +47,25 @@ impl Sequences {
         match generators.get(name) {
             Some(generator) => generator.clone(),
             None => {
-                let generator = Arc::new(SequenceGenerator::new(name.to_owned(), self.seq_batch_size, self.db.clone()));
+                let generator = Arc::new(SequenceGenerator::new(name.to_owned(), self.seq_batch_size, self.db.clone(), self.audit.clone()));
                 generators.insert(name.into(), generator.clone());
                 generator
             }
         }
     }
+
+    /// Enable or disable the audit mode of all generators.
+    ///
+    /// In the audit mode users of the generators verify, that a generated sequence number
+    /// was not used before (e.g. because of a lost pre-allocation after a crash).
+    pub fn set_audit(&self, audit: bool) {
+        self.audit.store(audit, Ordering::SeqCst)
+    }
+
+    /// Return persisted upper bound of the sequence numbers pre-allocated by the generator.
+    pub fn persisted(&self, name: &str) -> Result<Option<SequenceNumber>, SequenceError> {
+        self.db.get(&name.to_owned()).map_err(SequenceError::from)
+    }
 }
 
 impl KeyValueSchema for Sequences {
@@ -74,10 +90,12 @@ pub struct SequenceGenerator {
     seq_name: String,
     /// Guarding write access to a database
     guard: (Mutex<()>, Condvar),
+    /// Audit mode flag shared with [Sequences]
+    audit: Arc<AtomicBool>,
 }
 
 impl SequenceGenerator {
-    fn new(seq_name: String, seq_batch_size: u16, db: Arc<SequencerDatabase>) -> Self {
+    fn new(seq_name: String, seq_batch_size: u16, db: Arc<SequencerDatabase>, audit: Arc<AtomicBool>) -> Self {
         Self {
             seq_cur: AtomicU64::new(db.get(&seq_name).unwrap_or_default().unwrap_or(0)),
             seq_available: AtomicI32::new(0),
@@ -85,9 +103,45 @@ impl SequenceGenerator {
             db,
             seq_name,
             seq_batch_size,
+            audit,
         }
     }
 
+    /// Unique name of the sequence
+    pub fn name(&self) -> &str {
+        &self.seq_name
+    }
+
+    /// Check if generated sequence numbers should be verified against already stored data.
+    pub fn is_audited(&self) -> bool {
+        self.audit.load(Ordering::SeqCst)
+    }
+
+    /// Ensure that the next generated sequence number is greater than `max_used`.
+    ///
+    /// Returns `true` if the generator had to be moved forward, which means that
+    /// persisted pre-allocation was lost and the generator would hand out already used numbers.
+    pub fn recover(&self, max_used: SequenceNumber) -> Result<bool, SequenceError> {
+        // obtain mutex lock to ensure exclusive access to the database
+        let _allocated = self.guard.0.lock()?;
+
+        if self.seq_cur.load(Ordering::SeqCst) > max_used {
+            return Ok(false);
+        }
+
+        let seq_stored = self.db.get(&self.seq_name)?.unwrap_or(0);
+        let seq_new = std::cmp::max(max_used + 1, seq_stored);
+        if seq_new != seq_stored {
+            self.db.put(&self.seq_name, &seq_new)?;
+        }
+
+        // next call to `next()` will pre-allocate a new batch starting at `seq_new`
+        self.seq_cur.store(seq_new, Ordering::SeqCst);
+        self.seq_available.store(0, Ordering::SeqCst);
+
+        Ok(true)
+    }
+
     /// Get next unique sequence number. Value by this function is positive and always increasing.
     pub fn next(&self) -> Result<SequenceNumber, SequenceError> {
         let seq = loop {
@@ -134,6 +188,11 @@ pub enum SequenceError {
     },
     #[fail(display = "Thread synchronization error")]
     SynchronizationError,
+    #[fail(display = "Sequence number {} of generator {} was already used", id, name)]
+    IdReused {
+        name: String,
+        id: SequenceNumber,
+    },
 }
 
 impl From<DBError> for SequenceError {
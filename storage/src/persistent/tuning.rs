@@ -0,0 +1,231 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Tuning of the RocksDB database.
+//!
+//! Options of the column family are resolved in this order: options of the column family by its name
+//! (see [DbConfiguration] `column_overrides`), options of all column families (`column_tuning`) and options
+//! of the [DbProfile] preset. Options, which are not set anywhere, keep the RocksDB defaults.
+
+use std::str::FromStr;
+
+use failure::Fail;
+use rocksdb::{Cache, DBCompressionType};
+
+use crate::persistent::{DBError, DbConfiguration};
+
+/// Preset of the database tuning for the type of the machine
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DbProfile {
+    /// RocksDB defaults with 8MB block cache for every column family
+    Default,
+    Ssd,
+    /// Bigger blocks, files and memtables, so there are less random reads and writes
+    Hdd,
+    /// Small shared block cache and memtables
+    LowMemory,
+}
+
+impl DbProfile {
+    /// Options of all column families defined by the preset
+    pub fn column_tuning(&self) -> ColumnTuning {
+        match self {
+            DbProfile::Default => ColumnTuning::default(),
+            DbProfile::Ssd => ColumnTuning {
+                compression: Some(DbCompression::Lz4),
+                bloom_filter_bits: Some(10),
+                block_size: Some(16 * 1024),
+                write_buffer_size: Some(64 * 1024 * 1024),
+                max_write_buffer_number: Some(4),
+            },
+            DbProfile::Hdd => ColumnTuning {
+                compression: Some(DbCompression::Zstd),
+                bloom_filter_bits: Some(10),
+                block_size: Some(64 * 1024),
+                write_buffer_size: Some(128 * 1024 * 1024),
+                max_write_buffer_number: Some(4),
+            },
+            DbProfile::LowMemory => ColumnTuning {
+                compression: Some(DbCompression::Lz4),
+                bloom_filter_bits: Some(10),
+                block_size: Some(16 * 1024),
+                write_buffer_size: Some(8 * 1024 * 1024),
+                max_write_buffer_number: Some(2),
+            },
+        }
+    }
+
+    /// Size of the block cache shared by all column families
+    pub fn block_cache_size(&self) -> Option<usize> {
+        match self {
+            DbProfile::Default => None,
+            DbProfile::Ssd => Some(512 * 1024 * 1024),
+            DbProfile::Hdd => Some(1024 * 1024 * 1024),
+            DbProfile::LowMemory => Some(32 * 1024 * 1024),
+        }
+    }
+
+    /// Size of the read-ahead during the compaction, so compaction reads larger chunks of the files
+    pub fn compaction_readahead_size(&self) -> Option<usize> {
+        match self {
+            DbProfile::Hdd => Some(2 * 1024 * 1024),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for DbProfile {
+    type Err = ParseTuningError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "default" => Ok(DbProfile::Default),
+            "ssd" => Ok(DbProfile::Ssd),
+            "hdd" => Ok(DbProfile::Hdd),
+            "low-memory" => Ok(DbProfile::LowMemory),
+            _ => Err(ParseTuningError(format!("profile {}, supported values are: default, ssd, hdd, low-memory", s)))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DbCompression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl From<DbCompression> for DBCompressionType {
+    fn from(compression: DbCompression) -> Self {
+        match compression {
+            DbCompression::None => DBCompressionType::None,
+            DbCompression::Snappy => DBCompressionType::Snappy,
+            DbCompression::Lz4 => DBCompressionType::Lz4,
+            DbCompression::Zstd => DBCompressionType::Zstd,
+        }
+    }
+}
+
+impl FromStr for DbCompression {
+    type Err = ParseTuningError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(DbCompression::None),
+            "snappy" => Ok(DbCompression::Snappy),
+            "lz4" => Ok(DbCompression::Lz4),
+            "zstd" => Ok(DbCompression::Zstd),
+            _ => Err(ParseTuningError(format!("compression {}, supported values are: none, snappy, lz4, zstd", s)))
+        }
+    }
+}
+
+/// Options of the column family, options which are not set are taken from the less specific configuration
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnTuning {
+    pub compression: Option<DbCompression>,
+    /// Bits per key of the bloom filter, `0` disables the filter
+    pub bloom_filter_bits: Option<i32>,
+    /// Size of the table block in bytes
+    pub block_size: Option<usize>,
+    /// Size of one memtable in bytes
+    pub write_buffer_size: Option<usize>,
+    /// Max number of memtables (one active, the others waiting for the flush)
+    pub max_write_buffer_number: Option<i32>,
+}
+
+impl ColumnTuning {
+    /// Options set in `self` have priority over options set in `other`
+    pub fn or(self, other: ColumnTuning) -> ColumnTuning {
+        ColumnTuning {
+            compression: self.compression.or(other.compression),
+            bloom_filter_bits: self.bloom_filter_bits.or(other.bloom_filter_bits),
+            block_size: self.block_size.or(other.block_size),
+            write_buffer_size: self.write_buffer_size.or(other.write_buffer_size),
+            max_write_buffer_number: self.max_write_buffer_number.or(other.max_write_buffer_number),
+        }
+    }
+}
+
+/// Parses comma separated options, e.g. `compression=zstd,bloom_filter_bits=10,write_buffer_size=64M`.
+/// Sizes can have `K`, `M` or `G` suffix, see [parse_size].
+impl FromStr for ColumnTuning {
+    type Err = ParseTuningError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tuning = ColumnTuning::default();
+        for option in s.split(',').map(str::trim).filter(|option| !option.is_empty()) {
+            let mut parts = option.splitn(2, '=');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name.trim(), value.trim()),
+                _ => return Err(ParseTuningError(format!("option {}, expected <name>=<value>", option))),
+            };
+            let invalid_value = || ParseTuningError(format!("value of the option {}", option));
+            match name {
+                "compression" => tuning.compression = Some(value.parse()?),
+                "bloom_filter_bits" => tuning.bloom_filter_bits = Some(value.parse().map_err(|_| invalid_value())?),
+                "block_size" => tuning.block_size = Some(parse_size(value)?),
+                "write_buffer_size" => tuning.write_buffer_size = Some(parse_size(value)?),
+                "max_write_buffer_number" => tuning.max_write_buffer_number = Some(value.parse().map_err(|_| invalid_value())?),
+                _ => return Err(ParseTuningError(format!("option {}, supported options are: compression, bloom_filter_bits, block_size, write_buffer_size, max_write_buffer_number", name))),
+            }
+        }
+        Ok(tuning)
+    }
+}
+
+/// Parses size in bytes with optional binary suffix `K`, `M` or `G`, e.g. `512M`
+pub fn parse_size(s: &str) -> Result<usize, ParseTuningError> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last().map(|suffix| suffix.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    number.parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| ParseTuningError(format!("size {}", s)))
+}
+
+#[derive(Debug, Clone, Fail)]
+#[fail(display = "invalid database tuning: {}", _0)]
+pub struct ParseTuningError(String);
+
+/// Tuning resolved from the [DbConfiguration], which is used to create options of all column families of one database.
+/// Block cache is created just once and shared by all column families created with the same tuning.
+pub struct DbTuning {
+    cfg: DbConfiguration,
+    block_cache: Option<Cache>,
+}
+
+impl DbTuning {
+    pub fn new(cfg: &DbConfiguration) -> Result<Self, DBError> {
+        let block_cache = cfg.block_cache_size
+            .or_else(|| cfg.profile.block_cache_size())
+            .map(Cache::new_lru_cache)
+            .transpose()?;
+        Ok(Self { cfg: cfg.clone(), block_cache })
+    }
+
+    /// Resolved options of the column family
+    pub fn column_tuning(&self, name: &str) -> ColumnTuning {
+        self.cfg.column_overrides.get(name).cloned().unwrap_or_default()
+            .or(self.cfg.column_tuning.clone())
+            .or(self.cfg.profile.column_tuning())
+    }
+
+    #[inline]
+    pub fn block_cache(&self) -> Option<&Cache> {
+        self.block_cache.as_ref()
+    }
+}
+
+/// Tuning of the default configuration, which keeps the RocksDB defaults
+impl Default for DbTuning {
+    fn default() -> Self {
+        Self { cfg: DbConfiguration::default(), block_cache: None }
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Recovery of the sequence generators after a crash.
+//!
+//! Generators pre-allocate sequence numbers in batches and only the upper bound of the batch is stored.
+//! The bound is written without sync and not in the same atomic batch as the data using the numbers,
+//! so nothing guarantees, that the bound survives a crash whenever the data does (e.g. with a damaged write-ahead log
+//! or a database restored from a copy of its files). Generator would then hand out already
+//! used numbers again, e.g. overwritten context actions leave stale index entries behind,
+//! which break cursor paging in [ContextActionStorage::load_cursor].
+//!
+//! * [recover_sequences] runs on every startup. It scans the highest number used by each generator
+//!   and moves the generator past it.
+//! * In the audit mode ([Sequences::set_audit](crate::persistent::sequence::Sequences::set_audit)) every generated
+//!   number is verified not to be used yet, reuse is reported as [SequenceError::IdReused](crate::persistent::sequence::SequenceError::IdReused).
+
+use getset::{CopyGetters, Getters};
+use slog::{Logger, warn};
+
+use crate::{ContextActionStorage, IteratorMode, StorageError};
+use crate::persistent::{KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage};
+use crate::persistent::sequence::SequenceNumber;
+use crate::skip_list::DatabaseBackedSkipList;
+
+/// Recovery result of a single generator
+#[derive(Debug, Clone, Getters, CopyGetters)]
+pub struct SequenceRecovery {
+    /// Unique name of the generator
+    #[get = "pub"]
+    name: String,
+    /// Upper bound of the pre-allocated numbers stored in the database
+    #[get_copy = "pub"]
+    persisted: Option<SequenceNumber>,
+    /// Highest number used by the stored data
+    #[get_copy = "pub"]
+    max_used: Option<SequenceNumber>,
+    /// Generator was moved forward, because it would reuse already used numbers
+    #[get_copy = "pub"]
+    recovered: bool,
+}
+
+/// Summary of [recover_sequences]
+#[derive(Debug, Clone, Default, Getters)]
+pub struct SequenceRecoveryReport {
+    #[get = "pub"]
+    generators: Vec<SequenceRecovery>,
+}
+
+impl SequenceRecoveryReport {
+    /// Number of generators, which had to be moved forward
+    pub fn recovered(&self) -> usize {
+        self.generators.iter().filter(|generator| generator.recovered).count()
+    }
+}
+
+/// Ensure that no sequence generator hands out a number already used by the stored data
+pub fn recover_sequences(persistent_storage: &PersistentStorage, log: &Logger) -> Result<SequenceRecoveryReport, StorageError> {
+    let kv = persistent_storage.kv();
+    let mut report = SequenceRecoveryReport::default();
+
+    // context actions are keyed by the generated id, so the last key is the highest used one
+    let max_action_id = KeyValueStoreWithSchema::<ContextActionStorage>::iterator(kv.as_ref(), IteratorMode::End)?
+        .next()
+        .map(|(id, _)| id)
+        .transpose()?;
+    report.generators.push(recover_generator(persistent_storage, ContextActionStorage::name(), max_action_id, log)?);
+
+    let max_value_id = DatabaseBackedSkipList::max_value_id(kv.as_ref())?
+        .map(|id| id as SequenceNumber);
+    report.generators.push(recover_generator(persistent_storage, DatabaseBackedSkipList::name(), max_value_id, log)?);
+
+    Ok(report)
+}
+
+fn recover_generator(persistent_storage: &PersistentStorage, name: &str, max_used: Option<SequenceNumber>, log: &Logger) -> Result<SequenceRecovery, StorageError> {
+    let persisted = persistent_storage.seq().persisted(name)?;
+    let recovered = match max_used {
+        Some(max_used) => persistent_storage.seq().generator(name).recover(max_used)?,
+        None => false,
+    };
+    if recovered {
+        warn!(log, "Sequence generator was moved past already used numbers"; "generator" => name, "persisted" => persisted, "max_used" => max_used);
+    }
+
+    Ok(SequenceRecovery {
+        name: name.to_owned(),
+        persisted,
+        max_used,
+        recovered,
+    })
+}
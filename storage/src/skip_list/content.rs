@@ -1,6 +1,7 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use failure::_core::marker::PhantomData;
@@ -8,8 +9,12 @@ use failure::Fail;
 use rocksdb::{ColumnFamilyDescriptor, SliceTransform};
 use serde::{Deserialize, Serialize};
 
+use crypto::blake2b;
+use crypto::hash::Hash;
+
 use crate::num_from_slice;
-use crate::persistent::{BincodeEncoded, Codec, DBError, Decoder, default_table_options, Encoder, KeyValueSchema, KeyValueStoreWithSchema, SchemaError};
+use crate::persistent::{BincodeEncoded, Codec, DBError, DbTuning, Decoder, default_table_options, Encoder, KeyValueColumn, KeyValueSchema, KeyValueStoreWithSchema, SchemaError, WriteBatch};
+use crate::{Direction, IteratorMode};
 use crate::persistent::database::IteratorWithSchema;
 use crate::persistent::sequence::SequenceError;
 use crate::skip_list::{LEVEL_BASE, TryExtend};
@@ -122,18 +127,32 @@ impl BincodeEncoded for NodeHeader {}
 
 
 pub type ListValueDatabase = dyn KeyValueStoreWithSchema<ListValue> + Sync + Send;
+pub type ListValueBlobDatabase = dyn KeyValueStoreWithSchema<ListValueBlob> + Sync + Send;
+
+/// Values of at least this size are stored just once in the [ListValueBlob] column,
+/// list values (on all lanes) contain just a reference to it.
+///
+/// Reference takes 33 bytes (tag and 32 bytes hash) and the blob record adds its own 32 bytes key,
+/// so shorter values would not get smaller by deduplication, while every read would need one more lookup.
+pub(crate) const BLOB_MIN_LEN: usize = 64;
+/// Stored value is a hash of the value stored in [ListValueBlob]
+const TAG_BLOB_REF: u8 = 0xff;
+/// Stored value is an inline value starting with one of the tags
+const TAG_ESCAPED: u8 = 0xfe;
 
 pub struct ListValue {
     id: usize,
     db: Arc<ListValueDatabase>,
+    blob_db: Arc<ListValueBlobDatabase>,
 }
 
 impl ListValue {
-    pub fn new(id: usize, db: Arc<ListValueDatabase>) -> Self {
-        Self { id, db }
+    pub fn new(id: usize, db: Arc<ListValueDatabase>, blob_db: Arc<ListValueBlobDatabase>) -> Self {
+        Self { id, db, blob_db }
     }
 
-    /// Merge two values into one, in-place
+    /// Merge two values into one, in-place.
+    /// Values stored as blobs are not copied, just references to them.
     pub fn merge(&mut self, other: &Self) -> Result<(), SkipListError> {
         for (key, value) in self.db.prefix_iterator(&ListValueKey::from_id(other.id))? {
             self.db.put(&ListValueKey::new(self.id, &key?.key), &value?)?;
@@ -141,24 +160,185 @@ impl ListValue {
 
         Ok(())
     }
+
+    /// Convert encoded value to the form stored in the list value, large values are stored to the blob column
+    fn store(&self, value: Vec<u8>) -> Result<Vec<u8>, SkipListError> {
+        if value.len() >= BLOB_MIN_LEN {
+            let hash = blake2b::digest_256(&value);
+            self.blob_db.put(&hash, &value)?;
+            let mut stored = Vec::with_capacity(1 + hash.len());
+            stored.push(TAG_BLOB_REF);
+            stored.extend(hash);
+            Ok(stored)
+        } else if value.first().map_or(false, |tag| *tag == TAG_BLOB_REF || *tag == TAG_ESCAPED) {
+            let mut stored = Vec::with_capacity(1 + value.len());
+            stored.push(TAG_ESCAPED);
+            stored.extend(value);
+            Ok(stored)
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// Remove all key-value pairs stored in this value, returns number of removed keys
+    pub fn clear(&self) -> Result<usize, SkipListError> {
+        let keys = self.db.prefix_iterator(&ListValueKey::from_id(self.id))?
+            .map(|(key, _)| key)
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in &keys {
+            self.db.delete(key)?;
+        }
+
+        Ok(keys.len())
+    }
+
+    /// Check if any key-value pair is stored in the list value with given id
+    pub(crate) fn is_stored(db: &ListValueDatabase, id: usize) -> Result<bool, SkipListError> {
+        Ok(db.prefix_iterator(&ListValueKey::from_id(id))?.next().is_some())
+    }
+
+    /// Highest id of the list values (of any list), which contain at least one key-value pair
+    pub(crate) fn max_stored_id(db: &ListValueDatabase) -> Result<Option<usize>, SkipListError> {
+        match db.iterator(IteratorMode::End)?.next() {
+            Some((key, _)) => Ok(Some(key?.id)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Resolve value stored in the list value to the encoded value
+fn load(blob_db: &ListValueBlobDatabase, stored: Vec<u8>) -> Result<Vec<u8>, SkipListError> {
+    match stored.first() {
+        Some(&TAG_BLOB_REF) => {
+            let hash = blob_ref(&stored).ok_or(SkipListError::from(SchemaError::DecodeError))?;
+            blob_db.get(&hash.to_vec())?
+                .ok_or_else(|| SkipListError::InternalError { description: format!("Missing value blob: {}", hex::encode(hash)) })
+        }
+        Some(&TAG_ESCAPED) => Ok(stored[1..].to_vec()),
+        _ => Ok(stored),
+    }
+}
+
+/// Hash of the blob, if stored value is a reference
+pub(crate) fn blob_ref(stored: &[u8]) -> Option<&[u8]> {
+    match stored.first() {
+        Some(&TAG_BLOB_REF) if stored.len() > 1 => Some(&stored[1..]),
+        _ => None,
+    }
+}
+
+/// Escaped form of the value stored before values were tagged (database version 15 and older), which would be read as tagged.
+/// Stored context values were bincode encoded [Bucket]s, which start with the variant index and not with a tag,
+/// so this is checked by decoding instead of relying on the encoding. Tagged values (including escaped ones)
+/// cannot be decoded as a [Bucket], so nothing is escaped twice.
+pub(crate) fn escape_legacy_value(stored: &[u8]) -> Option<Vec<u8>> {
+    match stored.first() {
+        Some(&TAG_BLOB_REF) | Some(&TAG_ESCAPED) if <Bucket<Vec<u8>> as Decoder>::decode(stored).is_ok() => {
+            let mut escaped = Vec::with_capacity(1 + stored.len());
+            escaped.push(TAG_ESCAPED);
+            escaped.extend_from_slice(stored);
+            Some(escaped)
+        }
+        _ => None,
+    }
 }
 
 impl KeyValueSchema for ListValue {
     type Key = ListValueKey;
     type Value = Vec<u8>;
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        let mut cf_opts = default_table_options();
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
         cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(ListValueKey::LEN_ID));
         cf_opts.set_memtable_prefix_bloom_ratio(0.2);
         ColumnFamilyDescriptor::new(Self::name(), cf_opts)
     }
 
+    fn column() -> KeyValueColumn {
+        KeyValueColumn::new(Self::name()).with_prefix_len(ListValueKey::LEN_ID)
+    }
+
     fn name() -> &'static str {
         "skip_list_values"
     }
 }
 
+/// Removes blobs, which are not referenced by any list value (of any list) anymore.
+///
+/// Collection is split, so lists are blocked only for a short time:
+/// 1. [checkpoint](BlobCollector::checkpoint) - highest value id is read, lists must not be modified during the call,
+/// 2. [find_unreferenced](BlobCollector::find_unreferenced) - all values and blobs are scanned, lists can be modified,
+/// 3. [remove](BlobCollector::remove) - values added after the checkpoint are checked and blobs are removed in one batch,
+///    lists must not be modified during the call.
+///
+/// Values up to the checkpoint are complete, pushing to a list creates values with new (higher) ids,
+/// so just values added after the checkpoint can reference a blob found as unreferenced.
+pub struct BlobCollector {
+    db: Arc<ListValueDatabase>,
+    blob_db: Arc<ListValueBlobDatabase>,
+}
+
+impl BlobCollector {
+    pub fn new(db: Arc<ListValueDatabase>, blob_db: Arc<ListValueBlobDatabase>) -> Self {
+        Self { db, blob_db }
+    }
+
+    /// Highest id of the stored list values
+    pub fn checkpoint(&self) -> Result<Option<usize>, SkipListError> {
+        ListValue::max_stored_id(self.db.as_ref())
+    }
+
+    /// Blobs not referenced by any list value, values added after the `checkpoint` are not complete yet
+    /// and have to be checked by [remove](BlobCollector::remove)
+    pub fn find_unreferenced(&self) -> Result<HashSet<Hash>, SkipListError> {
+        let mut referenced = HashSet::new();
+        for (_, stored) in self.db.iterator(IteratorMode::Start)? {
+            if let Some(hash) = blob_ref(&stored?) {
+                referenced.insert(hash.to_vec());
+            }
+        }
+
+        Ok(self.blob_db.iterator(IteratorMode::Start)?
+            .filter_map(|(hash, _)| hash.ok())
+            .filter(|hash| !referenced.contains(hash))
+            .collect())
+    }
+
+    /// Remove `unreferenced` blobs, which are not referenced by values added after the `checkpoint` either.
+    /// Returns number of removed blobs.
+    pub fn remove(&self, checkpoint: Option<usize>, mut unreferenced: HashSet<Hash>) -> Result<usize, SkipListError> {
+        let added = match checkpoint {
+            Some(id) => self.db.iterator(IteratorMode::From(&ListValueKey::from_id(id + 1), Direction::Forward))?,
+            None => self.db.iterator(IteratorMode::Start)?,
+        };
+        for (_, stored) in added {
+            if let Some(hash) = blob_ref(&stored?) {
+                unreferenced.remove(hash);
+            }
+        }
+
+        let mut batch = WriteBatch::new();
+        for hash in &unreferenced {
+            batch.delete::<ListValueBlob>(hash)?;
+        }
+        self.blob_db.write_batch(batch)?;
+
+        Ok(unreferenced.len())
+    }
+}
+
+/// Content-addressed storage of large values (`blake2b hash -> value`), which are shared by all list values
+pub struct ListValueBlob;
+
+impl KeyValueSchema for ListValueBlob {
+    type Key = Hash;
+    type Value = Vec<u8>;
+
+    fn name() -> &'static str {
+        "skip_list_value_blobs"
+    }
+}
+
 impl<'a, K, V> TryExtend<(&'a K, &'a V)> for ListValue
     where
         K: Encoder + 'a,
@@ -166,7 +346,7 @@ impl<'a, K, V> TryExtend<(&'a K, &'a V)> for ListValue
 {
     fn try_extend<T: IntoIterator<Item=(&'a K, &'a V)>>(&mut self, iter: T) -> Result<(), SkipListError> {
         for (key, value) in iter {
-            self.db.put(&ListValueKey::new(self.id, &key.encode()?), &value.encode()?)?;
+            self.db.put(&ListValueKey::new(self.id, &key.encode()?), &self.store(value.encode()?)?)?;
         }
         Ok(())
     }
@@ -184,29 +364,29 @@ impl<'a, K: Codec, V: Decoder> TypedListValue<'a, K, V> for ListValue {
     /// Get value from stored container
     fn get(&self, value: &K) -> Result<Option<V>, SkipListError> {
         self.db.get(&ListValueKey::new(self.id, &value.encode()?))?
-            .map(|bytes| V::decode(&bytes))
+            .map(|stored| load(self.blob_db.as_ref(), stored).and_then(|bytes| V::decode(&bytes).map_err(SkipListError::from)))
             .transpose()
-            .map_err(SkipListError::from)
     }
 
     fn iter(&'a self) -> Result<Iter<'a, K, V>, SkipListError> {
-        Iter::create(self.id, &self.db)
+        Iter::create(self.id, &self.db, &self.blob_db)
     }
 
     fn iter_prefix(&'a self, prefix: &'a K) -> Result<IterPrefix<'a, K, V>, SkipListError> {
-        IterPrefix::create(self.id, prefix, &self.db)
+        IterPrefix::create(self.id, prefix, &self.db, &self.blob_db)
     }
 }
 
 pub struct Iter<'a, K, V> {
     inner: IteratorWithSchema<'a, ListValue>,
+    blob_db: &'a ListValueBlobDatabase,
     _phantom: PhantomData<(K, V)>,
 }
 
 impl<'a, K, V> Iter<'a, K, V> {
-    fn create(id: usize, db: &'a Arc<ListValueDatabase>) -> Result<Self, SkipListError> {
+    fn create(id: usize, db: &'a Arc<ListValueDatabase>, blob_db: &'a Arc<ListValueBlobDatabase>) -> Result<Self, SkipListError> {
         let inner = db.prefix_iterator(&ListValueKey::from_id(id))?;
-        Ok(Self { inner, _phantom: PhantomData })
+        Ok(Self { inner, blob_db: blob_db.as_ref(), _phantom: PhantomData })
     }
 }
 
@@ -214,21 +394,22 @@ impl<'a, K: Decoder, V: Decoder> Iterator for Iter<'a, K, V> {
     type Item = Result<(K, V), SkipListError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        extract_and_decode(self.inner.next())
+        extract_and_decode(self.blob_db, self.inner.next())
     }
 }
 
 pub struct IterPrefix<'a, K, V> {
     inner: IteratorWithSchema<'a, ListValue>,
+    blob_db: &'a ListValueBlobDatabase,
     prefix: Vec<u8>,
     _phantom: PhantomData<(K, V)>,
 }
 
 impl<'a, K: Encoder, V> IterPrefix<'a, K, V> {
-    fn create(id: usize, prefix: &'a K, db: &'a Arc<ListValueDatabase>) -> Result<Self, SkipListError> {
+    fn create(id: usize, prefix: &'a K, db: &'a Arc<ListValueDatabase>, blob_db: &'a Arc<ListValueBlobDatabase>) -> Result<Self, SkipListError> {
         let prefix = prefix.encode()?;
         let inner = db.prefix_iterator(&ListValueKey::new(id, &prefix))?;
-        Ok(Self { inner, prefix, _phantom: PhantomData })
+        Ok(Self { inner, blob_db: blob_db.as_ref(), prefix, _phantom: PhantomData })
     }
 }
 
@@ -243,16 +424,16 @@ impl<'a, K: Decoder, V: Decoder> Iterator for IterPrefix<'a, K, V> {
             }
         }
 
-        extract_and_decode(next)
+        extract_and_decode(self.blob_db, next)
     }
 }
 
-fn extract_and_decode<K: Decoder, V: Decoder>(value: Option<(Result<ListValueKey, SchemaError>, Result<Vec<u8>, SchemaError>)>) -> Option<Result<(K, V), SkipListError>> {
-    value.map(|(k, v)|
-        k.map(|k| k.key)
-            .and_then(|key| K::decode(&key))
-            .and_then(|k| v.and_then(|value| V::decode(&value)).map(|v| (k, v)))
-            .map_err(SkipListError::from))
+fn extract_and_decode<K: Decoder, V: Decoder>(blob_db: &ListValueBlobDatabase, value: Option<(Result<ListValueKey, SchemaError>, Result<Vec<u8>, SchemaError>)>) -> Option<Result<(K, V), SkipListError>> {
+    value.map(|(k, v)| -> Result<(K, V), SkipListError> {
+        let k = K::decode(&k?.key)?;
+        let v = V::decode(&load(blob_db, v?)?)?;
+        Ok((k, v))
+    })
 }
 
 trait StartsWith<T> {
@@ -370,6 +551,20 @@ impl<V: Serialize + for<'a> Deserialize<'a>> BincodeEncoded for Bucket<V> {}
 mod tests {
     use super::*;
 
+    #[test]
+    pub fn legacy_values_are_not_escaped() {
+        for value in vec![Bucket::Exists(vec![]), Bucket::Exists(vec![TAG_BLOB_REF, 1]), Bucket::Exists(vec![TAG_ESCAPED; 100]), Bucket::Deleted] {
+            let stored = Encoder::encode(&value).unwrap();
+            assert_eq!(None, escape_legacy_value(&stored));
+        }
+
+        // tagged values are not legacy values
+        let mut blob_ref = vec![TAG_BLOB_REF];
+        blob_ref.extend(blake2b::digest_256(&[1, 2, 3]));
+        assert_eq!(None, escape_legacy_value(&blob_ref));
+        assert_eq!(None, escape_legacy_value(&[TAG_ESCAPED, TAG_BLOB_REF, 1]));
+    }
+
     #[test]
     pub fn header_next() {
         let original = NodeHeader::new(0, 0, 0);
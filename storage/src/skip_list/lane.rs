@@ -5,9 +5,9 @@ use std::hash::Hash;
 use std::sync::Arc;
 
 use crate::persistent::{Codec, KeyValueSchema, KeyValueStoreWithSchema};
-use crate::persistent::sequence::SequenceGenerator;
+use crate::persistent::sequence::{SequenceError, SequenceGenerator, SequenceNumber};
 use crate::skip_list::{ListValue, SkipListError};
-use crate::skip_list::content::{ListValueDatabase, NodeHeader, SkipListId, TypedListValue};
+use crate::skip_list::content::{ListValueBlobDatabase, ListValueDatabase, NodeHeader, SkipListId, TypedListValue};
 
 pub type LaneDatabase = dyn KeyValueStoreWithSchema<Lane> + Sync + Send;
 
@@ -20,17 +20,18 @@ pub struct Lane {
     level: usize,
     lane_db: Arc<LaneDatabase>,
     value_db: Arc<ListValueDatabase>,
+    blob_db: Arc<ListValueBlobDatabase>,
     sequence_gen: Arc<SequenceGenerator>
 }
 
 impl Lane {
     /// Create new lane handler for given database
-    pub fn new(list_id: SkipListId, level: usize, lane_db: Arc<LaneDatabase>, value_db: Arc<ListValueDatabase>, sequence_gen: Arc<SequenceGenerator>) -> Self {
-        Lane { list_id, level, lane_db, value_db, sequence_gen }
+    pub fn new(list_id: SkipListId, level: usize, lane_db: Arc<LaneDatabase>, value_db: Arc<ListValueDatabase>, blob_db: Arc<ListValueBlobDatabase>, sequence_gen: Arc<SequenceGenerator>) -> Self {
+        Lane { list_id, level, lane_db, value_db, blob_db, sequence_gen }
     }
 
     fn new_level(self, level: usize) -> Self {
-        Self { list_id: self.list_id, level, lane_db: self.lane_db, value_db: self.value_db, sequence_gen: self.sequence_gen }
+        Self { list_id: self.list_id, level, lane_db: self.lane_db, value_db: self.value_db, blob_db: self.blob_db, sequence_gen: self.sequence_gen }
     }
 
     /// Get level of current handler
@@ -59,7 +60,7 @@ impl Lane {
 
     pub fn get_list_value(&self, index: usize) -> Result<Option<ListValue>, SkipListError> {
         self.lane_db.get(&self.node_header(index))
-            .map(|value_id| value_id.map(|value_id| ListValue::new(value_id, self.value_db.clone())))
+            .map(|value_id| value_id.map(|value_id| ListValue::new(value_id, self.value_db.clone(), self.blob_db.clone())))
             .map_err(SkipListError::from)
     }
 
@@ -71,12 +72,27 @@ impl Lane {
             None => {
                 // generate new unique value_id
                 let value_id = self.sequence_gen.next()? as usize;
+                if self.sequence_gen.is_audited() && ListValue::is_stored(self.value_db.as_ref(), value_id)? {
+                    return Err(SequenceError::IdReused { name: self.sequence_gen.name().to_owned(), id: value_id as SequenceNumber }.into());
+                }
                 self.lane_db.put(&self.node_header(index), &value_id)?;
                 value_id
             }
         };
 
-        Ok(ListValue::new(value_id, self.value_db.clone()))
+        Ok(ListValue::new(value_id, self.value_db.clone(), self.blob_db.clone()))
+    }
+
+    /// Remove node at given index together with its value, returns `false` if node was not stored
+    pub fn delete_list_value(&self, index: usize) -> Result<bool, SkipListError> {
+        match self.get_list_value(index)? {
+            Some(list_value) => {
+                list_value.clear()?;
+                self.lane_db.delete(&self.node_header(index))?;
+                Ok(true)
+            }
+            None => Ok(false)
+        }
     }
 }
 
@@ -17,9 +17,14 @@
 //! {S0 → S1 → S2}.
 //! * State re-creation for first 16 blocks can be done simply by traversing faster lanes (L1), and applying
 //! aggregated changes on lane descend {S015, S1215}.
+//!
+//! Aggregated nodes on higher lanes repeat values from lower lanes, so large values are stored just once
+//! in [ListValueBlob] column and nodes contain only references. Nodes and values, which are not needed anymore,
+//! are removed by [SkipList::prune], [SkipList::retain] and [BlobCollector].
 #![allow(dead_code)]
 
-pub use crate::skip_list::content::{Bucket, ListValue, SkipListError};
+pub use crate::skip_list::content::{BlobCollector, Bucket, ListValue, ListValueBlob, SkipListError};
+pub(crate) use crate::skip_list::content::escape_legacy_value;
 pub use crate::skip_list::lane::{Lane, TypedLane};
 pub use crate::skip_list::skip_list::{DatabaseBackedSkipList, SkipList, TypedSkipList};
 
@@ -1,17 +1,18 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::cmp::max;
-use std::collections::BTreeMap;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::hash::Hash;
 use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
-use crate::persistent::{BincodeEncoded, Codec, KeyValueSchema, KeyValueStoreWithSchema};
+use crate::IteratorMode;
+use crate::persistent::{BincodeEncoded, Codec, KeyValueSchema, KeyValueStore, KeyValueStoreWithSchema, SchemaError};
 use crate::persistent::sequence::SequenceGenerator;
-use crate::skip_list::{LEVEL_BASE, SkipListError, TryExtend};
-use crate::skip_list::content::{ListValueDatabase, NodeHeader, SkipListId};
+use crate::skip_list::{LEVEL_BASE, ListValue, SkipListError, TryExtend};
+use crate::skip_list::content::{BlobCollector, ListValueBlobDatabase, ListValueDatabase, NodeHeader, SkipListId};
 use crate::skip_list::lane::{Lane, LaneDatabase, TypedLane};
 
 pub type SkipListDatabase = dyn KeyValueStoreWithSchema<DatabaseBackedSkipList> + Sync + Send;
@@ -23,6 +24,7 @@ pub struct DatabaseBackedSkipList {
     list_db: Arc<SkipListDatabase>,
     lane_db: Arc<LaneDatabase>,
     value_db: Arc<ListValueDatabase>,
+    blob_db: Arc<ListValueBlobDatabase>,
     sequence_gen: Arc<SequenceGenerator>,
     list_id: SkipListId,
     state: SkipListState,
@@ -39,17 +41,40 @@ impl KeyValueSchema for DatabaseBackedSkipList {
 
 impl DatabaseBackedSkipList {
     /// Create new list in given database
-    pub fn new(list_id: SkipListId, db: Arc<rocksdb::DB>, sequence_gen: Arc<SequenceGenerator>) -> Result<Self, SkipListError> {
+    pub fn new(list_id: SkipListId, db: Arc<KeyValueStore>, sequence_gen: Arc<SequenceGenerator>) -> Result<Self, SkipListError> {
         let value_db: Arc<ListValueDatabase> = db.clone();
+        let blob_db: Arc<ListValueBlobDatabase> = db.clone();
         let lane_db: Arc<LaneDatabase> = db.clone();
         let list_db: Arc<SkipListDatabase> = db;
         let state = list_db.get(&list_id)?
             .unwrap_or_else(|| SkipListState {
                 levels: 1,
                 len: 0,
+                offset: 0,
             });
 
-        Ok(Self { list_db, lane_db, value_db, list_id, state, sequence_gen })
+        Ok(Self { list_db, lane_db, value_db, blob_db, list_id, state, sequence_gen })
+    }
+
+    /// Highest list value id referenced by any lane or stored in the value column.
+    /// Value ids are generated by a single sequence shared by all lists.
+    ///
+    /// Lane nodes are appended with increasing value ids, so just the last node of every lane is read.
+    /// Lane is followed past the length of the stored list state, which may be older than the lanes after a crash.
+    pub fn max_value_id(db: &KeyValueStore) -> Result<Option<usize>, SkipListError> {
+        let mut max_id = ListValue::max_stored_id(db)?;
+        for (list_id, state) in KeyValueStoreWithSchema::<DatabaseBackedSkipList>::iterator(db, IteratorMode::Start)? {
+            let (list_id, state) = (list_id?, state?);
+            // state can miss the last added lane as well
+            for lane_level in 0..=state.levels {
+                let mut node_index = (state.len / LEVEL_BASE.pow(lane_level as u32)).saturating_sub(1);
+                while let Some(value_id) = KeyValueStoreWithSchema::<Lane>::get(db, &NodeHeader::new(list_id, lane_level, node_index))? {
+                    max_id = max(max_id, Some(value_id));
+                    node_index += 1;
+                }
+            }
+        }
+        Ok(max_id)
     }
 
     /// Find highest level, which we should traverse to hit the index
@@ -62,7 +87,7 @@ impl DatabaseBackedSkipList {
     }
 
     fn lane(&self, level: usize) -> Lane {
-        Lane::new(self.list_id, level, self.lane_db.clone(), self.value_db.clone(), self.sequence_gen.clone())
+        Lane::new(self.list_id, level, self.lane_db.clone(), self.value_db.clone(), self.blob_db.clone(), self.sequence_gen.clone())
     }
 
     /// Rebuild state for given index
@@ -73,9 +98,10 @@ impl DatabaseBackedSkipList {
     {
         // There is an sequential index on lowest level, if expected index is bigger than
         // length of chain, it is not stored, otherwise, IT MUST BE FOUND.
-        if index >= self.state.len {
-            return Ok(None);
-        }
+        let index = match self.internal_index(index) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
 
         let mut results = Vec::with_capacity(2048);
         let mut lane = self.lane(Self::index_level(index));
@@ -124,6 +150,39 @@ impl DatabaseBackedSkipList {
 
         Ok(Some(results.into_iter().collect()))
     }
+
+    /// Translate index of the list to the index of the node on the lowest lane,
+    /// `None` for indexes before the [start](SkipList::start_at) or after the end of the list.
+    fn internal_index(&self, index: usize) -> Option<usize> {
+        index.checked_sub(self.state.offset)
+            .filter(|index| *index < self.state.len)
+    }
+
+    /// Nodes (as `(level, index)`), which are read to rebuild state for given index.
+    /// Traversal is the same as in [get_internal](DatabaseBackedSkipList::get_internal).
+    fn path(&self, index: usize) -> Vec<(usize, usize)> {
+        let mut path = vec![];
+        let mut pos = NodeHeader::new(self.list_id, Self::index_level(index), 0);
+
+        loop {
+            path.push((pos.level(), pos.index()));
+            if pos.base_index() == index {
+                return path;
+            }
+
+            let mut desc = false;
+            while pos.next().base_index() > index {
+                if pos.level() == 0 {
+                    return path;
+                }
+                pos = pos.lower();
+                desc = true;
+            }
+            if !desc {
+                pos = pos.next();
+            }
+        }
+    }
 }
 
 pub trait SkipList {
@@ -132,13 +191,24 @@ pub trait SkipList {
     fn levels(&self) -> usize;
 
     fn contains(&self, index: usize) -> bool;
+
+    fn prune(&self, from_index: usize, to_index: usize) -> Result<usize, SkipListError>;
+
+    fn retain(&self, indexes: &BTreeSet<usize>) -> Result<usize, SkipListError>;
+
+    fn collect_garbage(&self) -> Result<usize, SkipListError>;
+
+    fn start_at(&mut self, index: usize) -> Result<(), SkipListError>;
+
+    /// Load list state from the database again, it could have been changed by another instance
+    fn reload(&mut self) -> Result<(), SkipListError>;
 }
 
 impl SkipList for DatabaseBackedSkipList {
-    /// Get number of elements stored in this node
+    /// Get number of elements stored in this node, including not available elements before the start of the list
     #[inline]
     fn len(&self) -> usize {
-        self.state.len
+        self.state.offset + self.state.len
     }
 
     #[inline]
@@ -149,7 +219,95 @@ impl SkipList for DatabaseBackedSkipList {
     /// Check, that given index is stored in structure
     #[inline]
     fn contains(&self, index: usize) -> bool {
-        self.state.len > index
+        self.internal_index(index).is_some()
+    }
+
+    /// Remove nodes which are not needed to rebuild state at any index from `to_index` onwards.
+    /// `from_index` is the value of `to_index` used by previous pruning (or 0).
+    ///
+    /// Node can be removed, if its parent on the higher lane is complete and ends before `to_index`,
+    /// because parent already contains merged values of all its children. Edge nodes are kept,
+    /// as they are used when descending from a parent to the lower lane.
+    /// Returns number of removed nodes.
+    fn prune(&self, from_index: usize, to_index: usize) -> Result<usize, SkipListError> {
+        let from_index = from_index.saturating_sub(self.state.offset);
+        let to_index = min(to_index.saturating_sub(self.state.offset), self.state.len);
+        let mut removed = 0;
+
+        for level in 0..self.state.levels.saturating_sub(1) {
+            let lane = self.lane(level);
+            let parent_size = LEVEL_BASE.pow(level as u32 + 1);
+            for parent_index in (from_index / parent_size)..(to_index / parent_size) {
+                let start = parent_index * LEVEL_BASE;
+                for index in start..(start + LEVEL_BASE - 1) {
+                    if lane.delete_list_value(index)? {
+                        removed += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove all nodes, which are not needed to rebuild state at any of the `indexes`.
+    /// Nodes needed to rebuild the last state (and so to push new values) are always kept.
+    /// This goes through all nodes of the list, so it is meant to be run occasionally.
+    /// Returns number of removed nodes.
+    fn retain(&self, indexes: &BTreeSet<usize>) -> Result<usize, SkipListError> {
+        if self.state.len == 0 {
+            return Ok(0);
+        }
+
+        let mut needed: HashSet<(usize, usize)> = self.path(self.state.len - 1).into_iter().collect();
+        for index in indexes.iter().filter_map(|index| self.internal_index(*index)) {
+            needed.extend(self.path(index));
+        }
+
+        let mut removed = 0;
+        for level in 0..self.state.levels {
+            let lane = self.lane(level);
+            let lane_len = self.state.len / LEVEL_BASE.pow(level as u32);
+            for index in (0..lane_len).filter(|index| !needed.contains(&(level, *index))) {
+                if lane.delete_list_value(index)? {
+                    removed += 1;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove value blobs, which are not referenced anymore (e.g. after [prune](SkipList::prune)).
+    /// Blobs are shared by all lists, so list must not be modified during collection,
+    /// use [BlobCollector] directly to block lists just for a short time.
+    /// Returns number of removed blobs.
+    fn collect_garbage(&self) -> Result<usize, SkipListError> {
+        let collector = BlobCollector::new(self.value_db.clone(), self.blob_db.clone());
+        let checkpoint = collector.checkpoint()?;
+        let unreferenced = collector.find_unreferenced()?;
+        collector.remove(checkpoint, unreferenced)
+    }
+
+    /// Start empty list at the `index`, so the first pushed value is stored at the `index`
+    /// and states at lower indexes are not available. Used, when list is created from a state
+    /// (e.g. from a snapshot), without all previous values.
+    fn start_at(&mut self, index: usize) -> Result<(), SkipListError> {
+        if self.state.len > 0 {
+            return Err(SkipListError::InternalError {
+                description: format!("List can be started at index {} only when empty, but it has {} values", index, self.state.len),
+            });
+        }
+        self.state.offset = index;
+        self.list_db.put(&self.list_id, &self.state)
+            .map_err(SkipListError::from)
+    }
+
+    fn reload(&mut self) -> Result<(), SkipListError> {
+        if let Some(state) = self.list_db.get(&self.list_id)? {
+            self.state = state;
+        }
+        Ok(())
     }
 }
 
@@ -179,9 +337,10 @@ impl<K, V> TypedSkipList<K, V> for DatabaseBackedSkipList
 
     /// Get single value from state at given index
     fn get_key(&self, index: usize, key: &K) -> Result<Option<V>, SkipListError> {
-        if index >= self.state.len {
-            return Ok(None);
-        }
+        let index = match self.internal_index(index) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
 
         let highest_level = Self::index_level(index);
         let mut lane = self.lane(0);
@@ -247,6 +406,21 @@ pub struct SkipListState {
     levels: usize,
     /// length (number of unique items) stored in skip list
     len: usize,
+    /// index of the first item, see [start_at](SkipList::start_at)
+    offset: usize,
 }
 
-impl BincodeEncoded for SkipListState {}
\ No newline at end of file
+/// State stored before the `offset` was added
+#[derive(Deserialize)]
+struct SkipListStateV1 {
+    levels: usize,
+    len: usize,
+}
+
+impl BincodeEncoded for SkipListState {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        bincode::deserialize(bytes)
+            .or_else(|_| bincode::deserialize::<SkipListStateV1>(bytes).map(|SkipListStateV1 { levels, len }| SkipListState { levels, len, offset: 0 }))
+            .map_err(|_| SchemaError::DecodeError)
+    }
+}
\ No newline at end of file
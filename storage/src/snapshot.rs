@@ -0,0 +1,621 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Chain data snapshots for fast bootstrapping.
+//!
+//! Snapshot is a single portable file, which holds everything needed to start a node from the chosen block,
+//! without replaying the whole chain from genesis:
+//! * block headers with json/additional data (from `BlockStorage` commit log),
+//! * `BlockMetaStorage` and `OperationsMetaStorage` records,
+//! * operations from `OperationsStorage`,
+//! * the whole context (skip list state) of the chosen block with the metadata of its commit,
+//!   so the context hash can be recomputed and checked during the import.
+//!
+//! Full snapshot contains all blocks down to the genesis, rolling snapshot contains just last `n` blocks.
+//!
+//! * file layout: `[magic(8)][record_len(4)][record]...`, where record is bincode encoded [SnapshotRecord]
+//! * first record is always a header, than blocks follows (from the chosen block to the oldest one), than context chunks
+//!   and the last record is always an end record (with counts and context checksum)
+//!
+//! Snapshot file does not contain the OCaml (protocol) context, it is exported next to the snapshot file
+//! to the directory `<snapshot>.context` and imported from there to the `tezos_data_dir`.
+//! If the directory is missing, matching protocol context has to be already present in the `tezos_data_dir`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use failure::Fail;
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use slog::{info, Logger};
+
+use crypto::blake2b;
+use crypto::hash::{BlockHash, ChainId, ContextHash, HashType};
+use tezos_messages::Head;
+use tezos_messages::p2p::binary_message::MessageHash;
+use tezos_messages::p2p::encoding::prelude::OperationsForBlocksMessage;
+
+use tezos_context::channel::ContextAction;
+
+use crate::{BlockAdditionalData, BlockHeaderWithHash, BlockJsonData, BlockMetaStorage, BlockStorage, BlockStorageReader, ChainMetaStorage, ContextActionStorage, OperationsMetaStorage, OperationsStorage, OperationsStorageReader, StorageError};
+use crate::backup::copy_dir;
+use crate::block_meta_storage;
+use crate::chain_meta_storage::ChainMetaStorageReader;
+use crate::merkle_storage::{CommitInfo, hash_context, MerkleError, MerkleStorage};
+use crate::operations_meta_storage;
+use crate::persistent::{BincodeEncoded, ContextMap, Decoder, Encoder, PersistentStorage, SchemaError};
+use crate::skip_list::{Bucket, SkipList, SkipListError};
+
+/// Version of the snapshot file format
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TZEDGESN";
+/// How many context key-values are stored at most in one context record
+const CONTEXT_CHUNK_SIZE: usize = 4096;
+/// Context record is written, when its keys and values exceed this size
+const CONTEXT_CHUNK_BYTES: usize = 4 * 1024 * 1024;
+/// Limit of the binary record length, so the corrupted length does not allocate the whole memory
+const MAX_RECORD_LEN: usize = 64 * 1024 * 1024;
+/// Directory of the OCaml (protocol) context in the `tezos_data_dir`
+const PROTOCOL_CONTEXT_DIR: &str = "context";
+
+/// Possible errors for snapshot export/import
+#[derive(Debug, Fail)]
+pub enum SnapshotError {
+    #[fail(display = "Storage error: {}", error)]
+    StorageError {
+        error: StorageError
+    },
+    #[fail(display = "Context storage error: {}", error)]
+    ContextError {
+        error: SkipListError
+    },
+    #[fail(display = "Merkle storage error: {}", error)]
+    MerkleError {
+        error: MerkleError
+    },
+    #[fail(display = "Snapshot I/O error: {}", error)]
+    IOError {
+        error: io::Error
+    },
+    #[fail(display = "Snapshot schema error: {}", error)]
+    SchemaError {
+        error: SchemaError
+    },
+    #[fail(display = "Block {} was not found in storage", block_hash)]
+    MissingBlock {
+        block_hash: String
+    },
+    #[fail(display = "Block {} is not applied, only applied block can be exported", block_hash)]
+    BlockNotApplied {
+        block_hash: String
+    },
+    #[fail(display = "Context for level {} was not found", level)]
+    MissingContext {
+        level: i32
+    },
+    #[fail(display = "Commit of the context {} was found neither in merkle storage nor in context actions", context_hash)]
+    MissingCommit {
+        context_hash: String
+    },
+    #[fail(display = "Snapshot record length {} exceeds the limit {}", len, MAX_RECORD_LEN)]
+    RecordTooLarge {
+        len: usize
+    },
+    #[fail(display = "Storage is not empty, snapshot can be imported just to empty storage")]
+    StorageNotEmpty,
+    #[fail(display = "Protocol context was not found neither in {:?} nor in {:?}", snapshot_context, tezos_data_context)]
+    MissingProtocolContext {
+        snapshot_context: PathBuf,
+        tezos_data_context: PathBuf,
+    },
+    #[fail(display = "Protocol context directory {:?} already exists", path)]
+    ProtocolContextExists {
+        path: PathBuf
+    },
+    #[fail(display = "Invalid snapshot: {}", reason)]
+    InvalidSnapshot {
+        reason: String
+    },
+}
+
+impl From<StorageError> for SnapshotError {
+    fn from(error: StorageError) -> Self {
+        SnapshotError::StorageError { error }
+    }
+}
+
+impl From<SkipListError> for SnapshotError {
+    fn from(error: SkipListError) -> Self {
+        SnapshotError::ContextError { error }
+    }
+}
+
+impl From<MerkleError> for SnapshotError {
+    fn from(error: MerkleError) -> Self {
+        SnapshotError::MerkleError { error }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::IOError { error }
+    }
+}
+
+impl From<SchemaError> for SnapshotError {
+    fn from(error: SchemaError) -> Self {
+        SnapshotError::SchemaError { error }
+    }
+}
+
+impl slog::Value for SnapshotError {
+    fn serialize(&self, _record: &slog::Record, key: slog::Key, serializer: &mut dyn slog::Serializer) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{}", self))
+    }
+}
+
+macro_rules! invalid_snapshot {
+    ($($arg:tt)*) => {{
+        SnapshotError::InvalidSnapshot { reason: format!($($arg)*) }
+    }}
+}
+
+/// Which blocks are exported to the snapshot
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotMode {
+    /// All blocks down to the genesis
+    Full,
+    /// Just last `n` blocks (including the chosen one)
+    Rolling(usize),
+}
+
+/// Snapshot header describes the block, at which snapshot was created
+#[derive(Serialize, Deserialize, Debug, Clone, Getters, CopyGetters)]
+pub struct SnapshotHeader {
+    #[get_copy = "pub"]
+    version: u16,
+    #[get = "pub"]
+    chain_id: ChainId,
+    #[get = "pub"]
+    block_hash: BlockHash,
+    #[get_copy = "pub"]
+    level: i32,
+    #[get = "pub"]
+    context_hash: ContextHash,
+    #[get_copy = "pub"]
+    mode: SnapshotMode,
+    #[get = "pub"]
+    commit: SnapshotCommit,
+}
+
+/// Metadata of the context commit, which are needed (together with the context) to recompute the context hash
+#[derive(Serialize, Deserialize, Debug, Clone, Getters, CopyGetters)]
+pub struct SnapshotCommit {
+    #[get = "pub"]
+    parents: Vec<ContextHash>,
+    #[get_copy = "pub"]
+    date: i64,
+    #[get = "pub"]
+    author: String,
+    #[get = "pub"]
+    message: String,
+}
+
+impl SnapshotCommit {
+    fn info(&self) -> CommitInfo {
+        CommitInfo {
+            parents: &self.parents,
+            date: self.date,
+            author: &self.author,
+            message: &self.message,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotBlock {
+    header: BlockHeaderWithHash,
+    json_data: Option<BlockJsonData>,
+    additional_data: Option<BlockAdditionalData>,
+    /// encoded `block_meta_storage::Meta`
+    block_meta: Vec<u8>,
+    /// encoded `operations_meta_storage::Meta`
+    operations_meta: Option<Vec<u8>>,
+    /// encoded `OperationsForBlocksMessage` for every validation pass
+    operations: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum SnapshotRecord {
+    Header(SnapshotHeader),
+    Block(SnapshotBlock),
+    Context(Vec<(String, Vec<u8>)>),
+    End {
+        blocks: usize,
+        context_keys: usize,
+        context_checksum: Vec<u8>,
+    },
+}
+
+impl BincodeEncoded for SnapshotRecord {}
+
+/// Running checksum of the context records
+struct ContextChecksum(Vec<u8>);
+
+impl ContextChecksum {
+    fn new() -> Self {
+        ContextChecksum(Vec::new())
+    }
+
+    fn update(&mut self, chunk: &[(String, Vec<u8>)]) {
+        let mut data = self.0.clone();
+        for (key, value) in chunk {
+            data.extend(key.as_bytes());
+            data.extend(blake2b::digest_256(value));
+        }
+        self.0 = blake2b::digest_256(&data);
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &SnapshotRecord) -> Result<(), SnapshotError> {
+    let bytes = Encoder::encode(record)?;
+    if bytes.len() > MAX_RECORD_LEN {
+        return Err(SnapshotError::RecordTooLarge { len: bytes.len() });
+    }
+    writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+fn read_record<R: Read>(reader: &mut R) -> Result<SnapshotRecord, SnapshotError> {
+    let mut len_bytes = [0u8; 4];
+    reader.read_exact(&mut len_bytes)?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(SnapshotError::RecordTooLarge { len });
+    }
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    <SnapshotRecord as Decoder>::decode(&bytes).map_err(SnapshotError::from)
+}
+
+/// Directory, where the protocol context is exported next to the snapshot file at `path`
+pub fn protocol_context_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut context_path = OsString::from(path.as_ref().as_os_str());
+    context_path.push(".context");
+    PathBuf::from(context_path)
+}
+
+/// Metadata of the commit of the block context, which are read from the merkle storage
+/// or from the stored context actions of the block, whichever is available
+fn find_commit(persistent_storage: &PersistentStorage, block_hash: &BlockHash, context_hash: &ContextHash) -> Result<SnapshotCommit, SnapshotError> {
+    match MerkleStorage::new(persistent_storage).get_commit(context_hash) {
+        Ok(commit) => return Ok(SnapshotCommit {
+            parents: commit.parents,
+            date: commit.date,
+            author: commit.author,
+            message: commit.message,
+        }),
+        Err(MerkleError::EntryNotFound { .. }) => (),
+        Err(error) => return Err(error.into()),
+    }
+
+    ContextActionStorage::new(persistent_storage).get_by_block_hash(block_hash)?
+        .into_iter()
+        .find_map(|record| match record.into_action() {
+            ContextAction::Commit { new_context_hash, parents, date, author, message, .. } if &new_context_hash == context_hash => {
+                Some(SnapshotCommit { parents, date, author, message })
+            }
+            _ => None,
+        })
+        .ok_or_else(|| SnapshotError::MissingCommit { context_hash: HashType::ContextHash.bytes_to_string(context_hash) })
+}
+
+fn is_empty_dir(path: &Path) -> Result<bool, io::Error> {
+    if !path.exists() {
+        return Ok(true);
+    }
+    Ok(fs::read_dir(path)?.next().is_none())
+}
+
+/// Export snapshot of the chain data at `block_hash` to the file at `path`
+/// and protocol context from the `tezos_data_dir` to the [protocol_context_path].
+///
+/// Block must be applied, because we need its context. Metadata of the context commit are needed as well,
+/// so either context hash check (merkle storage) or storing of the context actions has to be enabled, when the block is applied.
+/// Protocol runner must not run during the export, because protocol context is copied as it is.
+pub fn export_snapshot<P: AsRef<Path>>(
+    persistent_storage: &PersistentStorage,
+    tezos_data_dir: &Path,
+    block_hash: &BlockHash,
+    mode: SnapshotMode,
+    path: P,
+    log: &Logger) -> Result<SnapshotHeader, SnapshotError> {
+    let block_storage = BlockStorage::new(persistent_storage);
+    let block_meta_storage = BlockMetaStorage::new(persistent_storage);
+    let operations_storage = OperationsStorage::new(persistent_storage);
+    let operations_meta_storage = OperationsMetaStorage::new(persistent_storage);
+
+    let block = block_storage.get(block_hash)?
+        .ok_or_else(|| SnapshotError::MissingBlock { block_hash: HashType::BlockHash.bytes_to_string(block_hash) })?;
+    let block_meta = block_meta_storage.get(block_hash)?
+        .ok_or_else(|| SnapshotError::MissingBlock { block_hash: HashType::BlockHash.bytes_to_string(block_hash) })?;
+    if !block_meta.is_applied() {
+        return Err(SnapshotError::BlockNotApplied { block_hash: HashType::BlockHash.bytes_to_string(block_hash) });
+    }
+    let context_path = protocol_context_path(&path);
+    if context_path.exists() {
+        return Err(SnapshotError::ProtocolContextExists { path: context_path });
+    }
+
+    let header = SnapshotHeader {
+        version: SNAPSHOT_VERSION,
+        chain_id: block_meta.chain_id().clone(),
+        block_hash: block_hash.clone(),
+        level: block.header.level(),
+        context_hash: block.header.context().clone(),
+        mode,
+        commit: find_commit(persistent_storage, block_hash, block.header.context())?,
+    };
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    write_record(&mut writer, &SnapshotRecord::Header(header.clone()))?;
+
+    // export blocks from the chosen one to the oldest one
+    let mut blocks = 0;
+    let mut current_block_hash = block_hash.clone();
+    loop {
+        let block = block_storage.get(&current_block_hash)?
+            .ok_or_else(|| SnapshotError::MissingBlock { block_hash: HashType::BlockHash.bytes_to_string(&current_block_hash) })?;
+        let mut block_meta = block_meta_storage.get(&current_block_hash)?
+            .ok_or_else(|| SnapshotError::MissingBlock { block_hash: HashType::BlockHash.bytes_to_string(&current_block_hash) })?;
+        if blocks == 0 {
+            // successors of the chosen block are not part of the snapshot
+            block_meta.set_successor(None);
+        }
+        let operations = operations_storage.get_operations(&current_block_hash)?
+            .iter()
+            .map(|operations| operations.encode())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        write_record(&mut writer, &SnapshotRecord::Block(SnapshotBlock {
+            json_data: block_storage.get_with_json_data(&current_block_hash)?.map(|(_, json_data)| json_data),
+            additional_data: block_storage.get_with_additional_data(&current_block_hash)?.map(|(_, additional_data)| additional_data),
+            block_meta: block_meta.encode()?,
+            operations_meta: operations_meta_storage.get(&current_block_hash)?.map(|meta| meta.encode()).transpose()?,
+            operations,
+            header: block.clone(),
+        }))?;
+        blocks += 1;
+
+        // genesis is predecessor of itself
+        let predecessor = block.header.predecessor();
+        if block.header.level() == 0 || *predecessor == current_block_hash {
+            break;
+        }
+        if let SnapshotMode::Rolling(depth) = mode {
+            if blocks >= depth {
+                break;
+            }
+        }
+        current_block_hash = predecessor.clone();
+    }
+
+    // export context of the chosen block
+    let context = {
+        let list = persistent_storage.context_storage();
+        let list = list.read().expect("lock poisoning");
+        list.get(header.level as usize)?
+            .ok_or(SnapshotError::MissingContext { level: header.level })?
+    };
+    let mut checksum = ContextChecksum::new();
+    let mut context_keys = 0;
+    let mut chunk = Vec::with_capacity(CONTEXT_CHUNK_SIZE);
+    let mut chunk_bytes = 0;
+    for (key, value) in context {
+        if let Bucket::Exists(value) = value {
+            chunk_bytes += key.len() + value.len();
+            chunk.push((key, value));
+        }
+        if chunk.len() >= CONTEXT_CHUNK_SIZE || chunk_bytes >= CONTEXT_CHUNK_BYTES {
+            checksum.update(&chunk);
+            context_keys += chunk.len();
+            write_record(&mut writer, &SnapshotRecord::Context(mem::take(&mut chunk)))?;
+            chunk_bytes = 0;
+        }
+    }
+    if !chunk.is_empty() {
+        checksum.update(&chunk);
+        context_keys += chunk.len();
+        write_record(&mut writer, &SnapshotRecord::Context(chunk))?;
+    }
+
+    write_record(&mut writer, &SnapshotRecord::End { blocks, context_keys, context_checksum: checksum.0 })?;
+    writer.flush()?;
+
+    // protocol context is needed to apply successors of the block
+    let tezos_data_context = tezos_data_dir.join(PROTOCOL_CONTEXT_DIR);
+    if tezos_data_context.exists() {
+        fs::create_dir_all(&context_path)?;
+        copy_dir(&tezos_data_context, &context_path, &[])?;
+    }
+
+    info!(log, "Snapshot exported";
+               "block_hash" => HashType::BlockHash.bytes_to_string(&header.block_hash),
+               "level" => header.level,
+               "blocks" => blocks,
+               "context_keys" => context_keys);
+
+    Ok(header)
+}
+
+/// Import snapshot from the file at `path` to the empty storage
+/// and protocol context from the [protocol_context_path] to the `tezos_data_dir`.
+///
+/// Every block is checked against its hash and against the hash chain, which starts at the snapshot header.
+/// Context hash of the header block is recomputed from the imported context and the commit metadata,
+/// context is stored (as a single state at the level of the header block) only if it matches.
+/// Current head is set only, if all checks pass, so storage with failed import should be deleted and import started again.
+pub fn import_snapshot<P: AsRef<Path>>(
+    persistent_storage: &PersistentStorage,
+    tezos_data_dir: &Path,
+    expected_chain_id: &ChainId,
+    path: P,
+    log: &Logger) -> Result<SnapshotHeader, SnapshotError> {
+    let block_storage = BlockStorage::new(persistent_storage);
+    let block_meta_storage = BlockMetaStorage::new(persistent_storage);
+    let chain_meta_storage = ChainMetaStorage::new(persistent_storage);
+    let operations_storage = OperationsStorage::new(persistent_storage);
+    let operations_meta_storage = OperationsMetaStorage::new(persistent_storage);
+    let context_list = persistent_storage.context_storage();
+
+    // we can import just to empty storage
+    if chain_meta_storage.get_current_head(expected_chain_id)?.is_some()
+        || context_list.read().expect("lock poisoning").len() > 0 {
+        return Err(SnapshotError::StorageNotEmpty);
+    }
+
+    // without protocol context, successors of the imported head could not be applied
+    let snapshot_context = protocol_context_path(&path);
+    let tezos_data_context = tezos_data_dir.join(PROTOCOL_CONTEXT_DIR);
+    let import_protocol_context = snapshot_context.exists();
+    if import_protocol_context {
+        if !is_empty_dir(&tezos_data_context)? {
+            return Err(SnapshotError::ProtocolContextExists { path: tezos_data_context });
+        }
+    } else if is_empty_dir(&tezos_data_context)? {
+        return Err(SnapshotError::MissingProtocolContext { snapshot_context, tezos_data_context });
+    }
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(invalid_snapshot!("file is not a snapshot"));
+    }
+
+    let header = match read_record(&mut reader)? {
+        SnapshotRecord::Header(header) => header,
+        _ => return Err(invalid_snapshot!("missing snapshot header")),
+    };
+    if header.version != SNAPSHOT_VERSION {
+        return Err(invalid_snapshot!("unsupported version {}, expected {}", header.version, SNAPSHOT_VERSION));
+    }
+    if header.chain_id != *expected_chain_id {
+        return Err(invalid_snapshot!("snapshot was created for chain {}, but expected chain is {}",
+                                     HashType::ChainId.bytes_to_string(&header.chain_id),
+                                     HashType::ChainId.bytes_to_string(expected_chain_id)));
+    }
+
+    // blocks are ordered from the header block to the oldest one, so we follow predecessors
+    let mut expected_block_hash = header.block_hash.clone();
+    let mut expected_level = header.level;
+    let mut blocks = 0;
+    let mut context: ContextMap = BTreeMap::new();
+    let mut checksum = ContextChecksum::new();
+    let (end_blocks, end_context_keys, end_context_checksum) = loop {
+        match read_record(&mut reader)? {
+            SnapshotRecord::Block(block) => {
+                if !context.is_empty() {
+                    return Err(invalid_snapshot!("block record found after context records"));
+                }
+                let block_hash = block.header.header.message_hash().map_err(StorageError::from)?;
+                if block_hash != block.header.hash {
+                    return Err(invalid_snapshot!("block {} has invalid hash", HashType::BlockHash.bytes_to_string(&block.header.hash)));
+                }
+                if block_hash != expected_block_hash || block.header.header.level() != expected_level {
+                    return Err(invalid_snapshot!("broken block chain, expected block {} at level {}, but found {} at level {}",
+                                                 HashType::BlockHash.bytes_to_string(&expected_block_hash), expected_level,
+                                                 HashType::BlockHash.bytes_to_string(&block_hash), block.header.header.level()));
+                }
+                if blocks == 0 && block.header.header.context() != &header.context_hash {
+                    return Err(invalid_snapshot!("context hash of block {} does not match snapshot header", HashType::BlockHash.bytes_to_string(&block_hash)));
+                }
+
+                let block_meta = <block_meta_storage::Meta as Decoder>::decode(&block.block_meta)?;
+                if block_meta.chain_id() != expected_chain_id || block_meta.level() != expected_level {
+                    return Err(invalid_snapshot!("invalid metadata for block {}", HashType::BlockHash.bytes_to_string(&block_hash)));
+                }
+
+                block_storage.put_block_header(&block.header)?;
+                if let Some(json_data) = block.json_data {
+                    block_storage.put_block_json_data(&block_hash, json_data)?;
+                }
+                if let Some(additional_data) = block.additional_data {
+                    block_storage.put_block_additional_data(&block_hash, additional_data)?;
+                }
+                block_meta_storage.put(&block_hash, &block_meta)?;
+                if let Some(operations_meta) = block.operations_meta {
+                    operations_meta_storage.put(&block_hash, &<operations_meta_storage::Meta as Decoder>::decode(&operations_meta)?)?;
+                }
+                for operations in block.operations {
+                    let operations = <OperationsForBlocksMessage as Decoder>::decode(&operations)?;
+                    if operations.operations_for_block().hash() != &block_hash {
+                        return Err(invalid_snapshot!("operations does not belong to block {}", HashType::BlockHash.bytes_to_string(&block_hash)));
+                    }
+                    operations_storage.put_operations(&operations)?;
+                }
+
+                blocks += 1;
+                expected_block_hash = block.header.header.predecessor().clone();
+                expected_level -= 1;
+            }
+            SnapshotRecord::Context(chunk) => {
+                checksum.update(&chunk);
+                context.extend(chunk.into_iter().map(|(key, value)| (key, Bucket::Exists(value))));
+            }
+            SnapshotRecord::End { blocks, context_keys, context_checksum } => break (blocks, context_keys, context_checksum),
+            SnapshotRecord::Header(_) => return Err(invalid_snapshot!("duplicated snapshot header")),
+        }
+    };
+
+    if blocks == 0 || blocks != end_blocks {
+        return Err(invalid_snapshot!("expected {} blocks, but found {}", end_blocks, blocks));
+    }
+    if context.len() != end_context_keys || checksum.0 != end_context_checksum {
+        return Err(invalid_snapshot!("context does not match its checksum"));
+    }
+    let context_hash = hash_context(&context, &header.commit.info());
+    if context_hash != header.context_hash {
+        return Err(invalid_snapshot!("context hash {} does not match computed context hash {}",
+                                     HashType::ContextHash.bytes_to_string(&header.context_hash),
+                                     HashType::ContextHash.bytes_to_string(&context_hash)));
+    }
+
+    // context is stored in skip list at the index of the block level, states of lower levels are not available
+    {
+        let mut list = context_list.write().expect("lock poisoning");
+        list.start_at(header.level as usize)?;
+        list.push(&context)?;
+    }
+    block_storage.assign_to_context(&header.block_hash, &header.context_hash)?;
+
+    if import_protocol_context {
+        fs::create_dir_all(&tezos_data_context)?;
+        copy_dir(&snapshot_context, &tezos_data_context, &[])?;
+    }
+
+    // everything is checked and stored, so we can mark current head
+    chain_meta_storage.set_current_head(
+        &header.chain_id,
+        &Head {
+            hash: header.block_hash.clone(),
+            level: header.level,
+        },
+    )?;
+
+    info!(log, "Snapshot imported";
+               "block_hash" => HashType::BlockHash.bytes_to_string(&header.block_hash),
+               "level" => header.level,
+               "blocks" => blocks,
+               "context_keys" => end_context_keys);
+
+    Ok(header)
+}
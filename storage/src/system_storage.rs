@@ -8,7 +8,7 @@ use serde::{Deserialize, Serialize};
 
 use crypto::hash::ChainId;
 
-use crate::persistent::{BincodeEncoded, default_table_options, KeyValueSchema, KeyValueStoreWithSchema};
+use crate::persistent::{BincodeEncoded, DbTuning, default_table_options, KeyValueSchema, KeyValueStoreWithSchema};
 use crate::StorageError;
 
 pub type SystemStorageKv = dyn KeyValueStoreWithSchema<SystemStorage> + Sync + Send;
@@ -27,6 +27,9 @@ impl SystemStorage {
     const CHAIN_ID: &'static str = "chain_id";
     const DB_VERSION: &'static str = "db_version";
     const CHAIN_NAME: &'static str = "chain_name";
+    const HISTORY_PRUNED_LEVEL: &'static str = "history_pruned_level";
+    const MIGRATION_CHECKPOINT: &'static str = "db_migration_checkpoint";
+    const BLOCK_STORAGE_VERIFIED_OFFSET: &'static str = "block_storage_verified_offset";
 
     pub fn new(kv: Arc<SystemStorageKv>) -> Self {
         SystemStorage { kv }
@@ -79,14 +82,71 @@ impl SystemStorage {
         self.kv.put(&Self::CHAIN_NAME.to_string(), &SystemValue::String(chain_name.clone()))
             .map_err(StorageError::from)
     }
+
+    /// Level below which data were already removed by history pruning
+    #[inline]
+    pub fn get_history_pruned_level(&self) -> Result<Option<i32>, StorageError> {
+        self.kv.get(&Self::HISTORY_PRUNED_LEVEL.to_string())
+            .map(|result| match result {
+                Some(SystemValue::Integer(value)) => Some(value as i32),
+                _ => None
+            })
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn set_history_pruned_level(&mut self, level: i32) -> Result<(), StorageError> {
+        self.kv.put(&Self::HISTORY_PRUNED_LEVEL.to_string(), &SystemValue::Integer(level as i64))
+            .map_err(StorageError::from)
+    }
+
+    /// Progress of the currently running database migration step (see `migration` module)
+    #[inline]
+    pub fn get_migration_checkpoint(&self) -> Result<Option<Vec<u8>>, StorageError> {
+        self.kv.get(&Self::MIGRATION_CHECKPOINT.to_string())
+            .map(|result| match result {
+                Some(SystemValue::Bytes(value)) => Some(value),
+                _ => None
+            })
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn set_migration_checkpoint(&mut self, checkpoint: Vec<u8>) -> Result<(), StorageError> {
+        self.kv.put(&Self::MIGRATION_CHECKPOINT.to_string(), &SystemValue::Bytes(checkpoint))
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn clear_migration_checkpoint(&mut self) -> Result<(), StorageError> {
+        self.kv.delete(&Self::MIGRATION_CHECKPOINT.to_string())
+            .map_err(StorageError::from)
+    }
+
+    /// Block storage commit log records below this offset were flushed and do not need to be verified after the restart
+    #[inline]
+    pub fn get_block_storage_verified_offset(&self) -> Result<Option<u64>, StorageError> {
+        self.kv.get(&Self::BLOCK_STORAGE_VERIFIED_OFFSET.to_string())
+            .map(|result| match result {
+                Some(SystemValue::Integer(offset)) => Some(offset as u64),
+                _ => None
+            })
+            .map_err(StorageError::from)
+    }
+
+    #[inline]
+    pub fn set_block_storage_verified_offset(&mut self, offset: u64) -> Result<(), StorageError> {
+        self.kv.put(&Self::BLOCK_STORAGE_VERIFIED_OFFSET.to_string(), &SystemValue::Integer(offset as i64))
+            .map_err(StorageError::from)
+    }
 }
 
 impl KeyValueSchema for SystemStorage {
     type Key = String;
     type Value = SystemValue;
 
-    fn descriptor() -> ColumnFamilyDescriptor {
-        let mut cf_opts = default_table_options();
+    fn descriptor(tuning: &DbTuning) -> ColumnFamilyDescriptor {
+        let mut cf_opts = default_table_options(tuning, Self::name());
         // 1 MB
         cf_opts.set_write_buffer_size(1024 * 1024);
         ColumnFamilyDescriptor::new(Self::name(), cf_opts)
@@ -103,6 +163,7 @@ pub enum SystemValue {
     String(String),
     Integer(i64),
     Hash(Vec<u8>),
+    Bytes(Vec<u8>),
 }
 
 impl BincodeEncoded for SystemValue {}
\ No newline at end of file
@@ -0,0 +1,91 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use failure::Error;
+use slog::{Discard, Logger, o};
+
+use crypto::hash::HashType;
+use storage::*;
+use storage::backup::{BackupError, create_backup, restore_backup, verify_backup};
+use storage::block_meta_storage::Meta;
+use storage::persistent::{CommitLogSchema, DbConfiguration, DbTuning, open_cl_read_only, open_kv_secondary, PersistentStorage};
+use storage::tests_common::{test_block, TmpStorage};
+use tezos_messages::Head;
+
+#[test]
+fn test_backup_and_restore() -> Result<(), Error> {
+    let log = Logger::root(Discard, o!());
+    let tezos_data_dir = Path::new("__backup_tezos_data");
+    // backups are inside of the tezos data dir, all of them are excluded from the backup
+    let backup_dir = tezos_data_dir.join("backups");
+    let backup_path = backup_dir.join("backup_1");
+    let restored_dir = Path::new("__backup_restored");
+    for path in &[tezos_data_dir, restored_dir] {
+        if path.exists() {
+            fs::remove_dir_all(path)?;
+        }
+    }
+    fs::create_dir_all(tezos_data_dir.join("context"))?;
+    fs::write(tezos_data_dir.join("context").join("store.pack"), vec![1; 64])?;
+
+    // database is inside of the tezos data dir, as it is by default
+    let db_path = tezos_data_dir.join("bootstrap_db");
+    let tmp_storage = TmpStorage::create(&db_path)?;
+    let chain_id = vec![1, 2, 3, 4];
+    let genesis = test_block(0, &vec![0; HashType::BlockHash.size()])?;
+    BlockStorage::new(tmp_storage.storage()).put_block_header(&genesis)?;
+    BlockMetaStorage::new(tmp_storage.storage()).put(&genesis.hash, &Meta::genesis_meta(&genesis.hash, &chain_id, true))?;
+    let chain_meta_storage = ChainMetaStorage::new(tmp_storage.storage());
+    assert!(matches!(create_backup(tmp_storage.storage(), &chain_id, &db_path, tezos_data_dir, &backup_dir, "backup_1", &log), Err(BackupError::MissingHead)));
+    chain_meta_storage.set_current_head(&chain_id, &Head { hash: genesis.hash.clone(), level: 0 })?;
+
+    let manifest = create_backup(tmp_storage.storage(), &chain_id, &db_path, tezos_data_dir, &backup_dir, "backup_1", &log)?;
+    assert_eq!(HashType::BlockHash.bytes_to_string(&genesis.hash), *manifest.head_hash());
+    assert_eq!(0, manifest.head_level());
+    assert!(manifest.files().iter().any(|file| file.path == "tezos_data/context/store.pack" && file.size == 64));
+    assert!(manifest.files().iter().any(|file| file.path.starts_with("db/block_storage/")));
+    assert!(manifest.files().iter().all(|file| !file.path.contains("bootstrap_db")));
+    let second_manifest = create_backup(tmp_storage.storage(), &chain_id, &db_path, tezos_data_dir, &backup_dir, "backup_2", &log)?;
+    assert!(second_manifest.files().iter().all(|file| !file.path.contains("backup_1")));
+    assert!(matches!(create_backup(tmp_storage.storage(), &chain_id, &db_path, tezos_data_dir, &backup_dir, "backup_1", &log), Err(BackupError::BackupExists { .. })));
+
+    // verification
+    let cfg = DbConfiguration::default();
+    assert_eq!(manifest.head_hash(), verify_backup(&backup_path, &chain_id, &cfg)?.head_hash());
+    assert!(matches!(verify_backup(&backup_path, &vec![4, 3, 2, 1], &cfg), Err(BackupError::InvalidBackup { .. })));
+
+    // restore to the empty location
+    let restored_db_path = restored_dir.join("db");
+    let restored_tezos_data_dir = restored_dir.join("tezos_data");
+    restore_backup(&backup_path, &restored_db_path, &restored_tezos_data_dir, &chain_id, &cfg, &log)?;
+    assert_eq!(vec![1; 64], fs::read(restored_tezos_data_dir.join("context").join("store.pack"))?);
+    {
+        let restored = PersistentStorage::new_secondary(
+            Arc::new(open_kv_secondary(&restored_db_path, restored_dir.join("secondary"), database_schemas(&DbTuning::default()), &cfg)?),
+            Arc::new(open_cl_read_only(&restored_db_path, restored_dir.join("secondary").join("commit_logs"), vec![BlockStorage::descriptor()])?),
+        );
+        assert_eq!(Some(genesis.clone()), BlockStorage::new(&restored).get(&genesis.hash)?);
+    }
+    assert!(matches!(restore_backup(&backup_path, &restored_db_path, &restored_tezos_data_dir, &chain_id, &cfg, &log), Err(BackupError::TargetNotEmpty { .. })));
+
+    // backup with modified file is not valid, even if the file has the same size
+    let backed_up_context = backup_path.join("tezos_data").join("context").join("store.pack");
+    fs::write(&backed_up_context, vec![2; 64])?;
+    assert!(matches!(verify_backup(&backup_path, &chain_id, &cfg), Err(BackupError::InvalidBackup { .. })));
+    fs::write(&backed_up_context, vec![1; 64])?;
+    assert_eq!(manifest.head_hash(), verify_backup(&backup_path, &chain_id, &cfg)?.head_hash());
+
+    // incomplete backup is not valid
+    fs::remove_file(backup_path.join("backup.json"))?;
+    assert!(matches!(verify_backup(&backup_path, &chain_id, &cfg), Err(BackupError::InvalidBackup { .. })));
+
+    drop(tmp_storage);
+    for path in &[tezos_data_dir, restored_dir] {
+        fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
@@ -3,8 +3,10 @@
 
 use failure::Error;
 
-use crypto::hash::HashType;
+use crypto::hash::{HashType, ProtocolHash};
 use storage::*;
+use storage::context_action_storage::contract_id_to_contract_address_for_index;
+use storage::persistent::WriteBatch;
 use storage::tests_common::TmpStorage;
 use tezos_messages::p2p::binary_message::BinaryMessage;
 use tezos_messages::p2p::encoding::prelude::*;
@@ -68,6 +70,122 @@ fn block_storage_assign_context() -> Result<(), Error> {
     Ok(())
 }
 
+#[test]
+fn block_storage_put_data_to_batch() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__block_put_data_to_batch")?;
+    let storage = BlockStorage::new(tmp_storage.storage());
+    let block_meta_storage = BlockMetaStorage::new(tmp_storage.storage());
+
+    let block_header = make_test_block_header()?;
+    storage.put_block_header(&block_header)?;
+
+    let json_data = BlockJsonDataBuilder::default()
+        .block_header_proto_json("{}".to_string())
+        .block_header_proto_metadata_json("{}".to_string())
+        .operations_proto_metadata_json("[]".to_string())
+        .build().unwrap();
+    let additional_data = BlockAdditionalDataBuilder::default()
+        .max_operations_ttl(60)
+        .last_allowed_fork_level(5)
+        .build().unwrap();
+    let mut batch = WriteBatch::new();
+    storage.put_block_data_to_batch(&mut batch, &block_header.hash, Some(json_data), Some(additional_data))?;
+
+    // nothing is visible until the batch is written
+    assert!(storage.get_with_json_data(&block_header.hash)?.is_none());
+    assert!(storage.get_with_additional_data(&block_header.hash)?.is_none());
+
+    block_meta_storage.write_batch(batch)?;
+    assert!(storage.get_with_json_data(&block_header.hash)?.is_some());
+    let (_, additional_data) = storage.get_with_additional_data(&block_header.hash)?.unwrap();
+    assert_eq!(60, additional_data.max_operations_ttl());
+
+    Ok(())
+}
+
+#[test]
+fn block_storage_queries() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__block_storage_queries")?;
+    let storage = BlockStorage::new(tmp_storage.storage());
+    let baker_1 = "tz1PirboZKFVqkfE45hVLpkpXaZtLk3mqC17";
+    let baker_2 = "tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSx";
+    let babylon = HashType::ProtocolHash.string_to_bytes("PsBabyM1eUXZseaJdmXFApDSBqj8YBfwELoxZHHW77EMcAbbwAS")?;
+    let carthage = HashType::ProtocolHash.string_to_bytes("PsCARTHAGazKbHtnKfLzQg3kms52kSRpgnDY982a9oYsSXRLQEb")?;
+
+    // level 3 is missing, proto level and protocol change at level 4, cycle has 4 blocks
+    for level in vec![0, 1, 2, 4, 5, 6] {
+        let (proto, protocol) = match level {
+            0 => (0, None),
+            1..=3 => (1, Some(&babylon)),
+            _ => (2, Some(&carthage)),
+        };
+        let baker = if level % 2 == 0 { baker_1 } else { baker_2 };
+        let block = make_block_header(level, proto)?;
+        storage.put_block_header(&block)?;
+        storage.put_block_json_data(&block.hash, block_json_data(protocol, baker, level / 4))?;
+    }
+    let levels = |blocks: Vec<BlockHeaderWithHash>| blocks.iter().map(|block| block.header.level()).collect::<Vec<_>>();
+
+    // timestamp of the block is 100 + level * 60
+    assert_eq!(vec![1, 2, 4], levels(storage.get_by_timestamp_range(160, 340, 10)?));
+    assert_eq!(vec![4, 5], levels(storage.get_by_timestamp_range(221, 10_000, 2)?));
+    assert!(storage.get_by_timestamp_range(0, 99, 10)?.is_empty());
+    assert!(storage.get_by_timestamp_range(10_000, 20_000, 10)?.is_empty());
+
+    assert_eq!(vec![0], levels(storage.get_by_proto_level(0, 10)?));
+    assert_eq!(vec![1, 2], levels(storage.get_by_proto_level(1, 10)?));
+    assert_eq!(vec![4, 5, 6], levels(storage.get_by_proto_level(2, 10)?));
+    assert!(storage.get_by_proto_level(3, 10)?.is_empty());
+
+    assert_eq!(vec![1, 2], levels(storage.get_by_protocol(&babylon, 0, 10)?));
+    assert_eq!(vec![5, 6], levels(storage.get_by_protocol(&carthage, 5, 10)?));
+
+    let baker_1 = contract_id_to_contract_address_for_index(baker_1)?;
+    let baker_2 = contract_id_to_contract_address_for_index(baker_2)?;
+    assert_eq!(vec![2, 4, 6], levels(storage.get_by_baker(&baker_1, None, 10)?));
+    assert_eq!(vec![4, 6], levels(storage.get_by_baker(&baker_1, Some(1), 10)?));
+    assert_eq!(vec![1], levels(storage.get_by_baker(&baker_2, Some(0), 10)?));
+
+    // index entries are removed together with the block
+    let block_5 = storage.get_by_block_level(5)?.unwrap();
+    storage.delete(&block_5.hash)?;
+    assert!(storage.get_by_baker(&baker_2, Some(1), 10)?.is_empty());
+    assert_eq!(vec![4, 6], levels(storage.get_by_protocol(&carthage, 0, 10)?));
+
+    Ok(())
+}
+
+fn make_block_header(level: i32, proto: u8) -> Result<BlockHeaderWithHash, Error> {
+    Ok(BlockHeaderWithHash::new(
+        BlockHeaderBuilder::default()
+            .level(level)
+            .proto(proto)
+            .predecessor(vec![0; HashType::BlockHash.size()])
+            .timestamp(100 + i64::from(level) * 60)
+            .validation_pass(1)
+            .operations_hash(HashType::OperationListListHash.string_to_bytes("LLoaGLRPRx3Zf8kB4ACtgku8F4feeBiskeb41J1ciwfcXB3KzHKXc")?)
+            .fitness(vec![])
+            .context(vec![level as u8; HashType::ContextHash.size()])
+            .protocol_data(vec![])
+            .build().unwrap()
+    )?)
+}
+
+fn block_json_data(protocol: Option<&ProtocolHash>, baker: &str, cycle: i32) -> BlockJsonData {
+    let metadata = match protocol {
+        Some(protocol) => format!(
+            r#"{{"protocol":"{}","baker":"{}","level":{{"cycle":{}}}}}"#,
+            HashType::ProtocolHash.bytes_to_string(protocol), baker, cycle
+        ),
+        None => "{}".to_string(),
+    };
+    BlockJsonDataBuilder::default()
+        .block_header_proto_json("{}".to_string())
+        .block_header_proto_metadata_json(metadata)
+        .operations_proto_metadata_json("[]".to_string())
+        .build().unwrap()
+}
+
 fn make_test_block_header() -> Result<BlockHeaderWithHash, Error> {
     let message_bytes = hex::decode("00006d6e0102dd00defaf70c53e180ea148b349a6feb4795610b2abc7b07fe91ce50a90814000000005c1276780432bc1d3a28df9a67b363aa1638f807214bb8987e5f9c0abcbd69531facffd1c80000001100000001000000000800000000000c15ef15a6f54021cb353780e2847fb9c546f1d72c1dc17c3db510f45553ce501ce1de000000000003c762c7df00a856b8bfcaf0676f069f825ca75f37f2bee9fe55ba109cec3d1d041d8c03519626c0c0faa557e778cb09d2e0c729e8556ed6a7a518c84982d1f2682bc6aa753f")?;
     let block_header = BlockHeaderWithHash::new(BlockHeader::from_bytes(message_bytes)?)?;
@@ -0,0 +1,182 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+
+use failure::Error;
+
+use crypto::hash::HashType;
+use storage::*;
+use storage::block_storage::BlockStorageColumn;
+use storage::commit_log_maintenance::{compact_commit_log, recover_commit_log};
+use storage::persistent::{CommitLogSchema, CommitLogWithSchema};
+use storage::tests_common::{test_block, TmpStorage};
+use tezos_messages::p2p::encoding::prelude::*;
+
+mod common;
+
+#[test]
+fn test_compact_commit_log() -> Result<(), Error> {
+    let log = common::create_logger();
+    let tmp_storage = TmpStorage::create("__commit_log_compaction")?;
+    let storage = BlockStorage::new(tmp_storage.storage());
+
+    let block_1 = test_block(1, &vec![0; HashType::BlockHash.size()])?;
+    let block_2 = test_block(2, &block_1.hash)?;
+    storage.put_block_header(&block_1)?;
+    storage.put_block_json_data(&block_1.hash, json_data("old"))?;
+    storage.put_block_header(&block_2)?;
+    storage.assign_to_context(&block_2.hash, block_2.header.context())?;
+    // replaced and removed data are not referenced anymore
+    storage.put_block_json_data(&block_1.hash, json_data("new"))?;
+    storage.delete(&block_2.hash)?;
+
+    let report = compact_commit_log(tmp_storage.storage(), &log)?;
+    assert_eq!(2, report.records());
+    assert!(report.size_after() < report.size_before());
+    assert_eq!(2, tmp_storage.storage().clog().next_offset(BlockStorage::name())?);
+
+    let location = storage.get_location(&block_1.hash)?.unwrap();
+    assert_eq!(0, location.block_header.0);
+    assert_eq!(1, location.block_json_data.unwrap().0);
+    let (header, data) = storage.get_with_json_data(&block_1.hash)?.unwrap();
+    assert_eq!(block_1, header);
+    assert_eq!("new", data.block_header_proto_json().as_str());
+    assert_eq!(block_1, storage.get_by_block_level(1)?.unwrap());
+    assert!(storage.get(&block_2.hash)?.is_none());
+    assert!(storage.get_by_context_hash(block_2.header.context())?.is_none());
+
+    // block storage works with the compacted commit log
+    storage.put_block_header(&block_2)?;
+    assert_eq!(block_2, storage.get(&block_2.hash)?.unwrap());
+    assert_eq!(2, storage.get_location(&block_2.hash)?.unwrap().block_header.0);
+
+    Ok(())
+}
+
+#[test]
+fn test_recover_commit_log() -> Result<(), Error> {
+    let log = common::create_logger();
+    let tmp_storage = TmpStorage::create("__commit_log_recovery")?;
+    let storage = BlockStorage::new(tmp_storage.storage());
+    let clog = tmp_storage.storage().clog();
+
+    // record appended before crash, but not indexed
+    let block_1 = test_block(1, &vec![0; HashType::BlockHash.size()])?;
+    storage.put_block_header(&block_1)?;
+    CommitLogWithSchema::<BlockStorage>::append(clog.as_ref(), &BlockStorageColumn::BlockJsonData(json_data("lost")))?;
+
+    let report = recover_commit_log(tmp_storage.storage(), &log)?;
+    assert_eq!(1, report.checked_records());
+    assert_eq!(0, report.removed_blocks());
+    assert_eq!(1, report.truncated_records());
+    assert_eq!(1, clog.next_offset(BlockStorage::name())?);
+
+    // nothing new to verify
+    let report = recover_commit_log(tmp_storage.storage(), &log)?;
+    assert_eq!(0, report.checked_records());
+
+    // indexed record, which was not written
+    let block_2 = test_block(2, &block_1.hash)?;
+    storage.put_block_header(&block_2)?;
+    storage.put_block_json_data(&block_1.hash, json_data("lost"))?;
+    clog.truncate(BlockStorage::name(), 1)?;
+    SystemStorage::new(tmp_storage.storage().kv()).set_block_storage_verified_offset(0)?;
+
+    let report = recover_commit_log(tmp_storage.storage(), &log)?;
+    assert_eq!(1, report.removed_blocks());
+    assert_eq!(1, report.cleared_records());
+    assert_eq!(0, report.truncated_records());
+    assert!(storage.get(&block_2.hash)?.is_none());
+    assert!(storage.get_by_block_level(2)?.is_none());
+    assert_eq!(block_1, storage.get(&block_1.hash)?.unwrap());
+    assert!(storage.get_with_json_data(&block_1.hash)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_recover_commit_log_with_torn_record() -> Result<(), Error> {
+    let log = common::create_logger();
+    let path = "__commit_log_recovery_torn";
+    let tmp_storage = TmpStorage::create(path)?;
+    let storage = BlockStorage::new(tmp_storage.storage());
+    let clog = tmp_storage.storage().clog();
+
+    let block_1 = test_block(1, &vec![0; HashType::BlockHash.size()])?;
+    let block_2 = test_block(2, &block_1.hash)?;
+    storage.put_block_header(&block_1)?;
+    storage.put_block_json_data(&block_1.hash, json_data("kept"))?;
+    storage.put_block_header(&block_2)?;
+    clog.flush()?;
+
+    // end of the last record (header of the block 2) was not written before crash
+    overwrite_end_of_segment(&Path::new(path).join(BlockStorage::name()), 16)?;
+
+    let report = recover_commit_log(tmp_storage.storage(), &log)?;
+    assert_eq!(3, report.checked_records());
+    assert_eq!(1, report.removed_blocks());
+    assert_eq!(0, report.cleared_records());
+    assert_eq!(1, report.truncated_records());
+    assert_eq!(2, clog.next_offset(BlockStorage::name())?);
+    assert!(storage.get(&block_2.hash)?.is_none());
+    assert!(storage.get_by_block_level(2)?.is_none());
+    assert!(storage.get_by_context_hash(block_2.header.context())?.is_none());
+    let (header, data) = storage.get_with_json_data(&block_1.hash)?.unwrap();
+    assert_eq!(block_1, header);
+    assert_eq!("kept", data.block_header_proto_json().as_str());
+
+    Ok(())
+}
+
+#[test]
+fn test_recover_commit_log_with_lost_records() -> Result<(), Error> {
+    let log = common::create_logger();
+    let tmp_storage = TmpStorage::create("__commit_log_recovery_lost")?;
+    let storage = BlockStorage::new(tmp_storage.storage());
+    let clog = tmp_storage.storage().clog();
+
+    let block_1 = test_block(1, &vec![0; HashType::BlockHash.size()])?;
+    let block_2 = test_block(2, &block_1.hash)?;
+    storage.put_block_header(&block_1)?;
+    storage.put_block_header(&block_2)?;
+    recover_commit_log(tmp_storage.storage(), &log)?;
+
+    // records were verified, but they were lost by a crash and the commit log is shorter now
+    clog.truncate(BlockStorage::name(), 1)?;
+
+    let report = recover_commit_log(tmp_storage.storage(), &log)?;
+    assert_eq!(1, report.checked_records());
+    assert_eq!(1, report.removed_blocks());
+    assert!(storage.get(&block_2.hash)?.is_none());
+    assert_eq!(block_1, storage.get(&block_1.hash)?.unwrap());
+
+    Ok(())
+}
+
+fn json_data(header_json: &str) -> BlockJsonData {
+    BlockJsonDataBuilder::default()
+        .block_header_proto_json(header_json.to_string())
+        .block_header_proto_metadata_json("{}".to_string())
+        .operations_proto_metadata_json("[]".to_string())
+        .build().unwrap()
+}
+
+/// Overwrite last `len` bytes of the last segment of the commit log in the `dir` with garbage
+fn overwrite_end_of_segment(dir: &Path, len: u64) -> Result<(), Error> {
+    let mut segments = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    segments.retain(|path| path.extension().map_or(false, |extension| extension == "log"));
+    segments.sort();
+    let segment = segments.last().expect("Commit log has no segment");
+
+    let mut file = OpenOptions::new().write(true).open(segment)?;
+    let file_len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(file_len - len))?;
+    file.write_all(&vec![0xff; len as usize])?;
+    file.sync_all()?;
+    Ok(())
+}
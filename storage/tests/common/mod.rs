@@ -0,0 +1,10 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use slog::{Drain, Level, Logger};
+
+pub fn create_logger() -> Logger {
+    let drain = slog_async::Async::new(slog_term::FullFormat::new(slog_term::TermDecorator::new().build()).build().fuse()).build().filter_level(Level::Info).fuse();
+
+    Logger::root(drain, slog::o!())
+}
@@ -5,6 +5,7 @@ use failure::Error;
 
 use crypto::hash::HashType;
 use storage::*;
+use storage::context_action_storage::{ContextActionFilters, ContextActionType};
 use storage::tests_common::TmpStorage;
 use tezos_context::channel::ContextAction;
 
@@ -102,3 +103,87 @@ fn context_get_values_by_contract_address() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn context_filter_and_aggregate_values() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__ctx_storage_filter_and_aggregate")?;
+
+    let block_hash = HashType::BlockHash.string_to_bytes("BKyQ9EofHrgaZKENioHyP4FZNsTmiSEcVmcghgzCC9cGhE7oCET")?;
+    let operation_hash = HashType::OperationHash.string_to_bytes("onvN8U6QJ6DGJKVYkHXYRtFm3tgBJScj9P5bbPjSZUuFaGzwFuJ")?;
+    let to_key = |key: &str| key.split('/').map(|segment| segment.to_string()).collect::<Vec<_>>();
+
+    let mut storage = ContextActionStorage::new(tmp_storage.storage());
+    storage.put_action(&block_hash, ContextAction::Set { key: to_key("data/contracts/index/a"), value: vec![1], operation_hash: Some(operation_hash.clone()), block_hash: None, context_hash: None, value_as_json: None, start_time: 100.0, end_time: 100.5, ignored: false })?;
+    storage.put_action(&block_hash, ContextAction::Get { key: to_key("data/contracts/global_counter"), value: vec![1], operation_hash: Some(operation_hash.clone()), block_hash: None, context_hash: None, value_as_json: None, start_time: 101.0, end_time: 101.25 })?;
+    storage.put_action(&block_hash, ContextAction::Set { key: to_key("data/rolls/index/b"), value: vec![1], operation_hash: None, block_hash: None, context_hash: None, value_as_json: None, start_time: 102.0, end_time: 103.0, ignored: false })?;
+
+    let ids = |values: Vec<ContextActionRecordValue>| values.iter().map(|value| value.id()).collect::<Vec<_>>();
+
+    // key prefix
+    let filters = ContextActionFilters::with_block_hash(block_hash.clone()).with_key_prefix(to_key("data/contracts"));
+    assert_eq!(vec![0, 1], ids(storage.load_cursor(None, None, filters)?));
+    let filters = ContextActionFilters::with_block_hash(block_hash.clone()).with_key_prefix(to_key("data/contracts")).with_action_types(vec![ContextActionType::Set]);
+    assert_eq!(vec![0], ids(storage.load_cursor(None, Some(1), filters)?));
+
+    // operation hash
+    assert_eq!(vec![0, 1], ids(storage.load_cursor(None, None, ContextActionFilters::with_operation_hash(operation_hash.clone()))?));
+    assert_eq!(vec![1], ids(storage.load_cursor(Some(1), None, ContextActionFilters::with_operation_hash(operation_hash))?));
+
+    // time range
+    let filters = ContextActionFilters::with_block_hash(block_hash.clone()).with_time_range(100.0, 102.5);
+    assert_eq!(vec![0, 1, 2], ids(storage.load_cursor(None, None, filters)?));
+    let filters = ContextActionFilters::with_block_hash(block_hash.clone()).with_time_range(100.5, 102.0);
+    assert_eq!(vec![1], ids(storage.load_cursor(None, None, filters)?));
+    let filters = ContextActionFilters::with_block_hash(block_hash.clone()).with_time_range(100.0, 200.0).with_action_types(vec![ContextActionType::Set]);
+    assert_eq!(vec![2], ids(storage.load_cursor(Some(1), Some(1), filters)?));
+    let filters = ContextActionFilters::with_block_hash(block_hash.clone()).with_time_range(103.0, 200.0);
+    assert!(storage.load_cursor(None, None, filters)?.is_empty());
+    assert_eq!(vec![1, 2], ids(storage.get_by_time_range(101.0, 200.0, 10)?));
+    assert_eq!(vec![1], ids(storage.get_by_time_range(101.0, 200.0, 1)?));
+
+    // aggregation
+    let stats = storage.aggregate_by_block_hash(&block_hash)?;
+    assert_eq!(2, stats.len());
+    assert_eq!(ContextActionType::Set, stats[0].action_type);
+    assert_eq!(2, stats[0].count);
+    assert_eq!(1500, (stats[0].total_duration * 1000.0) as u64);
+    assert_eq!(ContextActionType::Get, stats[1].action_type);
+    assert_eq!(1, stats[1].count);
+    assert_eq!(250, (stats[1].total_duration * 1000.0) as u64);
+
+    Ok(())
+}
+
+#[test]
+fn context_filter_by_indexed_key_prefix() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__ctx_storage_filter_by_key_prefix")?;
+
+    let block_hash = HashType::BlockHash.string_to_bytes("BKyQ9EofHrgaZKENioHyP4FZNsTmiSEcVmcghgzCC9cGhE7oCET")?;
+    let to_key = |key: &str| key.split('/').map(|segment| segment.to_string()).collect::<Vec<_>>();
+
+    let mut storage = ContextActionStorage::new(tmp_storage.storage());
+    storage.put_action(&block_hash, ContextAction::Set { key: to_key("data/rolls/index/b"), value: vec![1], operation_hash: None, block_hash: None, context_hash: None, value_as_json: None, start_time: 100.0, end_time: 101.0, ignored: false })?;
+    storage.put_action(&block_hash, ContextAction::Set { key: to_key("data/contracts/index/a/balance"), value: vec![1], operation_hash: None, block_hash: None, context_hash: None, value_as_json: None, start_time: 101.0, end_time: 102.0, ignored: false })?;
+    storage.put_action(&block_hash, ContextAction::Copy { from_key: to_key("data/contracts/index/a"), to_key: to_key("data/contracts/index/b"), context_hash: None, block_hash: None, operation_hash: None, ignored: false, start_time: 102.0, end_time: 103.0 })?;
+    storage.put_action(&block_hash, ContextAction::Set { key: to_key("data/contracts/index/b/balance"), value: vec![1], operation_hash: None, block_hash: None, context_hash: None, value_as_json: None, start_time: 103.0, end_time: 104.0, ignored: false })?;
+
+    let ids = |values: Vec<ContextActionRecordValue>| values.iter().map(|value| value.id()).collect::<Vec<_>>();
+    let load = |cursor_id: Option<u64>, limit: Option<usize>, key_prefix: &str| -> Result<Vec<u64>, Error> {
+        Ok(ids(storage.load_cursor(cursor_id, limit, ContextActionFilters::with_block_hash(block_hash.clone()).with_key_prefix(to_key(key_prefix)))?))
+    };
+
+    assert_eq!(vec![1, 2, 3], load(None, None, "data/contracts")?);
+    assert_eq!(vec![1, 2], load(None, Some(2), "data/contracts")?);
+    assert_eq!(vec![2, 3], load(Some(2), None, "data/contracts")?);
+    // copy action is found by both of its keys
+    assert_eq!(vec![2, 3], load(None, None, "data/contracts/index/b")?);
+    // prefix longer than the indexed depth is filtered after load
+    assert_eq!(vec![3], load(None, None, "data/contracts/index/b/balance")?);
+    assert_eq!(Vec::<u64>::new(), load(None, None, "data/contracts/index/c")?);
+
+    // index is created again from the stored actions
+    assert!(storage.rebuild_indexes()? > 0);
+    assert_eq!(vec![2, 3], load(None, None, "data/contracts/index/b")?);
+
+    Ok(())
+}
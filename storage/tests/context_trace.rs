@@ -0,0 +1,141 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::fs::{self, OpenOptions};
+use std::io::{BufReader, Cursor, Write};
+
+use failure::Error;
+
+use crypto::hash::{ContextHash, HashType};
+use storage::*;
+use storage::context::{ContextApi, ContextIndex, TezedgeContext};
+use storage::context_trace::{ContextReplayer, ContextTraceError, ContextTraceReader, ContextTraceWriter, export_context_trace, TraceFormat, TraceRecord};
+use storage::skip_list::Bucket;
+use storage::tests_common::{test_block, TmpStorage};
+use tezos_context::channel::ContextAction;
+
+mod common;
+
+#[test]
+fn test_export_and_replay_binary_trace() -> Result<(), Error> {
+    check_export_and_replay(TraceFormat::Binary, "__context_trace_binary")
+}
+
+#[test]
+fn test_export_and_replay_json_trace() -> Result<(), Error> {
+    check_export_and_replay(TraceFormat::JsonLines, "__context_trace_json")
+}
+
+fn check_export_and_replay(format: TraceFormat, name: &str) -> Result<(), Error> {
+    // source storage with stored blocks and context actions
+    let source_storage = TmpStorage::create(&format!("{}_source", name))?;
+    let block_storage = BlockStorage::new(source_storage.storage());
+    let mut context_action_storage = ContextActionStorage::new(source_storage.storage());
+
+    let genesis = test_block(0, &vec![0; HashType::BlockHash.size()])?;
+    let block_1 = test_block(1, &genesis.hash)?;
+    let block_2 = test_block(2, &block_1.hash)?;
+    for block in &[&genesis, &block_1, &block_2] {
+        block_storage.put_block_header(block)?;
+    }
+    context_action_storage.put_action(&genesis.hash, set(None, "protocol", vec![0]))?;
+    context_action_storage.put_action(&block_1.hash, set(Some(genesis.header.context()), "data/a", vec![1]))?;
+    context_action_storage.put_action(&block_1.hash, set(Some(genesis.header.context()), "data/b", vec![2]))?;
+    context_action_storage.put_action(&block_2.hash, delete(block_1.header.context(), "data/a"))?;
+
+    let mut trace = Vec::new();
+    let mut writer = ContextTraceWriter::new(&mut trace, format)?;
+    assert_eq!(3, export_context_trace(source_storage.storage(), 0, 2, &mut writer, &common::create_logger())?);
+    let records = writer.finish()?;
+    // block, actions and commit for every block, checkout for all except genesis
+    assert_eq!(3 + 4 + 3 + 2, records);
+
+    let reader = ContextTraceReader::new(BufReader::new(Cursor::new(trace.clone())))?;
+    assert_eq!(format, reader.format());
+    let read_records = reader.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(records, read_records.len());
+    match &read_records[0] {
+        TraceRecord::Block(block) => assert_eq!(&genesis, block),
+        _ => panic!("Trace has to start with the genesis block"),
+    }
+
+    // replay to the empty storage
+    let target_storage = TmpStorage::create(&format!("{}_target", name))?;
+    let reader = ContextTraceReader::new(BufReader::new(Cursor::new(trace)))?;
+    let report = ContextReplayer::new(target_storage.storage()).replay(reader)?;
+    assert_eq!(3, report.commits());
+    assert_eq!(4 + 3 + 2, report.actions());
+
+    let context = TezedgeContext::new(BlockStorage::new(target_storage.storage()), target_storage.storage().context_storage());
+    assert_eq!(Some(Bucket::Exists(vec![1])), context.get_key(&ContextIndex::new(Some(1), None), &key("data/a"))?);
+    assert_eq!(Some(Bucket::Exists(vec![2])), context.get_key(&ContextIndex::new(Some(2), None), &key("data/b"))?);
+    assert_eq!(Some(Bucket::Exists(vec![0])), context.get_key(&ContextIndex::new(Some(2), None), &key("protocol"))?);
+    assert!(!matches!(context.get_key(&ContextIndex::new(Some(2), None), &key("data/a"))?, Some(Bucket::Exists(_))));
+    assert_eq!(block_2.hash, BlockStorage::new(target_storage.storage()).get_by_context_hash(block_2.header.context())?.unwrap().hash);
+
+    Ok(())
+}
+
+#[test]
+fn test_append_binary_trace() -> Result<(), Error> {
+    check_append(TraceFormat::Binary, "__context_trace_append_binary")
+}
+
+#[test]
+fn test_append_json_trace() -> Result<(), Error> {
+    check_append(TraceFormat::JsonLines, "__context_trace_append_json")
+}
+
+fn check_append(format: TraceFormat, name: &str) -> Result<(), Error> {
+    let path = std::env::temp_dir().join(name);
+    let _ = fs::remove_file(&path);
+    let genesis = test_block(0, &vec![0; HashType::BlockHash.size()])?;
+
+    let mut writer = ContextTraceWriter::append(&path, format)?;
+    writer.write(&TraceRecord::Block(genesis.clone()))?;
+    writer.write(&TraceRecord::Action(set(None, "protocol", vec![0])))?;
+    assert_eq!(2, writer.finish()?);
+
+    // record partially written before the crash
+    OpenOptions::new().append(true).open(&path)?.write_all(&[0, 0, 1])?;
+
+    // recording continues after the restart
+    let mut writer = ContextTraceWriter::append(&path, format)?;
+    writer.write(&TraceRecord::Action(set(None, "data/a", vec![1])))?;
+    assert_eq!(1, writer.finish()?);
+
+    let records = ContextTraceReader::open(&path)?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(3, records.len());
+    match &records[2] {
+        TraceRecord::Action(ContextAction::Set { key, .. }) => assert_eq!(&self::key("data/a"), key),
+        _ => panic!("Appended action was expected"),
+    }
+
+    // trace in another format is not appended
+    let other_format = if format == TraceFormat::Binary { TraceFormat::JsonLines } else { TraceFormat::Binary };
+    assert!(matches!(ContextTraceWriter::append(&path, other_format), Err(ContextTraceError::FormatMismatch { .. })));
+
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_read_too_large_record_fails() -> Result<(), Error> {
+    let mut trace = b"TZEDGECT".to_vec();
+    trace.extend(&u32::MAX.to_be_bytes());
+    let mut reader = ContextTraceReader::new(BufReader::new(Cursor::new(trace)))?;
+    assert!(matches!(reader.next(), Some(Err(ContextTraceError::RecordTooLarge { .. }))));
+    Ok(())
+}
+
+fn key(key: &str) -> Vec<String> {
+    key.split('/').map(|segment| segment.to_string()).collect()
+}
+
+fn set(context_hash: Option<&ContextHash>, key_path: &str, value: Vec<u8>) -> ContextAction {
+    ContextAction::Set { key: key(key_path), value, operation_hash: None, block_hash: None, context_hash: context_hash.cloned(), value_as_json: None, start_time: 0.0, end_time: 0.0, ignored: false }
+}
+
+fn delete(context_hash: &ContextHash, key_path: &str) -> ContextAction {
+    ContextAction::Delete { key: key(key_path), operation_hash: None, block_hash: None, context_hash: Some(context_hash.clone()), start_time: 0.0, end_time: 0.0, ignored: false }
+}
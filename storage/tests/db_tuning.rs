@@ -0,0 +1,76 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use failure::Error;
+
+use storage::persistent::{ColumnTuning, DbCompression, DbConfigurationBuilder, DbProfile, DbTuning, KeyValueSchema, open_kv};
+use storage::persistent::sequence::Sequences;
+use storage::persistent::tuning::parse_size;
+
+#[test]
+fn column_tuning_resolution() -> Result<(), Error> {
+    assert_eq!(64 * 1024 * 1024, parse_size("64M")?);
+    assert_eq!(16 * 1024, parse_size("16k")?);
+    assert_eq!(100, parse_size("100")?);
+    assert!(parse_size("M").is_err());
+
+    let all: ColumnTuning = "compression=snappy,write_buffer_size=32M".parse()?;
+    assert_eq!(Some(DbCompression::Snappy), all.compression);
+    assert_eq!(Some(32 * 1024 * 1024), all.write_buffer_size);
+    assert!("compression=gzip".parse::<ColumnTuning>().is_err());
+    assert!("unknown=1".parse::<ColumnTuning>().is_err());
+
+    let mut overrides = HashMap::new();
+    overrides.insert(Sequences::name().to_string(), "compression=zstd,bloom_filter_bits=0".parse()?);
+    let cfg = DbConfigurationBuilder::default()
+        .profile(DbProfile::Hdd)
+        .column_tuning(all)
+        .column_overrides(overrides)
+        .build().unwrap();
+    let tuning = DbTuning::new(&cfg)?;
+    assert!(tuning.block_cache().is_some());
+
+    // column family override > all column families > profile
+    let sequences = tuning.column_tuning(Sequences::name());
+    assert_eq!(Some(DbCompression::Zstd), sequences.compression);
+    assert_eq!(Some(0), sequences.bloom_filter_bits);
+    assert_eq!(Some(32 * 1024 * 1024), sequences.write_buffer_size);
+    assert_eq!(Some(64 * 1024), sequences.block_size);
+    let other = tuning.column_tuning("other");
+    assert_eq!(Some(DbCompression::Snappy), other.compression);
+    assert_eq!(Some(10), other.bloom_filter_bits);
+
+    // default profile keeps the RocksDB defaults
+    assert!(DbTuning::default().block_cache().is_none());
+    assert_eq!(ColumnTuning::default(), DbTuning::default().column_tuning(Sequences::name()));
+    Ok(())
+}
+
+#[test]
+fn open_kv_with_profile() -> Result<(), Error> {
+    use rocksdb::{Options, DB};
+
+    let path = "__db_tuning_profile";
+    if Path::new(path).exists() {
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    {
+        let cfg = DbConfigurationBuilder::default()
+            .profile(DbProfile::LowMemory)
+            .rate_limit(Some(parse_size("16M")?))
+            .max_total_wal_size(Some(parse_size("64M")? as u64))
+            .build().unwrap();
+        let tuning = DbTuning::new(&cfg)?;
+        let db = open_kv(path, vec![Sequences::descriptor(&tuning)], &cfg)?;
+        let sequences = Sequences::new(Arc::new(db), 1);
+        let generator = sequences.generator("gen");
+        assert_eq!(0, generator.next()?);
+        assert_eq!(1, generator.next()?);
+    }
+    Ok(assert!(DB::destroy(&Options::default(), path).is_ok()))
+}
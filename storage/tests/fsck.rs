@@ -0,0 +1,129 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use failure::Error;
+
+use crypto::hash::{BlockHash, ChainId, HashType};
+use storage::*;
+use storage::chain_meta_storage::ChainMetaStorageReader;
+use storage::fsck::{check_storage, Issue};
+use storage::persistent::{KeyValueSchema, KeyValueStoreWithSchema};
+use storage::tests_common::{store_test_chain, TmpStorage};
+use tezos_messages::Head;
+
+mod common;
+
+/// Raw access to the block meta column family
+struct RawBlockMeta;
+
+impl KeyValueSchema for RawBlockMeta {
+    type Key = BlockHash;
+    type Value = Vec<u8>;
+
+    fn name() -> &'static str {
+        BlockMetaStorage::name()
+    }
+}
+
+#[test]
+fn test_fsck_consistent_storage() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let tmp_storage = TmpStorage::create("__fsck_consistent_storage")?;
+    store_test_chain(tmp_storage.storage(), &chain_id, 4, &log)?;
+
+    let report = check_storage(tmp_storage.storage(), false, &log)?;
+    assert_eq!(5, report.blocks_checked());
+    assert!(report.is_ok(), "Unexpected issues: {:?}", report.issues());
+
+    Ok(())
+}
+
+#[test]
+fn test_fsck_report_and_repair() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let tmp_storage = TmpStorage::create("__fsck_report_and_repair")?;
+    let blocks = store_test_chain(tmp_storage.storage(), &chain_id, 4, &log)?;
+
+    // corrupt storage like after unclean shutdown
+    let block_meta_storage = BlockMetaStorage::new(tmp_storage.storage());
+    let unknown_block: BlockHash = HashType::BlockHash.string_to_bytes("BKyQ9EofHrgaZKENioHyP4FZNsTmiSEcVmcghgzCC9cGhE7oCET")?;
+    let mut meta = block_meta_storage.get(&blocks[1].hash)?.unwrap();
+    meta.set_successor(Some(unknown_block.clone()));
+    block_meta_storage.replace(&blocks[1].hash, &meta)?;
+    OperationsStorage::new(tmp_storage.storage()).delete_operations(&blocks[3].hash)?;
+    // block is stored again without json data
+    let block_storage = BlockStorage::new(tmp_storage.storage());
+    block_storage.delete(&blocks[3].hash)?;
+    block_storage.put_block_header(&blocks[3])?;
+    block_storage.assign_to_context(&blocks[3].hash, blocks[3].header.context())?;
+
+    // check just reports issues
+    let report = check_storage(tmp_storage.storage(), false, &log)?;
+    assert_eq!(0, report.repaired());
+    assert_eq!(3, report.issues().len(), "Unexpected issues: {:?}", report.issues());
+    assert!(report.issues().contains(&Issue::DanglingSuccessor { block_hash: blocks[1].hash.clone(), successor: unknown_block }));
+    assert!(report.issues().contains(&Issue::MissingOperations { block_hash: blocks[3].hash.clone(), validation_passes: vec![0] }));
+    assert!(report.issues().contains(&Issue::AppliedWithoutJsonData { block_hash: blocks[3].hash.clone() }));
+    assert_eq!(report.issues().len(), check_storage(tmp_storage.storage(), false, &log)?.issues().len());
+
+    // repair
+    let report = check_storage(tmp_storage.storage(), true, &log)?;
+    assert_eq!(3, report.repaired());
+
+    assert_eq!(None, *block_meta_storage.get(&blocks[1].hash)?.unwrap().successor());
+    assert!(!block_meta_storage.get(&blocks[3].hash)?.unwrap().is_applied());
+    let operations_meta = OperationsMetaStorage::new(tmp_storage.storage()).get(&blocks[3].hash)?.unwrap();
+    assert!(!operations_meta.is_complete());
+    assert!(!operations_meta.is_validation_pass_present(0));
+
+    // current head was moved back to the last applied block
+    let head = ChainMetaStorage::new(tmp_storage.storage()).get_current_head(&chain_id)?.unwrap();
+    assert_eq!(blocks[2].hash, head.hash);
+    assert_eq!(3, head.level);
+
+    let report = check_storage(tmp_storage.storage(), false, &log)?;
+    assert!(report.is_ok(), "Unexpected issues: {:?}", report.issues());
+
+    Ok(())
+}
+
+#[test]
+fn test_fsck_reports_invalid_block_meta() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let tmp_storage = TmpStorage::create("__fsck_invalid_block_meta")?;
+    let blocks = store_test_chain(tmp_storage.storage(), &chain_id, 2, &log)?;
+    KeyValueStoreWithSchema::<RawBlockMeta>::put(tmp_storage.storage().kv().as_ref(), &blocks[0].hash, &vec![1, 2, 3])?;
+
+    let report = check_storage(tmp_storage.storage(), true, &log)?;
+    assert_eq!(vec![Issue::InvalidBlockMeta { block_hash: blocks[0].hash.clone() }], *report.issues());
+    assert_eq!(0, report.repaired());
+
+    Ok(())
+}
+
+#[test]
+fn test_fsck_not_repaired_current_head_is_not_counted() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let tmp_storage = TmpStorage::create("__fsck_not_repaired_current_head")?;
+    store_test_chain(tmp_storage.storage(), &chain_id, 2, &log)?;
+
+    // current head points to unknown block, so there is no applied block to move it to
+    let unknown_block: BlockHash = HashType::BlockHash.string_to_bytes("BKyQ9EofHrgaZKENioHyP4FZNsTmiSEcVmcghgzCC9cGhE7oCET")?;
+    let chain_meta_storage = ChainMetaStorage::new(tmp_storage.storage());
+    chain_meta_storage.set_current_head(&chain_id, &Head { hash: unknown_block.clone(), level: 3 })?;
+
+    let report = check_storage(tmp_storage.storage(), true, &log)?;
+    assert_eq!(vec![Issue::InvalidCurrentHead { block_hash: unknown_block.clone() }], *report.issues());
+    assert_eq!(0, report.repaired());
+    assert_eq!(unknown_block, chain_meta_storage.get_current_head(&chain_id)?.unwrap().hash);
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use failure::Error;
+
+use crypto::hash::{ChainId, HashType};
+use storage::*;
+use storage::fsck::check_storage;
+use storage::history::{HistoryConfiguration, HistoryMode, HistoryPruner};
+use storage::skip_list::Bucket;
+use storage::tests_common::{context_diff, store_test_chain, TmpStorage};
+
+mod common;
+
+#[test]
+fn test_history_prune_level() {
+    let cfg = HistoryConfiguration { mode: HistoryMode::Full, keep_cycles: 2, blocks_per_cycle: 8 };
+    assert_eq!(None, cfg.prune_level(16));
+    assert_eq!(None, cfg.prune_level(23));
+    assert_eq!(Some(8), cfg.prune_level(24));
+    assert_eq!(Some(8), cfg.prune_level(31));
+    assert_eq!(Some(16), cfg.prune_level(32));
+
+    let cfg = HistoryConfiguration { mode: HistoryMode::Archive, keep_cycles: 2, blocks_per_cycle: 8 };
+    assert_eq!(None, cfg.prune_level(1000));
+}
+
+#[test]
+fn test_history_rolling_prune() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let tmp_storage = TmpStorage::create("__history_rolling_prune")?;
+    let blocks = store_test_chain(tmp_storage.storage(), &chain_id, 40, &log)?;
+
+    let mut pruner = HistoryPruner::new(tmp_storage.storage(), HistoryConfiguration { mode: HistoryMode::Rolling, keep_cycles: 1, blocks_per_cycle: 8 });
+    let stats = pruner.prune(40)?.expect("Something should be pruned");
+    assert_eq!(32, stats.level());
+    assert_eq!(31, stats.blocks());
+    assert!(stats.context_nodes() > 0);
+    assert_eq!(32, pruner.pruned_level()?);
+
+    // nothing more to prune in the same cycle
+    assert!(pruner.prune(47)?.is_none());
+
+    let block_storage = BlockStorage::new(tmp_storage.storage());
+    let block_meta_storage = BlockMetaStorage::new(tmp_storage.storage());
+    for block in &blocks {
+        let level = block.header.level();
+        if level < 32 {
+            assert!(block_storage.get(&block.hash)?.is_none());
+            assert!(block_storage.get_by_block_level(level)?.is_none());
+            // metadata are kept and marked as pruned
+            let meta = block_meta_storage.get(&block.hash)?.expect("Metadata should be kept");
+            assert!(meta.is_pruned());
+            assert_eq!(Some(block.header.predecessor()), meta.predecessor().as_ref());
+        } else {
+            assert!(block_storage.get(&block.hash)?.is_some());
+            assert_eq!(block.hash, block_storage.get_by_block_level(level)?.unwrap().hash);
+            assert!(block_meta_storage.get(&block.hash)?.unwrap().is_applied());
+            assert!(!block_meta_storage.get(&block.hash)?.unwrap().is_pruned());
+        }
+    }
+
+    // pruned blocks are not reported as corruption
+    let report = check_storage(tmp_storage.storage(), false, &log)?;
+    assert!(report.is_ok(), "Unexpected issues: {:?}", report.issues());
+
+    // context of kept levels is still complete
+    let context = tmp_storage.storage().context_storage();
+    let context = context.read().unwrap();
+    for level in 32..=40 {
+        let state = context.get(level)?.expect("Context should be kept");
+        assert_eq!(level + 1, state.len());
+        assert_eq!(Some(Bucket::Exists(vec![3])), context.get_key(level, &"data/level/3".to_string())?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_history_full_prune_keeps_blocks() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let tmp_storage = TmpStorage::create("__history_full_prune")?;
+    let blocks = store_test_chain(tmp_storage.storage(), &chain_id, 20, &log)?;
+    // levels pushed to the context, whose context hash is not assigned yet
+    let context = tmp_storage.storage().context_storage();
+    context.write().unwrap().push(&context_diff(21))?;
+    context.write().unwrap().push(&context_diff(22))?;
+
+    let mut pruner = HistoryPruner::new(tmp_storage.storage(), HistoryConfiguration { mode: HistoryMode::Full, keep_cycles: 1, blocks_per_cycle: 8 });
+    let stats = pruner.prune(20)?.expect("Something should be pruned");
+    assert_eq!(8, stats.level());
+    assert_eq!(0, stats.blocks());
+
+    let state = context.read().unwrap().get(21)?.expect("Context of not assigned level should be kept");
+    assert_eq!(22, state.len());
+
+    let block_storage = BlockStorage::new(tmp_storage.storage());
+    for block in &blocks {
+        assert!(block_storage.get(&block.hash)?.is_some());
+        // context hash of the block with pruned context is unassigned
+        let level = block.header.level();
+        let by_context_hash = block_storage.get_by_context_hash(block.header.context())?;
+        if level < 8 {
+            assert!(by_context_hash.is_none());
+        } else {
+            assert_eq!(block.hash, by_context_hash.expect("Context hash should be assigned").hash);
+        }
+    }
+
+    // pruned context is not reported as corruption
+    let report = check_storage(tmp_storage.storage(), false, &log)?;
+    assert!(report.is_ok(), "Unexpected issues: {:?}", report.issues());
+
+    Ok(())
+}
@@ -0,0 +1,240 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use failure::Error;
+
+use crypto::hash::HashType;
+use storage::*;
+use storage::persistent::{CommitLogSchema, KeyValueSchema, KeyValueStoreWithSchema, WriteBatch};
+use storage::system_storage::SystemValue;
+use storage::tests_common::TmpStorage;
+use tezos_messages::p2p::encoding::prelude::*;
+
+mod common;
+
+/// Raw access to the system storage column family
+struct TestSchema;
+
+impl KeyValueSchema for TestSchema {
+    type Key = String;
+    type Value = SystemValue;
+
+    fn name() -> &'static str {
+        SystemStorage::name()
+    }
+}
+
+#[test]
+fn test_rocksdb_backend() -> Result<(), Error> {
+    check_backend(&TmpStorage::create("__kv_backend_rocksdb")?)
+}
+
+#[test]
+fn test_in_memory_backend() -> Result<(), Error> {
+    check_backend(&TmpStorage::create_in_memory("__kv_backend_in_memory")?)
+}
+
+#[test]
+fn test_in_memory_backend_failed_batch_is_not_applied() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create_in_memory("__kv_backend_in_memory_failed_batch")?;
+    let kv = tmp_storage.storage().kv();
+    let key = "failed_batch".to_string();
+
+    let mut batch = WriteBatch::new();
+    batch.put::<TestSchema>(&key, &SystemValue::Integer(1))?;
+    // system storage has no merge operator, so the merge fails
+    batch.merge::<TestSchema>(&"failed_batch_merge".to_string(), &SystemValue::Integer(2))?;
+    assert!(KeyValueStoreWithSchema::<TestSchema>::write_batch(kv.as_ref(), batch).is_err());
+
+    assert!(!KeyValueStoreWithSchema::<TestSchema>::contains(kv.as_ref(), &key)?);
+
+    Ok(())
+}
+
+/// Both backends have to behave the same
+fn check_backend(tmp_storage: &TmpStorage) -> Result<(), Error> {
+    check_put_get_delete(tmp_storage)?;
+    check_stats(tmp_storage)?;
+    check_iterator(tmp_storage)?;
+    check_prefix_iterator(tmp_storage)?;
+    check_merge(tmp_storage)?;
+    check_write_batch(tmp_storage)?;
+    Ok(())
+}
+
+fn check_put_get_delete(tmp_storage: &TmpStorage) -> Result<(), Error> {
+    let kv = tmp_storage.storage().kv();
+    let key = "key".to_string();
+
+    assert!(KeyValueStoreWithSchema::<TestSchema>::get(kv.as_ref(), &key)?.is_none());
+    assert!(!KeyValueStoreWithSchema::<TestSchema>::contains(kv.as_ref(), &key)?);
+
+    KeyValueStoreWithSchema::<TestSchema>::put(kv.as_ref(), &key, &SystemValue::Integer(1))?;
+    KeyValueStoreWithSchema::<TestSchema>::put(kv.as_ref(), &key, &SystemValue::Integer(2))?;
+    assert!(KeyValueStoreWithSchema::<TestSchema>::contains(kv.as_ref(), &key)?);
+    match KeyValueStoreWithSchema::<TestSchema>::get(kv.as_ref(), &key)? {
+        Some(SystemValue::Integer(value)) => assert_eq!(2, value),
+        _ => panic!("Value was not overwritten"),
+    }
+
+    KeyValueStoreWithSchema::<TestSchema>::delete(kv.as_ref(), &key)?;
+    assert!(KeyValueStoreWithSchema::<TestSchema>::get(kv.as_ref(), &key)?.is_none());
+    assert!(!KeyValueStoreWithSchema::<TestSchema>::contains(kv.as_ref(), &key)?);
+
+    Ok(())
+}
+
+fn check_stats(tmp_storage: &TmpStorage) -> Result<(), Error> {
+    let stats = tmp_storage.storage().stats()?;
+
+    // operations of check_put_get_delete
+    let column = stats.columns.iter()
+        .find(|column| column.name == TestSchema::name())
+        .expect("Stats of the used column are missing");
+    assert!(column.reads.count >= 6);
+    assert!(column.writes.count >= 3);
+    assert_eq!(column.reads.count, column.reads.histogram.iter().sum::<u64>());
+
+    let commit_log = stats.commit_logs.iter()
+        .find(|commit_log| commit_log.name == BlockStorage::name())
+        .expect("Stats of the commit log are missing");
+    assert_eq!(0, commit_log.records);
+
+    Ok(())
+}
+
+fn check_iterator(tmp_storage: &TmpStorage) -> Result<(), Error> {
+    let kv = tmp_storage.storage().kv();
+    for n in 1..=5 {
+        KeyValueStoreWithSchema::<TestSchema>::put(kv.as_ref(), &format!("iter_{}", n), &SystemValue::Integer(n))?;
+    }
+    let keys = |mode: IteratorMode<TestSchema>| -> Result<Vec<String>, Error> {
+        Ok(KeyValueStoreWithSchema::<TestSchema>::iterator(kv.as_ref(), mode)?
+            .filter_map(|(key, _)| key.ok())
+            .filter(|key| key.starts_with("iter_"))
+            .collect())
+    };
+
+    assert_eq!(vec!["iter_1", "iter_2", "iter_3", "iter_4", "iter_5"], keys(IteratorMode::Start)?);
+    assert_eq!(vec!["iter_5", "iter_4", "iter_3", "iter_2", "iter_1"], keys(IteratorMode::End)?);
+    assert_eq!(vec!["iter_3", "iter_4", "iter_5"], keys(IteratorMode::From(&"iter_3".to_string(), Direction::Forward))?);
+    assert_eq!(vec!["iter_3", "iter_2", "iter_1"], keys(IteratorMode::From(&"iter_3".to_string(), Direction::Reverse))?);
+
+    // whole iteration is recorded as a single read
+    let reads_count = || -> Result<u64, Error> {
+        Ok(tmp_storage.storage().stats()?.columns.iter()
+            .find(|column| column.name == TestSchema::name())
+            .map_or(0, |column| column.reads.count))
+    };
+    let reads_before = reads_count()?;
+    keys(IteratorMode::Start)?;
+    assert_eq!(reads_before + 1, reads_count()?);
+
+    // long iteration (in-memory backend reads the column in chunks) can modify the column
+    for n in 0..1000 {
+        KeyValueStoreWithSchema::<TestSchema>::put(kv.as_ref(), &format!("long_{:04}", n), &SystemValue::Integer(n))?;
+    }
+    let mut count = 0;
+    for (key, value) in KeyValueStoreWithSchema::<TestSchema>::iterator(kv.as_ref(), IteratorMode::From(&"long_0000".to_string(), Direction::Forward))? {
+        let key = key?;
+        if !key.starts_with("long_") {
+            break;
+        }
+        match value? {
+            SystemValue::Integer(value) => assert_eq!(format!("long_{:04}", value), key),
+            _ => panic!("Unexpected value"),
+        }
+        KeyValueStoreWithSchema::<TestSchema>::delete(kv.as_ref(), &key)?;
+        count += 1;
+    }
+    assert_eq!(1000, count);
+    let long_keys = KeyValueStoreWithSchema::<TestSchema>::iterator(kv.as_ref(), IteratorMode::End)?
+        .filter_map(|(key, _)| key.ok())
+        .filter(|key| key.starts_with("long_"))
+        .count();
+    assert_eq!(0, long_keys);
+
+    Ok(())
+}
+
+fn check_prefix_iterator(tmp_storage: &TmpStorage) -> Result<(), Error> {
+    let block_hash_1 = HashType::BlockHash.string_to_bytes("BKyQ9EofHrgaZKENioHyP4FZNsTmiSEcVmcghgzCC9cGhE7oCET")?;
+    let block_hash_2 = HashType::BlockHash.string_to_bytes("BLaf78njreWdt2WigJjM9e3ecEdVKm5ehahUfYBKvcWvZ8vfTcJ")?;
+
+    let storage = OperationsStorage::new(tmp_storage.storage());
+    for validation_pass in 0..3 {
+        storage.put_operations(&OperationsForBlocksMessage::new(OperationsForBlock::new(block_hash_1.clone(), validation_pass), Path::Op, vec![]))?;
+        storage.put_operations(&OperationsForBlocksMessage::new(OperationsForBlock::new(block_hash_2.clone(), validation_pass), Path::Op, vec![]))?;
+    }
+
+    let operations = storage.get_operations(&block_hash_1)?;
+    assert_eq!(3, operations.len());
+    assert!(operations.iter().all(|operations| operations.operations_for_block().hash() == &block_hash_1));
+
+    Ok(())
+}
+
+fn check_merge(tmp_storage: &TmpStorage) -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+    let block = BlockHeaderWithHash::new(
+        BlockHeaderBuilder::default()
+            .level(1)
+            .proto(1)
+            .predecessor(HashType::BlockHash.string_to_bytes("BLockGenesisGenesisGenesisGenesisGenesisb83baZgbyZe")?)
+            .timestamp(5_635_634)
+            .validation_pass(4)
+            .operations_hash(HashType::OperationListListHash.string_to_bytes("LLoaGLRPRx3Zf8kB4ACtgku8F4feeBiskeb41J1ciwfcXB3KzHKXc")?)
+            .fitness(vec![])
+            .context(HashType::ContextHash.string_to_bytes("CoVmAcMV64uAQo8XvfLr9VDuz7HVZLT4cgK1w1qYmTjQNbGwQwDd")?)
+            .protocol_data(vec![])
+            .build().unwrap()
+    )?;
+
+    // block meta storage merges flags
+    let block_meta_storage = BlockMetaStorage::new(tmp_storage.storage());
+    block_meta_storage.put_block_header(&block, &chain_id, &log)?;
+    let mut meta = block_meta_storage.get(&block.hash)?.unwrap();
+    meta.set_is_applied(true);
+    block_meta_storage.put(&block.hash, &meta)?;
+    meta.set_is_applied(false);
+    block_meta_storage.put(&block.hash, &meta)?;
+    assert!(block_meta_storage.get(&block.hash)?.unwrap().is_applied());
+
+    // operations meta storage merges validation passes
+    let operations_meta_storage = OperationsMetaStorage::new(tmp_storage.storage());
+    operations_meta_storage.put_block_header(&block, &chain_id)?;
+    for validation_pass in 0..4 {
+        assert!(!operations_meta_storage.is_complete(&block.hash)?);
+        operations_meta_storage.put_operations(&OperationsForBlocksMessage::new(OperationsForBlock::new(block.hash.clone(), validation_pass), Path::Op, vec![]))?;
+    }
+    assert!(operations_meta_storage.is_complete(&block.hash)?);
+
+    Ok(())
+}
+
+fn check_write_batch(tmp_storage: &TmpStorage) -> Result<(), Error> {
+    let kv = tmp_storage.storage().kv();
+    let key_1 = "batch_1".to_string();
+    let key_2 = "batch_2".to_string();
+    KeyValueStoreWithSchema::<TestSchema>::put(kv.as_ref(), &key_1, &SystemValue::Integer(1))?;
+
+    let mut batch = WriteBatch::new();
+    batch.delete::<TestSchema>(&key_1)?;
+    batch.put::<TestSchema>(&key_2, &SystemValue::Integer(2))?;
+    batch.put::<SystemStorage>(&"batch_system".to_string(), &SystemValue::Integer(3))?;
+    assert_eq!(3, batch.len());
+    KeyValueStoreWithSchema::<TestSchema>::write_batch(kv.as_ref(), batch)?;
+
+    assert!(!KeyValueStoreWithSchema::<TestSchema>::contains(kv.as_ref(), &key_1)?);
+    match KeyValueStoreWithSchema::<TestSchema>::get(kv.as_ref(), &key_2)? {
+        Some(SystemValue::Integer(value)) => assert_eq!(2, value),
+        _ => panic!("Value was not written by the batch"),
+    }
+    assert!(KeyValueStoreWithSchema::<SystemStorage>::contains(kv.as_ref(), &"batch_system".to_string())?);
+
+    // empty batch is no-op
+    KeyValueStoreWithSchema::<TestSchema>::write_batch(kv.as_ref(), WriteBatch::new())?;
+
+    Ok(())
+}
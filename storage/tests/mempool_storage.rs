@@ -1,11 +1,13 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use failure::Error;
 
-use storage::mempool_storage::MempoolOperationType;
+use crypto::hash::HashType;
+use storage::context_action_storage::contract_id_to_contract_address_for_index;
+use storage::mempool_storage::{MempoolOperationOutcome, MempoolOperationType, MempoolPrevalidation, PrevalidationStatus};
 use storage::MempoolStorage;
 use storage::tests_common::TmpStorage;
 use tezos_messages::p2p::binary_message::BinaryMessage;
@@ -32,6 +34,56 @@ fn mempool_storage_read_write() -> Result<(), Error> {
     Ok(())
 }
 
+#[test]
+fn mempool_storage_history() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__mempool_storage_history")?;
+    let storage = MempoolStorage::new(tmp_storage.storage());
+
+    let operation_hash_1 = HashType::OperationHash.string_to_bytes("onvN8U6QJ6DGJKVYkHXYRtFm3tgBJScj9P5bbPjSZUuFaGzwFuJ")?;
+    let operation_hash_2 = HashType::OperationHash.string_to_bytes("opMNCb1mCyrrSJ4MMEB1gQTn9ezMvgsTLpNVGq5F1vdtoAaDSib")?;
+    let source = "tz1PirboZKFVqkfE45hVLpkpXaZtLk3mqC17";
+    let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+
+    storage.record_received(&operation_hash_1, Some("127.0.0.1:9732".to_string()), at(100))?;
+    storage.record_received(&operation_hash_2, None, at(200))?;
+    // only the first reception is kept
+    storage.record_received(&operation_hash_1, None, at(300))?;
+
+    storage.record_prevalidation(&operation_hash_1, MempoolPrevalidation::new(PrevalidationStatus::Applied, at(101), None), vec![source.to_string()])?;
+    storage.record_prevalidation(&operation_hash_2, MempoolPrevalidation::new(PrevalidationStatus::BranchDelayed, at(201), Some("[]".to_string())), vec![source.to_string()])?;
+    storage.record_included(&operation_hash_1, &vec![1; HashType::BlockHash.size()], at(110))?;
+    storage.record_dropped(&operation_hash_1, "not included".to_string(), at(120))?;
+    storage.record_dropped(&operation_hash_2, "not included".to_string(), at(220))?;
+
+    let record = storage.get_history(&operation_hash_1)?.expect("History record is missing");
+    assert_eq!(100_000, record.first_seen());
+    assert_eq!(&Some("127.0.0.1:9732".to_string()), record.peer());
+    assert_eq!(PrevalidationStatus::Applied, record.prevalidation().as_ref().unwrap().status());
+    assert!(record.is_included());
+    match storage.get_history(&operation_hash_2)?.unwrap().outcome() {
+        Some(MempoolOperationOutcome::Dropped { reason, at }) => {
+            assert_eq!("not included", reason);
+            assert_eq!(220_000, *at);
+        }
+        _ => panic!("Operation should be dropped"),
+    }
+
+    // queries
+    let by_source = storage.find_history_by_source(&contract_id_to_contract_address_for_index(source)?, 10)?;
+    assert_eq!(vec![&operation_hash_1, &operation_hash_2], by_source.iter().map(|record| record.operation_hash()).collect::<Vec<_>>());
+    let by_time = storage.find_history_by_time(at(150), at(250), 10)?;
+    assert_eq!(1, by_time.len());
+    assert_eq!(&operation_hash_2, by_time[0].operation_hash());
+    assert_eq!(1, storage.find_history_by_time(at(0), at(1000), 1)?.len());
+
+    // pruning removes also index entries
+    assert_eq!(1, storage.prune_history(at(150))?);
+    assert!(storage.get_history(&operation_hash_1)?.is_none());
+    assert_eq!(1, storage.find_history_by_source(&contract_id_to_contract_address_for_index(source)?, 10)?.len());
+
+    Ok(())
+}
+
 fn make_test_operation_message() -> Result<OperationMessage, Error> {
     let message_bytes = hex::decode("10490b79070cf19175cd7e3b9c1ee66f6e85799980404b119132ea7e58a4a97e000008c387fa065a181d45d47a9b78ddc77e92a881779ff2cbabbf9646eade4bf1405a08e00b725ed849eea46953b10b5cdebc518e6fd47e69b82d2ca18c4cf6d2f312dd08")?;
     let operation = Operation::from_bytes(message_bytes)?;
@@ -0,0 +1,138 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::collections::BTreeMap;
+
+use failure::Error;
+
+use crypto::hash::{ContextHash, HashType};
+use storage::merkle_storage::{CommitInfo, hash_context, MerkleError, MerkleStorage};
+use storage::persistent::ContextMap;
+use storage::skip_list::Bucket;
+use storage::tests_common::TmpStorage;
+use tezos_api::environment::{TEZOS_ENV, TezosEnvironment};
+
+const DATE: i64 = 1_592_407_207;
+
+fn info(parents: &[ContextHash]) -> CommitInfo {
+    CommitInfo { parents, date: DATE, author: "Tezos", message: "Genesis" }
+}
+
+fn diff(entries: &[(&str, Option<Vec<u8>>)]) -> ContextMap {
+    entries.iter()
+        .map(|(key, value)| (key.to_string(), match value {
+            Some(value) => Bucket::Exists(value.to_vec()),
+            None => Bucket::Deleted,
+        }))
+        .collect::<BTreeMap<_, _>>()
+}
+
+fn key(key: &str) -> Vec<String> {
+    key.split('/').map(|step| step.to_string()).collect()
+}
+
+#[test]
+fn test_commit_and_get() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__merkle_storage_commit_and_get")?;
+    let storage = MerkleStorage::new(tmp_storage.storage());
+
+    let context_hash = storage.commit_diff(&None, &diff(&[
+        ("data/contracts/index/a/balance", Some(vec![1])),
+        ("data/contracts/index/b/balance", Some(vec![2])),
+        ("protocol", Some(vec![3])),
+    ]), &info(&[]))?;
+    assert_eq!(32, context_hash.len());
+    assert!(storage.contains_commit(&context_hash)?);
+
+    assert_eq!(Some(vec![1]), storage.get(&context_hash, &key("data/contracts/index/a/balance"))?);
+    assert_eq!(Some(vec![3]), storage.get(&context_hash, &key("protocol"))?);
+    assert_eq!(None, storage.get(&context_hash, &key("data/contracts/index/c/balance"))?);
+    // tree is not a value
+    assert_eq!(None, storage.get(&context_hash, &key("data/contracts"))?);
+
+    Ok(())
+}
+
+#[test]
+fn test_hash_is_deterministic() -> Result<(), Error> {
+    let tmp_storage_1 = TmpStorage::create("__merkle_storage_deterministic_1")?;
+    let tmp_storage_2 = TmpStorage::create_in_memory("__merkle_storage_deterministic_2")?;
+    let storage_1 = MerkleStorage::new(tmp_storage_1.storage());
+    let storage_2 = MerkleStorage::new(tmp_storage_2.storage());
+
+    // same context is built in different order (in one commit and in two commits)
+    let hash_1 = storage_1.commit_diff(&None, &diff(&[("a/b", Some(vec![1])), ("c", Some(vec![2]))]), &info(&[]))?;
+    let parent = storage_2.commit_diff(&None, &diff(&[("c", Some(vec![2]))]), &info(&[]))?;
+    let rebuilt = storage_2.commit_diff(&Some(parent), &diff(&[("a/b", Some(vec![1]))]), &info(&[]))?;
+    assert_eq!(hash_1, rebuilt);
+
+    // metadata are part of the hash
+    let hash_2 = storage_1.commit_diff(&None, &diff(&[("a/b", Some(vec![1])), ("c", Some(vec![2]))]), &CommitInfo { message: "other", ..info(&[]) })?;
+    assert_ne!(hash_1, hash_2);
+
+    Ok(())
+}
+
+#[test]
+fn test_commit_chain_with_delete() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__merkle_storage_commit_chain")?;
+    let storage = MerkleStorage::new(tmp_storage.storage());
+
+    let genesis = storage.commit_diff(&None, &diff(&[("a/b", Some(vec![1])), ("c", Some(vec![2]))]), &info(&[]))?;
+    let next = storage.commit_diff(&Some(genesis.clone()), &diff(&[("a/b", None), ("d/e", Some(vec![3]))]), &info(&[genesis.clone()]))?;
+
+    // parent context is untouched
+    assert_eq!(Some(vec![1]), storage.get(&genesis, &key("a/b"))?);
+    assert_eq!(None, storage.get(&genesis, &key("d/e"))?);
+    assert_eq!(None, storage.get(&next, &key("a/b"))?);
+    assert_eq!(Some(vec![3]), storage.get(&next, &key("d/e"))?);
+    assert_eq!(Some(vec![2]), storage.get(&next, &key("c"))?);
+
+    // deleting of the last key removes whole subtree, so hash is the same as if it never existed
+    let expected_root = storage.commit_diff(&None, &diff(&[("c", Some(vec![2])), ("d/e", Some(vec![3]))]), &info(&[genesis.clone()]))?;
+    assert_eq!(expected_root, next);
+
+    Ok(())
+}
+
+#[test]
+fn test_missing_parent() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__merkle_storage_missing_parent")?;
+    let storage = MerkleStorage::new(tmp_storage.storage());
+
+    match storage.commit_diff(&Some(vec![0; 32]), &diff(&[("a", Some(vec![1]))]), &info(&[])) {
+        Err(MerkleError::EntryNotFound { .. }) => (),
+        _ => panic!("Commit should fail for unknown parent"),
+    }
+
+    Ok(())
+}
+
+/// Genesis context hashes calculated by the OCaml node (see `ffi_tests.rs` and `init_storage_tests.rs` of `tezos_client`)
+#[test]
+fn test_genesis_context_hash_matches_ocaml() -> Result<(), Error> {
+    // genesis time of the environment in seconds
+    let genesis = [
+        (TezosEnvironment::Carthagenet, 1_574_946_133, "CoWZVRSM6DdNUpn3mamy7e8rUSxQVWkQCQfJBg7DrTVXUjzGZGCa"),
+        (TezosEnvironment::Sandbox, 1_530_374_852, "CoVewPVcrKctWXSbrRgoGD6NmkdbDhmTFk5oi1FZpEcRT3bmKxdQ"),
+    ];
+
+    for (net, date, expected_hash) in genesis.iter() {
+        let tezos_env = TEZOS_ENV.get(net).expect("no tezos environment configured");
+        // genesis commit contains just the protocol and the status of the test chain (not running)
+        let context = diff(&[
+            ("protocol", Some(HashType::ProtocolHash.string_to_bytes(&tezos_env.genesis.protocol)?)),
+            ("test_chain", Some(vec![0])),
+        ]);
+        let info = CommitInfo { parents: &[], date: *date, author: "Tezos", message: "Genesis" };
+
+        let tmp_storage = TmpStorage::create_in_memory(format!("__merkle_storage_genesis_{:?}", net))?;
+        let storage = MerkleStorage::new(tmp_storage.storage());
+        let committed_hash = storage.commit_diff(&None, &context, &info)?;
+
+        assert_eq!(*expected_hash, HashType::ContextHash.bytes_to_string(&hash_context(&context, &info)), "genesis context hash of {:?}", net);
+        assert_eq!(*expected_hash, HashType::ContextHash.bytes_to_string(&committed_hash), "genesis commit hash of {:?}", net);
+    }
+
+    Ok(())
+}
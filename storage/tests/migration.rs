@@ -0,0 +1,299 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use failure::Error;
+
+use storage::{BlockHeaderWithHash, BlockStorage, BlockStorageReader, PeerStorage, SystemStorage};
+use storage::block_storage::{BlockPrimaryIndex, BlockStorageColumn};
+use storage::migration::{database_migrations, Migration, MigrationContext, MigrationError, Migrator, Rewrite};
+use storage::persistent::{CommitLogSchema, KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage};
+use storage::skip_list::{Bucket, ListValue};
+use storage::system_storage::{DbVersion, SystemValue};
+use storage::tests_common::TmpStorage;
+use tezos_messages::p2p::binary_message::BinaryMessage;
+use tezos_messages::p2p::encoding::prelude::*;
+
+mod common;
+
+const TEST_RECORDS: i64 = 3000;
+
+/// Raw access to the system storage column family
+struct TestSchema;
+
+impl KeyValueSchema for TestSchema {
+    type Key = String;
+    type Value = SystemValue;
+
+    fn name() -> &'static str {
+        SystemStorage::name()
+    }
+}
+
+/// Raw access to the peer storage column family, so step can rewrite more column families
+struct OtherTestSchema;
+
+impl KeyValueSchema for OtherTestSchema {
+    type Key = String;
+    type Value = SystemValue;
+
+    fn name() -> &'static str {
+        PeerStorage::name()
+    }
+}
+
+/// Sets value of every `test_{n}` key to `n * 10`, optionally fails after `fail_after` processed records
+struct MultiplyTestValues {
+    fail_after: Option<usize>,
+    processed: Arc<AtomicUsize>,
+}
+
+impl Migration for MultiplyTestValues {
+    fn version(&self) -> DbVersion {
+        2
+    }
+
+    fn description(&self) -> &'static str {
+        "multiply test values"
+    }
+
+    fn migrate(&self, ctx: &mut MigrationContext) -> Result<(), MigrationError> {
+        let fail_after = self.fail_after;
+        let processed = self.processed.clone();
+        ctx.rewrite::<TestSchema, _>(|key, value| {
+            if fail_after.map_or(false, |fail_after| processed.load(Ordering::SeqCst) >= fail_after) {
+                return Err(MigrationError::StepFailed { version: 2, reason: "simulated crash".to_string() });
+            }
+            processed.fetch_add(1, Ordering::SeqCst);
+
+            if !key.starts_with("test_") {
+                return Ok(Rewrite::Keep);
+            }
+            let expected = key["test_".len()..].parse::<i64>().unwrap() * 10;
+            match value {
+                SystemValue::Integer(value) if value == expected => Ok(Rewrite::Keep),
+                _ => Ok(Rewrite::Put(SystemValue::Integer(expected))),
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// Negates values of both test column families, optionally fails after `fail_after` processed records
+struct NegateTestValues {
+    fail_after: Option<usize>,
+    processed: Arc<AtomicUsize>,
+}
+
+impl Migration for NegateTestValues {
+    fn version(&self) -> DbVersion {
+        2
+    }
+
+    fn description(&self) -> &'static str {
+        "negate test values"
+    }
+
+    fn migrate(&self, ctx: &mut MigrationContext) -> Result<(), MigrationError> {
+        let fail_after = self.fail_after;
+        let processed = self.processed.clone();
+        let mut negate = |key: &String, value: SystemValue| {
+            if fail_after.map_or(false, |fail_after| processed.load(Ordering::SeqCst) >= fail_after) {
+                return Err(MigrationError::StepFailed { version: 2, reason: "simulated crash".to_string() });
+            }
+            processed.fetch_add(1, Ordering::SeqCst);
+
+            match value {
+                SystemValue::Integer(value) if key.starts_with("test_") => Ok(Rewrite::Put(SystemValue::Integer(-value))),
+                _ => Ok(Rewrite::Keep),
+            }
+        };
+        ctx.rewrite::<TestSchema, _>(&mut negate)?;
+        ctx.rewrite::<OtherTestSchema, _>(&mut negate)?;
+        Ok(())
+    }
+}
+
+/// Appends every block header to the commit log again
+struct RelocateBlockHeaders;
+
+impl Migration for RelocateBlockHeaders {
+    fn version(&self) -> DbVersion {
+        2
+    }
+
+    fn description(&self) -> &'static str {
+        "relocate block headers"
+    }
+
+    fn migrate(&self, ctx: &mut MigrationContext) -> Result<(), MigrationError> {
+        ctx.rewrite_commit_log::<BlockPrimaryIndex, BlockStorage, _>(|_, record| match record {
+            BlockStorageColumn::BlockHeader(block_header) => Ok(Some(BlockStorageColumn::BlockHeader(block_header))),
+            _ => Ok(None),
+        })?;
+        Ok(())
+    }
+}
+
+fn migrator(fail_after: Option<usize>) -> Result<Migrator, MigrationError> {
+    Migrator::new(vec![Box::new(MultiplyTestValues { fail_after, processed: Arc::new(AtomicUsize::new(0)) })])
+}
+
+fn prepare_storage(persistent_storage: &PersistentStorage) -> Result<(), Error> {
+    let mut system_storage = SystemStorage::new(persistent_storage.kv());
+    system_storage.set_db_version(1)?;
+    for n in 0..TEST_RECORDS {
+        KeyValueStoreWithSchema::<TestSchema>::put(persistent_storage.kv().as_ref(), &format!("test_{}", n), &SystemValue::Integer(n))?;
+    }
+    Ok(())
+}
+
+fn assert_test_values(persistent_storage: &PersistentStorage, multiplier: i64) -> Result<(), Error> {
+    for n in 0..TEST_RECORDS {
+        match KeyValueStoreWithSchema::<TestSchema>::get(persistent_storage.kv().as_ref(), &format!("test_{}", n))? {
+            Some(SystemValue::Integer(value)) => assert_eq!(n * multiplier, value),
+            _ => panic!("Missing test value: {}", n),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_migration_check() -> Result<(), Error> {
+    let migrator = migrator(None)?;
+    assert!(migrator.check(1, 2).is_ok());
+    assert!(migrator.check(2, 2).is_ok());
+    match migrator.check(0, 2) {
+        Err(MigrationError::MissingMigration { missing_version, .. }) => assert_eq!(1, missing_version),
+        _ => panic!("Migration from version 0 should not be possible"),
+    }
+    match migrator.check(3, 2) {
+        Err(MigrationError::UnsupportedDowngrade { .. }) => (),
+        _ => panic!("Downgrade should not be possible"),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_migration_dry_run() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__migration_dry_run")?;
+    prepare_storage(tmp_storage.storage())?;
+
+    let report = migrator(None)?.migrate(tmp_storage.storage(), 2, true, &common::create_logger())?;
+    assert!(report.dry_run());
+    assert_eq!(1, report.steps().len());
+    // "test_0" already has expected value
+    assert_eq!(TEST_RECORDS as usize - 1, report.steps()[0].changes()[SystemStorage::name()].updated());
+
+    // nothing was changed
+    assert_eq!(Some(1), SystemStorage::new(tmp_storage.storage().kv()).get_db_version()?);
+    assert_test_values(tmp_storage.storage(), 1)
+}
+
+#[test]
+fn test_migration_resumes_after_crash() -> Result<(), Error> {
+    let log = common::create_logger();
+    let tmp_storage = TmpStorage::create("__migration_resume")?;
+    prepare_storage(tmp_storage.storage())?;
+
+    // first run is interrupted
+    assert!(migrator(Some(2500))?.migrate(tmp_storage.storage(), 2, false, &log).is_err());
+    let system_storage = SystemStorage::new(tmp_storage.storage().kv());
+    assert_eq!(Some(1), system_storage.get_db_version()?);
+    assert!(system_storage.get_migration_checkpoint()?.is_some());
+
+    // second run continues from the checkpoint
+    let report = migrator(None)?.migrate(tmp_storage.storage(), 2, false, &log)?;
+    assert!(report.steps()[0].resumed());
+    assert!(report.steps()[0].changes()[SystemStorage::name()].updated() < TEST_RECORDS as usize - 1);
+
+    assert_eq!(Some(2), system_storage.get_db_version()?);
+    assert!(system_storage.get_migration_checkpoint()?.is_none());
+    assert_test_values(tmp_storage.storage(), 10)
+}
+
+#[test]
+fn test_migration_resumes_rewrite_of_more_column_families() -> Result<(), Error> {
+    let log = common::create_logger();
+    let tmp_storage = TmpStorage::create("__migration_resume_more_columns")?;
+    prepare_storage(tmp_storage.storage())?;
+    for n in 0..TEST_RECORDS {
+        KeyValueStoreWithSchema::<OtherTestSchema>::put(tmp_storage.storage().kv().as_ref(), &format!("test_{}", n), &SystemValue::Integer(n))?;
+    }
+    let negate = |fail_after| Migrator::new(vec![Box::new(NegateTestValues { fail_after, processed: Arc::new(AtomicUsize::new(0)) })]);
+
+    // first run is interrupted during the rewrite of the second column family
+    assert!(negate(Some(TEST_RECORDS as usize + 2000))?.migrate(tmp_storage.storage(), 2, false, &log).is_err());
+
+    // second run does not rewrite the finished column family again
+    let report = negate(None)?.migrate(tmp_storage.storage(), 2, false, &log)?;
+    assert!(report.steps()[0].resumed());
+    assert!(report.steps()[0].changes().get(SystemStorage::name()).is_none());
+
+    assert_test_values(tmp_storage.storage(), -1)?;
+    for n in 0..TEST_RECORDS {
+        match KeyValueStoreWithSchema::<OtherTestSchema>::get(tmp_storage.storage().kv().as_ref(), &format!("test_{}", n))? {
+            Some(SystemValue::Integer(value)) => assert_eq!(-n, value),
+            _ => panic!("Missing test value: {}", n),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_migration_rewrites_commit_log() -> Result<(), Error> {
+    let log = common::create_logger();
+    let tmp_storage = TmpStorage::create("__migration_rewrite_commit_log")?;
+    SystemStorage::new(tmp_storage.storage().kv()).set_db_version(1)?;
+    let block_storage = BlockStorage::new(tmp_storage.storage());
+    let block_header = make_test_block_header()?;
+    block_storage.put_block_header(&block_header)?;
+    let location = block_storage.get_location(&block_header.hash)?.expect("Block location is stored");
+
+    // dry-run does not append anything
+    let report = Migrator::new(vec![Box::new(RelocateBlockHeaders)])?.migrate(tmp_storage.storage(), 2, true, &log)?;
+    assert_eq!(1, report.steps()[0].changes()[BlockStorage::name()].updated());
+    assert_eq!(location.block_header, block_storage.get_location(&block_header.hash)?.expect("Block location is stored").block_header);
+
+    Migrator::new(vec![Box::new(RelocateBlockHeaders)])?.migrate(tmp_storage.storage(), 2, false, &log)?;
+    let relocated = block_storage.get_location(&block_header.hash)?.expect("Block location is stored");
+    assert_ne!(location.block_header, relocated.block_header);
+    assert_eq!(Some(block_header.clone()), block_storage.get(&block_header.hash)?);
+    Ok(())
+}
+
+#[test]
+fn test_migration_of_context_values() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__migration_context_values")?;
+    SystemStorage::new(tmp_storage.storage().kv()).set_db_version(15)?;
+
+    // small values are stored as they were before the blobs were introduced, large values are stored as blobs
+    let context = tmp_storage.storage().context_storage();
+    let mut expected = BTreeMap::new();
+    for level in 0..20u8 {
+        let mut diff = BTreeMap::new();
+        diff.insert(format!("data/small/{}", level), Bucket::Exists(vec![0xff, level]));
+        diff.insert(format!("data/large/{}", level), Bucket::Exists(vec![level; 100]));
+        if level > 0 {
+            diff.insert(format!("data/small/{}", level - 1), Bucket::Deleted);
+        }
+        context.write().unwrap().push(&diff)?;
+        expected.extend(diff);
+    }
+
+    let report = Migrator::new(database_migrations())?.migrate(tmp_storage.storage(), 16, false, &common::create_logger())?;
+    assert_eq!(1, report.steps().len());
+    // stored values do not start with a tag, so nothing is escaped
+    assert!(report.steps()[0].changes().get(ListValue::name()).is_none());
+
+    assert_eq!(Some(expected), context.read().unwrap().get(19)?);
+    Ok(())
+}
+
+fn make_test_block_header() -> Result<BlockHeaderWithHash, Error> {
+    let message_bytes = hex::decode("00006d6e0102dd00defaf70c53e180ea148b349a6feb4795610b2abc7b07fe91ce50a90814000000005c1276780432bc1d3a28df9a67b363aa1638f807214bb8987e5f9c0abcbd69531facffd1c80000001100000001000000000800000000000c15ef15a6f54021cb353780e2847fb9c546f1d72c1dc17c3db510f45553ce501ce1de000000000003c762c7df00a856b8bfcaf0676f069f825ca75f37f2bee9fe55ba109cec3d1d041d8c03519626c0c0faa557e778cb09d2e0c729e8556ed6a7a518c84982d1f2682bc6aa753f")?;
+    Ok(BlockHeaderWithHash::new(BlockHeader::from_bytes(message_bytes)?)?)
+}
@@ -0,0 +1,123 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use failure::Error;
+
+use crypto::hash::HashType;
+use storage::*;
+use storage::context_action_storage::contract_id_to_contract_address_for_index;
+use storage::operations_index_storage::{AccountRole, OperationKind};
+use storage::tests_common::{test_block, TmpStorage};
+
+const BAKER: &str = "tz1PirboZKFVqkfE45hVLpkpXaZtLk3mqC17";
+const ALICE: &str = "tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSx";
+const CONTRACT: &str = "KT1A3hctnxN1fXi7oY1y4wLmrN9dkGPcu9kg";
+const ORIGINATED: &str = "KT1A91VqdhR8Xg6bRWDaC4h8MK9KfYo9o4Vi";
+
+const ENDORSEMENT_HASH: &str = "oneev3QMfQJd2fEM3zQPdFwwPY1aohkxffttrK8Qrswemnh2nML";
+const CALL_HASH: &str = "onf6ZPF8hsU6q6D7ZmLtjquoNqKh7N8Xr5vW8WzP1yYueNyCF86";
+const ORIGINATION_HASH: &str = "onfYCj5ukLdadXBt5YHPrRsfN8doR2W72Vx7QirMB5AAX1uUFxy";
+const TRANSFER_HASH: &str = "onfyr4vgnoo4RxAebKDty1qXMRwuigsgCuyigviKLAmRPaWRwdQ";
+
+#[test]
+fn operations_index_queries() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__operations_index_queries")?;
+    let block_storage = BlockStorage::new(tmp_storage.storage());
+    let index_storage = OperationsIndexStorage::new(tmp_storage.storage());
+
+    let block_1 = test_block(1, &vec![0; HashType::BlockHash.size()])?;
+    let block_2 = test_block(2, &block_1.hash)?;
+    block_storage.put_block_header(&block_1)?;
+    block_storage.put_block_header(&block_2)?;
+
+    // endorsement in the first pass, contract call with the internal transfer and origination in the last pass
+    let endorsement = format!(
+        r#"{{"hash":"{}","contents":[{{"kind":"endorsement","level":0,"metadata":{{"delegate":"{}","slots":[1]}}}}]}}"#,
+        ENDORSEMENT_HASH, BAKER
+    );
+    let call = format!(
+        r#"{{"hash":"{}","contents":[{{"kind":"transaction","source":"{}","destination":"{}","metadata":{{"operation_result":{{"status":"applied"}},"internal_operation_results":[{{"kind":"transaction","source":"{}","destination":"{}","result":{{"status":"applied"}}}}]}}}}]}}"#,
+        CALL_HASH, BAKER, CONTRACT, CONTRACT, ALICE
+    );
+    let origination = format!(
+        r#"{{"hash":"{}","contents":[{{"kind":"reveal","source":"{}"}},{{"kind":"origination","source":"{}","metadata":{{"operation_result":{{"originated_contracts":["{}"]}}}}}}]}}"#,
+        ORIGINATION_HASH, ALICE, ALICE, ORIGINATED
+    );
+    block_storage.put_block_json_data(&block_1.hash, json_data(&format!("[[{}],[],[],[{},{}]]", endorsement, call, origination)))?;
+    let transfer = format!(
+        r#"{{"hash":"{}","contents":[{{"kind":"transaction","source":"{}","destination":"{}"}}]}}"#,
+        TRANSFER_HASH, ALICE, BAKER
+    );
+    block_storage.put_block_json_data(&block_2.hash, json_data(&format!("[[],[],[],[{}]]", transfer)))?;
+
+    // by hash
+    let found = index_storage.get_by_hash(&operation_hash(CALL_HASH)?)?;
+    assert_eq!(1, found.len());
+    assert_eq!(&block_1.hash, found[0].block_hash());
+    assert_eq!((1, 3, 0), (found[0].level(), found[0].validation_pass(), found[0].index()));
+    assert_eq!(&vec![OperationKind::Transaction], found[0].kinds());
+    let found = index_storage.get_by_hash(&operation_hash(ORIGINATION_HASH)?)?;
+    assert_eq!(1, found[0].index());
+    assert_eq!(&vec![OperationKind::Reveal, OperationKind::Origination], found[0].kinds());
+
+    // by account
+    let baker = contract_id_to_contract_address_for_index(BAKER)?;
+    let operations = index_storage.get_by_account(&baker, None, 0, 10)?;
+    assert_eq!(vec![ENDORSEMENT_HASH, CALL_HASH, TRANSFER_HASH], hashes(&operations));
+    assert_eq!(&vec![AccountRole::Delegate], operations[0].roles());
+    assert_eq!(&vec![AccountRole::Source], operations[1].roles());
+    assert_eq!(&vec![AccountRole::Destination], operations[2].roles());
+    assert_eq!(vec![CALL_HASH, TRANSFER_HASH], hashes(&index_storage.get_by_account(&baker, Some(OperationKind::Transaction), 0, 10)?));
+    assert_eq!(vec![TRANSFER_HASH], hashes(&index_storage.get_by_account(&baker, Some(OperationKind::Transaction), 2, 10)?));
+    assert_eq!(vec![CALL_HASH], hashes(&index_storage.get_by_account(&baker, Some(OperationKind::Transaction), 0, 1)?));
+    assert!(index_storage.get_by_account(&baker, Some(OperationKind::Origination), 0, 10)?.is_empty());
+    assert_eq!(vec![TRANSFER_HASH], hashes(&index_storage.get_by_account(&baker, None, 2, 10)?));
+    assert_eq!(vec![ENDORSEMENT_HASH], hashes(&index_storage.get_by_account(&baker, None, 0, 1)?));
+
+    // internal operations
+    let contract = contract_id_to_contract_address_for_index(CONTRACT)?;
+    let operations = index_storage.get_by_account(&contract, None, 0, 10)?;
+    assert_eq!(vec![CALL_HASH], hashes(&operations));
+    assert_eq!(&vec![AccountRole::Destination, AccountRole::Source], operations[0].roles());
+    let alice = contract_id_to_contract_address_for_index(ALICE)?;
+    assert_eq!(vec![CALL_HASH, ORIGINATION_HASH, TRANSFER_HASH], hashes(&index_storage.get_by_account(&alice, None, 0, 10)?));
+    let originated = index_storage.get_by_account(&contract_id_to_contract_address_for_index(ORIGINATED)?, None, 0, 10)?;
+    assert_eq!(vec![ORIGINATION_HASH], hashes(&originated));
+    assert_eq!(&vec![AccountRole::Originated], originated[0].roles());
+    let originations = index_storage.get_by_account(&alice, Some(OperationKind::Origination), 0, 10)?;
+    assert_eq!(vec![ORIGINATION_HASH], hashes(&originations));
+    assert_eq!(&vec![AccountRole::Source], originations[0].roles());
+
+    // by kind
+    assert_eq!(vec![CALL_HASH, TRANSFER_HASH], hashes(&index_storage.get_by_kind(OperationKind::Transaction, 0, 10)?));
+    assert_eq!(vec![TRANSFER_HASH], hashes(&index_storage.get_by_kind(OperationKind::Transaction, 2, 10)?));
+    assert_eq!(vec![ENDORSEMENT_HASH], hashes(&index_storage.get_by_kind(OperationKind::Endorsement, 0, 10)?));
+    assert!(index_storage.get_by_kind(OperationKind::Delegation, 0, 10)?.is_empty());
+
+    // index entries are removed together with the block
+    block_storage.delete(&block_2.hash)?;
+    assert!(index_storage.get_by_hash(&operation_hash(TRANSFER_HASH)?)?.is_empty());
+    assert_eq!(vec![ENDORSEMENT_HASH, CALL_HASH], hashes(&index_storage.get_by_account(&baker, None, 0, 10)?));
+    assert_eq!(vec![CALL_HASH], hashes(&index_storage.get_by_kind(OperationKind::Transaction, 0, 10)?));
+    assert_eq!(vec![CALL_HASH], hashes(&index_storage.get_by_account(&baker, Some(OperationKind::Transaction), 0, 10)?));
+
+    Ok(())
+}
+
+fn hashes(operations: &[operations_index_storage::IndexedOperation]) -> Vec<String> {
+    operations.iter()
+        .map(|operation| HashType::OperationHash.bytes_to_string(operation.operation_hash()))
+        .collect()
+}
+
+fn operation_hash(hash: &str) -> Result<Vec<u8>, Error> {
+    Ok(HashType::OperationHash.string_to_bytes(hash)?)
+}
+
+fn json_data(operations: &str) -> BlockJsonData {
+    BlockJsonDataBuilder::default()
+        .block_header_proto_json("{}".to_string())
+        .block_header_proto_metadata_json("{}".to_string())
+        .operations_proto_metadata_json(operations.to_string())
+        .build().unwrap()
+}
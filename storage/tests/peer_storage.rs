@@ -0,0 +1,101 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use failure::Error;
+
+use storage::PeerStorage;
+use storage::peer_storage::MAX_PEER_RECORDS;
+use storage::tests_common::TmpStorage;
+
+#[test]
+fn peer_storage_reliability_order() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__peer_storage_reliability_order")?;
+    let storage = PeerStorage::new(tmp_storage.storage());
+    let now = SystemTime::now();
+
+    let seen: SocketAddr = "10.0.0.1:9732".parse()?;
+    let connected: SocketAddr = "10.0.0.2:9732".parse()?;
+    let failing: SocketAddr = "10.0.0.3:9732".parse()?;
+    let unknown: SocketAddr = "10.0.0.4:9732".parse()?;
+
+    storage.seen(&seen, now)?;
+    storage.seen(&failing, now)?;
+    storage.failed(&failing)?;
+    storage.connected(&connected, "idrRoknJh9zwEePNswF3MPGFzmKaVp", now - Duration::from_secs(60))?;
+    // failures are recorded only for the known addresses
+    storage.failed(&unknown)?;
+    assert!(storage.get(&unknown)?.is_none());
+
+    let records = storage.records()?;
+    let addresses = records.iter().map(|(address, _)| *address).collect::<Vec<_>>();
+    assert_eq!(vec![connected, seen, failing], addresses);
+    assert_eq!(Some(&"idrRoknJh9zwEePNswF3MPGFzmKaVp".to_string()), records[0].1.peer_id().as_ref());
+    assert_eq!(1, records[2].1.failures());
+
+    // successful connection resets failures
+    storage.connected(&failing, "idsg5ha2jqvmBnajw4BYP3ihJRP7Bs", now)?;
+    assert_eq!(0, storage.get(&failing)?.unwrap().failures());
+
+    Ok(())
+}
+
+#[test]
+fn peer_storage_ban_and_prune() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__peer_storage_ban_and_prune")?;
+    let storage = PeerStorage::new(tmp_storage.storage());
+    let now = SystemTime::now();
+    let old = now - Duration::from_secs(3_600);
+
+    let banned: SocketAddr = "10.0.0.1:9732".parse()?;
+    let banned_other_port: SocketAddr = "10.0.0.1:9733".parse()?;
+    let old_peer: SocketAddr = "10.0.0.2:9732".parse()?;
+    storage.seen(&banned, old)?;
+    storage.seen(&banned_other_port, now)?;
+    storage.seen(&old_peer, old)?;
+
+    storage.ban_ip(&banned.ip(), now + Duration::from_secs(600))?;
+    assert!(storage.get(&banned)?.unwrap().is_banned(now));
+    assert!(storage.get(&banned_other_port)?.unwrap().is_banned(now));
+    assert!(!storage.get(&old_peer)?.unwrap().is_banned(now));
+
+    // banned addresses are kept until the ban expires
+    assert_eq!(1, storage.prune(now - Duration::from_secs(60), now)?);
+    assert!(storage.get(&old_peer)?.is_none());
+    assert!(storage.get(&banned)?.is_some());
+    assert_eq!(1, storage.prune(now - Duration::from_secs(60), now + Duration::from_secs(600))?);
+    assert_eq!(1, storage.records()?.len());
+
+    Ok(())
+}
+
+#[test]
+fn peer_storage_capacity() -> Result<(), Error> {
+    let tmp_storage = TmpStorage::create("__peer_storage_capacity")?;
+    let storage = PeerStorage::new(tmp_storage.storage());
+    let old = SystemTime::now() - Duration::from_secs(3_600);
+    let now = SystemTime::now();
+
+    let addresses = (0..MAX_PEER_RECORDS)
+        .map(|port| SocketAddr::from(([10, 0, 0, 1], port as u16)))
+        .collect::<Vec<_>>();
+    storage.seen_all(&addresses, old)?;
+    assert_eq!(MAX_PEER_RECORDS, storage.records()?.len());
+
+    // new address is not added to the full address book, known ones are still updated
+    let new_address: SocketAddr = "10.0.0.2:9732".parse()?;
+    storage.seen_all(&[new_address, addresses[0]], now)?;
+    assert!(storage.get(&new_address)?.is_none());
+    assert_eq!(now, storage.get(&addresses[0])?.unwrap().last_seen());
+    assert_eq!(MAX_PEER_RECORDS, storage.records()?.len());
+
+    // pruned address book has room for the new address again
+    assert_eq!(MAX_PEER_RECORDS - 1, storage.prune(now - Duration::from_secs(60), now)?);
+    storage.seen_all(&[new_address], now)?;
+    assert!(storage.get(&new_address)?.is_some());
+    assert_eq!(2, storage.records()?.len());
+
+    Ok(())
+}
@@ -0,0 +1,77 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use failure::Error;
+
+use crypto::hash::HashType;
+use storage::*;
+use storage::context_action_storage::{ContextActionByContractIndex, ContextActionByContractIndexKey, ContextActionByTypeIndex, ContextActionByTypeIndexKey, ContextActionType};
+use storage::persistent::KeyValueStoreWithSchema;
+use storage::persistent::secondary_index::Index;
+use storage::tests_common::TmpStorage;
+use tezos_context::channel::ContextAction;
+
+#[test]
+fn test_secondary_index_rocksdb() -> Result<(), Error> {
+    check_secondary_index(&TmpStorage::create("__secondary_index_rocksdb")?)
+}
+
+#[test]
+fn test_secondary_index_in_memory() -> Result<(), Error> {
+    check_secondary_index(&TmpStorage::create_in_memory("__secondary_index_in_memory")?)
+}
+
+fn check_secondary_index(tmp_storage: &TmpStorage) -> Result<(), Error> {
+    let block_hash_1 = HashType::BlockHash.string_to_bytes("BKyQ9EofHrgaZKENioHyP4FZNsTmiSEcVmcghgzCC9cGhE7oCET")?;
+    let block_hash_2 = HashType::BlockHash.string_to_bytes("BLaf78njreWdt2WigJjM9e3ecEdVKm5ehahUfYBKvcWvZ8vfTcJ")?;
+    let contract_address = hex::decode("000003cb7d7842406496fc07288635562bfd17e176c4")?;
+
+    let mut storage = ContextActionStorage::new(tmp_storage.storage());
+    storage.put_action(&block_hash_1, set(vec!["hello".to_string()]))?;
+    storage.put_action(&block_hash_1, get(vec!["hello".to_string()]))?;
+    storage.put_action(&block_hash_2, set(contract_key()))?;
+    storage.put_action(&block_hash_2, get(contract_key()))?;
+
+    let type_index = Index::<ContextActionByTypeIndex>::new(tmp_storage.storage().kv());
+    let contract_index = Index::<ContextActionByContractIndex>::new(tmp_storage.storage().kv());
+    let sets: Vec<_> = type_index.prefix(&ContextActionByTypeIndexKey::new(ContextActionType::Set, 0))?.collect::<Result<_, _>>()?;
+    assert_eq!(vec![0, 2], sets);
+    let gets: Vec<_> = type_index.prefix(&ContextActionByTypeIndexKey::new(ContextActionType::Get, 0))?.collect::<Result<_, _>>()?;
+    assert_eq!(vec![1, 3], gets);
+    assert_eq!(
+        vec![2, 1],
+        type_index.range(&ContextActionByTypeIndexKey::new(ContextActionType::Set, 1), &ContextActionByTypeIndexKey::new(ContextActionType::Get, 2))?.collect::<Result<Vec<_>, _>>()?
+    );
+    assert_eq!(2, storage.get_by_contract_address(&contract_address, None, 10)?.len());
+
+    // index entries are removed together with the actions
+    assert_eq!(2, storage.delete_by_block_hash(&block_hash_2)?);
+    assert_eq!(vec![0], type_index.prefix(&ContextActionByTypeIndexKey::new(ContextActionType::Set, 0))?.collect::<Result<Vec<_>, _>>()?);
+    assert!(storage.get_by_contract_address(&contract_address, None, 10)?.is_empty());
+
+    // rebuild drops stale entries and creates missing ones
+    KeyValueStoreWithSchema::<ContextActionByTypeIndex>::delete(tmp_storage.storage().kv().as_ref(), &ContextActionByTypeIndexKey::new(ContextActionType::Set, 0))?;
+    KeyValueStoreWithSchema::<ContextActionByTypeIndex>::put(tmp_storage.storage().kv().as_ref(), &ContextActionByTypeIndexKey::new(ContextActionType::Fold, 7), &())?;
+    // type and time index entries of the two remaining actions
+    assert_eq!(4, storage.rebuild_indexes()?);
+    assert_eq!(vec![0], type_index.prefix(&ContextActionByTypeIndexKey::new(ContextActionType::Set, 0))?.collect::<Result<Vec<_>, _>>()?);
+    assert_eq!(0, type_index.prefix(&ContextActionByTypeIndexKey::new(ContextActionType::Fold, 0))?.count());
+    assert_eq!(0, contract_index.prefix(&ContextActionByContractIndexKey::new(&contract_address, 0))?.count());
+
+    Ok(())
+}
+
+fn contract_key() -> Vec<String> {
+    vec!["data", "contracts", "index", "ad", "af", "43", "23", "f9", "3e", "000003cb7d7842406496fc07288635562bfd17e176c4", "balance"]
+        .into_iter()
+        .map(|key| key.to_string())
+        .collect()
+}
+
+fn set(key: Vec<String>) -> ContextAction {
+    ContextAction::Set { key, value: vec![1], operation_hash: None, block_hash: None, context_hash: None, value_as_json: None, start_time: 0.0, end_time: 0.0, ignored: false }
+}
+
+fn get(key: Vec<String>) -> ContextAction {
+    ContextAction::Get { key, value: vec![1], operation_hash: None, block_hash: None, context_hash: None, value_as_json: None, start_time: 0.0, end_time: 0.0 }
+}
@@ -0,0 +1,84 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+use slog::{Discard, Logger, o};
+
+use crypto::hash::HashType;
+use storage::*;
+use storage::persistent::{CommitLogSchema, DbConfiguration, DbTuning, open_cl_read_only, open_kv_secondary, PersistentStorage};
+use storage::persistent::secondary::spawn_catch_up;
+use storage::skip_list::{Bucket, SkipList, TypedSkipList};
+use storage::tests_common::{test_block, TmpStorage};
+
+#[test]
+fn test_secondary_storage_catch_up() -> Result<(), Error> {
+    let primary_path = "__secondary_storage_primary";
+    let secondary_path = Path::new("__secondary_storage_secondary");
+    if secondary_path.exists() {
+        fs::remove_dir_all(secondary_path)?;
+    }
+
+    let primary = TmpStorage::create(primary_path)?;
+    let primary_block_storage = BlockStorage::new(primary.storage());
+    let genesis = test_block(0, &vec![0; HashType::BlockHash.size()])?;
+    primary_block_storage.put_block_header(&genesis)?;
+    primary.storage().clog().flush()?;
+
+    let secondary = PersistentStorage::new_secondary(
+        Arc::new(open_kv_secondary(primary_path, secondary_path, database_schemas(&DbTuning::default()), &DbConfiguration::default())?),
+        Arc::new(open_cl_read_only(primary_path, secondary_path.join("commit_logs"), vec![BlockStorage::descriptor()])?),
+    );
+    assert!(secondary.is_secondary());
+    let secondary_block_storage = BlockStorage::new(&secondary);
+    assert_eq!(Some(genesis.clone()), secondary_block_storage.get(&genesis.hash)?);
+
+    // new data are visible after the catch-up
+    let block_1 = test_block(1, &genesis.hash)?;
+    primary_block_storage.put_block_header(&block_1)?;
+    primary.storage().clog().flush()?;
+    let mut context_diff = BTreeMap::new();
+    context_diff.insert("data/a".to_string(), Bucket::Exists(vec![1]));
+    primary.storage().context_storage().write().unwrap().push(&context_diff)?;
+    // block meta is written with the merge operator
+    let chain_id = vec![1, 2, 3, 4];
+    BlockMetaStorage::new(primary.storage()).put_block_header(&block_1, &chain_id, &Logger::root(Discard, o!()))?;
+    assert!(secondary_block_storage.get(&block_1.hash)?.is_none());
+    assert_eq!(0, secondary.context_storage().read().unwrap().len());
+
+    secondary.catch_up_with_primary()?;
+    assert_eq!(Some(block_1.clone()), secondary_block_storage.get(&block_1.hash)?);
+    assert_eq!(Some(1), BlockMetaStorage::new(&secondary).get(&block_1.hash)?.map(|meta| meta.level()));
+    // commit logs are read from the copy owned by the secondary storage
+    assert!(secondary_path.join("commit_logs").join(BlockStorage::name()).exists());
+    assert_eq!(1, secondary.context_storage().read().unwrap().len());
+    assert_eq!(Some(Bucket::Exists(vec![1])), secondary.context_storage().read().unwrap().get_key(0, &"data/a".to_string())?);
+
+    // secondary storage is read-only
+    assert!(secondary_block_storage.put_block_header(&test_block(2, &block_1.hash)?).is_err());
+
+    // periodic catch-up
+    let catch_up = spawn_catch_up(&secondary, Duration::from_millis(10), Logger::root(Discard, o!()), || ())?;
+    let block_2 = test_block(2, &block_1.hash)?;
+    primary_block_storage.put_block_header(&block_2)?;
+    primary.storage().clog().flush()?;
+    let mut attempts = 0;
+    while secondary_block_storage.get(&block_2.hash)?.is_none() && attempts < 100 {
+        thread::sleep(Duration::from_millis(10));
+        attempts += 1;
+    }
+    drop(catch_up);
+    assert_eq!(Some(block_2), secondary_block_storage.get(&block_2.hash)?);
+
+    drop(secondary_block_storage);
+    drop(secondary);
+    fs::remove_dir_all(secondary_path)?;
+    Ok(())
+}
@@ -0,0 +1,98 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use failure::Error;
+use maplit::btreemap;
+use slog::{Discard, Logger, o};
+
+use crypto::hash::HashType;
+use storage::*;
+use storage::context_action_storage::ContextActionFilters;
+use storage::persistent::{KeyValueSchema, KeyValueStoreWithSchema, PersistentStorage};
+use storage::persistent::sequence::{SequenceError, Sequences};
+use storage::sequence_recovery::recover_sequences;
+use storage::skip_list::{DatabaseBackedSkipList, SkipListError, TypedSkipList};
+use storage::tests_common::TmpStorage;
+use tezos_context::channel::ContextAction;
+
+#[test]
+fn test_recover_sequences() -> Result<(), Error> {
+    let log = Logger::root(Discard, o!());
+    let tmp_storage = TmpStorage::create("__sequence_recovery")?;
+    let block_hash = HashType::BlockHash.string_to_bytes("BKyQ9EofHrgaZKENioHyP4FZNsTmiSEcVmcghgzCC9cGhE7oCET")?;
+
+    let mut actions = ContextActionStorage::new(tmp_storage.storage());
+    for value in 0..3 {
+        actions.put_action(&block_hash, action(value))?;
+    }
+    let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(1, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator(DatabaseBackedSkipList::name()))?);
+    for value in 0..3 {
+        list.push(&btreemap! { value => value })?;
+    }
+    let max_value_id = DatabaseBackedSkipList::max_value_id(tmp_storage.storage().kv().as_ref())?;
+    assert!(max_value_id.is_some());
+
+    // nothing to recover after a clean run
+    let report = recover_sequences(tmp_storage.storage(), &log)?;
+    assert_eq!(0, report.recovered());
+    assert_eq!(Some(2), report.generators()[0].max_used());
+    assert_eq!(Some(1000), report.generators()[0].persisted());
+
+    // restart with lost pre-allocations
+    lose_preallocations(tmp_storage.storage())?;
+    {
+        let restarted = PersistentStorage::new(tmp_storage.storage().kv(), tmp_storage.storage().clog());
+        let report = recover_sequences(&restarted, &log)?;
+        assert_eq!(2, report.recovered());
+        assert_eq!(Some(0), report.generators()[0].persisted());
+        assert_eq!(max_value_id.map(|id| id as u64), report.generators()[1].max_used());
+
+        restarted.seq().set_audit(true);
+        let mut actions = ContextActionStorage::new(&restarted);
+        actions.put_action(&block_hash, action(3))?;
+        let stored = actions.load_cursor(None, None, ContextActionFilters::with_block_hash(block_hash.clone()))?;
+        assert_eq!(4, stored.len());
+        assert_eq!(Some(3), stored.iter().map(|action| action.id()).max());
+
+        let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(2, restarted.kv(), restarted.seq().generator(DatabaseBackedSkipList::name()))?);
+        list.push(&btreemap! { 1 => 1 })?;
+    }
+
+    // audit detects reuse of the ids without recovery
+    lose_preallocations(tmp_storage.storage())?;
+    {
+        let restarted = PersistentStorage::new(tmp_storage.storage().kv(), tmp_storage.storage().clog());
+        restarted.seq().set_audit(true);
+        let result = ContextActionStorage::new(&restarted).put_action(&block_hash, action(4));
+        assert!(matches!(result, Err(StorageError::SequenceError { error: SequenceError::IdReused { id: 0, .. } })));
+
+        let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(3, restarted.kv(), restarted.seq().generator(DatabaseBackedSkipList::name()))?);
+        let result = list.push(&btreemap! { 1 => 1 });
+        assert!(matches!(result, Err(SkipListError::SequenceError { error: SequenceError::IdReused { id: 0, .. } })));
+    }
+
+    Ok(())
+}
+
+/// Simulate crash, which lost the stored upper bounds of the generators
+fn lose_preallocations(persistent_storage: &PersistentStorage) -> Result<(), Error> {
+    let kv = persistent_storage.kv();
+    for name in &[ContextActionStorage::name(), DatabaseBackedSkipList::name()] {
+        KeyValueStoreWithSchema::<Sequences>::put(kv.as_ref(), &name.to_string(), &0)?;
+    }
+    Ok(())
+}
+
+fn action(value: u8) -> ContextAction {
+    ContextAction::Set {
+        key: vec!["data".to_string(), "value".to_string()],
+        value: vec![value],
+        operation_hash: None,
+        block_hash: None,
+        context_hash: None,
+        value_as_json: None,
+        start_time: 0.0,
+        end_time: 0.0,
+        ignored: false,
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright (c) SimpleStaking and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use maplit::btreemap;
 use rand::{
@@ -11,8 +11,9 @@ use rand::{
 };
 use serde::{Deserialize, Serialize};
 
-use storage::persistent::BincodeEncoded;
-use storage::skip_list::{DatabaseBackedSkipList, TypedSkipList};
+use storage::IteratorMode;
+use storage::persistent::{BincodeEncoded, KeyValueStoreWithSchema};
+use storage::skip_list::{DatabaseBackedSkipList, ListValueBlob, TypedSkipList};
 use storage::tests_common::TmpStorage;
 
 #[derive(PartialEq, Serialize, Deserialize, Clone, Debug)]
@@ -37,14 +38,14 @@ impl BincodeEncoded for Operation {}
 
 #[test]
 fn list_new() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_new").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_new").expect("Storage error");
     let list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(1, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_new")).expect("failed to create skip list"));
     assert_eq!(list.len(), 0);
 }
 
 #[test]
 fn list_push() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_push").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_push").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(2, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_push")).expect("failed to create skip list"));
     list.push(&btreemap! { 1 => 1 }).expect("failed to push value to skip list");
     assert!(list.contains(0));
@@ -63,7 +64,7 @@ fn list_index_level() {
 
 #[test]
 fn list_check_first() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_check_first").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_check_first").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(3, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_check_first")).expect("failed to create skip list"));
     list.push(&btreemap! { 1 => 1 }).expect("failed to push value to skip list");
     let val = list.get(0).expect("failed to get value from skip list");
@@ -74,7 +75,7 @@ fn list_check_first() {
 
 #[test]
 fn list_check_second() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_check_second").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_check_second").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(4, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_check_second")).expect("failed to create skip list"));
     list.push(&btreemap! { 1 => 1 }).expect("failed to push value to skip list");
     list.push(&btreemap! { 2 => 2 }).expect("failed to push value to skip list");
@@ -86,7 +87,7 @@ fn list_check_second() {
 
 #[test]
 fn list_check_bottom_lane() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_check_bottom_lane").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_check_bottom_lane").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(5, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_check_bottom_lane")).expect("failed to create skip list"));
     for index in 0..=6 {
         list.push(&btreemap! { index => index }).expect("failed to push value to skip list");
@@ -100,7 +101,7 @@ fn list_check_bottom_lane() {
 
 #[test]
 pub fn list_check_faster_lane() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_check_faster_lane").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_check_faster_lane").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(6, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_check_faster_lane")).expect("failed to create skip list"));
     for index in 0..=7 {
         list.push(&btreemap! { index => index }).expect("failed to push value to skip list");
@@ -114,7 +115,7 @@ pub fn list_check_faster_lane() {
 
 #[test]
 pub fn list_check_lane_traversal() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_check_lane_traversal").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_check_lane_traversal").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(7, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_check_lane_traversal")).expect("failed to create skip list"));
     for index in 0..=63 {
         list.push(&btreemap! { index => index }).expect("failed to push value to skip list");
@@ -128,7 +129,7 @@ pub fn list_check_lane_traversal() {
 
 #[test]
 pub fn list_get_value_by_key() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_get_value_by_key").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_get_value_by_key").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(8, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_get_value_by_key")).expect("failed to create skip list"));
     for index in 0..=63 {
         list.push(&btreemap! { index => index * 5 }).expect("failed to push value to skip list");
@@ -144,7 +145,7 @@ pub fn list_get_value_by_key() {
 
 #[test]
 pub fn list_get_values_by_prefix() -> Result<(), failure::Error> {
-    let tmp_storage = TmpStorage::create("__skip_list:list_get_values_by_prefix").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_get_values_by_prefix").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<String, i32>> = Box::new(DatabaseBackedSkipList::new(9, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_get_values_by_prefix")).expect("failed to create skip list"));
 
     list.push(&btreemap! { String::from("/system") => 10 })?;
@@ -185,7 +186,7 @@ pub fn list_get_values_by_prefix() -> Result<(), failure::Error> {
 
 #[test]
 pub fn list_check_lane_order_traversal() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_check_lane_order_traversal").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_check_lane_order_traversal").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(8, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_check_lane_order_traversal")).expect("failed to create skip list"));
     for (value, key) in (0..=63).zip((0..=7).cycle()) {
         let mut map = BTreeMap::new();
@@ -205,7 +206,7 @@ pub fn list_check_lane_order_traversal() {
 
 #[test]
 pub fn list_check_get_key() {
-    let tmp_storage = TmpStorage::create("__skip_list:list_check_get_key").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_check_get_key").expect("Storage error");
     let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(8, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_check_get_key")).expect("failed to create skip list"));
     for x in 0..=7 {
         let mut map = BTreeMap::new();
@@ -219,9 +220,114 @@ pub fn list_check_get_key() {
     assert_eq!(val.unwrap(), None);
 }
 
+#[test]
+pub fn list_prune_keeps_recent_states() {
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_prune_keeps_recent_states").expect("Storage error");
+    let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(8, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_prune_keeps_recent_states")).expect("failed to create skip list"));
+    for value in 0..300 {
+        let mut map = BTreeMap::new();
+        map.insert(value % 10, value);
+        list.push(&map).expect("failed to store value into skip list");
+    }
+    let expected: Vec<_> = (0..300).map(|index| list.get(index).expect("failed to get value from skip list")).collect();
+
+    let removed = list.prune(0, 100).expect("failed to prune skip list");
+    assert!(removed > 0);
+    let removed = list.prune(100, 200).expect("failed to prune skip list");
+    assert!(removed > 0);
+
+    assert_eq!(list.len(), 300);
+    for index in 200..300 {
+        assert_eq!(expected[index], list.get(index).expect("failed to get value from skip list"));
+        assert_eq!(expected[index].as_ref().unwrap().get(&3).cloned(), list.get_key(index, &3).expect("failed to get key from skip list"));
+    }
+}
+
+#[test]
+pub fn list_large_values_are_deduplicated() {
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_large_values_are_deduplicated").expect("Storage error");
+    let mut list: Box<dyn TypedSkipList<i32, Vec<u8>>> = Box::new(DatabaseBackedSkipList::new(8, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_large_values_are_deduplicated")).expect("failed to create skip list"));
+    let large_value = vec![7; 100];
+    // small value, which looks like a reference to a blob
+    let tagged_value = vec![0xff, 1, 2];
+    for index in 0..16 {
+        let mut map = BTreeMap::new();
+        map.insert(index, large_value.clone());
+        map.insert(-1, tagged_value.clone());
+        list.push(&map).expect("failed to store value into skip list");
+    }
+
+    let state = list.get(15).expect("failed to get value from skip list").unwrap();
+    assert_eq!(17, state.len());
+    assert_eq!(Some(&large_value), state.get(&15));
+    assert_eq!(Some(&tagged_value), state.get(&-1));
+    assert_eq!(Some(large_value.clone()), list.get_key(15, &0).expect("failed to get key from skip list"));
+
+    // large value is stored just once for all indexes and lanes
+    let kv = tmp_storage.storage().kv();
+    assert_eq!(1, KeyValueStoreWithSchema::<ListValueBlob>::iterator(kv.as_ref(), IteratorMode::Start).expect("failed to iterate blobs").count());
+}
+
+#[test]
+pub fn list_retain_and_collect_garbage() {
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_retain_and_collect_garbage").expect("Storage error");
+    let mut list: Box<dyn TypedSkipList<i32, Vec<u8>>> = Box::new(DatabaseBackedSkipList::new(8, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_retain_and_collect_garbage")).expect("failed to create skip list"));
+    for index in 0..100 {
+        let mut map = BTreeMap::new();
+        map.insert(index % 10, vec![index as u8; 100]);
+        list.push(&map).expect("failed to store value into skip list");
+    }
+    let expected: Vec<_> = (0..100).map(|index| list.get(index).expect("failed to get value from skip list")).collect();
+
+    let retained: BTreeSet<usize> = std::iter::once(0).chain(70..80).collect();
+    assert!(list.retain(&retained).expect("failed to retain skip list") > 0);
+    assert!(list.collect_garbage().expect("failed to collect garbage") > 0);
+    // nothing else can be removed
+    assert_eq!(0, list.retain(&retained).expect("failed to retain skip list"));
+    assert_eq!(0, list.collect_garbage().expect("failed to collect garbage"));
+
+    // retained and the last state can be rebuilt
+    for index in retained.iter().cloned().chain(std::iter::once(99)) {
+        assert_eq!(expected[index], list.get(index).expect("failed to get value from skip list"));
+        assert_eq!(expected[index].as_ref().unwrap().get(&5).cloned(), list.get_key(index, &5).expect("failed to get key from skip list"));
+    }
+
+    // list can still grow
+    let mut map = BTreeMap::new();
+    map.insert(1, vec![1; 100]);
+    list.push(&map).expect("failed to store value into skip list");
+    let mut state = expected[99].clone().unwrap();
+    state.insert(1, vec![1; 100]);
+    assert_eq!(Some(state), list.get(100).expect("failed to get value from skip list"));
+}
+
+#[test]
+pub fn list_start_at() {
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:list_start_at").expect("Storage error");
+    let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(8, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_start_at")).expect("failed to create skip list"));
+    list.start_at(1000).expect("failed to start skip list");
+    assert_eq!(1000, list.len());
+    for value in 0..20 {
+        list.push(&btreemap! { value % 5 => value }).expect("failed to store value into skip list");
+    }
+
+    assert_eq!(1020, list.len());
+    assert!(!list.contains(999));
+    assert!(list.contains(1000));
+    assert_eq!(None, list.get(999).expect("failed to get value from skip list"));
+    assert_eq!(Some(btreemap! { 0 => 0 }), list.get(1000).expect("failed to get value from skip list"));
+    assert_eq!(Some(btreemap! { 0 => 15, 1 => 16, 2 => 17, 3 => 18, 4 => 19 }), list.get(1019).expect("failed to get value from skip list"));
+    assert_eq!(Some(12), list.get_key(1012, &2).expect("failed to get key from skip list"));
+
+    // state with the start is stored, but not empty list cannot be moved
+    let mut list: Box<dyn TypedSkipList<i32, i32>> = Box::new(DatabaseBackedSkipList::new(8, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:list_start_at")).expect("failed to create skip list"));
+    assert_eq!(1020, list.len());
+    assert!(list.start_at(0).is_err());
+}
+
 #[test]
 pub fn skip_list_simulate_ledger() {
-    let tmp_storage = TmpStorage::create("__skip_list:skip_list_simulate_ledger").expect("Storage error");
+    let tmp_storage = TmpStorage::create_in_memory("__skip_list:skip_list_simulate_ledger").expect("Storage error");
     let list: Box<dyn TypedSkipList<u64, Operation>> = Box::new(DatabaseBackedSkipList::new(8, tmp_storage.storage().kv(), tmp_storage.storage().seq().generator("__skip_list:skip_list_simulate_ledger")).expect("failed to create skip list"));
     simulate_ledger(list);
 }
@@ -0,0 +1,217 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+
+use crypto::hash::{ChainId, HashType};
+use storage::*;
+use storage::chain_meta_storage::ChainMetaStorageReader;
+use storage::merkle_storage::{Entry, MerkleStorage};
+use storage::persistent::KeyValueStoreWithSchema;
+use storage::skip_list::Bucket;
+use storage::snapshot::{export_snapshot, import_snapshot, protocol_context_path, SnapshotError, SnapshotMode};
+use storage::tests_common::{store_test_chain, TmpStorage};
+
+mod common;
+
+#[test]
+fn test_snapshot_export_import() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let source_storage = TmpStorage::create(test_storage_dir_path("__snapshot_export_source"))?;
+    let blocks = store_test_chain(source_storage.storage(), &chain_id, 4, &log)?;
+    let head = blocks.last().unwrap();
+
+    let source_tezos_data_dir = test_tezos_data_dir("__snapshot_export_source_tezos_data", true)?;
+
+    // export just last two blocks
+    let snapshot_path = test_snapshot_path("__snapshot_export_import.snapshot")?;
+    let header = export_snapshot(source_storage.storage(), &source_tezos_data_dir, &head.hash, SnapshotMode::Rolling(2), &snapshot_path, &log)?;
+    assert_eq!(&head.hash, header.block_hash());
+    assert_eq!(head.header.level(), header.level());
+    assert!(protocol_context_path(&snapshot_path).join("store").exists());
+
+    // import to empty storage
+    let target_storage = TmpStorage::create(test_storage_dir_path("__snapshot_import_target"))?;
+    let target_tezos_data_dir = test_tezos_data_dir("__snapshot_import_target_tezos_data", false)?;
+    let _ = import_snapshot(target_storage.storage(), &target_tezos_data_dir, &chain_id, &snapshot_path, &log)?;
+    assert_eq!(b"store".to_vec(), fs::read(target_tezos_data_dir.join("context").join("store"))?);
+
+    let current_head = ChainMetaStorage::new(target_storage.storage()).get_current_head(&chain_id)?.expect("Current head should be set");
+    assert_eq!(head.hash, current_head.hash);
+    assert_eq!(head.header.level(), current_head.level);
+
+    let block_storage = BlockStorage::new(target_storage.storage());
+    let block_meta_storage = BlockMetaStorage::new(target_storage.storage());
+    for block in &blocks[2..] {
+        assert_eq!(block, &block_storage.get(&block.hash)?.expect("Block should be imported"));
+        assert!(block_storage.get_with_json_data(&block.hash)?.is_some());
+        assert!(block_meta_storage.get(&block.hash)?.expect("Block meta should be imported").is_applied());
+        assert!(OperationsMetaStorage::new(target_storage.storage()).is_complete(&block.hash)?);
+    }
+    for block in &blocks[..2] {
+        assert!(block_storage.get(&block.hash)?.is_none());
+    }
+    assert!(block_meta_storage.get(&head.hash)?.unwrap().successor().is_none());
+
+    // context contains data from all previous levels
+    assert_eq!(head.hash, block_storage.get_by_context_hash(head.header.context())?.unwrap().hash);
+    let context = target_storage.storage().context_storage();
+    let context = context.read().unwrap();
+    for level in 0..=head.header.level() {
+        assert_eq!(Some(Bucket::Exists(vec![level as u8])), context.get_key(head.header.level() as usize, &format!("data/level/{}", level))?);
+    }
+    // context is imported just for the head level
+    assert_eq!(head.header.level() as usize + 1, context.len());
+    assert!(context.get(head.header.level() as usize - 1)?.is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_import_with_invalid_context_hash_fails() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let source_storage = TmpStorage::create(test_storage_dir_path("__snapshot_invalid_context_source"))?;
+    let blocks = store_test_chain(source_storage.storage(), &chain_id, 2, &log)?;
+    let head = blocks.last().unwrap();
+
+    // commit metadata does not match the context hash anymore
+    let merkle_kv = source_storage.storage().kv();
+    let mut commit = MerkleStorage::new(source_storage.storage()).get_commit(head.header.context())?;
+    commit.message = "tampered".to_string();
+    KeyValueStoreWithSchema::<MerkleStorage>::put(merkle_kv.as_ref(), head.header.context(), &Entry::Commit(commit))?;
+
+    let source_tezos_data_dir = test_tezos_data_dir("__snapshot_invalid_context_source_tezos_data", true)?;
+    let snapshot_path = test_snapshot_path("__snapshot_invalid_context.snapshot")?;
+    let _ = export_snapshot(source_storage.storage(), &source_tezos_data_dir, &head.hash, SnapshotMode::Rolling(1), &snapshot_path, &log)?;
+
+    let target_storage = TmpStorage::create(test_storage_dir_path("__snapshot_invalid_context_target"))?;
+    let target_tezos_data_dir = test_tezos_data_dir("__snapshot_invalid_context_target_tezos_data", false)?;
+    match import_snapshot(target_storage.storage(), &target_tezos_data_dir, &chain_id, &snapshot_path, &log) {
+        Err(SnapshotError::InvalidSnapshot { .. }) => (),
+        _ => panic!("Import of snapshot with invalid context hash should fail"),
+    }
+    assert!(ChainMetaStorage::new(target_storage.storage()).get_current_head(&chain_id)?.is_none());
+    assert_eq!(0, target_storage.storage().context_storage().read().unwrap().len());
+
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_import_with_too_large_record_fails() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    // valid magic followed by the corrupted record length
+    let snapshot_path = test_snapshot_path("__snapshot_too_large_record.snapshot")?;
+    let mut bytes = b"TZEDGESN".to_vec();
+    bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+    fs::write(&snapshot_path, bytes)?;
+
+    let target_storage = TmpStorage::create(test_storage_dir_path("__snapshot_too_large_record_target"))?;
+    let target_tezos_data_dir = test_tezos_data_dir("__snapshot_too_large_record_target_tezos_data", true)?;
+    match import_snapshot(target_storage.storage(), &target_tezos_data_dir, &chain_id, &snapshot_path, &log) {
+        Err(SnapshotError::RecordTooLarge { .. }) => Ok(()),
+        _ => panic!("Import of snapshot with too large record should fail"),
+    }
+}
+
+#[test]
+fn test_snapshot_import_to_not_empty_storage_fails() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let storage = TmpStorage::create(test_storage_dir_path("__snapshot_not_empty"))?;
+    let blocks = store_test_chain(storage.storage(), &chain_id, 2, &log)?;
+    let tezos_data_dir = test_tezos_data_dir("__snapshot_not_empty_tezos_data", true)?;
+    let snapshot_path = test_snapshot_path("__snapshot_not_empty.snapshot")?;
+    let _ = export_snapshot(storage.storage(), &tezos_data_dir, &blocks.last().unwrap().hash, SnapshotMode::Rolling(1), &snapshot_path, &log)?;
+
+    match import_snapshot(storage.storage(), &tezos_data_dir, &chain_id, &snapshot_path, &log) {
+        Err(SnapshotError::StorageNotEmpty) => Ok(()),
+        _ => panic!("Import to not empty storage should fail"),
+    }
+}
+
+#[test]
+fn test_snapshot_import_wrong_chain_fails() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let source_storage = TmpStorage::create(test_storage_dir_path("__snapshot_wrong_chain_source"))?;
+    let blocks = store_test_chain(source_storage.storage(), &chain_id, 2, &log)?;
+    let source_tezos_data_dir = test_tezos_data_dir("__snapshot_wrong_chain_source_tezos_data", true)?;
+    let snapshot_path = test_snapshot_path("__snapshot_wrong_chain.snapshot")?;
+    let _ = export_snapshot(source_storage.storage(), &source_tezos_data_dir, &blocks.last().unwrap().hash, SnapshotMode::Rolling(1), &snapshot_path, &log)?;
+
+    let target_storage = TmpStorage::create(test_storage_dir_path("__snapshot_wrong_chain_target"))?;
+    let target_tezos_data_dir = test_tezos_data_dir("__snapshot_wrong_chain_target_tezos_data", false)?;
+    let other_chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXdQprcVkpaWU")?;
+    match import_snapshot(target_storage.storage(), &target_tezos_data_dir, &other_chain_id, &snapshot_path, &log) {
+        Err(SnapshotError::InvalidSnapshot { .. }) => Ok(()),
+        _ => panic!("Import of snapshot for another chain should fail"),
+    }
+}
+
+#[test]
+fn test_snapshot_import_without_protocol_context_fails() -> Result<(), Error> {
+    let log = common::create_logger();
+    let chain_id: ChainId = HashType::ChainId.string_to_bytes("NetXgtSLGNJvNye")?;
+
+    let source_storage = TmpStorage::create(test_storage_dir_path("__snapshot_no_context_source"))?;
+    let blocks = store_test_chain(source_storage.storage(), &chain_id, 2, &log)?;
+    // source node has no protocol context, so nothing is exported next to the snapshot
+    let source_tezos_data_dir = test_tezos_data_dir("__snapshot_no_context_source_tezos_data", false)?;
+    let snapshot_path = test_snapshot_path("__snapshot_no_context.snapshot")?;
+    let _ = export_snapshot(source_storage.storage(), &source_tezos_data_dir, &blocks.last().unwrap().hash, SnapshotMode::Rolling(1), &snapshot_path, &log)?;
+    assert!(!protocol_context_path(&snapshot_path).exists());
+
+    let target_storage = TmpStorage::create(test_storage_dir_path("__snapshot_no_context_target"))?;
+    let target_tezos_data_dir = test_tezos_data_dir("__snapshot_no_context_target_tezos_data", false)?;
+    match import_snapshot(target_storage.storage(), &target_tezos_data_dir, &chain_id, &snapshot_path, &log) {
+        Err(SnapshotError::MissingProtocolContext { .. }) => (),
+        _ => panic!("Import without protocol context should fail"),
+    }
+    assert!(ChainMetaStorage::new(target_storage.storage()).get_current_head(&chain_id)?.is_none());
+
+    Ok(())
+}
+
+/// Snapshot path without leftovers of the previous runs
+fn test_snapshot_path(name: &str) -> Result<PathBuf, Error> {
+    let path = test_storage_dir_path(name);
+    let context_path = protocol_context_path(&path);
+    if context_path.exists() {
+        fs::remove_dir_all(context_path)?;
+    }
+    Ok(path)
+}
+
+/// Empty tezos data dir, `with_context` creates dummy protocol context in it
+fn test_tezos_data_dir(name: &str, with_context: bool) -> Result<PathBuf, Error> {
+    let path = test_storage_dir_path(name);
+    if path.exists() {
+        fs::remove_dir_all(&path)?;
+    }
+    fs::create_dir_all(&path)?;
+    if with_context {
+        fs::create_dir_all(path.join("context"))?;
+        fs::write(path.join("context").join("store"), b"store")?;
+    }
+    Ok(path)
+}
+
+pub fn test_storage_dir_path(dir_name: &str) -> PathBuf {
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is not defined");
+    let path = Path::new(out_dir.as_str())
+        .join(Path::new(dir_name))
+        .to_path_buf();
+    path
+}
@@ -13,7 +13,6 @@ use storage::chain_meta_storage::ChainMetaStorageReader;
 use storage::tests_common::TmpStorage;
 use tezos_api::environment::TezosEnvironmentConfiguration;
 use tezos_api::ffi::{ApplyBlockResponse, CommitGenesisResult, GenesisChain, ProtocolOverrides};
-use tezos_messages::Head;
 use tezos_messages::p2p::binary_message::BinaryMessage;
 use tezos_messages::p2p::encoding::prelude::*;
 
@@ -132,20 +131,13 @@ fn test_storage() -> Result<(), Error> {
     let (block_json_data, block_additional_data) = store_applied_block_result(
         &block_storage,
         &block_meta_storage,
+        &chain_meta_storage,
+        &init_data.chain_id,
         &block.hash,
         apply_result.clone(),
         &mut metadata,
     )?;
 
-    // set block as current head
-    chain_meta_storage.set_current_head(
-        &init_data.chain_id,
-        &Head {
-            hash: block.hash.clone(),
-            level: block.header.level(),
-        },
-    )?;
-
     // check if data stored
     assert!(metadata.is_applied());
     let metadata = block_meta_storage.get(&block.hash)?.expect("No metadata was found");
@@ -171,6 +163,7 @@ fn test_storage() -> Result<(), Error> {
     let current_head = chain_meta_storage.get_current_head(&init_data.chain_id)?;
     let current_head = current_head.expect("Current header should be set");
     assert_eq!(current_head.hash, block.hash);
+    assert_eq!(current_head.level, block.header.level());
 
     Ok(())
 }
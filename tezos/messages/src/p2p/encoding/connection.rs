@@ -22,6 +22,7 @@ pub struct ConnectionMessage {
     pub versions: Vec<NetworkVersion>,
     #[get = "pub"]
     pub public_key: Vec<u8>,
+    #[get = "pub"]
     pub proof_of_work_stamp: Vec<u8>,
     pub message_nonce: Vec<u8>,
 }
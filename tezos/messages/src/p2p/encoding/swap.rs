@@ -21,6 +21,16 @@ pub struct SwapMessage {
     body: BinaryDataCache,
 }
 
+impl SwapMessage {
+    pub fn new(point: String, peer_id: String) -> Self {
+        Self {
+            point,
+            peer_id,
+            body: Default::default(),
+        }
+    }
+}
+
 cached_data!(SwapMessage, body);
 has_encoding!(SwapMessage, SWAP_MESSAGE_ENCODING, {
         Encoding::Obj(vec![
@@ -42,6 +42,16 @@ impl NetworkVersion {
     pub fn supports(&self, other: &NetworkVersion) -> bool {
         self.chain_name == other.chain_name && self.distributed_db_version == other.distributed_db_version
     }
+
+    #[inline]
+    pub fn p2p_version(&self) -> u16 {
+        self.p2p_version
+    }
+
+    /// Returns true if the other version belongs to the same chain.
+    pub fn is_same_chain(&self, other: &NetworkVersion) -> bool {
+        self.chain_name == other.chain_name
+    }
 }
 
 cached_data!(NetworkVersion, body);